@@ -4,22 +4,51 @@ extern crate tower_web;
 
 use bytes::Bytes;
 use futures::Future;
+use interledger_ccp::RouteManagerStatus;
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::Address;
 use interledger_router::RouterStore;
 use interledger_service::{Account as AccountTrait, IncomingService};
-use interledger_service_util::{BalanceStore, ExchangeRateStore};
+use interledger_service_util::{BalanceStore, ExchangeRateStore, Pinger};
 use interledger_settlement::{SettlementAccount, SettlementStore};
-use serde::Serialize;
-use std::str;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{str, sync::Arc};
 use tower_web::{net::ConnectionStream, Extract, Response, ServiceBuilder};
 
+mod node_state;
 mod routes;
+pub use self::node_state::{
+    import_node_state, parse_transport_key, AccountState, ExportableAccount, IdempotentDataState,
+    NodeState, NodeStateStore, NODE_STATE_VERSION,
+};
 use self::routes::*;
 
 pub(crate) const BEARER_TOKEN_START: usize = 7;
 
+/// Whether the Authorization header holds the admin API token. The tokens are compared in
+/// constant time so the comparison does not reveal how much of the token was right
+pub(crate) fn is_admin_token(authorization: &str, admin_api_token: &str) -> bool {
+    authorization
+        .get(BEARER_TOKEN_START..)
+        .map(|token| {
+            let (token, admin_api_token) = (token.as_bytes(), admin_api_token.as_bytes());
+            token.len() == admin_api_token.len()
+                && token
+                    .iter()
+                    .zip(admin_api_token)
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+        .unwrap_or(false)
+}
+
+/// The first segments of the API's paths, which would be ambiguous as usernames
+const RESERVED_USERNAMES: &[&str] = &[
+    "accounts", "ccp", "ilp", "node", "pay", "ping", "rates", "routes", "settings", "spsp",
+];
+
 pub trait NodeStore: Clone + Send + Sync + 'static {
     type Account: AccountTrait;
 
@@ -31,6 +60,12 @@ pub trait NodeStore: Clone + Send + Sync + 'static {
     // TODO limit the number of results and page through them
     fn get_all_accounts(&self) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send>;
 
+    /// Look up the ID of the account with the given username (usernames are case-insensitive)
+    fn get_account_id_from_username(
+        &self,
+        username: &str,
+    ) -> Box<dyn Future<Item = <Self::Account as AccountTrait>::AccountId, Error = ()> + Send>;
+
     fn set_rates<R>(&self, rates: R) -> Box<dyn Future<Item = (), Error = ()> + Send>
     where
         R: IntoIterator<Item = (String, f64)>;
@@ -44,12 +79,31 @@ pub trait NodeStore: Clone + Send + Sync + 'static {
         prefix: String,
         account_id: <Self::Account as AccountTrait>::AccountId,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Move the addresses of the child accounts under the given node address, for example
+    /// once the node has gotten its own address from its parent. Each child keeps the last
+    /// segment of its configured address, so "local.alice" becomes "<node address>.alice".
+    fn update_child_addresses(
+        &self,
+        node_address: Address,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Change the asset an account is denominated in, for example to match the asset
+    /// the parent reported over ILDCP
+    fn set_account_asset(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+        asset_code: String,
+        asset_scale: u8,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
 }
 
 /// The Account type for the RedisStore.
-#[derive(Debug, Extract, Response, Clone)]
+#[derive(Debug, Extract, Response, Clone, Serialize, Deserialize)]
 pub struct AccountDetails {
     pub ilp_address: Address,
+    /// Human-readable name used in payment pointers instead of the account ID
+    pub username: Option<String>,
     pub asset_code: String,
     pub asset_scale: u8,
     #[serde(default = "u64::max_value")]
@@ -67,6 +121,23 @@ pub struct AccountDetails {
     #[serde(default)]
     pub receive_routes: bool,
     pub routing_relation: Option<String>,
+    /// ILP address prefixes this account may advertise routes for. Children are always
+    /// limited to routes under their own address
+    pub allowed_route_prefixes: Option<Vec<String>>,
+    /// Routes for these prefixes will not be accepted from this account
+    pub route_import_deny: Option<Vec<String>>,
+    /// Routes with longer paths will not be accepted from this account
+    pub route_import_max_path_length: Option<u32>,
+    /// If set, only routes for these prefixes will be sent to this account
+    pub route_export_allow: Option<Vec<String>>,
+    /// Routes for these prefixes will not be sent to this account
+    pub route_export_deny: Option<Vec<String>>,
+    /// Routes with longer paths will not be sent to this account
+    pub route_export_max_path_length: Option<u32>,
+    /// Whether to send this account the routes we learned from our parents (defaults to true)
+    pub advertise_parent_routes: Option<bool>,
+    /// Cost of forwarding packets to this account, used when the node picks routes by cost
+    pub route_cost: Option<u32>,
     pub round_trip_time: Option<u64>,
     pub amount_per_minute_limit: Option<u64>,
     pub packets_per_minute_limit: Option<u32>,
@@ -74,12 +145,41 @@ pub struct AccountDetails {
     pub settlement_engine_asset_scale: Option<u8>,
 }
 
+impl AccountDetails {
+    /// The username in lowercase, or an error if it is not valid. Usernames are used
+    /// in payment pointers so they may only contain ASCII letters, digits, `-` and `_`,
+    /// and they cannot be the same as one of the API's paths
+    pub fn validated_username(&self) -> Result<Option<String>, ()> {
+        if let Some(ref username) = self.username {
+            let username = username.to_lowercase();
+            if username.is_empty()
+                || !username
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                error!("Invalid username: {}", username);
+                return Err(());
+            }
+            if RESERVED_USERNAMES.contains(&username.as_str()) {
+                error!("Username is reserved for the API: {}", username);
+                return Err(());
+            }
+            Ok(Some(username))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
 pub struct NodeApi<S, I> {
     store: S,
     admin_api_token: String,
     default_spsp_account: Option<String>,
+    spsp_domain: Option<String>,
     incoming_handler: I,
     server_secret: Bytes,
+    route_manager: Option<Arc<dyn RouteManagerStatus>>,
+    pinger: Option<Pinger>,
 }
 
 impl<S, I, A> NodeApi<S, I>
@@ -88,10 +188,11 @@ where
         + HttpStore<Account = A>
         + BalanceStore<Account = A>
         + SettlementStore<Account = A>
+        + NodeStateStore<Account = A>
         + RouterStore
         + ExchangeRateStore,
     I: IncomingService<A> + Clone + Send + Sync + 'static,
-    A: AccountTrait
+    A: AccountTrait<AccountId = u64>
         + HttpAccount
         + IldcpAccount
         + SettlementAccount
@@ -110,8 +211,11 @@ where
             store,
             admin_api_token,
             default_spsp_account: None,
+            spsp_domain: None,
             incoming_handler,
             server_secret,
+            route_manager: None,
+            pinger: None,
         }
     }
 
@@ -120,6 +224,24 @@ where
         self
     }
 
+    /// Serve payment pointers like `$alice.<domain>` for the accounts' usernames
+    pub fn spsp_domain(&mut self, domain: String) -> &mut Self {
+        self.spsp_domain = Some(domain);
+        self
+    }
+
+    /// Expose the state of the node's CCP route manager through the admin API
+    pub fn route_manager(&mut self, route_manager: Arc<dyn RouteManagerStatus>) -> &mut Self {
+        self.route_manager = Some(route_manager);
+        self
+    }
+
+    /// Let admins ping other nodes through the API
+    pub fn pinger(&mut self, pinger: Pinger) -> &mut Self {
+        self.pinger = Some(pinger);
+        self
+    }
+
     pub fn serve<T>(&self, incoming: T) -> impl Future<Item = (), Error = ()>
     where
         T: ConnectionStream,
@@ -130,6 +252,30 @@ where
                 self.store.clone(),
                 self.incoming_handler.clone(),
             ))
+            .resource(AccountsApi::new(
+                self.admin_api_token.clone(),
+                self.store.clone(),
+            ))
+            .resource(SettingsApi::new(
+                self.admin_api_token.clone(),
+                self.store.clone(),
+            ))
+            .resource(NodeStateApi::new(
+                self.admin_api_token.clone(),
+                self.store.clone(),
+            ))
+            .resource(CcpApi::new(
+                self.admin_api_token.clone(),
+                self.route_manager.clone(),
+            ))
+            .resource(PingApi::new(
+                self.admin_api_token.clone(),
+                self.store.clone(),
+                self.incoming_handler.clone(),
+                self.pinger.clone(),
+            ))
+            // Routes are matched in order so this must come last,
+            // otherwise GET /:username would shadow the other APIs
             .resource({
                 let mut spsp = SpspApi::new(
                     self.server_secret.clone(),
@@ -139,16 +285,11 @@ where
                 if let Some(account_id) = &self.default_spsp_account {
                     spsp.default_spsp_account(account_id.clone());
                 }
+                if let Some(domain) = &self.spsp_domain {
+                    spsp.spsp_domain(domain.clone());
+                }
                 spsp
             })
-            .resource(AccountsApi::new(
-                self.admin_api_token.clone(),
-                self.store.clone(),
-            ))
-            .resource(SettingsApi::new(
-                self.admin_api_token.clone(),
-                self.store.clone(),
-            ))
             .serve(incoming)
     }
 }
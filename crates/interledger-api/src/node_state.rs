@@ -0,0 +1,622 @@
+use crate::{AccountDetails, NodeStore};
+use bytes::Bytes;
+use futures::{
+    future::{err, Either},
+    Future,
+};
+use http::StatusCode;
+use interledger_btp::BtpAccount;
+use interledger_ccp::CcpRoutingAccount;
+use interledger_http::HttpAccount;
+use interledger_ildcp::IldcpAccount;
+use interledger_service::Account as AccountTrait;
+use interledger_service_util::{MaxPacketAmountAccount, RateLimitAccount, RoundTripTimeAccount};
+use interledger_settlement::IdempotentStore;
+use log::{debug, error, warn};
+use ring::{
+    aead,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    str,
+};
+use url::Url;
+
+/// Version of the exported state document. Bump it whenever the format changes
+/// in a way older nodes cannot import
+pub const NODE_STATE_VERSION: u32 = 1;
+
+const NONCE_LENGTH: usize = 12;
+
+/// Everything a node needs to carry on where another one left off, in a form
+/// that does not depend on the store either of them uses.
+///
+/// Stores only keep HMACs of incoming tokens, so those cannot be exported. Exports
+/// fail if any account has them, unless the operator chooses to drop them, see
+/// `check_incoming_tokens`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeState {
+    pub version: u32,
+    pub accounts: Vec<AccountState>,
+    /// Static routes, pointing to the `id`s of the exported accounts
+    pub static_routes: BTreeMap<String, u64>,
+    pub rates: BTreeMap<String, f64>,
+    /// Saved responses of the settlement API, so that requests retried with the
+    /// same idempotency key are not applied twice after the import
+    pub idempotent_data: Vec<IdempotentDataState>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccountState {
+    /// ID of the account in the exported store. The importing store assigns its own
+    pub id: u64,
+    pub details: AccountDetails,
+    pub balance: i64,
+    pub prepaid_amount: i64,
+    /// Whether the account has incoming tokens in the exported store, which only
+    /// keeps their HMACs
+    #[serde(default)]
+    pub has_incoming_tokens: bool,
+}
+
+/// The settings of an account that the other account traits do not expose, which
+/// `AccountDetails::from_account` needs to export it
+pub trait ExportableAccount:
+    IldcpAccount
+    + HttpAccount
+    + BtpAccount
+    + CcpRoutingAccount
+    + MaxPacketAmountAccount
+    + RateLimitAccount
+    + RoundTripTimeAccount
+{
+    fn username(&self) -> Option<&str>;
+    fn min_balance(&self) -> Option<i64>;
+    fn settle_threshold(&self) -> Option<i64>;
+    fn settle_to(&self) -> Option<i64>;
+    fn settlement_engine_url(&self) -> Option<&Url>;
+    fn settlement_engine_asset_scale(&self) -> Option<u8>;
+}
+
+impl AccountDetails {
+    /// The details the account could be created from again, for exporting it. The
+    /// outgoing BTP token goes back into the BTP URI. Incoming tokens are left out
+    /// because the stores only keep their HMACs
+    pub fn from_account<A: ExportableAccount>(account: &A) -> Self {
+        let btp_uri = account.get_btp_uri().map(|uri| {
+            let mut uri = uri.clone();
+            if let Some(token) = account
+                .get_btp_token()
+                .and_then(|token| str::from_utf8(token).ok())
+            {
+                uri.set_password(Some(token)).unwrap();
+            }
+            uri.into_string()
+        });
+        let import_policy = account.route_import_policy();
+        let export_policy = account.route_export_policy();
+        AccountDetails {
+            ilp_address: account.client_address().clone(),
+            username: account.username().map(String::from),
+            asset_code: account.asset_code().to_string(),
+            asset_scale: account.asset_scale(),
+            max_packet_amount: account.max_packet_amount(),
+            min_balance: account.min_balance(),
+            http_endpoint: account.get_http_url().map(Url::to_string),
+            http_incoming_token: None,
+            http_outgoing_token: account.get_http_auth_token().map(String::from),
+            btp_uri,
+            btp_incoming_token: None,
+            settle_threshold: account.settle_threshold(),
+            settle_to: account.settle_to(),
+            send_routes: account.should_send_routes(),
+            receive_routes: account.should_receive_routes(),
+            routing_relation: Some(account.routing_relation().to_string()),
+            allowed_route_prefixes: import_policy.allow.as_ref().map(|p| from_prefixes(p)),
+            route_import_deny: Some(from_prefixes(&import_policy.deny))
+                .filter(|deny| !deny.is_empty()),
+            route_import_max_path_length: import_policy.max_path_length.map(|l| l as u32),
+            route_export_allow: export_policy.allow.as_ref().map(|p| from_prefixes(p)),
+            route_export_deny: Some(from_prefixes(&export_policy.deny))
+                .filter(|deny| !deny.is_empty()),
+            route_export_max_path_length: export_policy.max_path_length.map(|l| l as u32),
+            advertise_parent_routes: Some(account.should_advertise_parent_routes()),
+            route_cost: Some(account.route_cost()),
+            round_trip_time: Some(account.round_trip_time()),
+            amount_per_minute_limit: account.amount_per_minute_limit(),
+            packets_per_minute_limit: account.packets_per_minute_limit(),
+            settlement_engine_url: account.settlement_engine_url().map(Url::to_string),
+            settlement_engine_asset_scale: account.settlement_engine_asset_scale(),
+        }
+    }
+}
+
+fn from_prefixes(prefixes: &[Bytes]) -> Vec<String> {
+    prefixes
+        .iter()
+        .map(|prefix| String::from_utf8_lossy(prefix).to_string())
+        .collect()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IdempotentDataState {
+    pub idempotency_key: String,
+    pub status_code: u16,
+    /// Hex-encoded response body
+    pub data: String,
+    /// Hex-encoded hash of the request
+    pub input_hash: String,
+}
+
+impl IdempotentDataState {
+    pub fn new(
+        idempotency_key: String,
+        status_code: StatusCode,
+        data: &[u8],
+        input_hash: &[u8; 32],
+    ) -> Self {
+        IdempotentDataState {
+            idempotency_key,
+            status_code: status_code.as_u16(),
+            data: hex::encode(data),
+            input_hash: hex::encode(input_hash),
+        }
+    }
+
+    /// The status code, response body and request hash, or None if any of them is invalid
+    pub fn decode(&self) -> Option<(StatusCode, Bytes, [u8; 32])> {
+        let status_code = StatusCode::from_u16(self.status_code).ok()?;
+        let data = hex::decode(&self.data).ok()?;
+        let input_hash = hex::decode(&self.input_hash).ok()?;
+        if input_hash.len() != 32 {
+            return None;
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&input_hash);
+        Some((status_code, Bytes::from(data), hash))
+    }
+}
+
+impl NodeState {
+    pub fn new() -> Self {
+        NodeState {
+            version: NODE_STATE_VERSION,
+            accounts: Vec::new(),
+            static_routes: BTreeMap::new(),
+            rates: BTreeMap::new(),
+            idempotent_data: Vec::new(),
+        }
+    }
+
+    /// Fail if the accounts have incoming tokens that the export does not contain,
+    /// because those accounts cannot authenticate after the import until they are
+    /// given new tokens. With `drop_incoming_tokens`, the operator accepts that and
+    /// the accounts are only logged
+    pub fn check_incoming_tokens(&self, drop_incoming_tokens: bool) -> Result<(), ()> {
+        let dropped: Vec<u64> = self
+            .accounts
+            .iter()
+            .filter(|account| {
+                account.has_incoming_tokens
+                    && account.details.http_incoming_token.is_none()
+                    && account.details.btp_incoming_token.is_none()
+            })
+            .map(|account| account.id)
+            .collect();
+        if dropped.is_empty() {
+            Ok(())
+        } else if drop_incoming_tokens {
+            warn!(
+                "Dropping the incoming tokens of accounts {:?}. They need new ones before they can authenticate again",
+                dropped
+            );
+            Ok(())
+        } else {
+            error!(
+                "Cannot export the incoming tokens of accounts {:?} because the store only keeps their HMACs. Export with drop_incoming_tokens to give them new tokens after the import instead",
+                dropped
+            );
+            Err(())
+        }
+    }
+
+    /// Encrypt every token in the account details with the transport key, so the
+    /// exported document can be moved around without exposing them.
+    /// The password in the BTP URI is replaced with its encrypted form
+    pub fn encrypt_tokens(&mut self, transport_key: &[u8; 32]) -> Result<(), ()> {
+        let key = aead::SealingKey::new(&aead::AES_256_GCM, transport_key)
+            .map_err(|err| error!("Invalid transport key: {:?}", err))?;
+        for account in self.accounts.iter_mut() {
+            let id = account.id;
+            let details = &mut account.details;
+            for token in [
+                &mut details.http_incoming_token,
+                &mut details.http_outgoing_token,
+                &mut details.btp_incoming_token,
+            ]
+            .iter_mut()
+            {
+                if let Some(plaintext) = token.take() {
+                    **token = Some(encrypt(&key, plaintext.as_bytes()));
+                }
+            }
+            if let Some(ref mut btp_uri) = details.btp_uri {
+                let mut url = Url::parse(btp_uri)
+                    .map_err(|err| error!("Invalid BTP URI of account {}: {:?}", id, err))?;
+                if let Some(password) = url.password().map(|p| encrypt(&key, p.as_bytes())) {
+                    set_password(&mut url, &password, id)?;
+                    *btp_uri = url.into_string();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reverse `encrypt_tokens`. Fails if any token was not encrypted with the transport key
+    pub fn decrypt_tokens(&mut self, transport_key: &[u8; 32]) -> Result<(), ()> {
+        let key = aead::OpeningKey::new(&aead::AES_256_GCM, transport_key)
+            .map_err(|err| error!("Invalid transport key: {:?}", err))?;
+        for account in self.accounts.iter_mut() {
+            let id = account.id;
+            let details = &mut account.details;
+            for token in [
+                &mut details.http_incoming_token,
+                &mut details.http_outgoing_token,
+                &mut details.btp_incoming_token,
+            ]
+            .iter_mut()
+            {
+                if let Some(encrypted) = token.take() {
+                    **token = Some(decrypt(&key, &encrypted).ok_or_else(|| {
+                        error!(
+                            "Unable to decrypt a token of account {} with the transport key",
+                            id
+                        )
+                    })?);
+                }
+            }
+            if let Some(ref mut btp_uri) = details.btp_uri {
+                let mut url = Url::parse(btp_uri)
+                    .map_err(|err| error!("Invalid BTP URI of account {}: {:?}", id, err))?;
+                if let Some(encrypted) = url.password().map(str::to_string) {
+                    let password = decrypt(&key, &encrypted).ok_or_else(|| {
+                        error!(
+                            "Unable to decrypt the BTP token of account {} with the transport key",
+                            id
+                        )
+                    })?;
+                    set_password(&mut url, &password, id)?;
+                    *btp_uri = url.into_string();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for NodeState {
+    fn default() -> Self {
+        NodeState::new()
+    }
+}
+
+/// Parse a hex-encoded 32-byte transport key
+pub fn parse_transport_key(transport_key: &str) -> Result<[u8; 32], ()> {
+    let bytes = hex::decode(transport_key)
+        .map_err(|err| error!("Transport key must be hex-encoded: {:?}", err))?;
+    if bytes.len() != 32 {
+        error!("Transport key must be 32 bytes long");
+        return Err(());
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+/// Replace the password in an account's BTP URI, which fails for URIs that
+/// cannot have one
+fn set_password(url: &mut Url, password: &str, account_id: u64) -> Result<(), ()> {
+    url.set_password(Some(password)).map_err(|_| {
+        error!(
+            "Cannot set the password in the BTP URI of account {}",
+            account_id
+        )
+    })
+}
+
+/// Hex-encoded ciphertext followed by the nonce
+fn encrypt(key: &aead::SealingKey, plaintext: &[u8]) -> String {
+    let mut in_out = plaintext.to_vec();
+    in_out.extend_from_slice(&vec![0; key.algorithm().tag_len()]);
+    let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .expect("Unable to get sufficient entropy for nonce");
+    let out_len = aead::seal_in_place(
+        key,
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::empty(),
+        &mut in_out,
+        key.algorithm().tag_len(),
+    )
+    .expect("Unable to encrypt token");
+    in_out.truncate(out_len);
+    in_out.extend_from_slice(&nonce);
+    hex::encode(in_out)
+}
+
+fn decrypt(key: &aead::OpeningKey, encrypted: &str) -> Option<String> {
+    let mut encrypted = hex::decode(encrypted).ok()?;
+    if encrypted.len() < NONCE_LENGTH + aead::MAX_TAG_LEN {
+        return None;
+    }
+    let nonce_bytes = encrypted.split_off(encrypted.len() - NONCE_LENGTH);
+    let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
+    nonce.copy_from_slice(&nonce_bytes);
+    let plaintext = aead::open_in_place(
+        key,
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::empty(),
+        0,
+        &mut encrypted,
+    )
+    .ok()?;
+    String::from_utf8(plaintext.to_vec()).ok()
+}
+
+/// Stores that can export their state and import the state exported by another store
+pub trait NodeStateStore: NodeStore + IdempotentStore {
+    /// Export the accounts, balances, static routes, rates and saved settlement API
+    /// responses. The tokens in the exported account details are not encrypted
+    fn export_state(&self) -> Box<dyn Future<Item = NodeState, Error = ()> + Send>;
+
+    /// Insert the accounts with the IDs they had in the exported store, set their
+    /// balances and record that in their journals, replace the static routes and
+    /// rates, and save the settlement API responses. Either all of the state is
+    /// imported or, if any of it cannot be, none of it is. Accounts inserted
+    /// afterwards get IDs above the highest imported one.
+    ///
+    /// The state was already checked with `import_node_state`.
+    fn import_state(&self, state: NodeState) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+}
+
+/// Check everything that can be checked without the store before anything is
+/// written, so a problem with the document does not leave part of it imported
+fn validate_node_state(state: &NodeState) -> Result<(), ()> {
+    let mut ids = HashSet::new();
+    let mut usernames = HashSet::new();
+    let mut http_tokens = HashSet::new();
+    let mut btp_tokens = HashSet::new();
+    for account in state.accounts.iter() {
+        let details = &account.details;
+        if !ids.insert(account.id) {
+            error!("Account {} appears more than once", account.id);
+            return Err(());
+        }
+        if let Some(ref username) = details.username {
+            if !usernames.insert(username.to_lowercase()) {
+                error!("Username {} is used by more than one account", username);
+                return Err(());
+            }
+        }
+        if let Some(ref token) = details.http_incoming_token {
+            if !http_tokens.insert(token) {
+                error!(
+                    "HTTP incoming token of account {} is used by another account",
+                    account.id
+                );
+                return Err(());
+            }
+        }
+        if let Some(ref token) = details.btp_incoming_token {
+            if !btp_tokens.insert(token) {
+                error!(
+                    "BTP incoming token of account {} is used by another account",
+                    account.id
+                );
+                return Err(());
+            }
+        }
+    }
+    for (prefix, account_id) in state.static_routes.iter() {
+        if !ids.contains(account_id) {
+            error!(
+                "Static route for {} points to account {}, which is not in the node state",
+                prefix, account_id
+            );
+            return Err(());
+        }
+    }
+    for entry in state.idempotent_data.iter() {
+        if entry.decode().is_none() {
+            error!(
+                "Invalid saved response for idempotency key: {}",
+                entry.idempotency_key
+            );
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+/// Import the state exported by another node into a store that does not have any
+/// accounts yet. The accounts keep their IDs, so the static routes and the
+/// settlement engines' accounts still point to the right ones. The document is
+/// checked before anything is written and the store imports all of it at once, so a
+/// failed import leaves the store as it was and can be retried
+pub fn import_node_state<S, A>(store: S, state: NodeState) -> impl Future<Item = (), Error = ()>
+where
+    S: NodeStateStore<Account = A>,
+    A: AccountTrait<AccountId = u64>,
+{
+    if state.version != NODE_STATE_VERSION {
+        error!(
+            "Cannot import node state version {} (this node supports version {})",
+            state.version, NODE_STATE_VERSION
+        );
+        return Either::A(err(()));
+    }
+    if validate_node_state(&state).is_err() {
+        return Either::A(err(()));
+    }
+    let store_clone = store.clone();
+
+    Either::B(
+        store
+            .get_all_accounts()
+            .and_then(|existing| {
+                if existing.is_empty() {
+                    Ok(())
+                } else {
+                    error!("Node state can only be imported into a store without accounts");
+                    Err(())
+                }
+            })
+            .and_then(move |_| {
+                let count = state.accounts.len();
+                store_clone
+                    .import_state(state)
+                    .map_err(|_| error!("Unable to import the node state"))
+                    .map(move |_| debug!("Imported {} accounts", count))
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_packet::Address;
+    use std::str::FromStr;
+
+    fn state_with_tokens() -> NodeState {
+        let mut state = NodeState::new();
+        state.accounts.push(AccountState {
+            id: 3,
+            details: AccountDetails {
+                ilp_address: Address::from_str("example.alice").unwrap(),
+                username: None,
+                asset_code: "XYZ".to_string(),
+                asset_scale: 9,
+                max_packet_amount: 1000,
+                min_balance: None,
+                http_endpoint: None,
+                http_incoming_token: None,
+                http_outgoing_token: Some("outgoing".to_string()),
+                btp_uri: Some("btp+ws://:btp_token@example.com/ilp".to_string()),
+                btp_incoming_token: Some("incoming".to_string()),
+                settle_threshold: None,
+                settle_to: None,
+                send_routes: false,
+                receive_routes: false,
+                routing_relation: None,
+                allowed_route_prefixes: None,
+                route_import_deny: None,
+                route_import_max_path_length: None,
+                route_export_allow: None,
+                route_export_deny: None,
+                route_export_max_path_length: None,
+                advertise_parent_routes: None,
+                route_cost: None,
+                round_trip_time: None,
+                amount_per_minute_limit: None,
+                packets_per_minute_limit: None,
+                settlement_engine_url: None,
+                settlement_engine_asset_scale: None,
+            },
+            balance: -100,
+            prepaid_amount: 0,
+            has_incoming_tokens: true,
+        });
+        state
+    }
+
+    #[test]
+    fn encrypts_and_decrypts_tokens() {
+        let mut state = state_with_tokens();
+        state.encrypt_tokens(&[7; 32]).unwrap();
+        let details = &state.accounts[0].details;
+        assert_ne!(details.http_outgoing_token, Some("outgoing".to_string()));
+        assert_ne!(details.btp_incoming_token, Some("incoming".to_string()));
+        assert!(!details.btp_uri.as_ref().unwrap().contains("btp_token"));
+        assert_eq!(details.http_incoming_token, None);
+
+        state.decrypt_tokens(&[7; 32]).unwrap();
+        let details = &state.accounts[0].details;
+        assert_eq!(details.http_outgoing_token, Some("outgoing".to_string()));
+        assert_eq!(details.btp_incoming_token, Some("incoming".to_string()));
+        assert_eq!(
+            details.btp_uri,
+            Some("btp+ws://:btp_token@example.com/ilp".to_string())
+        );
+    }
+
+    #[test]
+    fn does_not_decrypt_with_wrong_key() {
+        let mut state = state_with_tokens();
+        state.encrypt_tokens(&[7; 32]).unwrap();
+        assert!(state.decrypt_tokens(&[8; 32]).is_err());
+    }
+
+    #[test]
+    fn does_not_export_tokens_of_invalid_btp_uris() {
+        let mut state = state_with_tokens();
+        state.accounts[0].details.btp_uri = Some("btp_token@example.com".to_string());
+        assert!(state.encrypt_tokens(&[7; 32]).is_err());
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let mut state = state_with_tokens();
+        state.static_routes.insert("example.bob".to_string(), 3);
+        state.rates.insert("XYZ".to_string(), 1.5);
+        let json = serde_json::to_string(&state).unwrap();
+        let parsed: NodeState = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.version, NODE_STATE_VERSION);
+        assert_eq!(parsed.accounts[0].balance, -100);
+        assert_eq!(parsed.static_routes["example.bob"], 3);
+    }
+
+    #[test]
+    fn refuses_to_drop_incoming_tokens_unless_asked_to() {
+        let mut state = state_with_tokens();
+        assert!(state.check_incoming_tokens(false).is_ok());
+
+        state.accounts[0].details.btp_incoming_token = None;
+        assert!(state.check_incoming_tokens(false).is_err());
+        assert!(state.check_incoming_tokens(true).is_ok());
+    }
+
+    #[test]
+    fn validates_the_whole_state_before_importing() {
+        let mut state = state_with_tokens();
+        assert!(validate_node_state(&state).is_ok());
+
+        state.static_routes.insert("example.bob".to_string(), 4);
+        assert!(validate_node_state(&state).is_err());
+        state.static_routes.clear();
+
+        let mut duplicate = state.accounts[0].clone();
+        duplicate.id = 4;
+        state.accounts.push(duplicate);
+        assert!(validate_node_state(&state).is_err());
+        state.accounts[1].details.btp_incoming_token = Some("other".to_string());
+        assert!(validate_node_state(&state).is_ok());
+
+        state.idempotent_data.push(IdempotentDataState {
+            idempotency_key: "key".to_string(),
+            status_code: 200,
+            data: "not hex".to_string(),
+            input_hash: hex::encode([0; 32]),
+        });
+        assert!(validate_node_state(&state).is_err());
+    }
+
+    #[test]
+    fn parses_transport_keys() {
+        assert_eq!(parse_transport_key(&"ab".repeat(32)), Ok([0xab; 32]));
+        assert!(parse_transport_key("abcd").is_err());
+        assert!(parse_transport_key(&"zz".repeat(32)).is_err());
+    }
+}
@@ -6,14 +6,14 @@ use futures::{
 use hyper::Response;
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_service::Account;
-use interledger_service_util::BalanceStore;
+use interledger_service_util::{BalanceStore, JournalEntry};
 use log::{debug, error, trace};
 use reqwest::r#async::Client;
 use serde::Serialize;
 use serde_json::{json, Value};
 use std::str::FromStr;
 use tokio_retry::{strategy::FixedInterval, Retry};
-use tower_web::{impl_web, Response};
+use tower_web::{impl_web, Extract, Response};
 use url::Url;
 
 #[derive(Serialize, Response, Debug)]
@@ -32,12 +32,33 @@ struct BalanceResponse {
     balance: String,
 }
 
+#[derive(Extract, Debug)]
+struct StatementQuery {
+    /// ID of the first journal entry to return
+    from: Option<u64>,
+    limit: Option<usize>,
+}
+
+fn journal_entry_to_json(entry: &JournalEntry) -> Value {
+    json!({
+        "id": entry.id,
+        "kind": entry.kind.as_str(),
+        "reference": entry.reference,
+        "amount": entry.amount.to_string(),
+        "balance": entry.balance.to_string(),
+        "prepaid_amount": entry.prepaid_amount.to_string(),
+        "timestamp": entry.timestamp,
+    })
+}
+
 pub struct AccountsApi<T> {
     store: T,
     admin_api_token: String,
 }
 
 const MAX_RETRIES: usize = 10;
+const DEFAULT_STATEMENT_LIMIT: usize = 100;
+const MAX_STATEMENT_LIMIT: usize = 1000;
 
 impl_web! {
     impl<T, A> AccountsApi<T>
@@ -208,5 +229,41 @@ impl_web! {
                     balance: balance.to_string(),
                 }))
         }
+
+        #[get("/accounts/:id/statement")]
+        #[content_type("application/json")]
+        fn get_statement(&self, id: String, authorization: String, query_string: Option<StatementQuery>) -> impl Future<Item = Value, Error = Response<()>> {
+            let (from, limit) = query_string
+                .map(|query| (query.from, query.limit))
+                .unwrap_or((None, None));
+            let from = from.unwrap_or(1);
+            let limit = limit.unwrap_or(DEFAULT_STATEMENT_LIMIT).min(MAX_STATEMENT_LIMIT);
+            let parsed_id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id"));
+            self.validate_admin(authorization)
+                .and_then(move |store| result(parsed_id)
+                    .map_err(|_| Response::builder().status(400).body(()).unwrap())
+                    .and_then(move |id| {
+                        let store_clone = store.clone();
+                        store.get_accounts(vec![id])
+                            .map_err(move |_| {
+                                debug!("Account not found: {}", id);
+                                Response::builder().status(404).body(()).unwrap()
+                            })
+                            .and_then(move |mut accounts| store_clone.get_balance_journal(accounts.pop().unwrap(), from, limit)
+                                .map_err(|_| Response::builder().status(500).body(()).unwrap()))
+                    }))
+                .and_then(move |entries| {
+                    // A full page means there may be more entries after it
+                    let next = if entries.len() == limit {
+                        entries.last().map(|entry| entry.id + 1)
+                    } else {
+                        None
+                    };
+                    Ok(json!({
+                        "entries": entries.iter().map(journal_entry_to_json).collect::<Vec<Value>>(),
+                        "next": next,
+                    }))
+                })
+        }
     }
 }
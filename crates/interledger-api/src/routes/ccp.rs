@@ -0,0 +1,207 @@
+use crate::is_admin_token;
+use bytes::Bytes;
+use futures::{
+    future::{err, ok, FutureResult},
+    Future,
+};
+use hyper::Response;
+use interledger_ccp::{Route, RouteManagerStatus, RoutingTableSnapshot};
+use log::error;
+use serde_json::{json, Map, Value};
+use std::{str, sync::Arc, time::UNIX_EPOCH};
+use tower_web::impl_web;
+
+#[derive(Response)]
+#[web(status = "200")]
+struct Success;
+
+#[derive(Extract, Debug)]
+struct UpdatesQuery {
+    /// Epoch of the first update to return
+    from: Option<u32>,
+    limit: Option<usize>,
+}
+
+const DEFAULT_UPDATES_LIMIT: usize = 100;
+const MAX_UPDATES_LIMIT: usize = 1000;
+
+fn route_to_json(route: &Route) -> Value {
+    json!({
+        "prefix": str::from_utf8(route.prefix()).unwrap_or("<not utf8>"),
+        "path": route
+            .path()
+            .iter()
+            .map(|hop| str::from_utf8(hop).unwrap_or("<not utf8>"))
+            .collect::<Vec<&str>>(),
+    })
+}
+
+fn table_to_json(table: &RoutingTableSnapshot) -> Value {
+    let routes: Vec<Value> = table
+        .routes
+        .iter()
+        .map(|(account_id, route)| {
+            let mut route = route_to_json(route);
+            route["next_hop"] = Value::String(account_id.clone());
+            route
+        })
+        .collect();
+    json!({
+        "routing_table_id": hex::encode(&table.id[..]),
+        "epoch": table.epoch,
+        "routes": routes,
+    })
+}
+
+pub struct CcpApi {
+    route_manager: Option<Arc<dyn RouteManagerStatus>>,
+    admin_api_token: String,
+}
+
+impl_web! {
+    impl CcpApi {
+        pub fn new(admin_api_token: String, route_manager: Option<Arc<dyn RouteManagerStatus>>) -> Self {
+            CcpApi {
+                route_manager,
+                admin_api_token,
+            }
+        }
+
+        fn validate_admin(&self, authorization: String) -> FutureResult<Arc<dyn RouteManagerStatus>, Response<()>> {
+            if !is_admin_token(&authorization, &self.admin_api_token) {
+                error!("Admin API endpoint called with non-admin API key");
+                err(Response::builder().status(401).body(()).unwrap())
+            } else if let Some(ref route_manager) = self.route_manager {
+                ok(route_manager.clone())
+            } else {
+                error!("Got request for CCP state but the node is not running a route manager");
+                err(Response::builder().status(404).body(()).unwrap())
+            }
+        }
+
+        #[get("/ccp/peers")]
+        #[content_type("application/json")]
+        fn get_peers(&self, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(|route_manager| {
+                    let mut peers = Map::new();
+                    for (account_id, session) in route_manager.peer_sessions() {
+                        let last_contact = session.last_contact
+                            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                            .map(|duration| duration.as_millis() as u64);
+                        peers.insert(account_id, json!({
+                            "mode": format!("{:?}", session.mode),
+                            "last_acknowledged_epoch": session.last_acknowledged_epoch,
+                            "last_contact": last_contact,
+                            "unacknowledged_updates": session.unacknowledged_updates,
+                            "unresponsive": session.unresponsive,
+                        }));
+                    }
+                    Ok(Value::Object(peers))
+                })
+        }
+
+        #[get("/ccp/incoming")]
+        #[content_type("application/json")]
+        fn get_incoming_tables(&self, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(|route_manager| {
+                    let mut tables = Map::new();
+                    for (account_id, table) in route_manager.incoming_tables() {
+                        tables.insert(account_id, table_to_json(&table));
+                    }
+                    Ok(Value::Object(tables))
+                })
+        }
+
+        #[get("/ccp/forwarding")]
+        #[content_type("application/json")]
+        fn get_forwarding_table(&self, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(|route_manager| Ok(table_to_json(&route_manager.forwarding_table())))
+        }
+
+        #[get("/ccp/forwarding/updates")]
+        #[content_type("application/json")]
+        fn get_forwarding_table_updates(&self, authorization: String, query_string: Option<UpdatesQuery>) -> impl Future<Item = Value, Error = Response<()>> {
+            let (from, limit) = query_string
+                .map(|query| (query.from, query.limit))
+                .unwrap_or((None, None));
+            let from = from.unwrap_or(1).max(1);
+            let limit = limit.unwrap_or(DEFAULT_UPDATES_LIMIT).min(MAX_UPDATES_LIMIT);
+            self.validate_admin(authorization)
+                .and_then(move |route_manager| {
+                    let updates: Vec<Value> = route_manager.forwarding_table_updates(from, limit)
+                        .iter()
+                        .enumerate()
+                        .map(|(index, (new_routes, withdrawn_routes))| json!({
+                            "epoch": from as usize + index,
+                            "new_routes": new_routes.iter().map(route_to_json).collect::<Vec<Value>>(),
+                            "withdrawn_routes": withdrawn_routes
+                                .iter()
+                                .map(|prefix| str::from_utf8(prefix).unwrap_or("<not utf8>"))
+                                .collect::<Vec<&str>>(),
+                        }))
+                        .collect();
+                    Ok(Value::Array(updates))
+                })
+        }
+
+        #[get("/ccp/routes/:prefix")]
+        #[content_type("application/json")]
+        fn get_route_explanation(&self, prefix: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |route_manager| {
+                    route_manager.explain_route(Bytes::from(prefix))
+                        .map_err(|_| {
+                            error!("Error loading local and configured routes to explain route");
+                            Response::builder().status(500).body(()).unwrap()
+                        })
+                })
+                .and_then(|explanation| {
+                    let candidates: Vec<Value> = explanation.candidates
+                        .iter()
+                        .map(|candidate| {
+                            let mut route = route_to_json(&candidate.route);
+                            route["account_id"] = Value::String(candidate.account_id.clone());
+                            route["relation"] = Value::String(candidate.relation.to_string());
+                            route["cost"] = json!(candidate.cost);
+                            route["equal_cost"] = Value::Bool(candidate.equal_cost);
+                            route
+                        })
+                        .collect();
+                    Ok(json!({
+                        "prefix": str::from_utf8(&explanation.prefix).unwrap_or("<not utf8>"),
+                        "next_hop": explanation.next_hop,
+                        "selection": format!("{:?}", explanation.selection),
+                        "candidates": candidates,
+                    }))
+                })
+        }
+
+        #[post("/ccp/broadcast")]
+        #[content_type("application/json")]
+        fn post_broadcast(&self, authorization: String) -> impl Future<Item = Success, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(|route_manager| {
+                    route_manager.trigger_broadcast()
+                        .map(|_| Success)
+                        .map_err(|_| Response::builder().status(500).body(()).unwrap())
+                })
+        }
+
+        #[post("/ccp/peers/:id/resync")]
+        #[content_type("application/json")]
+        fn post_resync(&self, id: String, authorization: String) -> impl Future<Item = Success, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |route_manager| {
+                    route_manager.resync_peer(id)
+                        .map(|_| Success)
+                        .map_err(|err| {
+                            error!("Error resyncing routes: {}", err);
+                            Response::builder().status(404).body(()).unwrap()
+                        })
+                })
+        }
+    }
+}
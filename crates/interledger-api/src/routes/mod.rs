@@ -1,9 +1,15 @@
 mod accounts;
+mod ccp;
 mod ilp;
+mod node;
+mod ping;
 mod settings;
 mod spsp;
 
 pub use accounts::AccountsApi;
+pub use ccp::CcpApi;
 pub use ilp::IlpApi;
+pub use node::NodeStateApi;
+pub use ping::PingApi;
 pub use settings::SettingsApi;
 pub use spsp::SpspApi;
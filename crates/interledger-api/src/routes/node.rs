@@ -0,0 +1,96 @@
+use crate::{import_node_state, is_admin_token, parse_transport_key, NodeState, NodeStateStore};
+use futures::{
+    future::{err, ok, result},
+    Future,
+};
+use hyper::Response;
+use interledger_service::Account;
+use log::{error, info};
+use serde_json::{json, Value};
+use tower_web::{impl_web, Extract};
+
+#[derive(Extract, Debug)]
+struct ExportRequest {
+    /// Hex-encoded 32-byte key the tokens in the exported state are encrypted with
+    transport_key: String,
+    /// Export even though the incoming tokens of the accounts are lost
+    drop_incoming_tokens: Option<bool>,
+}
+
+#[derive(Extract, Debug)]
+struct ImportRequest {
+    transport_key: String,
+    state: NodeState,
+}
+
+pub struct NodeStateApi<T> {
+    store: T,
+    admin_api_token: String,
+}
+
+impl_web! {
+    impl<T, A> NodeStateApi<T>
+    where T: NodeStateStore<Account = A>,
+    A: Account<AccountId = u64> + 'static,
+
+    {
+        pub fn new(admin_api_token: String, store: T) -> Self {
+            NodeStateApi {
+                store,
+                admin_api_token,
+            }
+        }
+
+        fn validate_admin(&self, authorization: String) -> impl Future<Item = T, Error = Response<()>> {
+            if is_admin_token(&authorization, &self.admin_api_token) {
+                ok(self.store.clone())
+            } else {
+                error!("Admin API endpoint called with non-admin API key");
+                err(Response::builder().status(401).body(()).unwrap())
+            }
+        }
+
+        #[post("/node/export")]
+        #[content_type("application/json")]
+        fn post_export(&self, body: ExportRequest, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    result(parse_transport_key(&body.transport_key))
+                        .map_err(|_| Response::builder().status(400).body(()).unwrap())
+                        .and_then(move |transport_key| {
+                            let drop_incoming_tokens = body.drop_incoming_tokens.unwrap_or(false);
+                            store.export_state()
+                                .map_err(|_| Response::builder().status(500).body(()).unwrap())
+                                .and_then(move |mut state| {
+                                    state.check_incoming_tokens(drop_incoming_tokens)
+                                        .map_err(|_| Response::builder().status(409).body(()).unwrap())?;
+                                    info!("Exporting the state of {} accounts", state.accounts.len());
+                                    state.encrypt_tokens(&transport_key)
+                                        .map_err(|_| Response::builder().status(500).body(()).unwrap())?;
+                                    Ok(json!(state))
+                                })
+                        })
+                })
+        }
+
+        #[post("/node/import")]
+        #[content_type("application/json")]
+        fn post_import(&self, body: ImportRequest, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    let ImportRequest { transport_key, mut state } = body;
+                    result(parse_transport_key(&transport_key).and_then(|transport_key| state.decrypt_tokens(&transport_key)))
+                        .map_err(|_| Response::builder().status(400).body(()).unwrap())
+                        .and_then(move |_| {
+                            let accounts = state.accounts.len();
+                            import_node_state(store, state)
+                                .map_err(|_| Response::builder().status(500).body(()).unwrap())
+                                .map(move |_| {
+                                    info!("Imported the state of {} accounts", accounts);
+                                    json!({ "accounts": accounts })
+                                })
+                        })
+                })
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use crate::is_admin_token;
+use futures::{future::result, Future};
+use hyper::Response;
+use interledger_packet::Address;
+use interledger_service::{Account, AccountStore, IncomingService};
+use interledger_service_util::Pinger;
+use log::{debug, error};
+use serde_json::json;
+use std::str::{self, FromStr};
+use tower_web::{impl_web, Extract, Response};
+
+#[derive(Extract, Debug)]
+struct PingRequest {
+    destination: String,
+    /// The account the ping is sent from
+    account_id: String,
+    #[serde(default)]
+    amount: u64,
+}
+
+#[derive(Response, Debug)]
+#[web(status = "200")]
+struct PingResponse {
+    round_trip_time_ms: u64,
+}
+
+pub struct PingApi<T, S> {
+    store: T,
+    admin_api_token: String,
+    incoming_handler: S,
+    pinger: Option<Pinger>,
+}
+
+impl_web! {
+    impl<T, S, A> PingApi<T, S>
+    where T: AccountStore<Account = A> + Clone + Send + Sync + 'static,
+    S: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + 'static,
+    {
+        pub fn new(admin_api_token: String, store: T, incoming_handler: S, pinger: Option<Pinger>) -> Self {
+            PingApi {
+                store,
+                admin_api_token,
+                incoming_handler,
+                pinger,
+            }
+        }
+
+        /// Rejected pings are returned with a 502 status code and a JSON body like:
+        /// `{"error": "F02", "message": "...", "triggered_by": "example.connector"}`
+        #[post("/ping")]
+        #[content_type("application/json")]
+        fn post_ping(&self, body: PingRequest, authorization: String) -> impl Future<Item = PingResponse, Error = Response<String>> {
+            let store = self.store.clone();
+            let mut service = self.incoming_handler.clone();
+            let pinger = self.pinger.clone();
+            let is_admin = is_admin_token(&authorization, &self.admin_api_token);
+            debug!("Got request to ping: {:?}", body);
+            result(if !is_admin {
+                error!("Admin API endpoint called with non-admin API key");
+                Err(error_response(401, json!({ "error": "Unauthorized" })))
+            } else if let Some(pinger) = pinger {
+                Ok(pinger)
+            } else {
+                error!("Got request to ping but the node is not running an echo service");
+                Err(error_response(404, json!({ "error": "PingNotSupported" })))
+            })
+            .and_then(move |pinger| {
+                let destination = Address::from_str(&body.destination)
+                    .map_err(|_| error_response(400, json!({ "error": "InvalidDestination" })))?;
+                let account_id = A::AccountId::from_str(&body.account_id)
+                    .map_err(|_| error_response(400, json!({ "error": "InvalidAccountId" })))?;
+                Ok((pinger, destination, account_id, body.amount))
+            })
+            .and_then(move |(pinger, destination, account_id, amount)| {
+                store.get_accounts(vec![account_id])
+                    .map_err(|_| error_response(404, json!({ "error": "AccountNotFound" })))
+                    .and_then(move |mut accounts| {
+                        let from = accounts.pop().unwrap();
+                        pinger.ping(&mut service, from, destination, amount)
+                            .map_err(|reject| {
+                                debug!("Ping was rejected: {:?}", reject);
+                                error_response(502, json!({
+                                    "error": reject.code().to_string(),
+                                    "message": str::from_utf8(reject.message()).unwrap_or_default(),
+                                    "triggered_by": reject
+                                        .triggered_by()
+                                        .map(|address| str::from_utf8(address.as_ref()).unwrap_or_default().to_string()),
+                                }))
+                            })
+                    })
+            })
+            .map(|round_trip_time| PingResponse {
+                round_trip_time_ms: round_trip_time.as_secs() * 1000 + u64::from(round_trip_time.subsec_millis()),
+            })
+        }
+    }
+}
+
+fn error_response(status: u16, body: serde_json::Value) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .unwrap()
+}
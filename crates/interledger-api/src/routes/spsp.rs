@@ -1,16 +1,18 @@
-use crate::BEARER_TOKEN_START;
+use crate::{NodeStore, BEARER_TOKEN_START};
 use bytes::Bytes;
 use futures::{
-    future::{err, result, Either},
+    future::{err, ok, result, Either},
     Future,
 };
 use hyper::{Body, Response};
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_ildcp::IldcpAccount;
 use interledger_service::{AccountStore, IncomingService};
-use interledger_spsp::{pay, SpspResponder};
+use interledger_spsp::{pay, Error as SpspError, SpspResponder};
+use interledger_stream::Error as StreamError;
 use log::{debug, error};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::str::FromStr;
 use tower_web::{impl_web, Extract, Response};
 
@@ -36,13 +38,14 @@ struct SpspQueryResponse {
 pub struct SpspApi<T, S> {
     store: T,
     default_spsp_account: Option<String>,
+    spsp_domain: Option<String>,
     incoming_handler: S,
     server_secret: Bytes,
 }
 
 impl_web! {
     impl<T, S, A> SpspApi<T, S>
-    where T: HttpStore<Account = A> + AccountStore<Account = A>,
+    where T: HttpStore<Account = A> + AccountStore<Account = A> + NodeStore<Account = A>,
     S: IncomingService<A> + Clone + Send + Sync + 'static,
     A: IldcpAccount + HttpAccount + 'static,
     {
@@ -50,6 +53,7 @@ impl_web! {
             SpspApi {
                 store,
                 default_spsp_account: None,
+                spsp_domain: None,
                 incoming_handler,
                 server_secret,
             }
@@ -60,6 +64,16 @@ impl_web! {
             self
         }
 
+        /// Set the node's domain so that payment pointers like `$alice.<domain>` are
+        /// resolved to the account with the username `alice`. Without it, only the
+        /// default SPSP account is served on /.well-known/pay
+        pub fn spsp_domain(&mut self, domain: String) -> &mut Self {
+            self.spsp_domain = Some(domain.trim_matches('.').to_lowercase());
+            self
+        }
+
+        /// Errors are returned with a status code matching the type of error and a JSON body like:
+        /// `{"error": "ExchangeRateTooLow", "message": "...", "sent_amount": 10, "delivered_amount": 9}`
         #[post("/pay")]
         #[content_type("application/json")]
         // TODO add a version that lets you specify the destination amount instead
@@ -78,8 +92,7 @@ impl_web! {
                         })
                         .map_err(|err| {
                             error!("Error sending SPSP payment: {:?}", err);
-                            // TODO give a different error message depending on what type of error it is
-                            Response::builder().status(500).body(format!("Error sending SPSP payment: {:?}", err)).unwrap()
+                            pay_error_response(err)
                         })
                 })
         }
@@ -91,32 +104,183 @@ impl_web! {
             let id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id: {}", id));
             result(id)
                 .map_err(|_| Response::builder().status(400).body(()).unwrap())
-                .and_then(move |id| store.get_accounts(vec![id])
+                .and_then(move |id| spsp_response(store, server_secret, id))
+        }
+
+        /// Resolves payment pointers like `$node.example/alice`
+        #[get("/:username")]
+        fn get_spsp_by_username(&self, username: String) -> impl Future<Item = Response<Body>, Error = Response<()>> {
+            let server_secret = self.server_secret.clone();
+            let store = self.store.clone();
+            self.store.get_account_id_from_username(&username)
                 .map_err(move |_| {
-                    error!("Account not found: {}", id);
+                    debug!("Got SPSP request for unknown username: {}", username);
                     Response::builder().status(404).body(()).unwrap()
-                }))
-                .and_then(move |accounts| {
-                    // TODO return the response without instantiating an SpspResponder (use a simple fn)
-                    Ok(SpspResponder::new(accounts[0].client_address().clone(), server_secret)
-                        .generate_http_response())
-                    })
+                })
+                .and_then(move |id| spsp_response(store, server_secret, id))
         }
 
-
-
-        // TODO resolve payment pointers with subdomains to the correct account
-        // also give accounts aliases to use in the payment pointer instead of the ids
+        /// Resolves payment pointers like `$alice.node.example` to the account with the
+        /// username given by the label in front of the node's domain, and `$node.example`
+        /// to the default SPSP account.
         #[get("/.well-known/pay")]
-        fn get_well_known(&self) -> impl Future<Item = Response<Body>, Error = Response<()>> {
-            if let Some(ref account_id) = &self.default_spsp_account {
-                Either::A(self.get_spsp(account_id.to_string()))
+        fn get_well_known(&self, host: Option<String>) -> impl Future<Item = Response<Body>, Error = Response<()>> {
+            let server_secret = self.server_secret.clone();
+            let store = self.store.clone();
+            let default_account_id = self.default_spsp_account.as_ref().and_then(|id| A::AccountId::from_str(id).ok());
+
+            let username = match (host.as_ref(), self.spsp_domain.as_ref()) {
+                (Some(host), Some(domain)) => username_from_host(host, domain),
+                _ => None,
+            };
+            let account_id = if let Some(username) = username {
+                Either::A(self.store.get_account_id_from_username(&username)
+                    .map(Some)
+                    .map_err(move |_| {
+                        debug!("Got SPSP request for unknown username: {}", username);
+                        Response::builder().status(404).body(()).unwrap()
+                    }))
             } else {
-                error!("Got SPSP request to /.well-known/pay endpoint but there is no default SPSP account configured");
-                Either::B(err(Response::builder().status(404).body(()).unwrap()))
-            }
+                Either::B(ok(default_account_id))
+            };
+            account_id.and_then(move |account_id| {
+                if let Some(account_id) = account_id {
+                    Either::A(spsp_response(store, server_secret, account_id))
+                } else {
+                    error!("Got SPSP request to /.well-known/pay endpoint but there is no matching username or default SPSP account configured");
+                    Either::B(err(Response::builder().status(404).body(()).unwrap()))
+                }
+            })
         }
 
         // TODO add quoting via SPSP/STREAM
     }
 }
+
+fn pay_error_response(error: SpspError) -> Response<String> {
+    // Failures after some money went through still tell the client how much was delivered
+    let (sent_amount, delivered_amount, error) = match error {
+        SpspError::StreamError(StreamError::PartialDelivery {
+            sent_amount,
+            delivered_amount,
+            error,
+        }) => (
+            sent_amount,
+            delivered_amount,
+            SpspError::StreamError(*error),
+        ),
+        error => (0, 0, error),
+    };
+    let (status, error_type) = match error {
+        SpspError::InvalidPaymentPointerError(_) => (400, "InvalidPaymentPointer"),
+        SpspError::HttpError(_) | SpspError::InvalidResponseError(_) => (502, "SpspQueryFailed"),
+        SpspError::QueryError { status: 404, .. } => (404, "SpspQueryFailed"),
+        SpspError::QueryError { .. } => (502, "SpspQueryFailed"),
+        SpspError::QueryTimeout(_) => (504, "Timeout"),
+        SpspError::StreamError(ref error) => match error {
+            StreamError::ReceiverUnreachable(_) => (502, "ReceiverUnreachable"),
+            StreamError::InsufficientLiquidity(_) => (503, "InsufficientLiquidity"),
+            StreamError::ExchangeRateTooLow(_) => (422, "ExchangeRateTooLow"),
+            StreamError::Timeout(_) => (504, "Timeout"),
+            _ => (500, "PaymentFailed"),
+        },
+        _ => (500, "PaymentFailed"),
+    };
+    let body = json!({
+        "error": error_type,
+        "message": error.to_string(),
+        "sent_amount": sent_amount,
+        "delivered_amount": delivered_amount,
+    });
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .unwrap()
+}
+
+fn spsp_response<T, A>(
+    store: T,
+    server_secret: Bytes,
+    id: A::AccountId,
+) -> impl Future<Item = Response<Body>, Error = Response<()>>
+where
+    T: AccountStore<Account = A>,
+    A: IldcpAccount,
+{
+    store
+        .get_accounts(vec![id])
+        .map_err(move |_| {
+            error!("Account not found: {}", id);
+            Response::builder().status(404).body(()).unwrap()
+        })
+        .and_then(move |accounts| {
+            // TODO return the response without instantiating an SpspResponder (use a simple fn)
+            Ok(
+                SpspResponder::new(accounts[0].client_address().clone(), server_secret)
+                    .generate_http_response(),
+            )
+        })
+}
+
+/// Get the username from a host like `alice.node.example`, where `node.example` is the node's
+/// domain. Only a single label directly in front of the domain is used, so that requests to
+/// the domain itself or to other hosts are not mistaken for payment pointers of accounts
+fn username_from_host(host: &str, domain: &str) -> Option<String> {
+    let host_name = host
+        .split(':')
+        .next()
+        .unwrap_or(host)
+        .trim_end_matches('.')
+        .to_lowercase();
+    let label = host_name.get(..host_name.len().checked_sub(domain.len() + 1)?)?;
+    if host_name.ends_with(domain)
+        && host_name.as_bytes()[label.len()] == b'.'
+        && !label.is_empty()
+        && !label.contains('.')
+    {
+        Some(label.to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod payment_pointer_host {
+    use super::*;
+
+    #[test]
+    fn gets_username_in_front_of_domain() {
+        assert_eq!(
+            username_from_host("alice.node.example", "node.example"),
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            username_from_host("Alice.Node.Example:7770", "node.example"),
+            Some("alice".to_string())
+        );
+    }
+
+    #[test]
+    fn no_username_for_the_domain_itself() {
+        assert_eq!(username_from_host("node.example", "node.example"), None);
+        assert_eq!(
+            username_from_host("pay.example.com", "pay.example.com"),
+            None
+        );
+        assert_eq!(username_from_host("localhost:7770", "node.example"), None);
+    }
+
+    #[test]
+    fn no_username_for_other_hosts() {
+        assert_eq!(username_from_host("pay.example.com", "node.example"), None);
+        assert_eq!(
+            username_from_host("alice.evilnode.example", "node.example"),
+            None
+        );
+        assert_eq!(
+            username_from_host("bob.alice.node.example", "node.example"),
+            None
+        );
+    }
+}
@@ -19,7 +19,7 @@ mod service;
 
 pub use self::client::{connect_client, parse_btp_url};
 pub use self::server::{create_open_signup_server, create_server};
-pub use self::service::{BtpOutgoingService, BtpService};
+pub use self::service::{BtpOutgoingService, BtpService, ConnectionEvent};
 use interledger_packet::Address;
 
 pub trait BtpAccount: Account {
@@ -33,6 +33,13 @@ type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 type IlpResultChannel = oneshot::Sender<Result<Fulfill, Reject>>;
 type IncomingRequestBuffer<A> = UnboundedReceiver<(A, u32, Prepare)>;
 
+/// An account's WebSocket connection opening or closing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionEvent<I> {
+    Opened(I),
+    Closed(I),
+}
+
 /// A container for BTP/WebSocket connections that implements OutgoingService
 /// for sending outgoing ILP Prepare packets over one of the connected BTP connections.
 #[derive(Clone)]
@@ -45,6 +52,8 @@ pub struct BtpOutgoingService<O, A: Account> {
     next: O,
     close_all_connections: Arc<Mutex<Option<Trigger>>>,
     stream_valve: Arc<Valve>,
+    closed_connection_listeners: Arc<Mutex<Vec<UnboundedSender<A::AccountId>>>>,
+    connection_event_listeners: Arc<Mutex<Vec<UnboundedSender<ConnectionEvent<A::AccountId>>>>>,
 }
 
 impl<O, A> BtpOutgoingService<O, A>
@@ -63,9 +72,33 @@ where
             next,
             close_all_connections: Arc::new(Mutex::new(Some(close_all_connections))),
             stream_valve: Arc::new(stream_valve),
+            closed_connection_listeners: Arc::new(Mutex::new(Vec::new())),
+            connection_event_listeners: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
+    /// Get a stream of the IDs of accounts whose WebSocket connection closed, for example
+    /// so that routes learned from them can be withdrawn
+    pub fn closed_connections(&self) -> UnboundedReceiver<A::AccountId> {
+        let (tx, rx) = unbounded();
+        self.closed_connection_listeners.lock().push(tx);
+        rx
+    }
+
+    /// Get a stream of the WebSocket connections opening and closing, in the order they did,
+    /// for example so that other instances sharing the store can forward packets for the
+    /// connected accounts to this one
+    pub fn connection_events(&self) -> UnboundedReceiver<ConnectionEvent<A::AccountId>> {
+        let (tx, rx) = unbounded();
+        self.connection_event_listeners.lock().push(tx);
+        rx
+    }
+
+    /// The IDs of the accounts that currently have an open WebSocket connection
+    pub fn connected_accounts(&self) -> Vec<A::AccountId> {
+        self.connections.read().keys().cloned().collect()
+    }
+
     /// Close all of the open WebSocket connections
     // TODO is there some more automatic way of knowing when we should close the connections?
     // The problem is that the WS client can be a server too, so it's not clear when we are done with it
@@ -174,6 +207,8 @@ where
 
         let connections = self.connections.clone();
         let keep_connections_open = self.close_all_connections.clone();
+        let closed_connection_listeners = self.closed_connection_listeners.clone();
+        let connection_event_listeners = self.connection_event_listeners.clone();
         let handle_connection = handle_incoming
             .select(forward_to_connection)
             .then(move |_| {
@@ -185,12 +220,26 @@ where
                     account_id,
                     connections.len()
                 );
+                // Drop the listeners that have gone away
+                closed_connection_listeners
+                    .lock()
+                    .retain(|listener| listener.unbounded_send(account_id).is_ok());
+                connection_event_listeners.lock().retain(|listener| {
+                    listener
+                        .unbounded_send(ConnectionEvent::Closed(account_id))
+                        .is_ok()
+                });
                 Ok(())
             });
         spawn(handle_connection);
 
         // Save the sender side of the channel so we have a way to forward outgoing requests to the WebSocket
         self.connections.write().insert(account_id, tx);
+        self.connection_event_listeners.lock().retain(|listener| {
+            listener
+                .unbounded_send(ConnectionEvent::Opened(account_id))
+                .is_ok()
+        });
     }
 
     /// Convert this BtpOutgoingService into a bidirectional BtpService by adding a handler for incoming requests.
@@ -363,6 +412,21 @@ where
     pub fn close(&self) {
         self.outgoing.close();
     }
+
+    /// Get a stream of the IDs of accounts whose WebSocket connection closed
+    pub fn closed_connections(&self) -> UnboundedReceiver<A::AccountId> {
+        self.outgoing.closed_connections()
+    }
+
+    /// Get a stream of the WebSocket connections opening and closing, in the order they did
+    pub fn connection_events(&self) -> UnboundedReceiver<ConnectionEvent<A::AccountId>> {
+        self.outgoing.connection_events()
+    }
+
+    /// The IDs of the accounts that currently have an open WebSocket connection
+    pub fn connected_accounts(&self) -> Vec<A::AccountId> {
+        self.outgoing.connected_accounts()
+    }
 }
 
 impl<I, O, A> OutgoingService<A> for BtpService<I, O, A>
@@ -10,7 +10,7 @@
 //! we know about.
 
 use bytes::Bytes;
-use futures::Future;
+use futures::{future::ok, Future};
 use interledger_ildcp::IldcpAccount;
 use interledger_service::Account;
 use std::collections::HashMap;
@@ -19,12 +19,23 @@ use std::{str::FromStr, string::ToString};
 #[cfg(test)]
 mod fixtures;
 mod packet;
+mod policy;
 mod routing_table;
+mod scoring;
 mod server;
 #[cfg(test)]
 mod test_helpers;
 
-pub use server::{CcpRouteManager, CcpRouteManagerBuilder};
+pub use packet::{Mode, Route, RouteProp};
+pub use policy::RoutePolicy;
+pub use scoring::{
+    DefaultRouteScorer, LinkStats, RouteCandidate, RouteScorer, RouteStats, RouteStatsService,
+    WeightedRouteScorer,
+};
+pub use server::{
+    CcpRouteManager, CcpRouteManagerBuilder, ExplainedRoute, PeerSession, RouteExplanation,
+    RouteSelection, RoutingTableSnapshot,
+};
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
@@ -71,11 +82,76 @@ pub trait CcpRoutingAccount: Account + IldcpAccount {
     fn should_receive_routes(&self) -> bool {
         false
     }
+
+    /// The prefixes this account is allowed to advertise routes for (including the routes under them).
+    /// None means the account is not restricted, except that children can only ever advertise
+    /// routes under their own address.
+    fn allowed_route_prefixes(&self) -> Option<Vec<Bytes>> {
+        None
+    }
+
+    /// Filters applied to the routes this account sends us.
+    /// By default, this only allows the `allowed_route_prefixes`
+    fn route_import_policy(&self) -> RoutePolicy {
+        RoutePolicy {
+            allow: self.allowed_route_prefixes(),
+            ..RoutePolicy::default()
+        }
+    }
+
+    /// Filters applied to the routes we send this account
+    fn route_export_policy(&self) -> RoutePolicy {
+        RoutePolicy::default()
+    }
+
+    /// Indicates whether we should send this account the routes we learned from our parents
+    fn should_advertise_parent_routes(&self) -> bool {
+        true
+    }
+
+    /// The cost of forwarding packets to this account, which route scorers like the
+    /// `WeightedRouteScorer` add to the cost of each route through it
+    fn route_cost(&self) -> u32 {
+        0
+    }
+}
+
+/// View of the Route Manager's state, and ways to trigger its route exchanges, used by the admin API
+pub trait RouteManagerStatus: Send + Sync {
+    /// The CCP session with each account we send routes to, keyed by the account ID
+    fn peer_sessions(&self) -> Vec<(String, PeerSession)>;
+
+    /// Our copy of the routing table of each account we receive routes from, keyed by the account ID
+    fn incoming_tables(&self) -> Vec<(String, RoutingTableSnapshot)>;
+
+    /// The routing table we advertise to our peers
+    fn forwarding_table(&self) -> RoutingTableSnapshot;
+
+    /// The routes added and withdrawn in up to `limit` epochs of the forwarding table,
+    /// starting with the update that led to `from_epoch` (the first epoch is 1)
+    fn forwarding_table_updates(
+        &self,
+        from_epoch: u32,
+        limit: usize,
+    ) -> Vec<(Vec<Route>, Vec<Bytes>)>;
+
+    /// Explain which next hop was chosen for exactly this prefix and why
+    fn explain_route(
+        &self,
+        prefix: Bytes,
+    ) -> Box<dyn Future<Item = RouteExplanation, Error = ()> + Send>;
+
+    /// Update the best routes and send updates to peers now, instead of waiting for the broadcast interval
+    fn trigger_broadcast(&self) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Ask the account to send us its whole routing table and send it ours.
+    /// Fails if we do not exchange routes with the account
+    fn resync_peer(&self, account_id: String) -> Box<dyn Future<Item = (), Error = String> + Send>;
 }
 
 // key = Bytes, key should be Address -- TODO
-type Route<T> = HashMap<Bytes, T>;
-type LocalAndConfiguredRoutes<T> = (Route<T>, Route<T>);
+type Routes<T> = HashMap<Bytes, T>;
+type LocalAndConfiguredRoutes<T> = (Routes<T>, Routes<T>);
 
 pub trait RouteManagerStore: Clone {
     type Account: CcpRoutingAccount;
@@ -97,4 +173,14 @@ pub trait RouteManagerStore: Clone {
         &mut self,
         routes: impl IntoIterator<Item = (Bytes, Self::Account)>,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Save the other routes we have for each prefix, best first, so that packets can fail
+    /// over to them. The flag indicates whether the route is as good as the best route for
+    /// the prefix. By default, alternate routes are not saved
+    fn set_alternate_routes(
+        &mut self,
+        _routes: impl IntoIterator<Item = (Bytes, Vec<(Self::Account, bool)>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(ok(()))
+    }
 }
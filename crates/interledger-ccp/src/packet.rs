@@ -175,6 +175,14 @@ impl TryFrom<&mut &[u8]> for RouteProp {
 }
 
 impl RouteProp {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value[..]
+    }
+
     pub fn write_to<B>(&self, buf: &mut B)
     where
         B: BufMut,
@@ -243,6 +251,20 @@ impl TryFrom<&mut &[u8]> for Route {
 }
 
 impl Route {
+    /// The ILP address prefix this route is for
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix[..]
+    }
+
+    /// The addresses of the connectors packets sent over this route will go through
+    pub fn path(&self) -> &[Bytes] {
+        &self.path[..]
+    }
+
+    pub fn props(&self) -> &[RouteProp] {
+        &self.props[..]
+    }
+
     pub fn write_to<B>(&self, buf: &mut B)
     where
         B: BufMut,
@@ -0,0 +1,73 @@
+use bytes::Bytes;
+
+/// Check whether the prefix is the same as, or under, the other prefix
+pub(crate) fn is_under_prefix(prefix: &[u8], other: &[u8]) -> bool {
+    prefix.starts_with(other)
+        && (prefix.len() == other.len() || other.ends_with(b".") || prefix[other.len()] == b'.')
+}
+
+/// Filters applied to the routes we import from or export to an account.
+///
+/// A route is permitted if its prefix is under one of the `allow` prefixes (or `allow` is None),
+/// it is not under any of the `deny` prefixes, and its path is no longer than `max_path_length`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RoutePolicy {
+    pub allow: Option<Vec<Bytes>>,
+    pub deny: Vec<Bytes>,
+    pub max_path_length: Option<usize>,
+}
+
+impl RoutePolicy {
+    pub fn permits(&self, prefix: &[u8], path_length: usize) -> bool {
+        let allowed = self.allow.as_ref().map_or(true, |allow| {
+            allow.iter().any(|allowed| is_under_prefix(prefix, allowed))
+        });
+        let denied = self
+            .deny
+            .iter()
+            .any(|denied| is_under_prefix(prefix, denied));
+        let too_long = self.max_path_length.map_or(false, |max| path_length > max);
+        allowed && !denied && !too_long
+    }
+}
+
+#[cfg(test)]
+mod route_policy {
+    use super::*;
+
+    #[test]
+    fn matches_prefixes_on_segment_boundaries() {
+        assert!(is_under_prefix(b"example.a", b"example.a"));
+        assert!(is_under_prefix(b"example.a.b", b"example.a"));
+        assert!(is_under_prefix(b"example.a.b", b"example."));
+        assert!(!is_under_prefix(b"example.ab", b"example.a"));
+        assert!(!is_under_prefix(b"example", b"example.a"));
+    }
+
+    #[test]
+    fn default_policy_permits_everything() {
+        assert!(RoutePolicy::default().permits(b"example.a", 10));
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let policy = RoutePolicy {
+            allow: Some(vec![Bytes::from("example.a")]),
+            deny: vec![Bytes::from("example.a.private")],
+            max_path_length: None,
+        };
+        assert!(policy.permits(b"example.a.public", 1));
+        assert!(!policy.permits(b"example.a.private.b", 1));
+        assert!(!policy.permits(b"example.b", 1));
+    }
+
+    #[test]
+    fn limits_path_length() {
+        let policy = RoutePolicy {
+            max_path_length: Some(2),
+            ..RoutePolicy::default()
+        };
+        assert!(policy.permits(b"example.a", 2));
+        assert!(!policy.permits(b"example.a", 3));
+    }
+}
@@ -64,7 +64,6 @@ where
         }
     }
 
-    #[cfg(test)]
     pub fn set_id(&mut self, id: [u8; 16]) {
         self.id = id;
         self.epoch = 0;
@@ -111,6 +110,11 @@ where
         self.prefix_map.resolve(prefix)
     }
 
+    /// Iterate over the routes in the table, along with the account each one goes through
+    pub fn routes(&self) -> impl Iterator<Item = &(A, Route)> {
+        self.prefix_map.map.values()
+    }
+
     pub fn get_simplified_table(&self) -> HashMap<Bytes, A> {
         HashMap::from_iter(
             self.prefix_map
@@ -0,0 +1,298 @@
+use crate::{packet::Route, RoutingRelation};
+use futures::Future;
+use interledger_packet::ErrorClass;
+use interledger_service::{Account, BoxedIlpFuture, OutgoingRequest, OutgoingService};
+use parking_lot::RwLock;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How much weight the latest observation gets in the moving averages of the link stats
+const STATS_SMOOTHING_FACTOR: f64 = 0.2;
+/// Every this many milliseconds of round trip time counts as much as one more hop
+const ROUND_TRIP_TIME_MS_PER_HOP: f64 = 100.0;
+/// Stops routes with a reject rate of 100% from having an infinite cost
+const MIN_SUCCESS_RATE: f64 = 0.01;
+
+/// A route to a prefix, along with details about the account it would be forwarded to
+pub struct RouteCandidate<'a> {
+    pub account_id: String,
+    pub relation: RoutingRelation,
+    /// The cost configured for the account
+    pub cost: u32,
+    pub route: &'a Route,
+}
+
+/// Decides which of the routes we have heard for a prefix is the best one.
+/// Routes configured by the node operator and local routes are always used before these.
+pub trait RouteScorer: Send + Sync {
+    /// Returns `Ordering::Less` if route `a` is better than route `b`
+    fn compare(&self, a: &RouteCandidate, b: &RouteCandidate) -> Ordering;
+
+    /// Indicates whether the routes are equally good, ignoring tie-breakers,
+    /// so that packets can be balanced between them
+    fn is_equal_cost(&self, a: &RouteCandidate, b: &RouteCandidate) -> bool {
+        self.compare(a, b) == Ordering::Equal
+    }
+}
+
+/// Prefers children over peers over parents, then shorter paths, then the lower account ID.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultRouteScorer;
+
+impl RouteScorer for DefaultRouteScorer {
+    fn compare(&self, a: &RouteCandidate, b: &RouteCandidate) -> Ordering {
+        b.relation
+            .partial_cmp(&a.relation)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.route.path.len().cmp(&b.route.path.len()))
+            .then_with(|| a.account_id.cmp(&b.account_id))
+    }
+
+    fn is_equal_cost(&self, a: &RouteCandidate, b: &RouteCandidate) -> bool {
+        a.relation == b.relation && a.route.path.len() == b.route.path.len()
+    }
+}
+
+/// What we have observed about forwarding packets to an account
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkStats {
+    /// Moving average of the time it took to get a response
+    pub round_trip_time: Duration,
+    /// Moving average of the share of packets that were rejected with a temporary or relative error
+    pub reject_rate: f64,
+}
+
+/// Link stats for each account, keyed by the account ID.
+/// These are collected by the `RouteStatsService` and used by the `WeightedRouteScorer`
+#[derive(Clone, Default)]
+pub struct RouteStats {
+    stats: Arc<RwLock<HashMap<String, LinkStats>>>,
+}
+
+impl RouteStats {
+    pub fn new() -> Self {
+        RouteStats::default()
+    }
+
+    pub fn get(&self, account_id: &str) -> Option<LinkStats> {
+        self.stats.read().get(account_id).cloned()
+    }
+
+    pub fn record(&self, account_id: String, round_trip_time: Duration, rejected: bool) {
+        let rejected = if rejected { 1.0 } else { 0.0 };
+        let mut stats = self.stats.write();
+        let entry = stats.entry(account_id).or_insert_with(|| LinkStats {
+            round_trip_time,
+            reject_rate: rejected,
+        });
+        let round_trip_time_ms = duration_as_ms(entry.round_trip_time)
+            + STATS_SMOOTHING_FACTOR
+                * (duration_as_ms(round_trip_time) - duration_as_ms(entry.round_trip_time));
+        entry.round_trip_time = Duration::from_micros((round_trip_time_ms * 1000.0) as u64);
+        entry.reject_rate += STATS_SMOOTHING_FACTOR * (rejected - entry.reject_rate);
+    }
+}
+
+fn duration_as_ms(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_micros()) / 1000.0
+}
+
+/// # Route Stats Service
+///
+/// Outgoing service that records how long each account takes to respond to the
+/// packets we send it and how often it rejects them.
+#[derive(Clone)]
+pub struct RouteStatsService<O> {
+    stats: RouteStats,
+    next: O,
+}
+
+impl<O> RouteStatsService<O> {
+    pub fn new(stats: RouteStats, next: O) -> Self {
+        RouteStatsService { stats, next }
+    }
+}
+
+impl<O, A> OutgoingService<A> for RouteStatsService<O>
+where
+    O: OutgoingService<A>,
+    O::Future: Send + 'static,
+    A: Account,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        let stats = self.stats.clone();
+        let account_id = request.to.id().to_string();
+        let start = Instant::now();
+        Box::new(self.next.send_request(request).then(move |result| {
+            let rejected = match result {
+                Err(ref reject) => match reject.code().class() {
+                    ErrorClass::Temporary | ErrorClass::Relative => true,
+                    _ => false,
+                },
+                Ok(_) => false,
+            };
+            stats.record(account_id, start.elapsed(), rejected);
+            result
+        }))
+    }
+}
+
+/// Prefers the route with the lowest expected cost, where the cost of a route is
+/// the account's configured cost plus the number of hops plus its round trip time,
+/// scaled up by how often the account rejects packets.
+/// Ties are broken by the `DefaultRouteScorer`.
+#[derive(Clone)]
+pub struct WeightedRouteScorer {
+    stats: RouteStats,
+}
+
+impl WeightedRouteScorer {
+    pub fn new(stats: RouteStats) -> Self {
+        WeightedRouteScorer { stats }
+    }
+
+    fn cost(&self, candidate: &RouteCandidate) -> f64 {
+        let stats = self.stats.get(&candidate.account_id).unwrap_or_default();
+        let cost = f64::from(candidate.cost)
+            + candidate.route.path.len() as f64
+            + duration_as_ms(stats.round_trip_time) / ROUND_TRIP_TIME_MS_PER_HOP;
+        cost / (1.0 - stats.reject_rate).max(MIN_SUCCESS_RATE)
+    }
+}
+
+impl RouteScorer for WeightedRouteScorer {
+    fn compare(&self, a: &RouteCandidate, b: &RouteCandidate) -> Ordering {
+        self.cost(a)
+            .partial_cmp(&self.cost(b))
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| DefaultRouteScorer.compare(a, b))
+    }
+
+    fn is_equal_cost(&self, a: &RouteCandidate, b: &RouteCandidate) -> bool {
+        (self.cost(a) - self.cost(b)).abs() < std::f64::EPSILON
+    }
+}
+
+#[cfg(test)]
+mod scorers {
+    use super::*;
+    use bytes::Bytes;
+
+    fn route(path_length: usize) -> Route {
+        Route {
+            prefix: Bytes::from("example.a"),
+            path: vec![Bytes::from("example.hop"); path_length],
+            auth: [0; 32],
+            props: Vec::new(),
+        }
+    }
+
+    fn candidate<'a>(
+        account_id: &str,
+        relation: RoutingRelation,
+        cost: u32,
+        route: &'a Route,
+    ) -> RouteCandidate<'a> {
+        RouteCandidate {
+            account_id: account_id.to_string(),
+            relation,
+            cost,
+            route,
+        }
+    }
+
+    #[test]
+    fn default_prefers_relation_then_path_length_then_id() {
+        let (short, long) = (route(1), route(3));
+        let scorer = DefaultRouteScorer;
+        assert_eq!(
+            scorer.compare(
+                &candidate("1", RoutingRelation::Child, 0, &long),
+                &candidate("2", RoutingRelation::Peer, 0, &short)
+            ),
+            Ordering::Less
+        );
+        assert_eq!(
+            scorer.compare(
+                &candidate("2", RoutingRelation::Peer, 0, &short),
+                &candidate("1", RoutingRelation::Peer, 0, &long)
+            ),
+            Ordering::Less
+        );
+        assert_eq!(
+            scorer.compare(
+                &candidate("1", RoutingRelation::Peer, 0, &short),
+                &candidate("2", RoutingRelation::Peer, 0, &short)
+            ),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn default_ignores_account_ids_for_equal_cost() {
+        let (short, long) = (route(1), route(3));
+        let scorer = DefaultRouteScorer;
+        assert!(scorer.is_equal_cost(
+            &candidate("1", RoutingRelation::Peer, 0, &short),
+            &candidate("2", RoutingRelation::Peer, 0, &short)
+        ));
+        assert!(!scorer.is_equal_cost(
+            &candidate("1", RoutingRelation::Peer, 0, &short),
+            &candidate("2", RoutingRelation::Peer, 0, &long)
+        ));
+    }
+
+    #[test]
+    fn weighted_prefers_cheaper_routes() {
+        let (short, long) = (route(1), route(3));
+        let scorer = WeightedRouteScorer::new(RouteStats::new());
+        assert_eq!(
+            scorer.compare(
+                &candidate("1", RoutingRelation::Peer, 0, &long),
+                &candidate("2", RoutingRelation::Child, 5, &short)
+            ),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn weighted_avoids_slow_and_unreliable_accounts() {
+        let route = route(1);
+        let stats = RouteStats::new();
+        stats.record("slow".to_string(), Duration::from_millis(1000), false);
+        stats.record("flaky".to_string(), Duration::from_millis(10), true);
+        stats.record("healthy".to_string(), Duration::from_millis(10), false);
+        let scorer = WeightedRouteScorer::new(stats);
+        let healthy = candidate("healthy", RoutingRelation::Peer, 0, &route);
+        assert_eq!(
+            scorer.compare(
+                &healthy,
+                &candidate("slow", RoutingRelation::Peer, 0, &route)
+            ),
+            Ordering::Less
+        );
+        assert_eq!(
+            scorer.compare(
+                &healthy,
+                &candidate("flaky", RoutingRelation::Peer, 0, &route)
+            ),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn averages_link_stats() {
+        let stats = RouteStats::new();
+        stats.record("1".to_string(), Duration::from_millis(100), false);
+        stats.record("1".to_string(), Duration::from_millis(200), true);
+        let link = stats.get("1").unwrap();
+        assert_eq!(link.round_trip_time, Duration::from_millis(120));
+        assert!((link.reject_rate - 0.2).abs() < std::f64::EPSILON);
+    }
+}
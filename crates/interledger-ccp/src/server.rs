@@ -5,8 +5,10 @@ use crate::{
         Mode, Route, RouteControlRequest, RouteUpdateRequest, CCP_CONTROL_DESTINATION,
         CCP_RESPONSE, CCP_UPDATE_DESTINATION,
     },
+    policy::is_under_prefix,
     routing_table::RoutingTable,
-    CcpRoutingAccount, RouteManagerStore,
+    scoring::{DefaultRouteScorer, RouteCandidate, RouteScorer},
+    CcpRoutingAccount, RouteManagerStatus, RouteManagerStore, RoutingRelation,
 };
 use bytes::Bytes;
 use futures::{
@@ -23,20 +25,27 @@ use interledger_service::{
 use lazy_static::lazy_static;
 use log::{debug, error, trace, warn};
 use parking_lot::{Mutex, RwLock};
-use ring::digest::{digest, SHA256};
+use ring::{
+    digest::{digest, SHA256},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
 use std::collections::HashMap;
 use std::{
-    cmp::min,
+    cmp::{min, Ordering},
     convert::TryFrom,
     str,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use tokio_executor::spawn;
 use tokio_timer::Interval;
 
 const DEFAULT_ROUTE_EXPIRY_TIME: u32 = 45000;
 const DEFAULT_BROADCAST_INTERVAL: u64 = 30000;
+const DEFAULT_MAX_UNACKNOWLEDGED_UPDATES: u32 = 3;
+/// The most broadcasts we skip between the updates we send to unresponsive peers
+const MAX_PROBE_BACKOFF: u32 = 32;
 const DUMMY_ROUTING_TABLE_ID: [u8; 16] = [0; 16];
 
 fn hash(preimage: &[u8; 32]) -> [u8; 32] {
@@ -45,8 +54,127 @@ fn hash(preimage: &[u8; 32]) -> [u8; 32] {
     out
 }
 
+/// The start of the hash chain for routes we originate. Only we can generate it, so
+/// nobody else can advertise a shorter path to our routes than the one they got from us.
+fn route_auth(routing_secret: &[u8; 32], prefix: &[u8]) -> [u8; 32] {
+    let mut out = [0; 32];
+    let key = hmac::SigningKey::new(&SHA256, routing_secret);
+    out.copy_from_slice(hmac::sign(&key, prefix).as_ref());
+    out
+}
+
+/// Routes for the same prefix should have auth values from the same hash chain.
+/// Each hop hashes the auth, so a route whose path is N hops longer than another's
+/// must have an auth that is the other one's hashed N more times.
+fn is_same_hash_chain(a: &Route, b: &Route) -> bool {
+    let (shorter, longer) = if a.path.len() <= b.path.len() {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let mut auth = shorter.auth;
+    for _ in shorter.path.len()..longer.path.len() {
+        auth = hash(&auth);
+    }
+    auth == longer.auth
+}
+
 type NewAndWithdrawnRoutes = (Vec<Route>, Vec<Bytes>);
 
+/// The state of our CCP session with an account we send routes to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerSession {
+    /// Sync if the peer wants our Route Update Requests, Idle if it asked us to stop.
+    /// Only the peer's Route Control Requests change it
+    pub mode: Mode,
+    /// The last epoch of our forwarding table the peer has acknowledged
+    pub last_acknowledged_epoch: u32,
+    /// When the peer last sent us a Route Control Request or acknowledged an update
+    pub last_contact: Option<SystemTime>,
+    /// The number of updates in a row the peer has not acknowledged
+    pub unacknowledged_updates: u32,
+    /// Set once the peer has not acknowledged too many updates in a row. We keep sending
+    /// it updates, but skip more broadcasts between them each time it does not respond
+    pub unresponsive: bool,
+    /// The number of broadcasts to skip before sending an unresponsive peer another update
+    pub broadcasts_until_probe: u32,
+}
+
+impl Default for PeerSession {
+    /// Peers start in Sync mode so that we broadcast to every account we are configured
+    /// to send routes to, until they tell us otherwise
+    fn default() -> Self {
+        PeerSession {
+            mode: Mode::Sync,
+            last_acknowledged_epoch: 0,
+            last_contact: None,
+            unacknowledged_updates: 0,
+            unresponsive: false,
+            broadcasts_until_probe: 0,
+        }
+    }
+}
+
+/// A copy of one of the Route Manager's routing tables
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutingTableSnapshot {
+    pub id: [u8; 16],
+    pub epoch: u32,
+    /// Each route in the table and the ID of the account it goes through
+    pub routes: Vec<(String, Route)>,
+}
+
+impl<A: Account> From<&RoutingTable<A>> for RoutingTableSnapshot {
+    fn from(table: &RoutingTable<A>) -> Self {
+        RoutingTableSnapshot {
+            id: table.id(),
+            epoch: table.epoch(),
+            routes: table
+                .routes()
+                .map(|(account, route)| (account.id().to_string(), route.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// The reason the Route Manager chose the next hop it did for a prefix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RouteSelection {
+    /// The node operator configured the route, so it is always used
+    Configured,
+    /// The prefix belongs to one of our own accounts
+    Local,
+    /// The route scorer ranked this route first of the ones peers advertised
+    BestAdvertised,
+    /// The prefix was recently withdrawn, so routes for it are ignored until its hold-down ends
+    HeldDown,
+    /// Nobody advertised a route for the prefix
+    NoRoute,
+}
+
+/// A route a peer advertised for the prefix being explained
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExplainedRoute {
+    pub account_id: String,
+    pub relation: RoutingRelation,
+    /// The cost configured for the account
+    pub cost: u32,
+    pub route: Route,
+    /// Whether the route is as good as the best advertised one, ignoring tie-breakers
+    pub equal_cost: bool,
+}
+
+/// How the Route Manager chose the next hop for a prefix
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteExplanation {
+    pub prefix: Bytes,
+    /// The account packets for the prefix are currently forwarded to
+    pub next_hop: Option<String>,
+    pub selection: RouteSelection,
+    /// The routes peers advertised for the prefix, best first
+    pub candidates: Vec<ExplainedRoute>,
+}
+
 pub struct CcpRouteManagerBuilder<I, O, S> {
     /// The next request handler that will be used both to pass on requests that are not CCP messages.
     next_incoming: I,
@@ -61,6 +189,11 @@ pub struct CcpRouteManagerBuilder<I, O, S> {
     global_prefix: Bytes,
     spawn_tasks: bool,
     broadcast_interval: u64,
+    max_unacknowledged_updates: u32,
+    route_expiry_time: Option<u64>,
+    route_hold_down_time: u64,
+    routing_secret: [u8; 32],
+    route_scorer: Arc<dyn RouteScorer>,
 }
 
 impl<I, O, S, A> CcpRouteManagerBuilder<I, O, S>
@@ -79,6 +212,17 @@ where
             store,
             spawn_tasks: true,
             broadcast_interval: DEFAULT_BROADCAST_INTERVAL,
+            max_unacknowledged_updates: DEFAULT_MAX_UNACKNOWLEDGED_UPDATES,
+            route_expiry_time: None,
+            route_hold_down_time: 0,
+            routing_secret: {
+                let mut secret = [0; 32];
+                SystemRandom::new()
+                    .fill(&mut secret)
+                    .expect("Unable to generate routing secret");
+                secret
+            },
+            route_scorer: Arc::new(DefaultRouteScorer),
         }
     }
 
@@ -104,6 +248,53 @@ where
         self
     }
 
+    /// Set how many Route Update Requests in a row a peer can fail to acknowledge
+    /// before we move it to Idle mode and stop sending it updates
+    pub fn max_unacknowledged_updates(&mut self, count: u32) -> &mut Self {
+        self.max_unacknowledged_updates = count;
+        self
+    }
+
+    /// Set how long (in milliseconds) routes from a peer are kept if it sends no updates or heartbeats.
+    /// This is also the hold time we advertise in our own updates.
+    /// By default, routes expire after the hold time each peer advertises.
+    /// Times longer than the longest hold time CCP can carry (about 49 days) are shortened to it.
+    pub fn route_expiry_time(&mut self, ms: u64) -> &mut Self {
+        let ms = u32::try_from(ms).unwrap_or_else(|_| {
+            warn!(
+                "Route expiry time of {} ms is longer than the longest hold time that can be advertised, using {} ms instead",
+                ms,
+                u32::max_value()
+            );
+            u32::max_value()
+        });
+        self.route_expiry_time = Some(u64::from(ms));
+        self
+    }
+
+    /// Set how long (in milliseconds) a withdrawn prefix is held down before routes
+    /// learned from peers for it are used again. This dampens flapping routes.
+    /// Defaults to 0, which disables hold-down.
+    pub fn route_hold_down_time(&mut self, ms: u64) -> &mut Self {
+        self.route_hold_down_time = ms;
+        self
+    }
+
+    /// Set the secret used to authenticate the routes we originate.
+    /// This should stay the same when the node restarts so that the routes
+    /// we advertise keep the same auth values. Defaults to a random secret.
+    pub fn routing_secret(&mut self, secret: [u8; 32]) -> &mut Self {
+        self.routing_secret = secret;
+        self
+    }
+
+    /// Set how the best route is chosen when we have heard routes for a prefix from
+    /// multiple accounts. Defaults to the `DefaultRouteScorer`.
+    pub fn route_scorer(&mut self, scorer: Arc<dyn RouteScorer>) -> &mut Self {
+        self.route_scorer = scorer;
+        self
+    }
+
     pub fn to_service(&self) -> CcpRouteManager<I, O, S, A> {
         let service = CcpRouteManager {
             ilp_address: self.ilp_address.clone(),
@@ -117,6 +308,14 @@ where
             last_epoch_updates_sent_for: Arc::new(Mutex::new(0)),
             local_table: Arc::new(RwLock::new(RoutingTable::default())),
             incoming_tables: Arc::new(RwLock::new(HashMap::new())),
+            peer_sessions: Arc::new(RwLock::new(HashMap::new())),
+            max_unacknowledged_updates: self.max_unacknowledged_updates,
+            incoming_table_expiry: Arc::new(RwLock::new(HashMap::new())),
+            route_expiry_time: self.route_expiry_time,
+            held_down_prefixes: Arc::new(RwLock::new(HashMap::new())),
+            route_hold_down_time: Duration::from_millis(self.route_hold_down_time),
+            routing_secret: Arc::new(self.routing_secret),
+            route_scorer: self.route_scorer.clone(),
         };
 
         if self.spawn_tasks {
@@ -158,6 +357,21 @@ pub struct CcpRouteManager<I, O, S, A: Account> {
     /// Updates from peers are applied to our local_table if they are better than the
     /// existing best route and if they do not attempt to overwrite configured routes.
     incoming_tables: Arc<RwLock<HashMap<A::AccountId, RoutingTable<A>>>>,
+    /// The CCP session with each peer we send routes to. Peers in Idle mode are skipped
+    /// when we broadcast updates.
+    peer_sessions: Arc<RwLock<HashMap<A::AccountId, PeerSession>>>,
+    max_unacknowledged_updates: u32,
+    /// When the routes in each peer's incoming table expire, unless the peer sends
+    /// another update or heartbeat before then
+    incoming_table_expiry: Arc<RwLock<HashMap<A::AccountId, Instant>>>,
+    route_expiry_time: Option<u64>,
+    /// Withdrawn prefixes and when their hold-down ends. Until then, routes from peers
+    /// for these prefixes are ignored.
+    held_down_prefixes: Arc<RwLock<HashMap<Bytes, Instant>>>,
+    route_hold_down_time: Duration,
+    /// Used to generate the auth for the routes we originate
+    routing_secret: Arc<[u8; 32]>,
+    route_scorer: Arc<dyn RouteScorer>,
     store: S,
     /// If true, tasks will be spawned to process Route Update Requests and respond
     /// to Route Control Requests. If false, the response to the incoming request
@@ -188,8 +402,78 @@ where
 
     pub fn broadcast_routes(&self) -> impl Future<Item = (), Error = ()> {
         let clone = self.clone();
-        self.update_best_routes(None)
-            .and_then(move |_| clone.send_route_updates())
+        let clone_2 = self.clone();
+        self.expire_routes()
+            .and_then(move |_| clone.update_best_routes(None))
+            .and_then(move |_| clone_2.send_route_updates())
+    }
+
+    /// Remove all of the routes we learned from the given peer, for example because
+    /// our connection to it went down. The withdrawn routes will be included in the
+    /// next update we broadcast.
+    pub fn withdraw_peer_routes(
+        &self,
+        account_id: A::AccountId,
+    ) -> impl Future<Item = (), Error = ()> {
+        debug!("Withdrawing routes learned from account {}", account_id);
+        let prefixes = self.remove_incoming_tables(&[account_id]);
+        self.update_best_routes(Some(prefixes))
+    }
+
+    /// Remove the routes of peers we have not heard from within the route expiry time
+    /// and check the prefixes whose hold-down period has ended.
+    fn expire_routes(&self) -> impl Future<Item = (), Error = ()> {
+        let now = Instant::now();
+        let expired: Vec<A::AccountId> = self
+            .incoming_table_expiry
+            .read()
+            .iter()
+            .filter(|(_account_id, expiry)| **expiry <= now)
+            .map(|(account_id, _expiry)| *account_id)
+            .collect();
+        for account_id in expired.iter() {
+            warn!(
+                "Routes from account {} expired because it did not send any updates within the hold time",
+                account_id
+            );
+        }
+        let mut prefixes = self.remove_incoming_tables(&expired);
+
+        let mut held_down_prefixes = self.held_down_prefixes.write();
+        let released: Vec<Bytes> = held_down_prefixes
+            .iter()
+            .filter(|(_prefix, until)| **until <= now)
+            .map(|(prefix, _until)| prefix.clone())
+            .collect();
+        for prefix in released {
+            held_down_prefixes.remove(&prefix);
+            prefixes.push(prefix);
+        }
+
+        if prefixes.is_empty() {
+            Either::A(ok(()))
+        } else {
+            Either::B(self.update_best_routes(Some(prefixes)))
+        }
+    }
+
+    /// Remove the incoming tables of the given peers and return the prefixes they had routes for
+    fn remove_incoming_tables(&self, account_ids: &[A::AccountId]) -> Vec<Bytes> {
+        let mut incoming_tables = self.incoming_tables.write();
+        let mut incoming_table_expiry = self.incoming_table_expiry.write();
+        let mut prefixes = Vec::new();
+        for account_id in account_ids {
+            incoming_table_expiry.remove(account_id);
+            if let Some(table) = incoming_tables.remove(account_id) {
+                prefixes.extend(
+                    table
+                        .get_simplified_table()
+                        .into_iter()
+                        .map(|(prefix, _)| prefix),
+                );
+            }
+        }
+        prefixes
     }
 
     /// Request routes from all the peers we are willing to receive routes from.
@@ -240,8 +524,22 @@ where
             control
         );
 
-        // TODO stop sending updates if they are in Idle mode
-        if control.mode == Mode::Sync {
+        {
+            let mut peer_sessions = self.peer_sessions.write();
+            let session = peer_sessions.entry(request.from.id()).or_default();
+            session.mode = control.mode;
+            session.last_contact = Some(SystemTime::now());
+            session.unacknowledged_updates = 0;
+            session.unresponsive = false;
+            session.broadcasts_until_probe = 0;
+        }
+
+        if control.mode == Mode::Idle {
+            debug!(
+                "Account {} is in Idle mode, no longer sending it route updates",
+                request.from.id()
+            );
+        } else {
             let (from_epoch_index, to_epoch_index) = {
                 let forwarding_table = self.forwarding_table.read();
                 let to_epoch_index = forwarding_table.epoch();
@@ -253,6 +551,9 @@ where
                     };
                 (from_epoch_index, to_epoch_index)
             };
+            if let Some(session) = self.peer_sessions.write().get_mut(&request.from.id()) {
+                session.last_acknowledged_epoch = from_epoch_index;
+            }
 
             let ilp_address = self.ilp_address.clone();
             if !self.spawn_tasks {
@@ -282,7 +583,9 @@ where
     }
 
     /// Remove invalid routes before processing the Route Update Request
-    fn filter_routes(&self, mut update: RouteUpdateRequest) -> RouteUpdateRequest {
+    fn filter_routes(&self, account: &A, mut update: RouteUpdateRequest) -> RouteUpdateRequest {
+        let import_policy = account.route_import_policy();
+        let incoming_tables = self.incoming_tables.read();
         update.new_routes = update
             .new_routes
             .into_iter()
@@ -299,6 +602,33 @@ where
                         route
                     );
                     false
+                } else if account.routing_relation() == RoutingRelation::Child
+                    && !is_under_prefix(&route.prefix, account.client_address().as_ref())
+                {
+                    warn!(
+                        "Child account {} advertised a route outside of its own address: {:?}",
+                        account.id(),
+                        route
+                    );
+                    false
+                } else if !import_policy.permits(&route.prefix, route.path.len()) {
+                    warn!(
+                        "Route from account {} is not permitted by its import policy: {:?}",
+                        account.id(),
+                        route
+                    );
+                    false
+                } else if let Some(trusted) = self
+                    .trusted_route(account, route, &incoming_tables)
+                    .filter(|trusted| !is_same_hash_chain(route, trusted))
+                {
+                    error!(
+                        "Route from account {} does not match the auth of the route from the prefix's originator, ignoring it: {:?} (originator's route: {:?})",
+                        account.id(),
+                        route,
+                        trusted
+                    );
+                    false
                 } else {
                     true
                 }
@@ -307,6 +637,58 @@ where
         update
     }
 
+    /// The route that a route for the same prefix must share a hash chain with, if we know
+    /// where the chain starts. The prefix is originated by whoever has the most specific
+    /// address it is under: us (for our own and our children's prefixes, unless the child
+    /// advertises them itself), the account advertising the route, or another account that
+    /// advertised the prefix to us directly. Which routes we happened to hear first does
+    /// not matter. Returns None if the route comes from its originator or we do not know
+    /// the originator's auth
+    fn trusted_route(
+        &self,
+        account: &A,
+        route: &Route,
+        incoming_tables: &HashMap<A::AccountId, RoutingTable<A>>,
+    ) -> Option<Route> {
+        let originates = |address: &Address| is_under_prefix(&route.prefix, address.as_ref());
+        let mut trusted: Option<(usize, Option<Route>)> = None;
+        let mut consider = |address: &Address, chain_start: Option<Route>| {
+            let more_specific = match trusted {
+                Some((len, _)) => address.len() > len,
+                None => true,
+            };
+            if originates(address) && more_specific {
+                trusted = Some((address.len(), chain_start));
+            }
+        };
+
+        consider(account.client_address(), None);
+        consider(
+            &self.ilp_address,
+            Some(Route {
+                prefix: route.prefix.clone(),
+                path: vec![self.ilp_address.to_bytes()],
+                auth: hash(&route_auth(&self.routing_secret, &route.prefix)),
+                props: Vec::new(),
+            }),
+        );
+        for (other_account, other) in incoming_tables
+            .iter()
+            .filter(|(account_id, _)| **account_id != account.id())
+            .filter_map(|(_, table)| table.get_route(&route.prefix))
+        {
+            // Only the originator's own advertisement starts the hash chain
+            if other.prefix == route.prefix
+                && other.path.len() == 1
+                && other.path[0] == other_account.client_address().to_bytes()
+            {
+                consider(other_account.client_address(), Some(other.clone()));
+            }
+        }
+
+        trusted.and_then(|(_, route)| route)
+    }
+
     /// Check if this Route Update Request is valid and, if so, apply any updates it contains.
     /// If updates are applied to the Incoming Routing Table for this peer, we will
     /// then check whether those routes are better than the current best ones we have in the
@@ -339,7 +721,16 @@ where
             update
         );
 
-        let update = self.filter_routes(update);
+        let update = self.filter_routes(&request.from, update);
+
+        // Any update, including an empty heartbeat, keeps the peer's routes from expiring
+        let hold_time = self
+            .route_expiry_time
+            .unwrap_or_else(|| u64::from(update.hold_down_time));
+        self.incoming_table_expiry.write().insert(
+            request.from.id(),
+            Instant::now() + Duration::from_millis(hold_time),
+        );
 
         let mut incoming_tables = self.incoming_tables.write();
         if !&incoming_tables.contains_key(&request.from.id()) {
@@ -454,9 +845,14 @@ where
         let forwarding_table = self.forwarding_table.clone();
         let forwarding_table_updates = self.forwarding_table_updates.clone();
         let incoming_tables = self.incoming_tables.clone();
+        let routing_secret = self.routing_secret.clone();
+        let route_scorer = self.route_scorer.clone();
+        let held_down_prefixes = self.held_down_prefixes.clone();
+        let route_hold_down_time = self.route_hold_down_time;
         let ilp_address = self.ilp_address.clone();
         let global_prefix = self.global_prefix.clone();
         let mut store = self.store.clone();
+        let mut alternates_store = self.store.clone();
 
         self.store.get_local_and_configured_routes().and_then(
             move |(ref local_routes, ref configured_routes)| {
@@ -464,6 +860,8 @@ where
                     // Note we only use a read lock here and later get a write lock if we need to update the table
                     let local_table = local_table.read();
                     let incoming_tables = incoming_tables.read();
+                    let held_down_prefixes = held_down_prefixes.read();
+                    let now = Instant::now();
 
                     // Either check the given prefixes or check all of our local and configured routes
                     let prefixes_to_check: Box<dyn Iterator<Item = Bytes>> = if let Some(prefixes) = prefixes {
@@ -478,18 +876,28 @@ where
                     let mut better_routes: Vec<(Bytes, A, Route)> = Vec::with_capacity(prefixes_to_check.size_hint().0);
                     let mut withdrawn_routes: Vec<Bytes> = Vec::new();
                     for prefix in prefixes_to_check {
+                        // Ignore routes from peers for prefixes that were recently withdrawn
+                        let held_down = held_down_prefixes.get(&prefix).map(|until| *until > now).unwrap_or(false)
+                            && !configured_routes.contains_key(&prefix)
+                            && !local_routes.contains_key(&prefix);
+                        if held_down {
+                            trace!("Prefix {} is held down, not updating its route", str::from_utf8(prefix.as_ref()).unwrap_or("<not utf8>"));
+                            continue
+                        }
+
                         // See which prefixes there is now a better route for
                         if let Some((best_next_account, best_route)) = get_best_route_for_prefix(
                             local_routes,
                             configured_routes,
                             &incoming_tables,
                             prefix.as_ref(),
+                            route_scorer.as_ref(),
                         ) {
                             if let Some((ref next_account, ref route)) = local_table.get_route(&prefix) {
-                                if next_account.id() == best_next_account.id() {
+                                if next_account.id() == best_next_account.id() && route.prefix == best_route.prefix {
                                     continue
                                 } else {
-                                    better_routes.push((prefix.clone(), next_account.clone(), route.clone()));
+                                    better_routes.push((prefix.clone(), best_next_account, best_route));
                                 }
                             } else {
                                 better_routes.push((prefix.clone(), best_next_account, best_route));
@@ -503,7 +911,7 @@ where
                 };
 
                 // Update the local and forwarding tables
-                if !better_routes.is_empty() || !withdrawn_routes.is_empty() {
+                let saved_routes = if !better_routes.is_empty() || !withdrawn_routes.is_empty() {
                     let mut local_table = local_table.write();
                     let mut forwarding_table = forwarding_table.write();
                     let mut forwarding_table_updates = forwarding_table_updates.write();
@@ -531,6 +939,10 @@ where
 
                                 let old_route = forwarding_table.get_route(&prefix);
                                 if old_route.is_none() || old_route.unwrap().0.id() != account.id() {
+                                    // Routes we originate start a new hash chain
+                                    if route.path.is_empty() {
+                                        route.auth = route_auth(&routing_secret, &route.prefix);
+                                    }
                                     route.path.insert(0, ilp_address.to_bytes());
                                     // Each hop hashes the auth before forwarding
                                     route.auth = hash(&route.auth);
@@ -544,6 +956,9 @@ where
                         debug!("Removed route for prefix: {}", str::from_utf8(&prefix[..]).unwrap_or("<not utf8>"));
                         local_table.delete_route(prefix);
                         forwarding_table.delete_route(prefix);
+                        if route_hold_down_time > Duration::from_millis(0) {
+                            held_down_prefixes.write().insert(prefix.clone(), Instant::now() + route_hold_down_time);
+                        }
                     }
 
                     let epoch = forwarding_table.increment_epoch();
@@ -554,7 +969,17 @@ where
                 } else {
                     // The routing table hasn't changed
                     Either::B(ok(()))
-                }
+                };
+
+                // The alternatives may have changed even if the best routes have not
+                let alternate_routes = get_alternate_routes(
+                    &local_table.read(),
+                    local_routes,
+                    configured_routes,
+                    &incoming_tables.read(),
+                    route_scorer.as_ref(),
+                );
+                saved_routes.and_then(move |_| alternates_store.set_alternate_routes(alternate_routes))
             },
         )
     }
@@ -579,12 +1004,25 @@ where
             route_update_request,
         );
 
-        let prepare = route_update_request.to_prepare();
+        let clone = self.clone();
         self.store
             .get_accounts_to_send_routes_to()
             .and_then(move |mut accounts| {
                 accounts.sort_unstable_by_key(|a| a.id().to_string());
                 accounts.dedup_by_key(|a| a.id());
+                {
+                    // Don't send updates to peers that asked us to stop, and only probe the
+                    // ones that stopped responding every so often
+                    let mut peer_sessions = clone.peer_sessions.write();
+                    accounts.retain(|account| match peer_sessions.get_mut(&account.id()) {
+                        Some(ref session) if session.mode == Mode::Idle => false,
+                        Some(ref mut session) if session.broadcasts_until_probe > 0 => {
+                            session.broadcasts_until_probe -= 1;
+                            false
+                        }
+                        _ => true,
+                    });
+                }
 
                 let broadcasting = !accounts.is_empty();
                 if broadcasting {
@@ -598,20 +1036,31 @@ where
                     Either::A(
                         join_all(accounts.into_iter().map(move |account| {
                             let account_id = account.id();
+                            let clone = clone.clone();
+                            let prepare = clone
+                                .apply_export_policy(&account, route_update_request.clone())
+                                .to_prepare();
                             outgoing
                                 .send_request(OutgoingRequest {
                                     from: account.clone(),
                                     to: account,
                                     original_amount: prepare.amount(),
-                                    prepare: prepare.clone(),
+                                    prepare,
                                 })
-                                .map_err(move |err| {
-                                    warn!(
-                                        "Error sending route update to account {}: {:?}",
-                                        account_id, err
-                                    )
+                                .then(move |result| {
+                                    if let Err(ref err) = result {
+                                        warn!(
+                                            "Error sending route update to account {}: {:?}",
+                                            account_id, err
+                                        )
+                                    }
+                                    clone.handle_update_response(
+                                        account_id,
+                                        to_epoch_index,
+                                        result.is_ok(),
+                                    );
+                                    Ok(())
                                 })
-                                .and_then(|_| Ok(()))
                         }))
                         .and_then(|_| {
                             trace!("Finished sending route updates");
@@ -683,10 +1132,49 @@ where
             new_routes: new_routes.clone(),
             withdrawn_routes: withdrawn_routes.clone(),
             speaker: self.ilp_address.clone(),
-            hold_down_time: DEFAULT_ROUTE_EXPIRY_TIME,
+            hold_down_time: self
+                .route_expiry_time
+                .map(|ms| u32::try_from(ms).unwrap_or_else(|_| u32::max_value()))
+                .unwrap_or(DEFAULT_ROUTE_EXPIRY_TIME),
         }
     }
 
+    /// Remove the routes the account's export policy does not permit from the update.
+    /// Those routes are sent as withdrawn instead, in case the account got them from us before.
+    fn apply_export_policy(
+        &self,
+        account: &A,
+        mut update: RouteUpdateRequest,
+    ) -> RouteUpdateRequest {
+        let policy = account.route_export_policy();
+        let advertise_parent_routes = account.should_advertise_parent_routes();
+        let local_table = self.local_table.read();
+        let (new_routes, filtered): (Vec<Route>, Vec<Route>) =
+            update.new_routes.into_iter().partition(|route| {
+                let from_parent =
+                    local_table
+                        .get_route(&route.prefix)
+                        .map_or(false, |(next_hop, best)| {
+                            best.prefix == route.prefix
+                                && next_hop.routing_relation() == RoutingRelation::Parent
+                        });
+                policy.permits(&route.prefix, route.path.len())
+                    && (advertise_parent_routes || !from_parent)
+            });
+        if !filtered.is_empty() {
+            trace!(
+                "Not sending routes to account {} because of its export policy: {:?}",
+                account.id(),
+                filtered
+            );
+        }
+        update.new_routes = new_routes;
+        update
+            .withdrawn_routes
+            .extend(filtered.into_iter().map(|route| route.prefix));
+        update
+    }
+
     /// Send a Route Update Request to a specific account for the given epoch range.
     /// This is used when the peer has fallen behind and has requested a specific range of updates.
     fn send_route_update(
@@ -695,14 +1183,14 @@ where
         from_epoch_index: u32,
         to_epoch_index: u32,
     ) -> impl Future<Item = (), Error = ()> {
-        let prepare = self
-            .create_route_update(from_epoch_index, to_epoch_index)
-            .to_prepare();
+        let update = self.create_route_update(from_epoch_index, to_epoch_index);
+        let prepare = self.apply_export_policy(&account, update).to_prepare();
         let account_id = account.id();
         debug!(
             "Sending individual route update to account: {} for epochs from: {} to: {}",
             account_id, from_epoch_index, to_epoch_index
         );
+        let clone = self.clone();
         self.outgoing
             .clone()
             .send_request(OutgoingRequest {
@@ -711,17 +1199,213 @@ where
                 original_amount: prepare.amount(),
                 prepare,
             })
-            .and_then(|_| Ok(()))
             .then(move |result| {
-                if let Err(err) = result {
+                if let Err(ref err) = result {
                     error!(
                         "Error sending route update to account {}: {:?}",
                         account_id, err
                     )
                 }
+                clone.handle_update_response(account_id, to_epoch_index, result.is_ok());
                 Ok(())
             })
     }
+
+    /// Record whether the peer acknowledged the Route Update Request we sent it.
+    /// Peers that stop acknowledging our updates are marked as unresponsive and only sent
+    /// an update every few broadcasts, backing off until they acknowledge one or send us
+    /// another Route Control Request. Unlike Idle mode, this never stops the updates entirely.
+    fn handle_update_response(
+        &self,
+        account_id: A::AccountId,
+        to_epoch_index: u32,
+        acknowledged: bool,
+    ) {
+        let mut peer_sessions = self.peer_sessions.write();
+        let session = peer_sessions.entry(account_id).or_default();
+        if acknowledged {
+            session.last_acknowledged_epoch = to_epoch_index;
+            session.last_contact = Some(SystemTime::now());
+            session.unacknowledged_updates = 0;
+            if session.unresponsive {
+                debug!(
+                    "Account {} is acknowledging route updates again",
+                    account_id
+                );
+            }
+            session.unresponsive = false;
+            session.broadcasts_until_probe = 0;
+        } else {
+            session.unacknowledged_updates += 1;
+            if session.unacknowledged_updates >= self.max_unacknowledged_updates {
+                if !session.unresponsive {
+                    warn!(
+                        "Account {} did not acknowledge the last {} route updates, only sending it updates occasionally until it does",
+                        account_id, session.unacknowledged_updates
+                    );
+                }
+                session.unresponsive = true;
+                let missed = session.unacknowledged_updates - self.max_unacknowledged_updates;
+                session.broadcasts_until_probe = 1u32
+                    .checked_shl(missed)
+                    .unwrap_or(MAX_PROBE_BACKOFF)
+                    .min(MAX_PROBE_BACKOFF);
+            }
+        }
+    }
+}
+
+impl<I, O, S, A> RouteManagerStatus for CcpRouteManager<I, O, S, A>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    O: OutgoingService<A> + Clone + Send + Sync + 'static,
+    S: RouteManagerStore<Account = A> + Clone + Send + Sync + 'static,
+    A: CcpRoutingAccount + Send + Sync + 'static,
+{
+    fn peer_sessions(&self) -> Vec<(String, PeerSession)> {
+        self.peer_sessions
+            .read()
+            .iter()
+            .map(|(account_id, session)| (account_id.to_string(), session.clone()))
+            .collect()
+    }
+
+    fn incoming_tables(&self) -> Vec<(String, RoutingTableSnapshot)> {
+        self.incoming_tables
+            .read()
+            .iter()
+            .map(|(account_id, table)| (account_id.to_string(), RoutingTableSnapshot::from(table)))
+            .collect()
+    }
+
+    fn forwarding_table(&self) -> RoutingTableSnapshot {
+        RoutingTableSnapshot::from(&*self.forwarding_table.read())
+    }
+
+    fn forwarding_table_updates(
+        &self,
+        from_epoch: u32,
+        limit: usize,
+    ) -> Vec<(Vec<Route>, Vec<Bytes>)> {
+        self.forwarding_table_updates
+            .read()
+            .iter()
+            .skip(from_epoch.saturating_sub(1) as usize)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    fn explain_route(
+        &self,
+        prefix: Bytes,
+    ) -> Box<dyn Future<Item = RouteExplanation, Error = ()> + Send> {
+        let local_table = self.local_table.clone();
+        let incoming_tables = self.incoming_tables.clone();
+        let held_down_prefixes = self.held_down_prefixes.clone();
+        let route_scorer = self.route_scorer.clone();
+        Box::new(self.store.get_local_and_configured_routes().map(
+            move |(local_routes, configured_routes)| {
+                // The tables return the longest prefix that covers the given one, so only
+                // the routes for exactly this prefix are kept
+                let next_hop = local_table
+                    .read()
+                    .get_route(&prefix)
+                    .filter(|(_account, route)| route.prefix == prefix)
+                    .map(|(account, _route)| account.id().to_string());
+
+                let incoming_tables = incoming_tables.read();
+                let mut advertised: Vec<(&A, RouteCandidate)> = incoming_tables
+                    .values()
+                    .filter_map(|incoming_table| incoming_table.get_route(&prefix))
+                    .filter(|(_account, route)| route.prefix == prefix)
+                    .map(|(account, route)| (account, route_candidate(account, route)))
+                    .collect();
+                advertised.sort_by(|(_, a), (_, b)| route_scorer.compare(a, b));
+                let candidates: Vec<ExplainedRoute> = advertised
+                    .iter()
+                    .map(|(_, candidate)| ExplainedRoute {
+                        account_id: candidate.account_id.clone(),
+                        relation: candidate.relation,
+                        cost: candidate.cost,
+                        route: candidate.route.clone(),
+                        equal_cost: route_scorer.is_equal_cost(&advertised[0].1, candidate),
+                    })
+                    .collect();
+
+                let held_down = held_down_prefixes
+                    .read()
+                    .get(&prefix)
+                    .map(|until| *until > Instant::now())
+                    .unwrap_or(false);
+                let selection = if configured_routes.contains_key(&prefix) {
+                    RouteSelection::Configured
+                } else if local_routes.contains_key(&prefix) {
+                    RouteSelection::Local
+                } else if held_down {
+                    RouteSelection::HeldDown
+                } else if !candidates.is_empty() {
+                    RouteSelection::BestAdvertised
+                } else {
+                    RouteSelection::NoRoute
+                };
+
+                RouteExplanation {
+                    prefix,
+                    next_hop,
+                    selection,
+                    candidates,
+                }
+            },
+        ))
+    }
+
+    fn trigger_broadcast(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(self.broadcast_routes())
+    }
+
+    fn resync_peer(&self, account_id: String) -> Box<dyn Future<Item = (), Error = String> + Send> {
+        let clone = self.clone();
+        let find_account = move |accounts: Vec<A>, account_id: &str| {
+            accounts
+                .into_iter()
+                .find(|account| account.id().to_string() == account_id)
+        };
+        let receive_from = self.store.get_accounts_to_receive_routes_from();
+        let send_to = self.store.get_accounts_to_send_routes_to();
+        Box::new(
+            receive_from
+                .join(send_to)
+                .map_err(|_| "Error loading accounts from the store".to_string())
+                .and_then(move |(receive_from, send_to)| {
+                    let receive_from = find_account(receive_from, &account_id);
+                    let send_to = find_account(send_to, &account_id);
+                    if receive_from.is_none() && send_to.is_none() {
+                        return Either::A(err(format!(
+                            "We do not exchange routes with account {}",
+                            account_id
+                        )));
+                    }
+                    debug!("Resyncing routes with account {}", account_id);
+
+                    let request_routes = receive_from.map(|account| {
+                        // Forget which version of the peer's table we have so that
+                        // it sends us the whole table again
+                        if let Some(table) = clone.incoming_tables.write().get_mut(&account.id()) {
+                            table.set_id(DUMMY_ROUTING_TABLE_ID);
+                        }
+                        clone.send_route_control_request(account, DUMMY_ROUTING_TABLE_ID, 0)
+                    });
+                    let send_routes = send_to.map(|account| {
+                        *clone.peer_sessions.write().entry(account.id()).or_default() =
+                            PeerSession::default();
+                        let to_epoch_index = clone.forwarding_table.read().epoch();
+                        clone.send_route_update(account, 0, to_epoch_index)
+                    });
+                    Either::B(request_routes.join(send_routes).then(|_| Ok(())))
+                }),
+        )
+    }
 }
 
 fn get_best_route_for_prefix<A: CcpRoutingAccount>(
@@ -729,6 +1413,7 @@ fn get_best_route_for_prefix<A: CcpRoutingAccount>(
     configured_routes: &HashMap<Bytes, A>,
     incoming_tables: &HashMap<A::AccountId, RoutingTable<A>>,
     prefix: &[u8],
+    scorer: &dyn RouteScorer,
 ) -> Option<(A, Route)> {
     if let Some(account) = configured_routes.get(prefix) {
         return Some((
@@ -753,41 +1438,75 @@ fn get_best_route_for_prefix<A: CcpRoutingAccount>(
         ));
     }
 
-    let mut candidate_routes = incoming_tables
+    incoming_tables
         .values()
-        .filter_map(|incoming_table| incoming_table.get_route(prefix));
-    if let Some((account, route)) = candidate_routes.next() {
-        let (best_account, best_route) = candidate_routes.fold(
-            (account, route),
-            |(best_account, best_route), (account, route)| {
-                // Prioritize child > peer > parent
-                if best_account.routing_relation() > account.routing_relation() {
-                    return (best_account, best_route);
-                } else if best_account.routing_relation() < account.routing_relation() {
-                    return (account, route);
-                }
-
-                // Prioritize shortest path
-                if best_route.path.len() < route.path.len() {
-                    return (best_account, best_route);
-                } else if best_route.path.len() > route.path.len() {
-                    return (account, route);
-                }
-
-                // Finally base it on account ID
-                if best_account.id().to_string() < account.id().to_string() {
-                    (best_account, best_route)
-                } else {
-                    (account, route)
+        .filter_map(|incoming_table| incoming_table.get_route(prefix))
+        .map(|(account, route)| (account, route_candidate(account, route)))
+        .fold(
+            None,
+            |best: Option<(&A, RouteCandidate)>, (account, candidate)| match best {
+                Some((best_account, best_candidate)) => {
+                    if scorer.compare(&best_candidate, &candidate) == Ordering::Greater {
+                        Some((account, candidate))
+                    } else {
+                        Some((best_account, best_candidate))
+                    }
                 }
+                None => Some((account, candidate)),
             },
-        );
-        Some((best_account.clone(), best_route.clone()))
-    } else {
-        None
+        )
+        .map(|(account, candidate)| (account.clone(), candidate.route.clone()))
+}
+
+fn route_candidate<'a, A: CcpRoutingAccount>(account: &A, route: &'a Route) -> RouteCandidate<'a> {
+    RouteCandidate {
+        account_id: account.id().to_string(),
+        relation: account.routing_relation(),
+        cost: account.route_cost(),
+        route,
     }
 }
 
+/// Get the other routes we have heard for each prefix in the Local Routing Table, best first,
+/// and whether each one is as good as the route we are using.
+/// Local and configured routes are always used so they have no alternatives.
+fn get_alternate_routes<A: CcpRoutingAccount>(
+    local_table: &RoutingTable<A>,
+    local_routes: &HashMap<Bytes, A>,
+    configured_routes: &HashMap<Bytes, A>,
+    incoming_tables: &HashMap<A::AccountId, RoutingTable<A>>,
+    scorer: &dyn RouteScorer,
+) -> Vec<(Bytes, Vec<(A, bool)>)> {
+    local_table
+        .get_simplified_table()
+        .into_iter()
+        .filter(|(prefix, _)| {
+            !configured_routes.contains_key(prefix) && !local_routes.contains_key(prefix)
+        })
+        .filter_map(|(prefix, best_account)| {
+            let (_, best_route) = local_table.get_route(&prefix)?;
+            let best = route_candidate(&best_account, best_route);
+            let mut alternates: Vec<(&A, RouteCandidate)> = incoming_tables
+                .iter()
+                .filter(|(account_id, _)| **account_id != best_account.id())
+                .filter_map(|(_, incoming_table)| incoming_table.get_route(&prefix))
+                .map(|(account, route)| (account, route_candidate(account, route)))
+                .collect();
+            if alternates.is_empty() {
+                return None;
+            }
+            alternates.sort_by(|(_, a), (_, b)| scorer.compare(a, b));
+            let alternates = alternates
+                .into_iter()
+                .map(|(account, candidate)| {
+                    (account.clone(), scorer.is_equal_cost(&best, &candidate))
+                })
+                .collect();
+            Some((prefix, alternates))
+        })
+        .collect()
+}
+
 impl<I, O, S, A> IncomingService<A> for CcpRouteManager<I, O, S, A>
 where
     I: IncomingService<A> + Clone + Send + Sync + 'static,
@@ -893,33 +1612,106 @@ mod ranking_routes {
 
     #[test]
     fn prioritizes_configured_routes() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.a");
+        let best_route = get_best_route_for_prefix(
+            &LOCAL,
+            &CONFIGURED,
+            &INCOMING,
+            b"example.a",
+            &DefaultRouteScorer,
+        );
         assert_eq!(best_route.unwrap().0.id(), 4);
     }
 
     #[test]
     fn prioritizes_local_routes_over_broadcasted_ones() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.c");
+        let best_route = get_best_route_for_prefix(
+            &LOCAL,
+            &CONFIGURED,
+            &INCOMING,
+            b"example.c",
+            &DefaultRouteScorer,
+        );
         assert_eq!(best_route.unwrap().0.id(), 3);
     }
 
     #[test]
     fn prioritizes_children_over_peers() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.d");
+        let best_route = get_best_route_for_prefix(
+            &LOCAL,
+            &CONFIGURED,
+            &INCOMING,
+            b"example.d",
+            &DefaultRouteScorer,
+        );
         assert_eq!(best_route.unwrap().0.id(), 6);
     }
 
     #[test]
     fn prioritizes_shorter_paths() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.e");
+        let best_route = get_best_route_for_prefix(
+            &LOCAL,
+            &CONFIGURED,
+            &INCOMING,
+            b"example.e",
+            &DefaultRouteScorer,
+        );
         assert_eq!(best_route.unwrap().0.id(), 7);
     }
 
     #[test]
     fn returns_none_for_no_route() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.z");
+        let best_route = get_best_route_for_prefix(
+            &LOCAL,
+            &CONFIGURED,
+            &INCOMING,
+            b"example.z",
+            &DefaultRouteScorer,
+        );
         assert!(best_route.is_none());
     }
+
+    #[test]
+    fn ranks_alternate_routes() {
+        let mut local_table = RoutingTable::default();
+        for prefix in &["example.d", "example.e"] {
+            let (account, route) = get_best_route_for_prefix(
+                &LOCAL,
+                &CONFIGURED,
+                &INCOMING,
+                prefix.as_bytes(),
+                &DefaultRouteScorer,
+            )
+            .unwrap();
+            local_table.set_route(Bytes::from(*prefix), account, route);
+        }
+        let mut alternates = get_alternate_routes(
+            &local_table,
+            &LOCAL,
+            &CONFIGURED,
+            &INCOMING,
+            &DefaultRouteScorer,
+        );
+        alternates.sort_by_key(|(prefix, _)| prefix.clone());
+        let alternates: Vec<(Bytes, Vec<(u64, bool)>)> = alternates
+            .into_iter()
+            .map(|(prefix, routes)| {
+                (
+                    prefix,
+                    routes
+                        .into_iter()
+                        .map(|(account, equal_cost)| (account.id(), equal_cost))
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            alternates,
+            vec![
+                (Bytes::from("example.d"), vec![(7, false)]),
+                (Bytes::from("example.e"), vec![(8, false)]),
+            ]
+        );
+    }
 }
 
 #[cfg(test)]
@@ -1006,6 +1798,34 @@ mod handle_route_control_request {
         assert_eq!(update.new_routes.len(), 2);
     }
 
+    #[test]
+    fn stops_sending_updates_in_idle_mode() {
+        let (mut service, outgoing_requests) = test_service_with_routes();
+        service
+            .handle_request(IncomingRequest {
+                from: TestAccount::new(1, "example.local.1"),
+                prepare: RouteControlRequest {
+                    last_known_routing_table_id: [0; 16],
+                    mode: Mode::Idle,
+                    last_known_epoch: 0,
+                    features: Vec::new(),
+                }
+                .to_prepare(),
+            })
+            .wait()
+            .unwrap();
+        assert!(outgoing_requests.lock().is_empty());
+        assert_eq!(service.peer_sessions.read()[&1].mode, Mode::Idle);
+
+        service.send_route_updates().wait().unwrap();
+        let accounts: Vec<u64> = outgoing_requests
+            .lock()
+            .iter()
+            .map(|request| request.to.id())
+            .collect();
+        assert_eq!(accounts, vec![2]);
+    }
+
     #[test]
     fn sends_whole_table_if_id_is_different() {
         let (mut service, outgoing_requests) = test_service_with_routes();
@@ -1131,7 +1951,7 @@ mod handle_route_update_request {
             auth: [0; 32],
             props: Vec::new(),
         });
-        let request = service.filter_routes(request);
+        let request = service.filter_routes(&ROUTING_ACCOUNT, request);
         assert_eq!(request.new_routes.len(), 1);
         assert_eq!(request.new_routes[0].prefix, Bytes::from("example.valid"));
     }
@@ -1152,7 +1972,7 @@ mod handle_route_update_request {
             auth: [0; 32],
             props: Vec::new(),
         });
-        let request = service.filter_routes(request);
+        let request = service.filter_routes(&ROUTING_ACCOUNT, request);
         assert_eq!(request.new_routes.len(), 1);
         assert_eq!(request.new_routes[0].prefix, Bytes::from("example.valid"));
     }
@@ -1177,11 +1997,153 @@ mod handle_route_update_request {
             auth: [0; 32],
             props: Vec::new(),
         });
-        let request = service.filter_routes(request);
+        let request = service.filter_routes(&ROUTING_ACCOUNT, request);
         assert_eq!(request.new_routes.len(), 1);
         assert_eq!(request.new_routes[0].prefix, Bytes::from("example.valid"));
     }
 
+    #[test]
+    fn filters_child_routes_outside_its_address() {
+        let service = test_service();
+        let mut child = TestAccount::new(2, "example.child");
+        child.relation = RoutingRelation::Child;
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        for prefix in &["example.child", "example.child.sub", "example.children"] {
+            request.new_routes.push(Route {
+                prefix: Bytes::from(*prefix),
+                path: Vec::new(),
+                auth: [0; 32],
+                props: Vec::new(),
+            });
+        }
+        let request = service.filter_routes(&child, request);
+        let prefixes: Vec<&[u8]> = request
+            .new_routes
+            .iter()
+            .map(|route| route.prefix.as_ref())
+            .collect();
+        assert_eq!(
+            prefixes,
+            vec![&b"example.child"[..], &b"example.child.sub"[..]]
+        );
+    }
+
+    #[test]
+    fn filters_routes_outside_allowed_prefixes() {
+        let service = test_service();
+        let mut peer = TestAccount::new(2, "example.peer");
+        peer.allowed_route_prefixes = Some(vec![Bytes::from("example.allowed")]);
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        for prefix in &["example.allowed.a", "example.other"] {
+            request.new_routes.push(Route {
+                prefix: Bytes::from(*prefix),
+                path: Vec::new(),
+                auth: [0; 32],
+                props: Vec::new(),
+            });
+        }
+        let request = service.filter_routes(&peer, request);
+        assert_eq!(request.new_routes.len(), 1);
+        assert_eq!(
+            request.new_routes[0].prefix,
+            Bytes::from("example.allowed.a")
+        );
+    }
+
+    #[test]
+    fn filters_routes_with_forged_auth() {
+        let service = test_service();
+        let auth = hash(&[1; 32]);
+        let mut table = RoutingTable::default();
+        table.add_route(
+            TestAccount::new(3, "example.target"),
+            Route {
+                prefix: Bytes::from("example.target"),
+                path: vec![Bytes::from("example.target")],
+                auth,
+                props: Vec::new(),
+            },
+        );
+        service.incoming_tables.write().insert(3, table);
+
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        // Claims to be one hop away from the target, but can't produce the right auth
+        request.new_routes.push(Route {
+            prefix: Bytes::from("example.target"),
+            path: vec![Bytes::from("example.peer"), Bytes::from("example.target")],
+            auth: [9; 32],
+            props: Vec::new(),
+        });
+        let forged = service.filter_routes(&ROUTING_ACCOUNT, request);
+        assert!(forged.new_routes.is_empty());
+
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        request.new_routes.push(Route {
+            prefix: Bytes::from("example.target"),
+            path: vec![Bytes::from("example.peer"), Bytes::from("example.target")],
+            auth: hash(&auth),
+            props: Vec::new(),
+        });
+        let genuine = service.filter_routes(&ROUTING_ACCOUNT, request);
+        assert_eq!(genuine.new_routes.len(), 1);
+    }
+
+    #[test]
+    fn does_not_trust_the_first_route_seen() {
+        let service = test_service();
+        let mut table = RoutingTable::default();
+        // A peer that is not the originator advertised the prefix first, with a made up auth
+        table.add_route(
+            TestAccount::new(3, "example.first"),
+            Route {
+                prefix: Bytes::from("example.target"),
+                path: vec![Bytes::from("example.first"), Bytes::from("example.target")],
+                auth: [9; 32],
+                props: Vec::new(),
+            },
+        );
+        service.incoming_tables.write().insert(3, table);
+
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        request.new_routes.push(Route {
+            prefix: Bytes::from("example.target"),
+            path: vec![Bytes::from("example.peer"), Bytes::from("example.target")],
+            auth: [1; 32],
+            props: Vec::new(),
+        });
+        let request = service.filter_routes(&ROUTING_ACCOUNT, request);
+        assert_eq!(request.new_routes.len(), 1);
+    }
+
+    #[test]
+    fn filters_forged_routes_for_our_own_prefixes() {
+        let service = test_service();
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        request.new_routes.push(Route {
+            prefix: Bytes::from("example.connector.child"),
+            path: vec![Bytes::from("example.peer")],
+            auth: [9; 32],
+            props: Vec::new(),
+        });
+        let request = service.filter_routes(&ROUTING_ACCOUNT, request);
+        assert!(request.new_routes.is_empty());
+
+        // Children originate the routes under their own address
+        let child = TestAccount {
+            relation: RoutingRelation::Child,
+            ..TestAccount::new(4, "example.connector.child")
+        };
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        request.new_routes.push(Route {
+            prefix: Bytes::from("example.connector.child.sub"),
+            path: vec![Bytes::from("example.connector.child")],
+            auth: [9; 32],
+            props: Vec::new(),
+        });
+        let request = service.filter_routes(&child, request);
+        assert_eq!(request.new_routes.len(), 1);
+    }
+
     #[test]
     fn updates_local_routing_table() {
         let mut service = test_service();
@@ -1412,6 +2374,7 @@ mod handle_route_update_request {
 mod create_route_update {
     use super::*;
     use crate::test_helpers::*;
+    use interledger_service::{incoming_service_fn, outgoing_service_fn};
 
     #[test]
     fn heartbeat_message_for_empty_table() {
@@ -1482,13 +2445,48 @@ mod create_route_update {
         assert!(!new_routes.contains(&"example.m"));
         assert_eq!(update.withdrawn_routes[0], &Bytes::from("example.m"));
     }
+
+    #[test]
+    fn authenticates_our_own_routes() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        let update = service.create_route_update(0, 1);
+        let route = update
+            .new_routes
+            .iter()
+            .find(|route| route.prefix == Bytes::from("example.local.1"))
+            .unwrap();
+        assert_eq!(
+            route.auth,
+            hash(&route_auth(&service.routing_secret, b"example.local.1"))
+        );
+    }
+
+    #[test]
+    fn limits_advertised_hold_time() {
+        let store = TestStore::new();
+        let service = CcpRouteManagerBuilder::new(
+            EXAMPLE_CONNECTOR.clone(),
+            store,
+            outgoing_service_fn(|_request| -> Result<Fulfill, Reject> { unreachable!() }),
+            incoming_service_fn(|_request| -> Result<Fulfill, Reject> { unreachable!() }),
+        )
+        .disable_spawn()
+        .route_expiry_time(u64::from(u32::max_value()) + 1)
+        .to_service();
+        assert_eq!(
+            service.create_route_update(0, 0).hold_down_time,
+            u32::max_value()
+        );
+    }
 }
 
 #[cfg(test)]
 mod send_route_updates {
     use super::*;
     use crate::test_helpers::*;
-    use std::str::FromStr;
+    use interledger_service::{incoming_service_fn, outgoing_service_fn};
+    use std::{iter::FromIterator, str::FromStr};
 
     #[test]
     fn broadcasts_to_all_accounts_we_send_updates_to() {
@@ -1503,6 +2501,51 @@ mod send_route_updates {
         assert_eq!(accounts, vec![1, 2]);
     }
 
+    #[test]
+    fn moves_unresponsive_peers_to_idle_mode() {
+        let local_routes = HashMap::from_iter(vec![(
+            Bytes::from("example.local.1"),
+            TestAccount::new(1, "example.local.1"),
+        )]);
+        let store = TestStore::with_routes(local_routes, HashMap::new());
+        let outgoing_requests = Arc::new(Mutex::new(0));
+        let outgoing_requests_clone = outgoing_requests.clone();
+        let addr = Address::from_str("example.connector").unwrap();
+        let service = CcpRouteManagerBuilder::new(
+            addr.clone(),
+            store,
+            outgoing_service_fn(move |_request| {
+                *outgoing_requests_clone.lock() += 1;
+                Err(RejectBuilder {
+                    code: ErrorCode::T01_PEER_UNREACHABLE,
+                    message: &[],
+                    data: &[],
+                    triggered_by: Some(&EXAMPLE_CONNECTOR),
+                }
+                .build())
+            }),
+            incoming_service_fn(|_request| -> Result<Fulfill, Reject> { unreachable!() }),
+        )
+        .disable_spawn()
+        .max_unacknowledged_updates(2)
+        .to_service();
+
+        // After two unacknowledged updates, the peer is sent updates on the 4th and 7th
+        // broadcasts, skipping one and then two broadcasts in between
+        let mut sent = Vec::new();
+        for _ in 0..8 {
+            service.send_route_updates().wait().unwrap();
+            sent.push(*outgoing_requests.lock());
+        }
+        assert_eq!(sent, vec![1, 2, 2, 3, 3, 3, 4, 4]);
+        let sessions = service.peer_sessions();
+        assert_eq!(sessions[0].0, "1");
+        // The peer did not ask for Idle mode
+        assert_eq!(sessions[0].1.mode, Mode::Sync);
+        assert!(sessions[0].1.unresponsive);
+        assert_eq!(sessions[0].1.unacknowledged_updates, 4);
+    }
+
     #[test]
     fn broadcasts_configured_and_local_routes() {
         let (service, outgoing_requests) = test_service_with_routes();
@@ -1630,3 +2673,398 @@ mod send_route_updates {
         );
     }
 }
+
+#[cfg(test)]
+mod route_expiry {
+    use super::*;
+    use crate::test_helpers::*;
+    use std::str::FromStr;
+
+    fn update_from_peer(
+        from_epoch_index: u32,
+        new_routes: Vec<Route>,
+        withdrawn_routes: Vec<Bytes>,
+    ) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount::new(10, "example.peer"),
+            prepare: RouteUpdateRequest {
+                routing_table_id: [0; 16],
+                current_epoch_index: from_epoch_index + 1,
+                from_epoch_index,
+                to_epoch_index: from_epoch_index + 1,
+                hold_down_time: 30000,
+                speaker: Address::from_str("example.remote").unwrap(),
+                new_routes,
+                withdrawn_routes,
+            }
+            .to_prepare(),
+        }
+    }
+
+    fn remote_route() -> Route {
+        Route {
+            prefix: Bytes::from("example.remote"),
+            path: vec![Bytes::from("example.peer")],
+            auth: [0; 32],
+            props: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn expires_routes_from_silent_peers() {
+        let (mut service, outgoing_requests) = test_service_with_routes();
+        service.route_expiry_time = Some(0);
+        service.update_best_routes(None).wait().unwrap();
+        service
+            .handle_route_update_request(update_from_peer(0, vec![remote_route()], Vec::new()))
+            .wait()
+            .unwrap();
+        assert!(service
+            .local_table
+            .read()
+            .get_route(b"example.remote")
+            .is_some());
+
+        service.broadcast_routes().wait().unwrap();
+        assert!(service
+            .local_table
+            .read()
+            .get_route(b"example.remote")
+            .is_none());
+        assert!(service.incoming_tables.read().is_empty());
+        let update = RouteUpdateRequest::try_from(&outgoing_requests.lock()[0].prepare).unwrap();
+        assert_eq!(update.withdrawn_routes, vec![Bytes::from("example.remote")]);
+        assert_eq!(update.hold_down_time, 0);
+    }
+
+    #[test]
+    fn keeps_routes_from_peers_that_send_updates() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        service
+            .handle_route_update_request(update_from_peer(0, vec![remote_route()], Vec::new()))
+            .wait()
+            .unwrap();
+
+        service.broadcast_routes().wait().unwrap();
+        assert!(service
+            .local_table
+            .read()
+            .get_route(b"example.remote")
+            .is_some());
+    }
+
+    #[test]
+    fn withdraws_routes_when_peer_disconnects() {
+        let (service, outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        service
+            .handle_route_update_request(update_from_peer(0, vec![remote_route()], Vec::new()))
+            .wait()
+            .unwrap();
+
+        service.withdraw_peer_routes(10).wait().unwrap();
+        assert!(service
+            .local_table
+            .read()
+            .get_route(b"example.remote")
+            .is_none());
+        service.send_route_updates().wait().unwrap();
+        let update = RouteUpdateRequest::try_from(&outgoing_requests.lock()[0].prepare).unwrap();
+        assert_eq!(update.withdrawn_routes, vec![Bytes::from("example.remote")]);
+    }
+
+    #[test]
+    fn holds_down_withdrawn_prefixes() {
+        let (mut service, _outgoing_requests) = test_service_with_routes();
+        service.route_hold_down_time = Duration::from_secs(3600);
+        service.update_best_routes(None).wait().unwrap();
+        service
+            .handle_route_update_request(update_from_peer(0, vec![remote_route()], Vec::new()))
+            .wait()
+            .unwrap();
+        service
+            .handle_route_update_request(update_from_peer(
+                1,
+                Vec::new(),
+                vec![Bytes::from("example.remote")],
+            ))
+            .wait()
+            .unwrap();
+        assert!(service
+            .local_table
+            .read()
+            .get_route(b"example.remote")
+            .is_none());
+
+        // The prefix comes back but is ignored until the hold-down ends
+        service
+            .handle_route_update_request(update_from_peer(2, vec![remote_route()], Vec::new()))
+            .wait()
+            .unwrap();
+        assert!(service
+            .local_table
+            .read()
+            .get_route(b"example.remote")
+            .is_none());
+
+        service.route_hold_down_time = Duration::from_millis(0);
+        service
+            .held_down_prefixes
+            .write()
+            .insert(Bytes::from("example.remote"), Instant::now());
+        service.broadcast_routes().wait().unwrap();
+        assert!(service
+            .local_table
+            .read()
+            .get_route(b"example.remote")
+            .is_some());
+    }
+}
+
+#[cfg(test)]
+mod route_policies {
+    use super::*;
+    use crate::test_helpers::*;
+    use crate::{fixtures::UPDATE_REQUEST_SIMPLE, RoutePolicy};
+
+    fn route(prefix: &str, path: &[&str]) -> Route {
+        Route {
+            prefix: Bytes::from(prefix),
+            path: path.iter().map(|hop| Bytes::from(*hop)).collect(),
+            auth: [0; 32],
+            props: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn applies_import_policy() {
+        let service = test_service();
+        let mut peer = TestAccount::new(2, "example.peer");
+        peer.route_import_policy = Some(RoutePolicy {
+            allow: None,
+            deny: vec![Bytes::from("example.denied")],
+            max_path_length: Some(2),
+        });
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        request.new_routes = vec![
+            route("example.ok", &["example.peer", "example.ok"]),
+            route("example.denied.a", &["example.peer"]),
+            route("example.far", &["example.peer", "example.x", "example.far"]),
+        ];
+        let request = service.filter_routes(&peer, request);
+        assert_eq!(request.new_routes.len(), 1);
+        assert_eq!(request.new_routes[0].prefix, Bytes::from("example.ok"));
+    }
+
+    #[test]
+    fn withdraws_routes_the_export_policy_does_not_permit() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        let mut peer = TestAccount::new(10, "example.peer");
+        peer.route_export_policy.deny = vec![Bytes::from("example.configured")];
+
+        let update = service.apply_export_policy(&peer, service.create_route_update(0, 1));
+        let prefixes: Vec<&[u8]> = update
+            .new_routes
+            .iter()
+            .map(|route| route.prefix.as_ref())
+            .collect();
+        assert!(prefixes.contains(&&b"example.local.1"[..]));
+        assert!(!prefixes.contains(&&b"example.configured.1"[..]));
+        assert_eq!(
+            update.withdrawn_routes,
+            vec![Bytes::from("example.configured.1")]
+        );
+    }
+
+    #[test]
+    fn only_advertises_parent_routes_if_configured_to() {
+        let service = test_service();
+        let mut parent = TestAccount::new(3, "example.parent");
+        parent.relation = RoutingRelation::Parent;
+        service.local_table.write().set_route(
+            Bytes::from("example.upstream"),
+            parent,
+            route("example.upstream", &["example.parent"]),
+        );
+        let mut update = UPDATE_REQUEST_SIMPLE.clone();
+        update.new_routes = vec![
+            route("example.upstream", &["example.connector", "example.parent"]),
+            route("example.other", &["example.connector"]),
+        ];
+
+        let mut peer = TestAccount::new(10, "example.peer");
+        let sent = service.apply_export_policy(&peer, update.clone());
+        assert_eq!(sent.new_routes.len(), 2);
+
+        peer.advertise_parent_routes = false;
+        let sent = service.apply_export_policy(&peer, update);
+        assert_eq!(sent.new_routes.len(), 1);
+        assert_eq!(sent.new_routes[0].prefix, Bytes::from("example.other"));
+        assert_eq!(sent.withdrawn_routes, vec![Bytes::from("example.upstream")]);
+    }
+}
+
+#[cfg(test)]
+mod admin_status {
+    use super::*;
+    use crate::test_helpers::*;
+
+    fn add_incoming_route(
+        service: &CcpRouteManager<
+            impl IncomingService<TestAccount> + Clone + Send + Sync + 'static,
+            impl OutgoingService<TestAccount> + Clone + Send + Sync + 'static,
+            TestStore,
+            TestAccount,
+        >,
+        account: TestAccount,
+        path: &[&str],
+    ) {
+        let mut table = RoutingTable::default();
+        let account_id = account.id();
+        table.add_route(
+            account,
+            Route {
+                prefix: Bytes::from("example.remote"),
+                path: path.iter().map(|hop| Bytes::from(*hop)).collect(),
+                auth: [0; 32],
+                props: Vec::new(),
+            },
+        );
+        service.incoming_tables.write().insert(account_id, table);
+    }
+
+    #[test]
+    fn snapshots_the_forwarding_table() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        let forwarding_table = service.forwarding_table();
+        assert_eq!(forwarding_table.epoch, 1);
+        let mut prefixes: Vec<Bytes> = forwarding_table
+            .routes
+            .iter()
+            .map(|(_account_id, route)| route.prefix.clone())
+            .collect();
+        prefixes.sort();
+        assert_eq!(
+            prefixes,
+            vec![
+                Bytes::from("example.configured.1"),
+                Bytes::from("example.local.1")
+            ]
+        );
+        assert_eq!(service.forwarding_table_updates(1, 10).len(), 1);
+        assert!(service.forwarding_table_updates(2, 10).is_empty());
+    }
+
+    #[test]
+    fn snapshots_incoming_tables() {
+        let service = test_service();
+        add_incoming_route(
+            &service,
+            TestAccount::new(10, "example.peer"),
+            &["example.peer"],
+        );
+        let incoming_tables = service.incoming_tables();
+        assert_eq!(incoming_tables.len(), 1);
+        assert_eq!(incoming_tables[0].0, "10");
+        assert_eq!(
+            incoming_tables[0].1.routes[0].1.path,
+            vec![Bytes::from("example.peer")]
+        );
+    }
+
+    #[test]
+    fn explains_configured_routes() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        let explanation = service
+            .explain_route(Bytes::from("example.configured.1"))
+            .wait()
+            .unwrap();
+        assert_eq!(explanation.selection, RouteSelection::Configured);
+        assert_eq!(explanation.next_hop, Some("2".to_string()));
+        assert!(explanation.candidates.is_empty());
+    }
+
+    #[test]
+    fn explains_advertised_routes() {
+        let service = test_service();
+        add_incoming_route(
+            &service,
+            TestAccount::new(10, "example.far"),
+            &["example.far", "example.x"],
+        );
+        add_incoming_route(
+            &service,
+            TestAccount::new(11, "example.near"),
+            &["example.near"],
+        );
+        service
+            .update_best_routes(Some(vec![Bytes::from("example.remote")]))
+            .wait()
+            .unwrap();
+        let explanation = service
+            .explain_route(Bytes::from("example.remote"))
+            .wait()
+            .unwrap();
+        assert_eq!(explanation.selection, RouteSelection::BestAdvertised);
+        assert_eq!(explanation.next_hop, Some("11".to_string()));
+        let candidates: Vec<(&str, bool)> = explanation
+            .candidates
+            .iter()
+            .map(|candidate| (candidate.account_id.as_str(), candidate.equal_cost))
+            .collect();
+        assert_eq!(candidates, vec![("11", true), ("10", false)]);
+
+        let explanation = service
+            .explain_route(Bytes::from("example.unknown"))
+            .wait()
+            .unwrap();
+        assert_eq!(explanation.selection, RouteSelection::NoRoute);
+        assert_eq!(explanation.next_hop, None);
+
+        // Routes for shorter prefixes that cover the given one are not explained
+        let explanation = service
+            .explain_route(Bytes::from("example.remote.bob"))
+            .wait()
+            .unwrap();
+        assert_eq!(explanation.selection, RouteSelection::NoRoute);
+        assert_eq!(explanation.next_hop, None);
+        assert!(explanation.candidates.is_empty());
+    }
+
+    #[test]
+    fn resyncs_with_peer() {
+        let (service, outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        service.peer_sessions.write().insert(
+            1,
+            PeerSession {
+                mode: Mode::Idle,
+                ..PeerSession::default()
+            },
+        );
+        service.resync_peer("1".to_string()).wait().unwrap();
+
+        let requests = outgoing_requests.lock();
+        let destinations: Vec<Address> = requests
+            .iter()
+            .filter(|request| request.to.id() == 1)
+            .map(|request| request.prepare.destination())
+            .collect();
+        assert!(destinations.contains(&*CCP_CONTROL_DESTINATION));
+        assert!(destinations.contains(&*CCP_UPDATE_DESTINATION));
+        let session = &service.peer_sessions()[0].1;
+        assert_eq!(session.mode, Mode::Sync);
+        assert_eq!(session.last_acknowledged_epoch, 1);
+    }
+
+    #[test]
+    fn refuses_to_resync_with_unknown_account() {
+        let (service, outgoing_requests) = test_service_with_routes();
+        assert!(service.resync_peer("99".to_string()).wait().is_err());
+        assert!(outgoing_requests.lock().is_empty());
+    }
+}
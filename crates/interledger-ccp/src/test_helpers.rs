@@ -25,6 +25,10 @@ lazy_static! {
         send_routes: true,
         receive_routes: true,
         relation: RoutingRelation::Peer,
+        allowed_route_prefixes: None,
+        route_import_policy: None,
+        route_export_policy: RoutePolicy::default(),
+        advertise_parent_routes: true,
     };
     pub static ref NON_ROUTING_ACCOUNT: TestAccount = TestAccount {
         id: 2,
@@ -32,6 +36,10 @@ lazy_static! {
         send_routes: false,
         receive_routes: false,
         relation: RoutingRelation::Child,
+        allowed_route_prefixes: None,
+        route_import_policy: None,
+        route_export_policy: RoutePolicy::default(),
+        advertise_parent_routes: true,
     };
     pub static ref EXAMPLE_CONNECTOR: Address = Address::from_str("example.connector").unwrap();
 }
@@ -43,6 +51,10 @@ pub struct TestAccount {
     pub receive_routes: bool,
     pub send_routes: bool,
     pub relation: RoutingRelation,
+    pub allowed_route_prefixes: Option<Vec<Bytes>>,
+    pub route_import_policy: Option<RoutePolicy>,
+    pub route_export_policy: RoutePolicy,
+    pub advertise_parent_routes: bool,
 }
 
 impl TestAccount {
@@ -53,6 +65,10 @@ impl TestAccount {
             receive_routes: true,
             send_routes: true,
             relation: RoutingRelation::Peer,
+            allowed_route_prefixes: None,
+            route_import_policy: None,
+            route_export_policy: RoutePolicy::default(),
+            advertise_parent_routes: true,
         }
     }
 }
@@ -91,6 +107,27 @@ impl CcpRoutingAccount for TestAccount {
     fn should_send_routes(&self) -> bool {
         self.send_routes
     }
+
+    fn allowed_route_prefixes(&self) -> Option<Vec<Bytes>> {
+        self.allowed_route_prefixes.clone()
+    }
+
+    fn route_import_policy(&self) -> RoutePolicy {
+        self.route_import_policy
+            .clone()
+            .unwrap_or_else(|| RoutePolicy {
+                allow: self.allowed_route_prefixes.clone(),
+                ..RoutePolicy::default()
+            })
+    }
+
+    fn route_export_policy(&self) -> RoutePolicy {
+        self.route_export_policy.clone()
+    }
+
+    fn should_advertise_parent_routes(&self) -> bool {
+        self.advertise_parent_routes
+    }
 }
 
 #[derive(Clone)]
@@ -232,6 +269,10 @@ pub fn test_service_with_routes() -> (
                 send_routes: false,
                 receive_routes: false,
                 relation: RoutingRelation::Child,
+                allowed_route_prefixes: None,
+                route_import_policy: None,
+                route_export_policy: RoutePolicy::default(),
+                advertise_parent_routes: true,
             },
         ),
     ]);
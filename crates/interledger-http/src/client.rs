@@ -25,19 +25,8 @@ where
     A: HttpAccount,
 {
     pub fn new(store: S, next: O) -> Self {
-        let mut headers = HeaderMap::with_capacity(2);
-        headers.insert(
-            HeaderName::from_static("content-type"),
-            HeaderValue::from_static("application/octet-stream"),
-        );
-        let client = ClientBuilder::new()
-            .default_headers(headers)
-            .timeout(Duration::from_secs(30))
-            .build()
-            .unwrap();
-
         HttpClientService {
-            client,
+            client: new_http_client(),
             store: Arc::new(store),
             next,
             account_type: PhantomData,
@@ -88,7 +77,21 @@ where
     }
 }
 
-fn parse_packet_from_response(
+/// Create a client for sending ILP packets over HTTP
+pub(crate) fn new_http_client() -> Client {
+    let mut headers = HeaderMap::with_capacity(2);
+    headers.insert(
+        HeaderName::from_static("content-type"),
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    ClientBuilder::new()
+        .default_headers(headers)
+        .timeout(Duration::from_secs(30))
+        .build()
+        .unwrap()
+}
+
+pub(crate) fn parse_packet_from_response(
     response: HttpResponse,
 ) -> impl Future<Item = Fulfill, Error = Reject> {
     result(response.error_for_status().map_err(|err| {
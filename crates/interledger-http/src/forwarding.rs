@@ -0,0 +1,527 @@
+use super::client::{new_http_client, parse_packet_from_response};
+use super::server::{ilp_response_to_http_response, parse_prepare_from_request, MAX_MESSAGE_SIZE};
+use super::HttpForwardingStore;
+use bytes::BytesMut;
+use futures::{
+    future::{err, ok, Either},
+    Future,
+};
+use hyper::{
+    body::Body, header::AUTHORIZATION, service::Service as HttpService, Error, Method, Request,
+    Response,
+};
+use interledger_packet::{ErrorCode, RejectBuilder};
+use interledger_service::*;
+use log::{debug, error, trace};
+use reqwest::r#async::Client;
+use std::{marker::PhantomData, str::FromStr};
+use url::Url;
+
+const BEARER_TOKEN_START: usize = 7;
+
+/// An OutgoingService for instances of a node that share their store with other
+/// instances. Packets for accounts whose connection is held by another instance are
+/// sent over ILP-over-HTTP to that instance's HttpForwardingServer, and all other
+/// packets are passed to the next service.
+///
+/// Without an instance URL, nothing is forwarded and the store is not checked.
+#[derive(Clone)]
+pub struct HttpForwardingService<S, O, A> {
+    client: Client,
+    store: S,
+    instance_url: Option<Url>,
+    auth_token: String,
+    next: O,
+    account_type: PhantomData<A>,
+}
+
+impl<S, O, A> HttpForwardingService<S, O, A>
+where
+    S: HttpForwardingStore<Account = A>,
+    O: OutgoingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    /// The `auth_token` must be the same one the other instances' servers use
+    pub fn new(store: S, instance_url: Option<Url>, auth_token: String, next: O) -> Self {
+        HttpForwardingService {
+            client: new_http_client(),
+            store,
+            instance_url,
+            auth_token,
+            next,
+            account_type: PhantomData,
+        }
+    }
+}
+
+impl<S, O, A> OutgoingService<A> for HttpForwardingService<S, O, A>
+where
+    S: HttpForwardingStore<Account = A>,
+    O: OutgoingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        let instance_url = match self.instance_url {
+            Some(ref instance_url) => instance_url.clone(),
+            None => return Box::new(self.next.send_request(request)),
+        };
+        let client = self.client.clone();
+        let store = self.store.clone();
+        let auth_token = self.auth_token.clone();
+        let mut next = self.next.clone();
+        Box::new(
+            self.store
+                .get_connection_owner(request.to.id())
+                .then(move |owner| match owner {
+                    Ok(Some(owner)) if owner != instance_url => {
+                        Either::A(forward_request(client, store, owner, &auth_token, request))
+                    }
+                    _ => Either::B(next.send_request(request)),
+                }),
+        )
+    }
+}
+
+/// If the owner cannot be reached, its entry is removed from the store so that an instance
+/// that went away without cleaning up does not keep getting the account's packets. The
+/// owner's address is only removed if no other instance has registered itself since.
+fn forward_request<S, A>(
+    client: Client,
+    store: S,
+    owner: Url,
+    auth_token: &str,
+    request: OutgoingRequest<A>,
+) -> BoxedIlpFuture
+where
+    S: HttpForwardingStore<Account = A>,
+    A: Account + 'static,
+{
+    let mut url = owner.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().extend(&[
+            "forward",
+            &request.from.id().to_string(),
+            &request.to.id().to_string(),
+        ]);
+    } else {
+        error!("Cannot forward packets to instance URL: {}", owner);
+        return Box::new(err(RejectBuilder {
+            code: ErrorCode::T00_INTERNAL_ERROR,
+            message: &[],
+            triggered_by: None,
+            data: &[],
+        }
+        .build()));
+    }
+    trace!(
+        "Forwarding packet for account {} to the instance holding its connection: {}",
+        request.to.id(),
+        owner
+    );
+    let to_id = request.to.id();
+    Box::new(
+        client
+            .post(url)
+            .header("authorization", format!("Bearer {}", auth_token))
+            .body(BytesMut::from(request.prepare).freeze())
+            .send()
+            .or_else(move |err| {
+                error!(
+                    "Error forwarding packet to another instance ({}), removing it as the owner of account {}'s connection: {:?}",
+                    owner, to_id, err
+                );
+                store
+                    .remove_connection_owner(to_id, &owner)
+                    .then(|_| {
+                        Err(RejectBuilder {
+                            code: ErrorCode::T01_PEER_UNREACHABLE,
+                            message: &[],
+                            triggered_by: None,
+                            data: &[],
+                        }
+                        .build())
+                    })
+            })
+            .and_then(parse_packet_from_response),
+    )
+}
+
+/// A Hyper::Service that receives the packets other instances of a node forward to
+/// this one with the HttpForwardingService, and sends them on the connections this
+/// instance holds. Requests are `POST /forward/{from account ID}/{to account ID}`
+/// with the ILP Prepare as the body.
+///
+/// Packets are only sent if the store still lists this instance as the owner of the
+/// connection, so that they are not forwarded back and forth between instances.
+#[derive(Clone)]
+pub struct HttpForwardingServer<S, O, A> {
+    store: S,
+    instance_url: Url,
+    auth_token: String,
+    next: O,
+    account_type: PhantomData<A>,
+}
+
+impl<S, O, A> HttpForwardingServer<S, O, A>
+where
+    S: HttpForwardingStore<Account = A> + AccountStore<Account = A>,
+    O: OutgoingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    pub fn new(store: S, instance_url: Url, auth_token: String, next: O) -> Self {
+        HttpForwardingServer {
+            store,
+            instance_url,
+            auth_token,
+            next,
+            account_type: PhantomData,
+        }
+    }
+
+    fn is_authorized(&self, request: &Request<Body>) -> bool {
+        request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|auth| auth.to_str().ok())
+            .filter(|auth| auth.starts_with("Bearer "))
+            .map(|auth| {
+                tokens_match(
+                    &auth.as_bytes()[BEARER_TOKEN_START..],
+                    self.auth_token.as_bytes(),
+                )
+            })
+            .unwrap_or(false)
+    }
+
+    pub fn handle_http_request(
+        &mut self,
+        request: Request<Body>,
+    ) -> impl Future<Item = Response<Body>, Error = Error> {
+        if request.method() != Method::POST {
+            return Either::A(ok(empty_response(405)));
+        }
+        if !self.is_authorized(&request) {
+            error!("Unauthorized request to forward a packet");
+            return Either::A(ok(empty_response(401)));
+        }
+        let (from_id, to_id) = match parse_account_ids::<A::AccountId>(request.uri().path()) {
+            Some(ids) => ids,
+            None => return Either::A(ok(empty_response(404))),
+        };
+
+        let store = self.store.clone();
+        let instance_url = self.instance_url.clone();
+        let mut next = self.next.clone();
+        Either::B(
+            self.store
+                .get_connection_owner(to_id)
+                .map_err(|_| empty_response(500))
+                .and_then(move |owner| {
+                    if owner.as_ref() == Some(&instance_url) {
+                        Ok(())
+                    } else {
+                        debug!(
+                            "Not sending forwarded packet for account {} because this instance does not hold its connection (owner: {:?})",
+                            to_id, owner
+                        );
+                        Err(empty_response(503))
+                    }
+                })
+                .and_then(move |_| {
+                    // Stores reject lists with the same ID twice, so an account sending
+                    // packets to itself is only looked up once
+                    let account_ids = if from_id == to_id {
+                        vec![to_id]
+                    } else {
+                        vec![from_id, to_id]
+                    };
+                    store
+                        .get_accounts(account_ids)
+                        .map_err(|_| empty_response(404))
+                })
+                .and_then(move |accounts| {
+                    parse_prepare_from_request(request, Some(MAX_MESSAGE_SIZE))
+                        .map(move |prepare| (accounts, prepare))
+                })
+                .and_then(move |(accounts, prepare)| {
+                    let mut accounts = accounts.into_iter();
+                    let accounts = match (accounts.next(), accounts.next()) {
+                        (Some(account), None) if from_id == to_id => {
+                            Some((account.clone(), account))
+                        }
+                        (Some(from), Some(to)) => Some((from, to)),
+                        _ => None,
+                    };
+                    match accounts {
+                        Some((from, to)) => {
+                            trace!("Sending packet forwarded for account {}", to_id);
+                            // The services before the connection already ran on the
+                            // forwarding instance, so the original amount is not needed
+                            Either::A(
+                                next.send_request(OutgoingRequest {
+                                    from,
+                                    to,
+                                    original_amount: prepare.amount(),
+                                    prepare,
+                                })
+                                .then(ilp_response_to_http_response),
+                            )
+                        }
+                        None => Either::B(err(empty_response(404))),
+                    }
+                })
+                .then(|result| match result {
+                    Ok(response) => Ok(response),
+                    Err(response) => Ok(response),
+                }),
+        )
+    }
+}
+
+impl<S, O, A> HttpService for HttpForwardingServer<S, O, A>
+where
+    S: HttpForwardingStore<Account = A> + AccountStore<Account = A>,
+    O: OutgoingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = Error;
+    type Future =
+        Box<dyn Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send + 'static>;
+
+    fn call(&mut self, request: Request<Self::ReqBody>) -> Self::Future {
+        Box::new(self.handle_http_request(request))
+    }
+}
+
+fn empty_response(status: u16) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Parse the IDs from a `.../forward/{from}/{to}` path. Only the end of the path is
+/// checked so that the instance URL may include a path, for example behind a proxy
+fn parse_account_ids<I: FromStr>(path: &str) -> Option<(I, I)> {
+    let mut segments = path.trim_end_matches('/').rsplit('/');
+    let to = segments.next()?.parse().ok()?;
+    let from = segments.next()?.parse().ok()?;
+    if segments.next()? == "forward" {
+        Some((from, to))
+    } else {
+        None
+    }
+}
+
+/// Compare the tokens in constant time
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::result;
+    use interledger_packet::{Address, Fulfill, FulfillBuilder, PrepareBuilder, Reject};
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestStore {
+        owner: Option<Url>,
+        removed_owner: Arc<Mutex<Option<(u64, Url)>>>,
+    }
+
+    impl AccountStore for TestStore {
+        type Account = TestAccount;
+
+        fn get_accounts(
+            &self,
+            account_ids: Vec<u64>,
+        ) -> Box<dyn Future<Item = Vec<TestAccount>, Error = ()> + Send> {
+            // Like the real stores, fail if an ID is listed twice
+            if account_ids.len() == 2 && account_ids[0] == account_ids[1] {
+                return Box::new(err(()));
+            }
+            Box::new(ok(account_ids.into_iter().map(TestAccount).collect()))
+        }
+    }
+
+    impl HttpForwardingStore for TestStore {
+        type Account = TestAccount;
+
+        fn set_connection_owner(
+            &self,
+            _account_id: u64,
+            _instance_url: &Url,
+        ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+            Box::new(ok(()))
+        }
+
+        fn remove_connection_owner(
+            &self,
+            account_id: u64,
+            instance_url: &Url,
+        ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+            *self.removed_owner.lock().unwrap() = Some((account_id, instance_url.clone()));
+            Box::new(ok(()))
+        }
+
+        fn get_connection_owner(
+            &self,
+            _account_id: u64,
+        ) -> Box<dyn Future<Item = Option<Url>, Error = ()> + Send> {
+            Box::new(ok(self.owner.clone()))
+        }
+    }
+
+    fn test_server(
+        owner: &str,
+        from_id: u64,
+    ) -> HttpForwardingServer<TestStore, impl OutgoingService<TestAccount> + Clone, TestAccount>
+    {
+        HttpForwardingServer::new(
+            TestStore {
+                owner: Some(Url::parse(owner).unwrap()),
+                removed_owner: Arc::new(Mutex::new(None)),
+            },
+            Url::parse("http://instance-1:7771").unwrap(),
+            "token".to_string(),
+            outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+                assert_eq!(request.from.id(), from_id);
+                assert_eq!(request.to.id(), 2);
+                result(Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: b"forwarded",
+                }
+                .build()))
+            }),
+        )
+    }
+
+    fn forward_request(token: &str, from_id: u64) -> Request<Body> {
+        let prepare = PrepareBuilder {
+            amount: 100,
+            destination: Address::from_str("example.destination").unwrap(),
+            execution_condition: &[0; 32],
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            data: &[],
+        }
+        .build();
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://instance-1:7771/forward/{}/2", from_id))
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::from(BytesMut::from(prepare).freeze()))
+            .unwrap()
+    }
+
+    #[test]
+    fn sends_forwarded_packets_on_owned_connections() {
+        let response = test_server("http://instance-1:7771", 1)
+            .handle_http_request(forward_request("token", 1))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn sends_forwarded_packets_between_the_same_account() {
+        let response = test_server("http://instance-1:7771", 2)
+            .handle_http_request(forward_request("token", 2))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let response = test_server("http://instance-1:7771", 1)
+            .handle_http_request(forward_request("wrong", 1))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), 401);
+    }
+
+    #[test]
+    fn does_not_send_packets_for_connections_owned_by_other_instances() {
+        let response = test_server("http://instance-2:7771", 1)
+            .handle_http_request(forward_request("token", 1))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), 503);
+    }
+
+    #[test]
+    fn removes_owners_that_cannot_be_reached() {
+        // Nothing is listening on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let owner = Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let store = TestStore {
+            owner: Some(owner.clone()),
+            removed_owner: Arc::new(Mutex::new(None)),
+        };
+        let mut service = HttpForwardingService::new(
+            store.clone(),
+            Some(Url::parse("http://instance-1:7771").unwrap()),
+            "token".to_string(),
+            outgoing_service_fn(
+                |_request: OutgoingRequest<TestAccount>| -> Result<Fulfill, Reject> {
+                    panic!("Should not send packets for connections owned by other instances")
+                },
+            ),
+        );
+        let prepare = PrepareBuilder {
+            amount: 100,
+            destination: Address::from_str("example.destination").unwrap(),
+            execution_condition: &[0; 32],
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            data: &[],
+        }
+        .build();
+        let reject = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(service.send_request(OutgoingRequest {
+                from: TestAccount(1),
+                to: TestAccount(2),
+                original_amount: 100,
+                prepare,
+            }))
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+        assert_eq!(*store.removed_owner.lock().unwrap(), Some((2, owner)));
+    }
+
+    #[test]
+    fn parses_account_ids_from_path() {
+        assert_eq!(parse_account_ids::<u64>("/forward/1/2"), Some((1, 2)));
+        assert_eq!(
+            parse_account_ids::<u64>("/ilp/node/forward/3/4/"),
+            Some((3, 4))
+        );
+        assert_eq!(parse_account_ids::<u64>("/accounts/1/2"), None);
+        assert_eq!(parse_account_ids::<u64>("/forward/1"), None);
+    }
+}
@@ -8,12 +8,14 @@ use interledger_service::Account;
 use url::Url;
 
 mod client;
+mod forwarding;
 mod server;
 
 /// Originally from [interledger-relay](https://github.com/coilhq/interledger-relay/blob/master/crates/interledger-relay/src/combinators/limit_stream.rs).
 mod limit_stream;
 
 pub use self::client::HttpClientService;
+pub use self::forwarding::{HttpForwardingServer, HttpForwardingService};
 pub use self::server::HttpServerService;
 
 pub trait HttpAccount: Account {
@@ -33,3 +35,31 @@ pub trait HttpStore: Clone + Send + Sync + 'static {
         token: &str,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send>;
 }
+
+/// The interface for Stores shared by several instances of a node, which record the
+/// instance that holds each account's connection so that the other instances can
+/// forward the packets for that account to it.
+pub trait HttpForwardingStore: Clone + Send + Sync + 'static {
+    type Account: Account;
+
+    /// Record that the instance reachable at `instance_url` holds the account's connection
+    fn set_connection_owner(
+        &self,
+        account_id: <Self::Account as Account>::AccountId,
+        instance_url: &Url,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Forget the owner of the account's connection, unless another instance has
+    /// registered itself as the owner since
+    fn remove_connection_owner(
+        &self,
+        account_id: <Self::Account as Account>::AccountId,
+        instance_url: &Url,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Load the URL of the instance that holds the account's connection, if any does
+    fn get_connection_owner(
+        &self,
+        account_id: <Self::Account as Account>::AccountId,
+    ) -> Box<dyn Future<Item = Option<Url>, Error = ()> + Send>;
+}
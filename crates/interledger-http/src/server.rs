@@ -106,7 +106,7 @@ where
     }
 }
 
-fn parse_prepare_from_request(
+pub(crate) fn parse_prepare_from_request(
     request: Request<Body>,
     max_message_size: Option<usize>,
 ) -> impl Future<Item = Prepare, Error = Response<Body>> + 'static {
@@ -128,7 +128,7 @@ fn parse_prepare_from_request(
         })
 }
 
-fn ilp_response_to_http_response(
+pub(crate) fn ilp_response_to_http_response(
     result: Result<Fulfill, Reject>,
 ) -> Result<Response<Body>, Response<Body>> {
     let bytes: BytesMut = match result {
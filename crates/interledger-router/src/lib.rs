@@ -18,7 +18,17 @@ use std::collections::HashMap;
 
 mod router;
 
-pub use self::router::Router;
+pub use self::router::{Router, DEFAULT_FAILOVER_ATTEMPTS, DEFAULT_MIN_FAILOVER_EXPIRY};
+
+/// Another next hop for a prefix that the Router can fail over to if the best one
+/// rejects a packet with a temporary error.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AlternateRoute<AccountId> {
+    pub account_id: AccountId,
+    /// Whether the route through this account is as good as the best route for the prefix.
+    /// The Router can balance packets between routes of equal cost
+    pub equal_cost: bool,
+}
 
 /// A trait for Store implmentations that have ILP routing tables.
 pub trait RouterStore: AccountStore + Clone + Send + Sync + 'static {
@@ -28,4 +38,13 @@ pub trait RouterStore: AccountStore + Clone + Send + Sync + 'static {
     /// This ensures that individual packets can be routed without hitting the underlying store.
     // TODO avoid using HashMap because it means it'll be cloned a lot
     fn routing_table(&self) -> HashMap<Bytes, <Self::Account as Account>::AccountId>;
+
+    /// **Synchronously** return the other next hops for the given prefix, in order of preference.
+    /// The best next hop is the one in the `routing_table`. Defaults to no alternatives.
+    fn alternate_routes(
+        &self,
+        _prefix: &[u8],
+    ) -> Vec<AlternateRoute<<Self::Account as Account>::AccountId>> {
+        Vec::new()
+    }
 }
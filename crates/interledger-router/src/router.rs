@@ -1,10 +1,26 @@
 use super::RouterStore;
 use bytes::Bytes;
-use futures::{future::err, Future};
-use interledger_packet::{ErrorCode, RejectBuilder};
+use futures::{
+    future::{err, join_all, Either},
+    Future,
+};
+use interledger_packet::{Address, ErrorCode, RejectBuilder};
 use interledger_service::*;
-use log::{error, trace};
-use std::str;
+use log::{debug, error, trace, warn};
+use std::{
+    collections::VecDeque,
+    str,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// How many alternate next hops the Router tries if the best one rejects a packet
+pub const DEFAULT_FAILOVER_ATTEMPTS: usize = 2;
+/// How much time must be left before a packet expires for the Router to try an alternate next hop
+pub const DEFAULT_MIN_FAILOVER_EXPIRY: Duration = Duration::from_millis(2000);
 
 /// # Interledger Router
 ///
@@ -22,11 +38,21 @@ use std::str;
 ///   - reduce the Prepare packet's expiry
 ///
 /// That is done by OutgoingServices.
-
+///
+/// If the next hop rejects the packet with a temporary error (T01, T02 or T04), the router
+/// retries on the alternate next hops the store knows for the prefix, as long as the packet
+/// has enough time left before it expires. Rejects triggered by this node itself, such as
+/// the ones for exceeding an account's balance limits, are not retried when the router is
+/// given the node's address. Packets can also be balanced between equal-cost routes.
 #[derive(Clone)]
 pub struct Router<S, O> {
     store: S,
     next: O,
+    failover_attempts: usize,
+    min_failover_expiry: Duration,
+    load_balance: bool,
+    packets_routed: Arc<AtomicUsize>,
+    ilp_address: Option<Address>,
 }
 
 impl<S, O> Router<S, O>
@@ -35,13 +61,138 @@ where
     O: OutgoingService<S::Account>,
 {
     pub fn new(store: S, next: O) -> Self {
-        Router { store, next }
+        Router {
+            store,
+            next,
+            failover_attempts: DEFAULT_FAILOVER_ATTEMPTS,
+            min_failover_expiry: DEFAULT_MIN_FAILOVER_EXPIRY,
+            load_balance: false,
+            packets_routed: Arc::new(AtomicUsize::new(0)),
+            ilp_address: None,
+        }
+    }
+
+    /// Set the node's own address so that packets this node rejected itself are not
+    /// sent to an alternate next hop
+    pub fn ilp_address(&mut self, ilp_address: Address) -> &mut Self {
+        self.ilp_address = Some(ilp_address);
+        self
+    }
+
+    /// Set how many alternate next hops to try if the best one rejects a packet with a
+    /// temporary error. Set this to 0 to disable failover
+    pub fn failover_attempts(&mut self, attempts: usize) -> &mut Self {
+        self.failover_attempts = attempts;
+        self
+    }
+
+    /// Set how much time must be left before a packet expires to try an alternate next hop
+    pub fn min_failover_expiry(&mut self, expiry: Duration) -> &mut Self {
+        self.min_failover_expiry = expiry;
+        self
+    }
+
+    /// Spread packets between the routes that are as good as the best one for a prefix,
+    /// instead of always sending them to the best one first
+    pub fn load_balance(&mut self, load_balance: bool) -> &mut Self {
+        self.load_balance = load_balance;
+        self
     }
+
+    /// The next hops to try for a packet, starting with the best one
+    fn next_hops(
+        &self,
+        prefix: &[u8],
+        best: <S::Account as Account>::AccountId,
+    ) -> Vec<<S::Account as Account>::AccountId> {
+        if self.failover_attempts == 0 && !self.load_balance {
+            return vec![best];
+        }
+        let (equal_cost, others): (Vec<_>, Vec<_>) = self
+            .store
+            .alternate_routes(prefix)
+            .into_iter()
+            .filter(|route| route.account_id != best)
+            .partition(|route| route.equal_cost);
+        let mut next_hops = vec![best];
+        next_hops.extend(equal_cost.into_iter().map(|route| route.account_id));
+        if self.load_balance && next_hops.len() > 1 {
+            let start = self.packets_routed.fetch_add(1, Ordering::Relaxed) % next_hops.len();
+            next_hops.rotate_left(start);
+        }
+        next_hops.extend(others.into_iter().map(|route| route.account_id));
+        next_hops.truncate(self.failover_attempts + 1);
+        next_hops
+    }
+}
+
+/// Send the request to the first of the accounts and, if another node rejects it with a
+/// temporary error, to the next one, until one of them fulfills it or it is too close to expiring
+fn send_with_failover<O, A>(
+    mut next: O,
+    request: IncomingRequest<A>,
+    mut accounts: VecDeque<A>,
+    min_failover_expiry: Duration,
+    ilp_address: Option<Address>,
+) -> BoxedIlpFuture
+where
+    O: OutgoingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    let to = match accounts.pop_front() {
+        Some(to) => to,
+        None => {
+            return Box::new(err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                triggered_by: None,
+                data: &[],
+            }
+            .build()))
+        }
+    };
+    let to_id = to.id();
+    Box::new(
+        next.send_request(request.clone().into_outgoing(to))
+            .or_else(move |reject| {
+                let is_temporary = match reject.code() {
+                    ErrorCode::T01_PEER_UNREACHABLE
+                    | ErrorCode::T02_PEER_BUSY
+                    | ErrorCode::T04_INSUFFICIENT_LIQUIDITY => true,
+                    _ => false,
+                };
+                let rejected_by_us = ilp_address.is_some() && reject.triggered_by() == ilp_address;
+                let can_retry = is_temporary && !rejected_by_us;
+                let time_left = request
+                    .prepare
+                    .expires_at()
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                if can_retry && !accounts.is_empty() && time_left >= min_failover_expiry {
+                    debug!(
+                        "Next hop {} rejected packet with code {}, trying account {} instead",
+                        to_id,
+                        reject.code(),
+                        accounts[0].id()
+                    );
+                    Either::A(send_with_failover(
+                        next,
+                        request,
+                        accounts,
+                        min_failover_expiry,
+                        ilp_address,
+                    ))
+                } else {
+                    Either::B(err(reject))
+                }
+            }),
+    )
 }
 
 impl<S, O> IncomingService<S::Account> for Router<S, O>
 where
     S: RouterStore,
+    S::Account: 'static,
     O: OutgoingService<S::Account> + Clone + Send + 'static,
 {
     type Future = BoxedIlpFuture;
@@ -54,6 +205,7 @@ where
     fn handle_request(&mut self, request: IncomingRequest<S::Account>) -> Self::Future {
         let destination = request.prepare.destination();
         let mut next_hop = None;
+        let mut matching_prefix = Bytes::new();
         let routing_table = self.store.routing_table();
 
         // Check if we have a direct path for that account or if we need to scan
@@ -66,8 +218,8 @@ where
                 account_id
             );
             next_hop = Some(*account_id);
+            matching_prefix = Bytes::from(dest);
         } else if !routing_table.is_empty() {
-            let mut matching_prefix = Bytes::new();
             for route in self.store.routing_table() {
                 trace!(
                     "Checking route: \"{}\" -> {}",
@@ -95,12 +247,29 @@ where
         }
 
         if let Some(account_id) = next_hop {
-            let mut next = self.next.clone();
+            let next = self.next.clone();
+            let min_failover_expiry = self.min_failover_expiry;
+            let ilp_address = self.ilp_address.clone();
+            let mut next_hops = self.next_hops(&matching_prefix, account_id).into_iter();
+            let first_hop = next_hops.next().unwrap_or(account_id);
+            // An alternate that cannot be loaded is skipped rather than failing the packet
+            let store = self.store.clone();
+            let alternates = join_all(next_hops.map(move |account_id| {
+                store.get_accounts(vec![account_id]).then(move |result| {
+                    if result.is_err() {
+                        warn!(
+                            "Skipping alternate next hop {} because it could not be loaded",
+                            account_id
+                        );
+                    }
+                    Ok(result.ok().and_then(|mut accounts| accounts.pop()))
+                })
+            }));
             Box::new(
                 self.store
-                    .get_accounts(vec![account_id])
+                    .get_accounts(vec![first_hop])
                     .map_err(move |_| {
-                        error!("No record found for account: {}", account_id);
+                        error!("No record found for account: {}", first_hop);
                         RejectBuilder {
                             code: ErrorCode::F02_UNREACHABLE,
                             message: &[],
@@ -109,9 +278,22 @@ where
                         }
                         .build()
                     })
-                    .and_then(move |mut accounts| {
-                        let request = request.into_outgoing(accounts.remove(0));
-                        next.send_request(request)
+                    .and_then(move |first_hop| {
+                        alternates.map(move |alternates| {
+                            first_hop
+                                .into_iter()
+                                .chain(alternates.into_iter().flatten())
+                                .collect::<VecDeque<_>>()
+                        })
+                    })
+                    .and_then(move |accounts| {
+                        send_with_failover(
+                            next,
+                            request,
+                            accounts,
+                            min_failover_expiry,
+                            ilp_address,
+                        )
                     }),
             )
         } else {
@@ -130,6 +312,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::AlternateRoute;
     use futures::future::ok;
     use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
     use interledger_service::outgoing_service_fn;
@@ -140,6 +323,72 @@ mod tests {
     use std::sync::Arc;
     use std::time::UNIX_EPOCH;
 
+    fn alternate(account_id: u64, equal_cost: bool) -> AlternateRoute<u64> {
+        AlternateRoute {
+            account_id,
+            equal_cost,
+        }
+    }
+
+    /// The test store fails to load this account
+    const MISSING_ACCOUNT: u64 = 99;
+
+    fn failover_store() -> TestStore {
+        TestStore {
+            routes: HashMap::from_iter(vec![(Bytes::from("example."), 1)]),
+            alternates: HashMap::from_iter(vec![(
+                Bytes::from("example."),
+                vec![alternate(2, true), alternate(3, false)],
+            )]),
+        }
+    }
+
+    fn prepare_expiring_in(seconds: u64) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount(0),
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                execution_condition: &[1; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(seconds),
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    /// Records which accounts packets were sent to and rejects them for all but `fulfilled_by`
+    fn outgoing_recorder(
+        fulfilled_by: u64,
+        code: ErrorCode,
+        triggered_by: Option<Address>,
+    ) -> (
+        impl OutgoingService<TestAccount> + Clone,
+        Arc<Mutex<Vec<u64>>>,
+    ) {
+        let sent_to = Arc::new(Mutex::new(Vec::new()));
+        let sent_to_clone = sent_to.clone();
+        let outgoing = outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+            sent_to_clone.lock().push(request.to.0);
+            if request.to.0 == fulfilled_by {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            } else {
+                Err(RejectBuilder {
+                    code,
+                    message: &[],
+                    triggered_by: triggered_by.as_ref(),
+                    data: &[],
+                }
+                .build())
+            }
+        });
+        (outgoing, sent_to)
+    }
+
     #[derive(Debug, Clone)]
     struct TestAccount(u64);
 
@@ -153,6 +402,7 @@ mod tests {
     #[derive(Clone)]
     struct TestStore {
         routes: HashMap<Bytes, u64>,
+        alternates: HashMap<Bytes, Vec<AlternateRoute<u64>>>,
     }
 
     impl AccountStore for TestStore {
@@ -162,6 +412,9 @@ mod tests {
             &self,
             account_ids: Vec<<<Self as AccountStore>::Account as Account>::AccountId>,
         ) -> Box<dyn Future<Item = Vec<TestAccount>, Error = ()> + Send> {
+            if account_ids.contains(&MISSING_ACCOUNT) {
+                return Box::new(err(()));
+            }
             Box::new(ok(account_ids.into_iter().map(TestAccount).collect()))
         }
     }
@@ -170,6 +423,10 @@ mod tests {
         fn routing_table(&self) -> HashMap<Bytes, u64> {
             self.routes.clone()
         }
+
+        fn alternate_routes(&self, prefix: &[u8]) -> Vec<AlternateRoute<u64>> {
+            self.alternates.get(prefix).cloned().unwrap_or_default()
+        }
     }
 
     #[test]
@@ -177,6 +434,7 @@ mod tests {
         let mut router = Router::new(
             TestStore {
                 routes: HashMap::new(),
+                alternates: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -208,6 +466,7 @@ mod tests {
         let mut router = Router::new(
             TestStore {
                 routes: HashMap::from_iter(vec![(Bytes::from("example.other"), 1)].into_iter()),
+                alternates: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -241,6 +500,7 @@ mod tests {
                 routes: HashMap::from_iter(
                     vec![(Bytes::from("example.destination"), 1)].into_iter(),
                 ),
+                alternates: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -272,6 +532,7 @@ mod tests {
         let mut router = Router::new(
             TestStore {
                 routes: HashMap::from_iter(vec![(Bytes::from(""), 0)].into_iter()),
+                alternates: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -303,6 +564,7 @@ mod tests {
         let mut router = Router::new(
             TestStore {
                 routes: HashMap::from_iter(vec![(Bytes::from("example."), 1)].into_iter()),
+                alternates: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -343,6 +605,7 @@ mod tests {
                     ]
                     .into_iter(),
                 ),
+                alternates: HashMap::new(),
             },
             outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
                 *to_clone.lock() = Some(request.to.clone());
@@ -371,4 +634,97 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(to.lock().take().unwrap().0, 2);
     }
+
+    #[test]
+    fn fails_over_to_alternate_routes() {
+        let (outgoing, sent_to) = outgoing_recorder(3, ErrorCode::T01_PEER_UNREACHABLE, None);
+        let mut router = Router::new(failover_store(), outgoing);
+        let result = router.handle_request(prepare_expiring_in(30)).wait();
+        assert!(result.is_ok());
+        assert_eq!(*sent_to.lock(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn does_not_fail_over_on_own_rejects() {
+        let node_address = Address::from_str("example.node").unwrap();
+        let (outgoing, sent_to) = outgoing_recorder(
+            3,
+            ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+            Some(node_address.clone()),
+        );
+        let mut router = Router::new(failover_store(), outgoing);
+        router.ilp_address(node_address);
+        let result = router.handle_request(prepare_expiring_in(30)).wait();
+        assert!(result.is_err());
+        assert_eq!(*sent_to.lock(), vec![1]);
+    }
+
+    #[test]
+    fn fails_over_on_rejects_from_other_nodes() {
+        let (outgoing, sent_to) = outgoing_recorder(
+            3,
+            ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+            Some(Address::from_str("example.other").unwrap()),
+        );
+        let mut router = Router::new(failover_store(), outgoing);
+        router.ilp_address(Address::from_str("example.node").unwrap());
+        let result = router.handle_request(prepare_expiring_in(30)).wait();
+        assert!(result.is_ok());
+        assert_eq!(*sent_to.lock(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn skips_alternates_that_cannot_be_loaded() {
+        let (outgoing, sent_to) = outgoing_recorder(3, ErrorCode::T01_PEER_UNREACHABLE, None);
+        let mut store = failover_store();
+        store.alternates.insert(
+            Bytes::from("example."),
+            vec![alternate(MISSING_ACCOUNT, true), alternate(3, false)],
+        );
+        let mut router = Router::new(store, outgoing);
+        let result = router.handle_request(prepare_expiring_in(30)).wait();
+        assert!(result.is_ok());
+        assert_eq!(*sent_to.lock(), vec![1, 3]);
+    }
+
+    #[test]
+    fn does_not_fail_over_on_final_errors() {
+        let (outgoing, sent_to) = outgoing_recorder(3, ErrorCode::F02_UNREACHABLE, None);
+        let mut router = Router::new(failover_store(), outgoing);
+        let result = router.handle_request(prepare_expiring_in(30)).wait();
+        assert!(result.is_err());
+        assert_eq!(*sent_to.lock(), vec![1]);
+    }
+
+    #[test]
+    fn does_not_fail_over_close_to_expiry() {
+        let (outgoing, sent_to) = outgoing_recorder(3, ErrorCode::T04_INSUFFICIENT_LIQUIDITY, None);
+        let mut router = Router::new(failover_store(), outgoing);
+        router.min_failover_expiry(Duration::from_secs(60));
+        let result = router.handle_request(prepare_expiring_in(30)).wait();
+        assert!(result.is_err());
+        assert_eq!(*sent_to.lock(), vec![1]);
+    }
+
+    #[test]
+    fn limits_failover_attempts() {
+        let (outgoing, sent_to) = outgoing_recorder(3, ErrorCode::T02_PEER_BUSY, None);
+        let mut router = Router::new(failover_store(), outgoing);
+        router.failover_attempts(1);
+        let result = router.handle_request(prepare_expiring_in(30)).wait();
+        assert!(result.is_err());
+        assert_eq!(*sent_to.lock(), vec![1, 2]);
+    }
+
+    #[test]
+    fn balances_between_equal_cost_routes() {
+        let (outgoing, sent_to) = outgoing_recorder(0, ErrorCode::F02_UNREACHABLE, None);
+        let mut router = Router::new(failover_store(), outgoing);
+        router.load_balance(true);
+        for _ in 0..4 {
+            let _ = router.handle_request(prepare_expiring_in(30)).wait();
+        }
+        // Account 3 is not as good as the others so it is never used first
+        assert_eq!(*sent_to.lock(), vec![1, 2, 1, 2]);
+    }
 }
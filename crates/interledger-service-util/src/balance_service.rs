@@ -1,3 +1,4 @@
+use super::JournalEntry;
 use futures::Future;
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::{Address, ErrorCode, Fulfill, Reject, RejectBuilder};
@@ -7,6 +8,8 @@ use log::{debug, error};
 use std::marker::PhantomData;
 use tokio_executor::spawn;
 
+/// Every balance change is recorded in the account's journal along with the
+/// `reference` of the packet that caused it (its hex-encoded execution condition)
 pub trait BalanceStore: AccountStore {
     /// Fetch the current balance for the given account.
     fn get_balance(&self, account: Self::Account)
@@ -16,6 +19,7 @@ pub trait BalanceStore: AccountStore {
         &self,
         from_account: Self::Account,
         incoming_amount: u64,
+        reference: &str,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
 
     /// Increases the account's balance, and returns the updated balance
@@ -24,13 +28,24 @@ pub trait BalanceStore: AccountStore {
         &self,
         to_account: Self::Account,
         outgoing_amount: u64,
+        reference: &str,
     ) -> Box<dyn Future<Item = (i64, u64), Error = ()> + Send>;
 
     fn update_balances_for_reject(
         &self,
         from_account: Self::Account,
         incoming_amount: u64,
+        reference: &str,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Fetch up to `limit` entries of the account's balance journal, oldest first,
+    /// starting with the entry whose ID is `from_entry`
+    fn get_balance_journal(
+        &self,
+        account: Self::Account,
+        from_entry: u64,
+        limit: usize,
+    ) -> Box<dyn Future<Item = Vec<JournalEntry>, Error = ()> + Send>;
 }
 
 /// # Balance Service
@@ -98,6 +113,10 @@ where
         let ilp_address = self.ilp_address.clone();
         let settlement_client = self.settlement_client.clone();
         let to_has_engine = to.settlement_engine_details().is_some();
+        // The execution condition ties together the journal entries for each packet
+        let reference = hex::encode(request.prepare.execution_condition());
+        let reference_clone = reference.clone();
+        let reference_reject = reference.clone();
 
         // Update the balance _before_ sending the settlement so that we don't accidentally send
         // multiple settlements for the same balance. While there will be a small moment of time (the delta
@@ -114,6 +133,7 @@ where
                 .update_balances_for_prepare(
                     from.clone(),
                     incoming_amount,
+                    &reference,
                 )
                 .map_err(move |_| {
                     debug!("Rejecting packet because it would exceed a balance limit");
@@ -139,6 +159,7 @@ where
                             let fulfill_balance_update = store.update_balances_for_fulfill(
                                 to.clone(),
                                 outgoing_amount,
+                                &reference_clone,
                             )
                             .map_err(move |_| error!("Error applying balance changes for fulfill from account: {} to account: {}. Incoming amount was: {}, outgoing amount was: {}", from_id, to_id, incoming_amount, outgoing_amount))
                             .and_then(move |(balance, amount_to_settle)| {
@@ -176,6 +197,7 @@ where
                             let reject_balance_update = store_clone.update_balances_for_reject(
                                 from_clone.clone(),
                                 incoming_amount,
+                                &reference_reject,
                             ).map_err(move |_| error!("Error rolling back balance change for accounts: {} and {}. Incoming amount was: {}, outgoing amount was: {}", from_clone.id(), to_clone.id(), incoming_amount, outgoing_amount));
                             spawn(reject_balance_update);
 
@@ -0,0 +1,67 @@
+use std::cmp::max;
+
+/// Length of the period the rate limits apply to
+const RATE_LIMIT_PERIOD_NANOS: u128 = 60_000_000_000;
+
+/// A per-minute rate limit applied with the Generic Cell Rate Algorithm, which is what
+/// the redis-cell module used by the RedisStore implements. This allows up to `limit`
+/// units per minute, including bursts of up to `limit` units.
+///
+/// Stores keep the state of each limit as its theoretical arrival time. The methods here
+/// take and return how far in the future that is, in nanoseconds (0 if it has passed).
+#[derive(Clone, Copy, Debug)]
+pub struct CellRateLimit {
+    emission_interval: u128,
+    tolerance: u128,
+}
+
+impl CellRateLimit {
+    pub fn per_minute(limit: u64) -> Self {
+        let emission_interval = RATE_LIMIT_PERIOD_NANOS / u128::from(max(limit, 1));
+        CellRateLimit {
+            emission_interval,
+            tolerance: emission_interval * u128::from(limit),
+        }
+    }
+
+    /// Use up `quantity` units of the limit and return the new time until the theoretical
+    /// arrival. Returns None, without using up any of the limit, if that would exceed it
+    pub fn apply(&self, remaining: u128, quantity: u64) -> Option<u128> {
+        let new_remaining = remaining + self.emission_interval * u128::from(quantity);
+        if new_remaining > self.tolerance {
+            None
+        } else {
+            Some(new_remaining)
+        }
+    }
+
+    /// Give back `quantity` units of the limit and return the new time until the
+    /// theoretical arrival
+    pub fn refund(&self, remaining: u128, quantity: u64) -> u128 {
+        remaining.saturating_sub(self.emission_interval * u128::from(quantity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_bursts_up_to_the_limit() {
+        let limit = CellRateLimit::per_minute(3);
+        let remaining = limit.apply(0, 2).unwrap();
+        let remaining = limit.apply(remaining, 1).unwrap();
+        assert_eq!(limit.apply(remaining, 1), None);
+        assert_eq!(remaining, RATE_LIMIT_PERIOD_NANOS);
+    }
+
+    #[test]
+    fn refunds_used_up_units() {
+        let limit = CellRateLimit::per_minute(10);
+        let remaining = limit.apply(0, 10).unwrap();
+        assert_eq!(limit.apply(remaining, 1), None);
+        let remaining = limit.refund(remaining, 4);
+        assert!(limit.apply(remaining, 4).is_some());
+        assert_eq!(limit.refund(remaining, 100), 0);
+    }
+}
@@ -1,15 +1,27 @@
 use byteorder::ReadBytesExt;
 use bytes::{BufMut, BytesMut};
 use core::borrow::Borrow;
-use futures::future::err;
+use futures::{
+    future::{err, ok},
+    Future,
+};
 use interledger_packet::{
-    oer::BufOerExt, Address, ErrorCode, Prepare, PrepareBuilder, RejectBuilder,
+    oer, oer::BufOerExt, oer::MutBufOerExt, Address, ErrorCode, Fulfill, FulfillBuilder, Prepare,
+    PrepareBuilder, Reject, RejectBuilder,
 };
 use interledger_service::*;
+use log::debug;
+use parking_lot::Mutex;
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::marker::PhantomData;
 use std::str;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 /// A service that responds to the Echo Protocol.
 /// Currently, this service only supports bidirectional mode (unidirectional mode is not supported yet).
@@ -20,17 +32,23 @@ use std::time::SystemTime;
 const ECHO_PREFIX: &str = "ECHOECHOECHOECHO";
 /// The length of the `ECHO_PREFIX`
 const ECHO_PREFIX_LEN: usize = 16;
+/// How long we wait for the echo response to our pings
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
 
 enum EchoPacketType {
     Request = 0,
     Response = 1,
 }
 
+/// Fulfillments of the pings we are waiting for echo responses to, keyed by their condition
+type PendingPings = Arc<Mutex<HashMap<[u8; 32], [u8; 32]>>>;
+
 #[derive(Clone)]
 pub struct EchoService<I, A> {
     /// The ILP address which this ECHO service should respond for
     ilp_address: Address,
     next: I,
+    pending_pings: PendingPings,
     account_type: PhantomData<A>,
 }
 
@@ -43,9 +61,32 @@ where
         EchoService {
             ilp_address,
             next,
+            pending_pings: Arc::new(Mutex::new(HashMap::new())),
             account_type: PhantomData,
         }
     }
+
+    /// Returns a `Pinger` that sends pings from this service's address.
+    /// The echo responses are fulfilled by this service when they come back.
+    pub fn pinger(&self) -> Pinger {
+        Pinger {
+            ilp_address: self.ilp_address.clone(),
+            pending_pings: self.pending_pings.clone(),
+        }
+    }
+
+    /// Fulfill the echo response if it is for one of our pings
+    fn fulfill_ping(&self, prepare: &Prepare) -> Option<Fulfill> {
+        let condition = <[u8; 32]>::try_from(prepare.execution_condition()).ok()?;
+        let fulfillment = self.pending_pings.lock().remove(&condition)?;
+        Some(
+            FulfillBuilder {
+                fulfillment: &fulfillment,
+                data: &[],
+            }
+            .build(),
+        )
+    }
 }
 
 impl<I, A> IncomingService<A> for EchoService<I, A>
@@ -84,8 +125,11 @@ where
             }
         };
         if echo_packet_type == EchoPacketType::Response as u8 {
-            // if the echo packet type is Response, just pass it to the next service
-            // so that the initiator could handle this packet
+            // if the echo packet type is Response and it is for one of our pings, fulfill it.
+            // Otherwise, just pass it to the next service so that the initiator could handle this packet
+            if let Some(fulfill) = self.fulfill_ping(&request.prepare) {
+                return Box::new(ok(fulfill));
+            }
             return Box::new(self.next.handle_request(request));
         }
         if echo_packet_type != EchoPacketType::Request as u8 {
@@ -146,14 +190,73 @@ where
         }
         .build();
 
+        // We pinged ourselves
+        if source_address == self.ilp_address {
+            if let Some(fulfill) = self.fulfill_ping(&request.prepare) {
+                return Box::new(ok(fulfill));
+            }
+        }
+
         Box::new(self.next.handle_request(request))
     }
 }
 
-#[cfg(test)]
-use interledger_packet::{oer, oer::MutBufOerExt};
-// This should be exported when we have a use for it outside of the tests
-#[cfg(test)]
+/// Sends echo requests ("pings") and measures how long it takes for them to come back.
+///
+/// The destination echoes the request back to our address, where the `EchoService` this
+/// was created from fulfills it, so a ping only succeeds if packets can get to the destination
+/// and back. If it fails, the reject says where along the path the packet was rejected.
+#[derive(Clone)]
+pub struct Pinger {
+    ilp_address: Address,
+    pending_pings: PendingPings,
+}
+
+impl Pinger {
+    /// Send a ping to the destination through the given service, from the given account.
+    /// Returns the round trip time
+    pub fn ping<S, A>(
+        &self,
+        service: &mut S,
+        from: A,
+        destination: Address,
+        amount: u64,
+    ) -> impl Future<Item = Duration, Error = Reject>
+    where
+        S: IncomingService<A>,
+        A: Account,
+    {
+        let mut fulfillment = [0; 32];
+        SystemRandom::new().fill(&mut fulfillment).unwrap();
+        let mut execution_condition = [0; 32];
+        execution_condition.copy_from_slice(digest(&SHA256, &fulfillment).as_ref());
+        self.pending_pings
+            .lock()
+            .insert(execution_condition, fulfillment);
+
+        let prepare = EchoRequestBuilder {
+            amount,
+            expires_at: SystemTime::now() + PING_TIMEOUT,
+            execution_condition: &execution_condition,
+            destination: &destination,
+            source_address: &self.ilp_address,
+        }
+        .build();
+        debug!(
+            "Sending ping to {}",
+            str::from_utf8(destination.as_ref()).unwrap_or("<not utf8>")
+        );
+        let pending_pings = self.pending_pings.clone();
+        let start = Instant::now();
+        service
+            .handle_request(IncomingRequest { from, prepare })
+            .then(move |result| {
+                pending_pings.lock().remove(&execution_condition);
+                result.map(|_fulfill| start.elapsed())
+            })
+    }
+}
+
 pub struct EchoRequestBuilder<'a> {
     pub amount: u64,
     pub expires_at: SystemTime,
@@ -164,7 +267,6 @@ pub struct EchoRequestBuilder<'a> {
     pub source_address: &'a Address,
 }
 
-#[cfg(test)]
 impl<'a> EchoRequestBuilder<'a> {
     pub fn build(&self) -> Prepare {
         let source_address_len = oer::predict_var_octet_string(self.source_address.len());
@@ -449,6 +551,89 @@ mod echo_tests {
         assert!(result.is_err());
     }
 
+    /// Pings are echoed back by the destination and fulfilled by the pinger's echo service.
+    #[test]
+    fn ping_round_trip() {
+        let unreachable = incoming_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                triggered_by: None,
+                data: &[],
+            }
+            .build())
+        });
+        let initiator =
+            EchoService::new(Address::from_str("example.initiator").unwrap(), unreachable);
+        let pinger = initiator.pinger();
+        // The recipient sends the echo response straight back to the initiator
+        let mut recipient =
+            EchoService::new(Address::from_str("example.recipient").unwrap(), initiator);
+
+        let result = pinger
+            .ping(
+                &mut recipient,
+                TestAccount(1),
+                Address::from_str("example.recipient").unwrap(),
+                0,
+            )
+            .wait();
+        assert!(result.is_ok());
+        assert!(pinger.pending_pings.lock().is_empty());
+    }
+
+    #[test]
+    fn ping_self() {
+        let unreachable = incoming_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                triggered_by: None,
+                data: &[],
+            }
+            .build())
+        });
+        let node_address = Address::from_str("example.node").unwrap();
+        let mut echo_service = EchoService::new(node_address.clone(), unreachable);
+        let result = echo_service
+            .pinger()
+            .ping(&mut echo_service, TestAccount(1), node_address, 0)
+            .wait();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn ping_returns_reject() {
+        let connector = Address::from_str("example.connector").unwrap();
+        let unreachable = incoming_service_fn(move |_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                triggered_by: Some(&connector),
+                data: &[],
+            }
+            .build())
+        });
+        let mut echo_service =
+            EchoService::new(Address::from_str("example.node").unwrap(), unreachable);
+        let pinger = echo_service.pinger();
+        let reject = pinger
+            .ping(
+                &mut echo_service,
+                TestAccount(1),
+                Address::from_str("example.nowhere").unwrap(),
+                0,
+            )
+            .wait()
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+        assert_eq!(
+            reject.triggered_by(),
+            Some(Address::from_str("example.connector").unwrap())
+        );
+        assert!(pinger.pending_pings.lock().is_empty());
+    }
+
     fn get_random_fulfillment() -> [u8; 32] {
         let mut bytes: [u8; 32] = [0; 32];
         SystemRandom::new().fill(&mut bytes).unwrap();
@@ -0,0 +1,168 @@
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// What caused a change to an account's balance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalEntryKind {
+    /// An incoming Prepare packet from the account was forwarded
+    Prepare,
+    /// An outgoing Prepare packet to the account was fulfilled
+    Fulfill,
+    /// A packet from the account was rejected, so the Prepare was rolled back
+    Reject,
+    /// A settlement was sent to the account
+    OutgoingSettlement,
+    /// A settlement was received from the account
+    IncomingSettlement,
+    /// Sending a settlement to the account failed, so it was rolled back
+    SettlementRefund,
+    /// The balance was carried over from the exported state of another node
+    Import,
+}
+
+impl JournalEntryKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JournalEntryKind::Prepare => "prepare",
+            JournalEntryKind::Fulfill => "fulfill",
+            JournalEntryKind::Reject => "reject",
+            JournalEntryKind::OutgoingSettlement => "outgoing_settlement",
+            JournalEntryKind::IncomingSettlement => "incoming_settlement",
+            JournalEntryKind::SettlementRefund => "settlement_refund",
+            JournalEntryKind::Import => "import",
+        }
+    }
+}
+
+impl fmt::Display for JournalEntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for JournalEntryKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prepare" => Ok(JournalEntryKind::Prepare),
+            "fulfill" => Ok(JournalEntryKind::Fulfill),
+            "reject" => Ok(JournalEntryKind::Reject),
+            "outgoing_settlement" => Ok(JournalEntryKind::OutgoingSettlement),
+            "incoming_settlement" => Ok(JournalEntryKind::IncomingSettlement),
+            "settlement_refund" => Ok(JournalEntryKind::SettlementRefund),
+            "import" => Ok(JournalEntryKind::Import),
+            _ => Err(()),
+        }
+    }
+}
+
+/// One change to an account's balance. Stores append an entry to the account's
+/// journal in the same atomic operation as every balance change, so the journal
+/// explains how the account got to its current balance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournalEntry {
+    /// Position of the entry in the account's journal, starting from 1. Stores can be
+    /// set to keep only the latest entries, which does not change the IDs of the rest
+    pub id: u64,
+    pub kind: JournalEntryKind,
+    /// The packet's execution condition (hex-encoded) or the settlement's idempotency key
+    pub reference: Option<String>,
+    /// How much the balance (including the prepaid amount) changed
+    pub amount: i64,
+    /// The account's balance after the change
+    pub balance: i64,
+    /// The account's prepaid amount after the change
+    pub prepaid_amount: i64,
+    /// Milliseconds since the UNIX epoch
+    pub timestamp: u64,
+}
+
+impl JournalEntry {
+    /// Encode the entry without its ID, for stores that keep entries as strings.
+    /// The reference goes last because it may contain any character
+    pub fn to_record(&self) -> String {
+        format!(
+            "{}|{}|{}|{}|{}|{}",
+            self.kind,
+            self.amount,
+            self.balance,
+            self.prepaid_amount,
+            self.timestamp,
+            self.reference.as_deref().unwrap_or("")
+        )
+    }
+
+    /// Decode an entry encoded with `to_record`
+    pub fn from_record(id: u64, record: &str) -> Result<Self, ()> {
+        let mut parts = record.splitn(6, '|');
+        let mut next = || parts.next().ok_or(());
+        let kind = JournalEntryKind::from_str(next()?)?;
+        let amount = i64::from_str(next()?).map_err(|_| ())?;
+        let balance = i64::from_str(next()?).map_err(|_| ())?;
+        let prepaid_amount = i64::from_str(next()?).map_err(|_| ())?;
+        let timestamp = u64::from_str(next()?).map_err(|_| ())?;
+        let reference = next()?;
+        Ok(JournalEntry {
+            id,
+            kind,
+            reference: if reference.is_empty() {
+                None
+            } else {
+                Some(reference.to_string())
+            },
+            amount,
+            balance,
+            prepaid_amount,
+            timestamp,
+        })
+    }
+}
+
+/// The current time in milliseconds since the UNIX epoch, for journal entries
+pub fn journal_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_records() {
+        let entry = JournalEntry {
+            id: 7,
+            kind: JournalEntryKind::IncomingSettlement,
+            reference: Some("key|with|pipes".to_string()),
+            amount: 100,
+            balance: -50,
+            prepaid_amount: 0,
+            timestamp: 1_565_000_000_000,
+        };
+        assert_eq!(
+            entry.to_record(),
+            "incoming_settlement|100|-50|0|1565000000000|key|with|pipes"
+        );
+        assert_eq!(JournalEntry::from_record(7, &entry.to_record()), Ok(entry));
+    }
+
+    #[test]
+    fn decodes_records_without_reference() {
+        let entry = JournalEntry::from_record(1, "settlement_refund|91|-909|0|5|").unwrap();
+        assert_eq!(entry.kind, JournalEntryKind::SettlementRefund);
+        assert_eq!(entry.reference, None);
+        assert_eq!(entry.amount, 91);
+    }
+
+    #[test]
+    fn rejects_invalid_records() {
+        assert!(JournalEntry::from_record(1, "prepare|1|2").is_err());
+        assert!(JournalEntry::from_record(1, "unknown|1|2|3|4|").is_err());
+    }
+}
@@ -3,19 +3,23 @@
 //! Miscellaneous, small Interledger Services.
 
 mod balance_service;
+mod cell_rate;
 mod echo_service;
 mod exchange_rates_service;
 mod expiry_shortener_service;
+mod journal;
 mod max_packet_amount_service;
 mod rate_limit_service;
 mod validator_service;
 
 pub use self::balance_service::{BalanceService, BalanceStore};
-pub use self::echo_service::EchoService;
+pub use self::cell_rate::CellRateLimit;
+pub use self::echo_service::{EchoRequestBuilder, EchoResponseBuilder, EchoService, Pinger};
 pub use self::exchange_rates_service::{ExchangeRateService, ExchangeRateStore};
 pub use self::expiry_shortener_service::{
     ExpiryShortenerService, RoundTripTimeAccount, DEFAULT_ROUND_TRIP_TIME,
 };
+pub use self::journal::{journal_timestamp, JournalEntry, JournalEntryKind};
 pub use self::max_packet_amount_service::{MaxPacketAmountAccount, MaxPacketAmountService};
 pub use self::rate_limit_service::{
     RateLimitAccount, RateLimitError, RateLimitService, RateLimitStore,
@@ -815,6 +815,7 @@ fn prefixed_mesage(challenge: Vec<u8>) -> Vec<u8> {
 #[allow(clippy::all)]
 pub fn run_ethereum_engine<R, Si>(
     redis_uri: R,
+    redis_key_prefix: Option<String>,
     ethereum_endpoint: String,
     settlement_port: u16,
     secret_seed: &[u8; 32],
@@ -834,7 +835,11 @@ where
     let redis_secret = generate_redis_secret(secret_seed);
     let redis_uri = redis_uri.into_connection_info().unwrap();
 
-    EthereumLedgerRedisStoreBuilder::new(redis_uri.clone())
+    let mut ethereum_store_builder = EthereumLedgerRedisStoreBuilder::new(redis_uri.clone());
+    if let Some(ref redis_key_prefix) = redis_key_prefix {
+        ethereum_store_builder.key_prefix(redis_key_prefix);
+    }
+    ethereum_store_builder
         .connect()
         .and_then(move |ethereum_store| {
             let engine = EthereumLedgerSettlementEngineBuilder::new(ethereum_store, private_key)
@@ -848,18 +853,20 @@ where
                 .token_address(token_address)
                 .connect();
 
-            RedisStoreBuilder::new(redis_uri, redis_secret)
-                .connect()
-                .and_then(move |store| {
-                    let addr = SocketAddr::from(([127, 0, 0, 1], settlement_port));
-                    let listener = TcpListener::bind(&addr)
-                        .expect("Unable to bind to Settlement Engine address");
-                    info!("Ethereum Settlement Engine listening on: {}", addr);
-
-                    let api = SettlementEngineApi::new(engine, store);
-                    tokio::spawn(api.serve(listener.incoming()));
-                    Ok(())
-                })
+            let mut store_builder = RedisStoreBuilder::new(redis_uri, redis_secret);
+            if let Some(ref redis_key_prefix) = redis_key_prefix {
+                store_builder.key_prefix(redis_key_prefix);
+            }
+            store_builder.connect().and_then(move |store| {
+                let addr = SocketAddr::from(([127, 0, 0, 1], settlement_port));
+                let listener =
+                    TcpListener::bind(&addr).expect("Unable to bind to Settlement Engine address");
+                info!("Ethereum Settlement Engine listening on: {}", addr);
+
+                let api = SettlementEngineApi::new(engine, store);
+                tokio::spawn(api.serve(listener.incoming()));
+                Ok(())
+            })
         })
 }
 
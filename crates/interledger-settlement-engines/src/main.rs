@@ -41,6 +41,10 @@ pub fn main() {
                             .long("redis_uri")
                             .help("Redis database to add the account to")
                             .default_value("redis://127.0.0.1:6379"),
+                        Arg::with_name("redis_key_prefix")
+                            .long("redis_key_prefix")
+                            .help("Prefix for the engine's Redis keys, so that several engines and nodes can share one Redis database. Must match the node's redis_key_prefix")
+                            .takes_value(true),
                         Arg::with_name("server_secret")
                             .long("server_secret")
                             .help("Cryptographic seed used to derive keys")
@@ -87,6 +91,7 @@ pub fn main() {
             let connector_url: String = value_t!(matches, "connector_url", String).unwrap();
             let redis_uri = value_t!(matches, "redis_uri", String).expect("redis_uri is required");
             let redis_uri = Url::parse(&redis_uri).expect("redis_uri is not a valid URI");
+            let redis_key_prefix = matches.value_of("redis_key_prefix").map(String::from);
             let server_secret: [u8; 32] = {
                 let encoded: String = value_t!(matches, "server_secret", String).unwrap();
                 let mut server_secret = [0; 32];
@@ -103,6 +108,7 @@ pub fn main() {
 
             tokio::run(run_ethereum_engine(
                 redis_uri,
+                redis_key_prefix,
                 ethereum_endpoint,
                 settlement_port,
                 &server_secret,
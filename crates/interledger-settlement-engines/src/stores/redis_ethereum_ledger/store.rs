@@ -73,6 +73,12 @@ impl EthereumLedgerRedisStoreBuilder {
         }
     }
 
+    /// Prefix for all of the keys the store uses. Keys are named `<prefix>:<key>`
+    pub fn key_prefix(&mut self, key_prefix: &str) -> &mut Self {
+        self.redis_store_builder.key_prefix(key_prefix);
+        self
+    }
+
     pub fn connect(&self) -> impl Future<Item = EthereumLedgerRedisStore, Error = ()> {
         self.redis_store_builder
             .connect()
@@ -115,7 +121,10 @@ impl EthereumStore for EthereumLedgerRedisStore {
         debug!("Loading account addresses {:?}", account_ids);
         let mut pipe = redis::pipe();
         for account_id in account_ids.iter() {
-            pipe.hgetall(ethereum_ledger_key(*account_id));
+            pipe.hgetall(
+                self.redis_store
+                    .prefixed_key(&ethereum_ledger_key(*account_id)),
+            );
         }
         Box::new(
             pipe.query_async(self.connection.clone())
@@ -170,13 +179,16 @@ impl EthereumStore for EthereumLedgerRedisStore {
             } else {
                 vec![]
             };
-            let acc_id = ethereum_ledger_key(account_id);
+            let acc_id = self
+                .redis_store
+                .prefixed_key(&ethereum_ledger_key(account_id));
             let addrs = &[
                 ("own_address", d.own_address.to_vec()),
                 ("token_address", token_address),
             ];
             pipe.hset_multiple(acc_id, addrs).ignore();
-            pipe.set(addrs_to_key(d), account_id).ignore();
+            pipe.set(self.redis_store.prefixed_key(&addrs_to_key(d)), account_id)
+                .ignore();
         }
         Box::new(
             pipe.query_async(self.connection.clone())
@@ -190,8 +202,11 @@ impl EthereumStore for EthereumLedgerRedisStore {
         block: U256,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
         let mut pipe = redis::pipe();
-        pipe.set(RECENTLY_OBSERVED_BLOCK_KEY, block.low_u64())
-            .ignore();
+        pipe.set(
+            self.redis_store.prefixed_key(RECENTLY_OBSERVED_BLOCK_KEY),
+            block.low_u64(),
+        )
+        .ignore();
         Box::new(
             pipe.query_async(self.connection.clone())
                 .map_err(move |err| {
@@ -203,7 +218,7 @@ impl EthereumStore for EthereumLedgerRedisStore {
 
     fn load_recently_observed_block(&self) -> Box<dyn Future<Item = U256, Error = ()> + Send> {
         let mut pipe = redis::pipe();
-        pipe.get(RECENTLY_OBSERVED_BLOCK_KEY);
+        pipe.get(self.redis_store.prefixed_key(RECENTLY_OBSERVED_BLOCK_KEY));
         Box::new(
             pipe.query_async(self.connection.clone())
                 .map_err(move |err| error!("Error loading last observed block: {:?}", err))
@@ -223,7 +238,7 @@ impl EthereumStore for EthereumLedgerRedisStore {
         eth_address: EthereumAddresses,
     ) -> Box<dyn Future<Item = <Self::Account as AccountTrait>::AccountId, Error = ()> + Send> {
         let mut pipe = redis::pipe();
-        pipe.get(addrs_to_key(eth_address));
+        pipe.get(self.redis_store.prefixed_key(&addrs_to_key(eth_address)));
         Box::new(
             pipe.query_async(self.connection.clone())
                 .map_err(move |err| error!("Error loading account data: {:?}", err))
@@ -237,7 +252,10 @@ impl EthereumStore for EthereumLedgerRedisStore {
     ) -> Box<dyn Future<Item = bool, Error = ()> + Send> {
         Box::new(
             cmd("EXISTS")
-                .arg(ethereum_transactions_key(tx_hash))
+                .arg(
+                    self.redis_store
+                        .prefixed_key(&ethereum_transactions_key(tx_hash)),
+                )
                 .query_async(self.connection.clone())
                 .map_err(move |err| error!("Error loading account data: {:?}", err))
                 .and_then(move |(_conn, ret): (_, bool)| Ok(ret)),
@@ -247,7 +265,10 @@ impl EthereumStore for EthereumLedgerRedisStore {
     fn mark_tx_processed(&self, tx_hash: H256) -> Box<dyn Future<Item = (), Error = ()> + Send> {
         Box::new(
             cmd("SETNX")
-                .arg(ethereum_transactions_key(tx_hash))
+                .arg(
+                    self.redis_store
+                        .prefixed_key(&ethereum_transactions_key(tx_hash)),
+                )
                 .arg(true)
                 .query_async(self.connection.clone())
                 .map_err(move |err| error!("Error loading account data: {:?}", err))
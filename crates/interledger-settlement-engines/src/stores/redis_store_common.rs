@@ -10,14 +10,30 @@ use log::{debug, error, trace};
 
 pub struct EngineRedisStoreBuilder {
     redis_uri: ConnectionInfo,
+    key_prefix: String,
 }
 
 impl EngineRedisStoreBuilder {
     pub fn new(redis_uri: ConnectionInfo) -> Self {
-        EngineRedisStoreBuilder { redis_uri }
+        EngineRedisStoreBuilder {
+            redis_uri,
+            key_prefix: String::new(),
+        }
+    }
+
+    /// Prefix for all of the keys the store uses, so that several engines can share one
+    /// Redis database. Keys are named `<prefix>:<key>`. Defaults to no prefix
+    pub fn key_prefix(&mut self, key_prefix: &str) -> &mut Self {
+        self.key_prefix = key_prefix.to_string();
+        self
     }
 
     pub fn connect(&self) -> impl Future<Item = EngineRedisStore, Error = ()> {
+        let key_prefix = if self.key_prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}:", self.key_prefix)
+        };
         result(Client::open(self.redis_uri.clone()))
             .map_err(|err| error!("Error creating Redis client: {:?}", err))
             .and_then(|client| {
@@ -26,7 +42,12 @@ impl EngineRedisStoreBuilder {
                     .get_shared_async_connection()
                     .map_err(|err| error!("Error connecting to Redis: {:?}", err))
             })
-            .and_then(move |connection| Ok(EngineRedisStore { connection }))
+            .and_then(move |connection| {
+                Ok(EngineRedisStore {
+                    connection,
+                    key_prefix,
+                })
+            })
     }
 }
 
@@ -37,6 +58,14 @@ impl EngineRedisStoreBuilder {
 #[derive(Clone)]
 pub struct EngineRedisStore {
     pub connection: SharedConnection,
+    key_prefix: String,
+}
+
+impl EngineRedisStore {
+    /// The name of the key with the store's prefix
+    pub fn prefixed_key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
 }
 
 impl IdempotentEngineStore for EngineRedisStore {
@@ -47,7 +76,7 @@ impl IdempotentEngineStore for EngineRedisStore {
         let idempotency_key_clone = idempotency_key.clone();
         Box::new(
             cmd("HGETALL")
-                .arg(idempotency_key.clone())
+                .arg(self.prefixed_key(&idempotency_key))
                 .query_async(self.connection.clone())
                 .map_err(move |err| {
                     error!(
@@ -88,7 +117,7 @@ impl IdempotentEngineStore for EngineRedisStore {
         let mut pipe = redis::pipe();
         pipe.atomic()
             .cmd("HMSET") // cannot use hset_multiple since data and status_code have different types
-            .arg(&idempotency_key)
+            .arg(self.prefixed_key(&idempotency_key))
             .arg("status_code")
             .arg(status_code.as_u16())
             .arg("data")
@@ -96,7 +125,7 @@ impl IdempotentEngineStore for EngineRedisStore {
             .arg("input_hash")
             .arg(&input_hash)
             .ignore()
-            .expire(&idempotency_key, 86400)
+            .expire(self.prefixed_key(&idempotency_key), 86400)
             .ignore();
         Box::new(
             pipe.query_async(self.connection.clone())
@@ -153,4 +182,35 @@ mod tests {
         }))
         .unwrap_err() // the second idempotent load fails
     }
+
+    #[test]
+    fn separates_idempotency_keys_by_prefix() {
+        block_on(test_store().and_then(|(store, context)| {
+            let input_hash: [u8; 32] = Default::default();
+            EngineRedisStoreBuilder::new(context.get_client_connection_info())
+                .key_prefix("other")
+                .connect()
+                .and_then(move |other| {
+                    other
+                        .save_idempotent_data(
+                            IDEMPOTENCY_KEY.clone(),
+                            input_hash,
+                            StatusCode::OK,
+                            Bytes::from("TEST"),
+                        )
+                        .and_then(move |_| other.load_idempotent_data(IDEMPOTENCY_KEY.clone()))
+                })
+                .and_then(move |data| {
+                    assert_eq!(data, (StatusCode::OK, Bytes::from("TEST"), input_hash));
+                    store
+                        .load_idempotent_data(IDEMPOTENCY_KEY.clone())
+                        .then(move |result| {
+                            assert!(result.is_err());
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap()
+    }
 }
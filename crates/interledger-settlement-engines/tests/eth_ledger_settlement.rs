@@ -4,7 +4,7 @@ use env_logger;
 use futures::{Future, Stream};
 use interledger::{
     cli,
-    node::{AccountDetails, InterledgerNode},
+    node::{AccountDetails, InterledgerNode, StoreBackend},
 };
 use interledger_packet::Address;
 use serde_json::json;
@@ -72,20 +72,37 @@ fn eth_ledger_settlement() {
 
     let node1_secret = cli::random_secret();
     let node1 = InterledgerNode {
-        ilp_address: Address::from_str("example.alice").unwrap(),
+        ilp_address: Some(Address::from_str("example.alice").unwrap()),
         default_spsp_account: Some(0),
+        spsp_domain: None,
         admin_auth_token: "hi_alice".to_string(),
+        store: StoreBackend::Redis,
         redis_connection: connection_info1.clone(),
+        redis_key_prefix: None,
+        redis_sentinels: Vec::new(),
+        redis_sentinel_master: None,
+        redis_cluster: false,
+        database_url: None,
+        journal_max_entries: None,
         btp_address: ([127, 0, 0, 1], get_open_port(None)).into(),
+        forwarding_url: None,
+        forwarding_address: ([127, 0, 0, 1], get_open_port(None)).into(),
         http_address: ([127, 0, 0, 1], node1_http).into(),
         settlement_address: ([127, 0, 0, 1], node1_settlement).into(),
         secret_seed: node1_secret,
+        previous_secret_seeds: Vec::new(),
         route_broadcast_interval: Some(200),
+        route_expiry_time: None,
+        route_hold_down_time: None,
+        weighted_route_scoring: false,
+        route_failover_attempts: None,
+        route_load_balancing: false,
     };
     let node1_clone = node1.clone();
     runtime.spawn(
         run_ethereum_engine(
             connection_info1,
+            None,
             "http://localhost:8545".to_string(),
             node1_engine,
             &node1_secret,
@@ -103,6 +120,7 @@ fn eth_ledger_settlement() {
             node1_clone
                 .insert_account(AccountDetails {
                     ilp_address: Address::from_str("example.alice").unwrap(),
+                    username: None,
                     asset_code: "ETH".to_string(),
                     asset_scale: 18,
                     btp_incoming_token: None,
@@ -117,6 +135,14 @@ fn eth_ledger_settlement() {
                     send_routes: false,
                     receive_routes: false,
                     routing_relation: None,
+                    allowed_route_prefixes: None,
+                    route_import_deny: None,
+                    route_import_max_path_length: None,
+                    route_export_allow: None,
+                    route_export_deny: None,
+                    route_export_max_path_length: None,
+                    advertise_parent_routes: None,
+                    route_cost: None,
                     round_trip_time: None,
                     packets_per_minute_limit: None,
                     amount_per_minute_limit: None,
@@ -126,6 +152,7 @@ fn eth_ledger_settlement() {
                 .and_then(move |_| {
                     node1_clone.insert_account(AccountDetails {
                         ilp_address: Address::from_str("example.bob").unwrap(),
+                        username: None,
                         asset_code: "ETH".to_string(),
                         asset_scale: 18,
                         btp_incoming_token: None,
@@ -140,6 +167,14 @@ fn eth_ledger_settlement() {
                         send_routes: false,
                         receive_routes: false,
                         routing_relation: None,
+                        allowed_route_prefixes: None,
+                        route_import_deny: None,
+                        route_import_max_path_length: None,
+                        route_export_allow: None,
+                        route_export_deny: None,
+                        route_export_max_path_length: None,
+                        advertise_parent_routes: None,
+                        route_cost: None,
                         round_trip_time: None,
                         packets_per_minute_limit: None,
                         amount_per_minute_limit: None,
@@ -153,19 +188,36 @@ fn eth_ledger_settlement() {
 
     let node2_secret = cli::random_secret();
     let node2 = InterledgerNode {
-        ilp_address: Address::from_str("example.bob").unwrap(),
+        ilp_address: Some(Address::from_str("example.bob").unwrap()),
         default_spsp_account: Some(0),
+        spsp_domain: None,
         admin_auth_token: "admin".to_string(),
+        store: StoreBackend::Redis,
         redis_connection: connection_info2.clone(),
+        redis_key_prefix: None,
+        redis_sentinels: Vec::new(),
+        redis_sentinel_master: None,
+        redis_cluster: false,
+        database_url: None,
+        journal_max_entries: None,
         btp_address: ([127, 0, 0, 1], get_open_port(None)).into(),
+        forwarding_url: None,
+        forwarding_address: ([127, 0, 0, 1], get_open_port(None)).into(),
         http_address: ([127, 0, 0, 1], node2_http).into(),
         settlement_address: ([127, 0, 0, 1], node2_settlement).into(),
         secret_seed: node2_secret,
+        previous_secret_seeds: Vec::new(),
         route_broadcast_interval: Some(200),
+        route_expiry_time: None,
+        route_hold_down_time: None,
+        weighted_route_scoring: false,
+        route_failover_attempts: None,
+        route_load_balancing: false,
     };
     runtime.spawn(
         run_ethereum_engine(
             connection_info2,
+            None,
             "http://localhost:8545".to_string(),
             node2_engine,
             &node2_secret,
@@ -182,6 +234,7 @@ fn eth_ledger_settlement() {
             node2
                 .insert_account(AccountDetails {
                     ilp_address: Address::from_str("example.bob").unwrap(),
+                    username: None,
                     asset_code: "ETH".to_string(),
                     asset_scale: 18,
                     btp_incoming_token: None,
@@ -196,6 +249,14 @@ fn eth_ledger_settlement() {
                     send_routes: false,
                     receive_routes: false,
                     routing_relation: None,
+                    allowed_route_prefixes: None,
+                    route_import_deny: None,
+                    route_import_max_path_length: None,
+                    route_export_allow: None,
+                    route_export_deny: None,
+                    route_export_max_path_length: None,
+                    advertise_parent_routes: None,
+                    route_cost: None,
                     round_trip_time: None,
                     packets_per_minute_limit: None,
                     amount_per_minute_limit: None,
@@ -206,6 +267,7 @@ fn eth_ledger_settlement() {
                     node2
                         .insert_account(AccountDetails {
                             ilp_address: Address::from_str("example.alice").unwrap(),
+                            username: None,
                             asset_code: "ETH".to_string(),
                             asset_scale: 18,
                             btp_incoming_token: None,
@@ -220,6 +282,14 @@ fn eth_ledger_settlement() {
                             send_routes: false,
                             receive_routes: false,
                             routing_relation: None,
+                            allowed_route_prefixes: None,
+                            route_import_deny: None,
+                            route_import_max_path_length: None,
+                            route_export_allow: None,
+                            route_export_deny: None,
+                            route_export_max_path_length: None,
+                            advertise_parent_routes: None,
+                            route_cost: None,
                             round_trip_time: None,
                             packets_per_minute_limit: None,
                             amount_per_minute_limit: None,
@@ -16,7 +16,24 @@ pub fn query(server: &str) -> impl Future<Item = SpspResponse, Error = Error> {
         .get(&server)
         .header("Accept", "application/spsp4+json")
         .send()
-        .map_err(|err| Error::HttpError(format!("Error querying SPSP receiver: {:?}", err)))
+        .map_err(|err| {
+            if err.is_timeout() {
+                Error::QueryTimeout(format!("{:?}", err))
+            } else {
+                Error::HttpError(format!("Error querying SPSP receiver: {:?}", err))
+            }
+        })
+        .and_then(|res| {
+            let status = res.status();
+            if status.is_success() {
+                Ok(res)
+            } else {
+                Err(Error::QueryError {
+                    status: status.as_u16(),
+                    message: status.canonical_reason().unwrap_or_default().to_string(),
+                })
+            }
+        })
         .and_then(|mut res| {
             res.json::<SpspResponse>()
                 .map_err(|err| Error::InvalidResponseError(format!("{:?}", err)))
@@ -47,16 +64,16 @@ where
             debug!("Sending SPSP payment to address: {}", addr);
 
             send_money(service, &from_account, addr, &shared_secret, source_amount)
-                .map(move |(amount_delivered, _plugin)| {
+                .map(move |(delivery, _plugin)| {
                     debug!(
                         "Sent SPSP payment of {} and delivered {} of the receiver's units",
-                        source_amount, amount_delivered
+                        source_amount, delivery.delivered_amount
                     );
-                    amount_delivered
+                    delivery.delivered_amount
                 })
                 .map_err(move |err| {
                     error!("Error sending payment: {:?}", err);
-                    Error::SendMoneyError(source_amount)
+                    Error::from(err)
                 })
         })
     })
@@ -20,18 +20,27 @@ pub use server::SpspResponder;
 pub enum Error {
     #[fail(display = "Unable to query SPSP server: {:?}", _0)]
     HttpError(String),
+    #[fail(display = "SPSP query failed with status {}: {}", status, message)]
+    QueryError { status: u16, message: String },
+    #[fail(display = "Timed out querying SPSP server: {}", _0)]
+    QueryTimeout(String),
     #[fail(display = "Got invalid SPSP response from server: {:?}", _0)]
     InvalidResponseError(String),
+    /// Sending the payment failed, see the STREAM error for why and how much was delivered
     #[fail(display = "STREAM error: {}", _0)]
-    StreamError(StreamError),
-    #[fail(display = "Error sending money: {}", _0)]
-    SendMoneyError(u64),
+    StreamError(#[cause] StreamError),
     #[fail(display = "Error listening: {}", _0)]
     ListenError(String),
     #[fail(display = "Invalid Payment Pointer: {}", _0)]
     InvalidPaymentPointerError(String),
 }
 
+impl From<StreamError> for Error {
+    fn from(error: StreamError) -> Self {
+        Error::StreamError(error)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SpspResponse {
     destination_account: Address,
@@ -0,0 +1,259 @@
+//! # interledger-store-crypto
+//!
+//! Key derivation and token encryption shared by the Redis and SQL stores.
+//!
+//! Each store derives its keys from its secret and its own name, so stores given
+//! the same secret still use different keys.
+
+use bytes::Bytes;
+use log::error;
+use ring::{
+    aead, digest, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::iter;
+
+const NONCE_LENGTH: usize = 12;
+/// Length of the ID that is prepended to the tokens each version of the keys encrypts
+/// and to the index entries it writes
+const KEY_ID_LENGTH: usize = 4;
+
+/// The keys derived from one version of a store's secret
+pub struct StoreKeys {
+    id: [u8; KEY_ID_LENGTH],
+    hmac_key: hmac::SigningKey,
+    encryption_key: aead::SealingKey,
+    decryption_key: aead::OpeningKey,
+}
+
+impl StoreKeys {
+    /// Derive the keys of the store with the given name (for example, "redis") from its secret
+    pub fn new(store_name: &str, server_secret: &[u8]) -> Self {
+        let generation_key = hmac::SigningKey::new(&digest::SHA256, server_secret);
+        let derive = |purpose: &str| {
+            hmac::sign(
+                &generation_key,
+                format!("ilp_store_{}_{}", store_name, purpose).as_bytes(),
+            )
+        };
+        let mut id = [0; KEY_ID_LENGTH];
+        id.copy_from_slice(&derive("key_id").as_ref()[..KEY_ID_LENGTH]);
+        let hmac_key = hmac::SigningKey::new(&digest::SHA256, derive("hmac_key").as_ref());
+        let encryption_key =
+            aead::SealingKey::new(&aead::AES_256_GCM, derive("encryption_key").as_ref()).unwrap();
+        let decryption_key =
+            aead::OpeningKey::new(&aead::AES_256_GCM, derive("encryption_key").as_ref()).unwrap();
+        StoreKeys {
+            id,
+            hmac_key,
+            encryption_key,
+            decryption_key,
+        }
+    }
+
+    /// The HMAC of the token, which stores keep instead of incoming tokens
+    pub fn hmac(&self, token: &[u8]) -> hmac::Signature {
+        hmac::sign(&self.hmac_key, token)
+    }
+
+    /// Encrypt the token with a random nonce, which is appended to it
+    pub fn encrypt(&self, token: &[u8]) -> Bytes {
+        let mut token = token.to_vec();
+        token.extend_from_slice(&[0u8; 16]);
+
+        let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("Unable to get sufficient entropy for nonce");
+        let nonce_copy = nonce;
+        let nonce = aead::Nonce::assume_unique_for_key(nonce);
+        if let Ok(out_len) = aead::seal_in_place(
+            &self.encryption_key,
+            nonce,
+            aead::Aad::from(&[]),
+            &mut token,
+            self.encryption_key.algorithm().tag_len(),
+        ) {
+            token.truncate(out_len);
+            token.append(&mut nonce_copy.as_ref().to_vec());
+            Bytes::from(token)
+        } else {
+            panic!("Unable to encrypt token");
+        }
+    }
+
+    /// Decrypt a token encrypted with these keys
+    pub fn decrypt(&self, encrypted: &[u8]) -> Option<Bytes> {
+        if encrypted.len() < aead::MAX_TAG_LEN {
+            error!("Cannot decrypt token, encrypted value does not have a nonce attached");
+            return None;
+        }
+
+        let mut encrypted = encrypted.to_vec();
+        let nonce_bytes = encrypted.split_off(encrypted.len() - NONCE_LENGTH);
+        let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
+        nonce.copy_from_slice(nonce_bytes.as_ref());
+        let nonce = aead::Nonce::assume_unique_for_key(nonce);
+
+        aead::open_in_place(
+            &self.decryption_key,
+            nonce,
+            aead::Aad::empty(),
+            0,
+            &mut encrypted,
+        )
+        .ok()
+        .map(|token| Bytes::from(token.to_vec()))
+    }
+
+    /// The field these keys index an incoming token under: the key ID followed
+    /// by the HMAC of the token
+    fn index_field(&self, token: &[u8]) -> Vec<u8> {
+        let mut field = self.id.to_vec();
+        field.extend_from_slice(self.hmac(token).as_ref());
+        field
+    }
+}
+
+/// The keys derived from the current store secret, which are used for everything the
+/// store writes, and the keys derived from the secrets it was rotated away from.
+/// The old keys are only used to read what was written with them until it is migrated.
+///
+/// Tokens and index entries written before keys were versioned have no key ID,
+/// so every key is tried on them.
+pub struct KeyRing {
+    current: StoreKeys,
+    previous: Vec<StoreKeys>,
+}
+
+impl KeyRing {
+    pub fn new(store_name: &str, server_secret: &[u8], previous_secrets: &[[u8; 32]]) -> Self {
+        KeyRing {
+            current: StoreKeys::new(store_name, server_secret),
+            previous: previous_secrets
+                .iter()
+                .map(|secret| StoreKeys::new(store_name, &secret[..]))
+                .collect(),
+        }
+    }
+
+    /// The current keys first
+    fn all(&self) -> impl Iterator<Item = &StoreKeys> {
+        iter::once(&self.current).chain(self.previous.iter())
+    }
+
+    /// Encrypt the token with the current key and prefix it with the key's ID
+    pub fn encrypt(&self, token: &[u8]) -> Bytes {
+        let mut encrypted = self.current.id.to_vec();
+        encrypted.extend_from_slice(&self.current.encrypt(token));
+        Bytes::from(encrypted)
+    }
+
+    /// Decrypt a token encrypted with any of the known keys. The flag is true if it
+    /// was not encrypted with the current key, so it should be re-encrypted
+    pub fn decrypt(&self, encrypted: &[u8]) -> Option<(Bytes, bool)> {
+        if encrypted.len() > KEY_ID_LENGTH {
+            let (id, rest) = encrypted.split_at(KEY_ID_LENGTH);
+            if let Some(keys) = self.all().find(|keys| keys.id == id) {
+                if let Some(token) = keys.decrypt(rest) {
+                    return Some((token, keys.id != self.current.id));
+                }
+            }
+        }
+        let token = self.all().filter_map(|keys| keys.decrypt(encrypted)).next();
+        if token.is_none() {
+            error!("Unable to decrypt token with any of the known keys");
+        }
+        token.map(|token| (token, true))
+    }
+
+    /// The field the current keys index an incoming token under: the key ID followed
+    /// by the HMAC of the token
+    pub fn index_field(&self, token: &[u8]) -> Vec<u8> {
+        self.current.index_field(token)
+    }
+
+    /// Every field an incoming token may be indexed under, starting with the current one
+    pub fn index_fields(&self, token: &[u8]) -> Vec<Vec<u8>> {
+        let mut fields: Vec<Vec<u8>> = self.all().map(|keys| keys.index_field(token)).collect();
+        fields.extend(self.all().map(|keys| keys.hmac(token).as_ref().to_vec()));
+        fields
+    }
+
+    /// Whether the index field was written with the current keys
+    pub fn is_current_index_field(&self, field: &[u8]) -> bool {
+        field.len() > KEY_ID_LENGTH && field[..KEY_ID_LENGTH] == self.current.id
+    }
+}
+
+#[cfg(test)]
+mod encryption {
+    use super::*;
+    use std::str;
+
+    #[test]
+    fn encrypts_and_decrypts() {
+        let keys = KeyRing::new("test", &[9; 32], &[]);
+        let encrypted = keys.encrypt(b"test test");
+        let (decrypted, stale) = keys.decrypt(encrypted.as_ref()).unwrap();
+        assert_eq!(str::from_utf8(decrypted.as_ref()).unwrap(), "test test");
+        assert!(!stale);
+    }
+
+    #[test]
+    fn derives_different_keys_for_each_store() {
+        let redis_keys = StoreKeys::new("redis", &[9; 32]);
+        let sql_keys = StoreKeys::new("sql", &[9; 32]);
+        assert_ne!(
+            redis_keys.hmac(b"token").as_ref(),
+            sql_keys.hmac(b"token").as_ref()
+        );
+        assert!(sql_keys.decrypt(&redis_keys.encrypt(b"token")).is_none());
+
+        // The keys must stay the same as the ones the stores derived before, or
+        // they would no longer find their tokens
+        let generation_key = hmac::SigningKey::new(&digest::SHA256, &[9; 32]);
+        let hmac_key = hmac::SigningKey::new(
+            &digest::SHA256,
+            hmac::sign(&generation_key, b"ilp_store_sql_hmac_key").as_ref(),
+        );
+        assert_eq!(
+            sql_keys.hmac(b"token").as_ref(),
+            hmac::sign(&hmac_key, b"token").as_ref()
+        );
+    }
+
+    #[test]
+    fn decrypts_with_previous_keys() {
+        let old_keys = KeyRing::new("test", &[1; 32], &[]);
+        let encrypted = old_keys.encrypt(b"test test");
+        let keys = KeyRing::new("test", &[2; 32], &[[1; 32]]);
+        let (decrypted, stale) = keys.decrypt(encrypted.as_ref()).unwrap();
+        assert_eq!(str::from_utf8(decrypted.as_ref()).unwrap(), "test test");
+        assert!(stale);
+        assert!(KeyRing::new("test", &[2; 32], &[])
+            .decrypt(encrypted.as_ref())
+            .is_none());
+    }
+
+    #[test]
+    fn decrypts_tokens_without_key_id() {
+        let encrypted = StoreKeys::new("test", &[1; 32]).encrypt(b"test test");
+        let keys = KeyRing::new("test", &[2; 32], &[[1; 32]]);
+        let (decrypted, stale) = keys.decrypt(encrypted.as_ref()).unwrap();
+        assert_eq!(str::from_utf8(decrypted.as_ref()).unwrap(), "test test");
+        assert!(stale);
+    }
+
+    #[test]
+    fn looks_up_index_fields_from_all_keys() {
+        let old_keys = KeyRing::new("test", &[1; 32], &[]);
+        let keys = KeyRing::new("test", &[2; 32], &[[1; 32]]);
+        let fields = keys.index_fields(b"token");
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[0], keys.index_field(b"token"));
+        assert!(fields.contains(&old_keys.index_field(b"token")));
+        assert!(keys.is_current_index_field(&fields[0]));
+        assert!(!keys.is_current_index_field(&old_keys.index_field(b"token")));
+    }
+}
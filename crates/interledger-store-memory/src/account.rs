@@ -1,11 +1,22 @@
 use bytes::Bytes;
+use interledger_api::{AccountDetails as NodeAccountDetails, ExportableAccount};
 use interledger_btp::BtpAccount;
+use interledger_ccp::{CcpRoutingAccount, RoutePolicy, RoutingRelation};
 use interledger_http::HttpAccount;
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::Address;
 use interledger_service::Account as AccountTrait;
-use interledger_service_util::MaxPacketAmountAccount;
-use std::{fmt, str, sync::Arc};
+use interledger_service_util::{
+    MaxPacketAmountAccount, RateLimitAccount, RoundTripTimeAccount, DEFAULT_ROUND_TRIP_TIME,
+};
+use interledger_settlement::{SettlementAccount, SettlementEngineDetails};
+use log::error;
+use serde::{Serialize, Serializer};
+use std::{
+    fmt,
+    str::{self, FromStr},
+    sync::Arc,
+};
 use url::Url;
 
 /// A helper to create Accounts.
@@ -19,15 +30,31 @@ impl AccountBuilder {
             ilp_address,
             max_packet_amount: u64::max_value(),
             id: 0,
+            username: None,
             additional_routes: Vec::new(),
             asset_code: String::new(),
             asset_scale: 0,
+            min_balance: None,
             http_incoming_token: None,
             http_outgoing_token: None,
             http_endpoint: None,
             btp_uri: None,
             btp_incoming_token: None,
             btp_outgoing_token: None,
+            settle_threshold: None,
+            settle_to: None,
+            routing_relation: RoutingRelation::Child,
+            send_routes: false,
+            receive_routes: false,
+            route_import_policy: RoutePolicy::default(),
+            route_export_policy: RoutePolicy::default(),
+            advertise_parent_routes: true,
+            route_cost: 0,
+            round_trip_time: DEFAULT_ROUND_TRIP_TIME,
+            packets_per_minute_limit: None,
+            amount_per_minute_limit: None,
+            settlement_engine_url: None,
+            settlement_engine_asset_scale: None,
         };
         AccountBuilder { details }
     }
@@ -46,6 +73,11 @@ impl AccountBuilder {
         self
     }
 
+    pub fn username(mut self, username: String) -> Self {
+        self.details.username = Some(username.to_lowercase());
+        self
+    }
+
     pub fn additional_routes(mut self, routes: &[&[u8]]) -> Self {
         self.details.additional_routes = routes.iter().map(|route| Bytes::from(*route)).collect();
         self
@@ -61,6 +93,11 @@ impl AccountBuilder {
         self
     }
 
+    pub fn min_balance(mut self, min_balance: i64) -> Self {
+        self.details.min_balance = Some(min_balance);
+        self
+    }
+
     pub fn http_endpoint(mut self, http_endpoint: Url) -> Self {
         self.details.http_endpoint = Some(http_endpoint);
         self
@@ -95,22 +132,136 @@ impl AccountBuilder {
         self.details.max_packet_amount = amount;
         self
     }
+
+    /// Settle once the balance goes over the `threshold`, bringing it back down to `settle_to`
+    pub fn settlement(mut self, threshold: i64, settle_to: i64) -> Self {
+        self.details.settle_threshold = Some(threshold);
+        self.details.settle_to = Some(settle_to);
+        self
+    }
+
+    pub fn settlement_engine(mut self, url: Url, asset_scale: u8) -> Self {
+        self.details.settlement_engine_url = Some(url);
+        self.details.settlement_engine_asset_scale = Some(asset_scale);
+        self
+    }
+
+    pub fn routing_relation(mut self, relation: RoutingRelation) -> Self {
+        self.details.routing_relation = relation;
+        self
+    }
+
+    pub fn send_routes(mut self, send_routes: bool) -> Self {
+        self.details.send_routes = send_routes;
+        self
+    }
+
+    pub fn receive_routes(mut self, receive_routes: bool) -> Self {
+        self.details.receive_routes = receive_routes;
+        self
+    }
+
+    pub fn route_import_policy(mut self, policy: RoutePolicy) -> Self {
+        self.details.route_import_policy = policy;
+        self
+    }
+
+    pub fn route_export_policy(mut self, policy: RoutePolicy) -> Self {
+        self.details.route_export_policy = policy;
+        self
+    }
+
+    pub fn route_cost(mut self, cost: u32) -> Self {
+        self.details.route_cost = cost;
+        self
+    }
+
+    pub fn round_trip_time(mut self, round_trip_time: u64) -> Self {
+        self.details.round_trip_time = round_trip_time;
+        self
+    }
+
+    pub fn packets_per_minute_limit(mut self, limit: u32) -> Self {
+        self.details.packets_per_minute_limit = Some(limit);
+        self
+    }
+
+    pub fn amount_per_minute_limit(mut self, limit: u64) -> Self {
+        self.details.amount_per_minute_limit = Some(limit);
+        self
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub(crate) struct AccountDetails {
     pub(crate) id: u64,
+    #[serde(serialize_with = "address_to_string")]
     pub(crate) ilp_address: Address,
+    pub(crate) username: Option<String>,
+    #[serde(skip)]
     pub(crate) additional_routes: Vec<Bytes>,
     pub(crate) asset_code: String,
     pub(crate) asset_scale: u8,
+    pub(crate) max_packet_amount: u64,
+    pub(crate) min_balance: Option<i64>,
+    #[serde(serialize_with = "optional_url_to_string")]
     pub(crate) http_endpoint: Option<Url>,
+    #[serde(skip)]
     pub(crate) http_incoming_token: Option<String>,
     pub(crate) http_outgoing_token: Option<String>,
+    #[serde(serialize_with = "optional_url_to_string")]
     pub(crate) btp_uri: Option<Url>,
     pub(crate) btp_outgoing_token: Option<String>,
+    #[serde(skip)]
     pub(crate) btp_incoming_token: Option<String>,
-    pub(crate) max_packet_amount: u64,
+    pub(crate) settle_threshold: Option<i64>,
+    pub(crate) settle_to: Option<i64>,
+    #[serde(serialize_with = "routing_relation_to_string")]
+    pub(crate) routing_relation: RoutingRelation,
+    pub(crate) send_routes: bool,
+    pub(crate) receive_routes: bool,
+    #[serde(skip)]
+    pub(crate) route_import_policy: RoutePolicy,
+    #[serde(skip)]
+    pub(crate) route_export_policy: RoutePolicy,
+    pub(crate) advertise_parent_routes: bool,
+    pub(crate) route_cost: u32,
+    pub(crate) round_trip_time: u64,
+    pub(crate) packets_per_minute_limit: Option<u32>,
+    pub(crate) amount_per_minute_limit: Option<u64>,
+    #[serde(serialize_with = "optional_url_to_string")]
+    pub(crate) settlement_engine_url: Option<Url>,
+    pub(crate) settlement_engine_asset_scale: Option<u8>,
+}
+
+fn address_to_string<S>(address: &Address, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(str::from_utf8(address.as_ref()).unwrap_or(""))
+}
+
+fn optional_url_to_string<S>(url: &Option<Url>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if let Some(ref url) = url {
+        serializer.serialize_str(url.as_ref())
+    } else {
+        serializer.serialize_none()
+    }
+}
+
+// This needs to be pass by ref because serde expects this function to take a ref
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn routing_relation_to_string<S>(
+    relation: &RoutingRelation,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(relation.to_string().as_str())
 }
 
 impl AccountDetails {
@@ -128,6 +279,115 @@ pub struct Account {
     pub(crate) inner: Arc<AccountDetails>,
 }
 
+impl Account {
+    /// The account as the store keeps it, without the incoming tokens. The store only
+    /// keeps the HMACs of those, so they cannot be recovered
+    pub(crate) fn without_incoming_tokens(self) -> Account {
+        if self.inner.http_incoming_token.is_none() && self.inner.btp_incoming_token.is_none() {
+            return self;
+        }
+        let mut inner = (*self.inner).clone();
+        inner.http_incoming_token = None;
+        inner.btp_incoming_token = None;
+        Account {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Create an account from the details sent to the node's API, checking them the same way the RedisStore does
+    pub fn try_from(id: u64, details: NodeAccountDetails) -> Result<Account, ()> {
+        let http_endpoint = if let Some(ref url) = details.http_endpoint {
+            Some(Url::parse(url).map_err(|err| error!("Invalid URL: {:?}", err))?)
+        } else {
+            None
+        };
+        let (btp_uri, btp_outgoing_token) = if let Some(ref url) = details.btp_uri {
+            let mut btp_uri = Url::parse(url).map_err(|err| error!("Invalid URL: {:?}", err))?;
+            let btp_outgoing_token = btp_uri.password().map(|token| token.to_string());
+            btp_uri.set_password(None).unwrap();
+            (Some(btp_uri), btp_outgoing_token)
+        } else {
+            (None, None)
+        };
+        let routing_relation = if let Some(ref relation) = details.routing_relation {
+            RoutingRelation::from_str(relation)?
+        } else {
+            RoutingRelation::Child
+        };
+        let username = details.validated_username()?;
+        let route_import_policy = RoutePolicy {
+            allow: to_prefixes(details.allowed_route_prefixes)?,
+            deny: to_prefixes(details.route_import_deny)?.unwrap_or_default(),
+            max_path_length: details
+                .route_import_max_path_length
+                .map(|length| length as usize),
+        };
+        let route_export_policy = RoutePolicy {
+            allow: to_prefixes(details.route_export_allow)?,
+            deny: to_prefixes(details.route_export_deny)?.unwrap_or_default(),
+            max_path_length: details
+                .route_export_max_path_length
+                .map(|length| length as usize),
+        };
+        Ok(AccountDetails {
+            id,
+            ilp_address: details.ilp_address,
+            username,
+            additional_routes: Vec::new(),
+            asset_code: details.asset_code.to_uppercase(),
+            asset_scale: details.asset_scale,
+            max_packet_amount: details.max_packet_amount,
+            min_balance: details.min_balance,
+            http_endpoint,
+            http_incoming_token: details.http_incoming_token,
+            http_outgoing_token: details.http_outgoing_token,
+            btp_uri,
+            btp_outgoing_token,
+            btp_incoming_token: details.btp_incoming_token,
+            settle_threshold: details.settle_threshold,
+            settle_to: details.settle_to,
+            routing_relation,
+            send_routes: details.send_routes,
+            receive_routes: details.receive_routes,
+            route_import_policy,
+            route_export_policy,
+            advertise_parent_routes: details.advertise_parent_routes.unwrap_or(true),
+            route_cost: details.route_cost.unwrap_or(0),
+            round_trip_time: details.round_trip_time.unwrap_or(DEFAULT_ROUND_TRIP_TIME),
+            packets_per_minute_limit: details.packets_per_minute_limit,
+            amount_per_minute_limit: details.amount_per_minute_limit,
+            settlement_engine_url: details
+                .settlement_engine_url
+                .and_then(|url| Url::parse(&url).ok()),
+            settlement_engine_asset_scale: details.settlement_engine_asset_scale,
+        }
+        .build())
+    }
+
+    /// The details the account could be created from again, for exporting it. Unlike
+    /// the other stores, this one still has the incoming tokens so they are included
+    pub(crate) fn to_node_details(&self) -> NodeAccountDetails {
+        let mut details = NodeAccountDetails::from_account(self);
+        details.http_incoming_token = self.inner.http_incoming_token.clone();
+        details.btp_incoming_token = self.inner.btp_incoming_token.clone();
+        details
+    }
+}
+
+fn to_prefixes(prefixes: Option<Vec<String>>) -> Result<Option<Vec<Bytes>>, ()> {
+    if let Some(prefixes) = prefixes {
+        let mut parsed = Vec::with_capacity(prefixes.len());
+        for prefix in prefixes {
+            Address::from_str(&prefix)
+                .map_err(|err| error!("Invalid route prefix {}: {:?}", prefix, err))?;
+            parsed.push(Bytes::from(prefix));
+        }
+        Ok(Some(parsed))
+    } else {
+        Ok(None)
+    }
+}
+
 impl fmt::Debug for Account {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -138,6 +398,15 @@ impl fmt::Debug for Account {
     }
 }
 
+impl Serialize for Account {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.inner.serialize(serializer)
+    }
+}
+
 impl AccountTrait for Account {
     type AccountId = u64;
 
@@ -190,6 +459,97 @@ impl BtpAccount for Account {
     }
 }
 
+impl CcpRoutingAccount for Account {
+    fn routing_relation(&self) -> RoutingRelation {
+        self.inner.routing_relation
+    }
+
+    fn should_send_routes(&self) -> bool {
+        self.inner.send_routes
+    }
+
+    fn should_receive_routes(&self) -> bool {
+        self.inner.receive_routes
+    }
+
+    fn allowed_route_prefixes(&self) -> Option<Vec<Bytes>> {
+        self.inner.route_import_policy.allow.clone()
+    }
+
+    fn route_import_policy(&self) -> RoutePolicy {
+        self.inner.route_import_policy.clone()
+    }
+
+    fn route_export_policy(&self) -> RoutePolicy {
+        self.inner.route_export_policy.clone()
+    }
+
+    fn should_advertise_parent_routes(&self) -> bool {
+        self.inner.advertise_parent_routes
+    }
+
+    fn route_cost(&self) -> u32 {
+        self.inner.route_cost
+    }
+}
+
+impl RoundTripTimeAccount for Account {
+    fn round_trip_time(&self) -> u64 {
+        self.inner.round_trip_time
+    }
+}
+
+impl RateLimitAccount for Account {
+    fn amount_per_minute_limit(&self) -> Option<u64> {
+        self.inner.amount_per_minute_limit
+    }
+
+    fn packets_per_minute_limit(&self) -> Option<u32> {
+        self.inner.packets_per_minute_limit
+    }
+}
+
+impl ExportableAccount for Account {
+    fn username(&self) -> Option<&str> {
+        self.inner.username.as_ref().map(String::as_str)
+    }
+
+    fn min_balance(&self) -> Option<i64> {
+        self.inner.min_balance
+    }
+
+    fn settle_threshold(&self) -> Option<i64> {
+        self.inner.settle_threshold
+    }
+
+    fn settle_to(&self) -> Option<i64> {
+        self.inner.settle_to
+    }
+
+    fn settlement_engine_url(&self) -> Option<&Url> {
+        self.inner.settlement_engine_url.as_ref()
+    }
+
+    fn settlement_engine_asset_scale(&self) -> Option<u8> {
+        self.inner.settlement_engine_asset_scale
+    }
+}
+
+impl SettlementAccount for Account {
+    fn settlement_engine_details(&self) -> Option<SettlementEngineDetails> {
+        match (
+            &self.inner.settlement_engine_url,
+            self.inner.settlement_engine_asset_scale,
+        ) {
+            (Some(url), Some(asset_scale)) => Some(SettlementEngineDetails {
+                url: url.clone(),
+                asset_scale,
+            }),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -4,30 +4,117 @@ use futures::{
     future::{err, ok},
     Future,
 };
+use http::StatusCode;
+use interledger_api::{
+    AccountDetails, AccountState, IdempotentDataState, NodeState, NodeStateStore, NodeStore,
+};
 use interledger_btp::{BtpOpenSignupAccount, BtpOpenSignupStore, BtpStore};
-use interledger_http::HttpStore;
+use interledger_ccp::{RouteManagerStore, RoutingRelation};
+use interledger_http::{HttpForwardingStore, HttpStore};
 use interledger_ildcp::IldcpAccount;
-use interledger_router::RouterStore;
+use interledger_packet::Address;
+use interledger_router::{AlternateRoute, RouterStore};
 use interledger_service::{Account as AccountTrait, AccountStore};
+use interledger_service_util::{
+    journal_timestamp, BalanceStore, CellRateLimit, ExchangeRateStore, JournalEntry,
+    JournalEntryKind, RateLimitError, RateLimitStore,
+};
+use interledger_settlement::{IdempotentData, IdempotentStore, SettlementStore};
+use log::{debug, error, trace, warn};
 use parking_lot::{Mutex, RwLock};
-use std::collections::HashMap;
+use ring::{digest, hmac, rand::SystemRandom};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::{
     cmp::max,
     iter::{empty, once, FromIterator, IntoIterator},
     str,
     sync::Arc,
+    time::{Duration, Instant},
 };
+use url::Url;
+
+/// Idempotency keys are forgotten after 24 hours, like in the RedisStore
+const IDEMPOTENCY_KEY_EXPIRY: Duration = Duration::from_secs(86400);
+
+type RoutingTable<A> = HashMap<Bytes, A>;
+
+fn token_hmac(hmac_key: &hmac::SigningKey, token: &str) -> Bytes {
+    Bytes::from(hmac::sign(hmac_key, token.as_bytes()).as_ref())
+}
+
+/// The balance of an account, the amount it has paid us in advance
+/// and the journal of the latest changes to them
+#[derive(Clone, Debug, Default)]
+struct Balance {
+    balance: i64,
+    prepaid_amount: i64,
+    journal: VecDeque<JournalEntry>,
+    // How many of the oldest entries were dropped from the journal
+    trimmed_entries: u64,
+}
+
+impl Balance {
+    fn total(&self) -> i64 {
+        self.balance + self.prepaid_amount
+    }
+
+    /// Record a change to the balance, after it was applied, keeping at most
+    /// `max_entries` entries in the journal (0 keeps all of them)
+    fn record(
+        &mut self,
+        kind: JournalEntryKind,
+        reference: Option<&str>,
+        amount: i64,
+        max_entries: usize,
+    ) {
+        let entry = JournalEntry {
+            id: self.trimmed_entries + self.journal.len() as u64 + 1,
+            kind,
+            reference: reference.map(str::to_string),
+            amount,
+            balance: self.balance,
+            prepaid_amount: self.prepaid_amount,
+            timestamp: journal_timestamp(),
+        };
+        self.journal.push_back(entry);
+        while max_entries > 0 && self.journal.len() > max_entries {
+            self.journal.pop_front();
+            self.trimmed_entries += 1;
+        }
+    }
+}
 
 /// A simple in-memory store intended primarily for testing and
 /// stateless sender/receiver services that are passed all of the
 /// relevant account details when the store is instantiated.
+///
+/// It implements all of the same store traits as the RedisStore, so it can
+/// also be used to run a full node when the data does not need to be persisted.
 #[derive(Clone)]
 pub struct InMemoryStore {
     accounts: Arc<RwLock<HashMap<u64, Account>>>,
     routing_table: Arc<RwLock<HashMap<Bytes, u64>>>,
-    btp_auth: Arc<RwLock<HashMap<String, u64>>>,
-    http_auth: Arc<RwLock<HashMap<String, u64>>>,
+    static_routes: Arc<RwLock<HashMap<Bytes, u64>>>,
+    alternate_routes: Arc<RwLock<HashMap<Bytes, Vec<AlternateRoute<u64>>>>>,
+    // Like the RedisStore, only the HMACs of incoming tokens are kept. The key is
+    // random because nothing the store holds outlives it
+    hmac_key: Arc<hmac::SigningKey>,
+    btp_auth: Arc<RwLock<HashMap<Bytes, u64>>>,
+    http_auth: Arc<RwLock<HashMap<Bytes, u64>>>,
+    usernames: Arc<RwLock<HashMap<String, u64>>>,
+    // Every balance change holds this lock for the whole update, which makes
+    // them atomic in the same way as the RedisStore's Lua scripts
+    balances: Arc<Mutex<HashMap<u64, Balance>>>,
+    settlement_idempotency_keys: Arc<Mutex<HashMap<String, Instant>>>,
+    idempotent_data: Arc<RwLock<HashMap<String, (IdempotentData, Instant)>>>,
+    exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
+    // The theoretical arrival time of the next request for each rate limit
+    rate_limits: Arc<Mutex<HashMap<String, Instant>>>,
+    // Every insert holds this lock from the conflict check until the account is
+    // added, which makes it atomic like the RedisStore's transaction
     next_account_id: Arc<Mutex<u64>>,
+    connection_owners: Arc<RwLock<HashMap<u64, Url>>>,
+    journal_max_entries: usize,
 }
 
 impl InMemoryStore {
@@ -40,12 +127,15 @@ impl InMemoryStore {
     }
 
     pub fn from_accounts(accounts: impl IntoIterator<Item = Account>) -> Self {
+        let hmac_key = hmac::SigningKey::generate(&digest::SHA256, &SystemRandom::new())
+            .expect("Unable to generate HMAC key");
         let mut next_account_id: u64 = 0;
 
-        let accounts = HashMap::from_iter(accounts.into_iter().map(|account| {
-            next_account_id = max(account.id(), next_account_id);
-            (account.id(), account)
-        }));
+        let accounts: HashMap<u64, Account> =
+            HashMap::from_iter(accounts.into_iter().map(|account| {
+                next_account_id = max(account.id(), next_account_id);
+                (account.id(), account)
+            }));
         next_account_id += 1;
 
         let routing_table: HashMap<Bytes, u64> =
@@ -61,7 +151,7 @@ impl InMemoryStore {
 
         let btp_auth = HashMap::from_iter(accounts.iter().filter_map(|(account_id, account)| {
             if let Some(ref token) = account.inner.btp_incoming_token {
-                Some((token.to_string(), *account_id))
+                Some((token_hmac(&hmac_key, token), *account_id))
             } else {
                 None
             }
@@ -69,7 +159,20 @@ impl InMemoryStore {
 
         let http_auth = HashMap::from_iter(accounts.iter().filter_map(|(account_id, account)| {
             if let Some(ref auth) = account.inner.http_incoming_token {
-                Some((auth.to_string(), *account_id))
+                Some((token_hmac(&hmac_key, auth), *account_id))
+            } else {
+                None
+            }
+        }));
+        let accounts = HashMap::from_iter(
+            accounts
+                .into_iter()
+                .map(|(account_id, account)| (account_id, account.without_incoming_tokens())),
+        );
+
+        let usernames = HashMap::from_iter(accounts.iter().filter_map(|(account_id, account)| {
+            if let Some(ref username) = account.inner.username {
+                Some((username.to_string(), *account_id))
             } else {
                 None
             }
@@ -78,13 +181,48 @@ impl InMemoryStore {
         InMemoryStore {
             accounts: Arc::new(RwLock::new(accounts)),
             routing_table: Arc::new(RwLock::new(routing_table)),
+            static_routes: Arc::new(RwLock::new(HashMap::new())),
+            alternate_routes: Arc::new(RwLock::new(HashMap::new())),
+            hmac_key: Arc::new(hmac_key),
             btp_auth: Arc::new(RwLock::new(btp_auth)),
             http_auth: Arc::new(RwLock::new(http_auth)),
+            usernames: Arc::new(RwLock::new(usernames)),
+            balances: Arc::new(Mutex::new(HashMap::new())),
+            settlement_idempotency_keys: Arc::new(Mutex::new(HashMap::new())),
+            idempotent_data: Arc::new(RwLock::new(HashMap::new())),
+            exchange_rates: Arc::new(RwLock::new(HashMap::new())),
+            rate_limits: Arc::new(Mutex::new(HashMap::new())),
             next_account_id: Arc::new(Mutex::new(next_account_id)),
+            connection_owners: Arc::new(RwLock::new(HashMap::new())),
+            journal_max_entries: 0,
         }
     }
 
+    /// Only keep the latest `max_entries` entries of each account's balance journal in
+    /// memory. Defaults to 0, which keeps every entry
+    pub fn journal_max_entries(&mut self, max_entries: usize) -> &mut Self {
+        self.journal_max_entries = max_entries;
+        self
+    }
+
     pub fn add_account(&self, account: Account) {
+        let mut next_account_id = self.next_account_id.lock();
+        self.index_account(account, &mut next_account_id);
+    }
+
+    /// Add the account while the caller holds the lock on the next account ID
+    fn index_account(&self, account: Account, next_account_id: &mut u64) {
+        if let Some(ref btp_auth) = account.inner.btp_incoming_token {
+            self.btp_auth
+                .write()
+                .insert(token_hmac(&self.hmac_key, btp_auth), account.id());
+        }
+        if let Some(ref http_auth) = account.inner.http_incoming_token {
+            self.http_auth
+                .write()
+                .insert(token_hmac(&self.hmac_key, http_auth), account.id());
+        }
+        let account = account.without_incoming_tokens();
         self.accounts.write().insert(account.id(), account.clone());
         self.routing_table
             .write()
@@ -94,16 +232,49 @@ impl InMemoryStore {
                 .write()
                 .insert(route.clone(), account.id());
         }
-        if let Some(ref btp_auth) = account.inner.btp_incoming_token {
-            self.btp_auth.write().insert(btp_auth.clone(), account.id());
-        }
-        if let Some(ref http_auth) = account.inner.http_incoming_token {
-            self.http_auth
+        if let Some(ref username) = account.inner.username {
+            self.usernames
                 .write()
-                .insert(http_auth.clone(), account.id());
+                .insert(username.clone(), account.id());
         }
-        let mut next_account_id = self.next_account_id.lock();
-        *next_account_id = max(*next_account_id, account.inner.id);
+        *next_account_id = max(*next_account_id, account.inner.id + 1);
+    }
+
+    fn account_exists(&self, account_id: u64) -> bool {
+        self.accounts.read().contains_key(&account_id)
+    }
+
+    /// Check that there isn't already an account with values that must be unique
+    fn has_conflicting_account(&self, account: &Account) -> bool {
+        let username_taken = account.inner.username.as_ref().map_or(false, |username| {
+            self.usernames.read().contains_key(username)
+        });
+        let btp_token_taken = account
+            .inner
+            .btp_incoming_token
+            .as_ref()
+            .map_or(false, |token| {
+                self.btp_auth
+                    .read()
+                    .contains_key(&token_hmac(&self.hmac_key, token))
+            });
+        let http_token_taken = account
+            .inner
+            .http_incoming_token
+            .as_ref()
+            .map_or(false, |token| {
+                self.http_auth
+                    .read()
+                    .contains_key(&token_hmac(&self.hmac_key, token))
+            });
+        if username_taken || btp_token_taken || http_token_taken {
+            warn!(
+                "An account already exists with the same username or incoming token as account {}",
+                account.id()
+            );
+            return true;
+        }
+        false
     }
 }
 
@@ -126,6 +297,176 @@ impl AccountStore for InMemoryStore {
     }
 }
 
+impl BalanceStore for InMemoryStore {
+    /// Returns the balance **from the account holder's perspective**, meaning the sum of
+    /// the Payable Balance and Pending Outgoing minus the Receivable Balance and the Pending Incoming.
+    fn get_balance(&self, account: Account) -> Box<dyn Future<Item = i64, Error = ()> + Send> {
+        let balance = self
+            .balances
+            .lock()
+            .get(&account.id())
+            .map(Balance::total)
+            .unwrap_or_default();
+        Box::new(ok(balance))
+    }
+
+    fn update_balances_for_prepare(
+        &self,
+        from_account: Account,
+        incoming_amount: u64,
+        reference: &str,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if incoming_amount == 0 {
+            return Box::new(ok(()));
+        }
+        let incoming_amount = incoming_amount as i64;
+        let mut balances = self.balances.lock();
+        let balance = balances.entry(from_account.id()).or_default();
+
+        // Check that the prepare wouldn't go under the account's minimum balance
+        if let Some(min_balance) = from_account.inner.min_balance {
+            if balance.total() - incoming_amount < min_balance {
+                warn!(
+                    "Incoming prepare of {} would bring account {} under its minimum balance. Current balance: {}, min balance: {}",
+                    incoming_amount, from_account.id(), balance.balance, min_balance
+                );
+                return Box::new(err(()));
+            }
+        }
+
+        // Deduct the amount from the prepaid_amount and/or the balance
+        if balance.prepaid_amount >= incoming_amount {
+            balance.prepaid_amount -= incoming_amount;
+        } else if balance.prepaid_amount > 0 {
+            balance.balance -= incoming_amount - balance.prepaid_amount;
+            balance.prepaid_amount = 0;
+        } else {
+            balance.balance -= incoming_amount;
+        }
+        balance.record(
+            JournalEntryKind::Prepare,
+            Some(reference),
+            -incoming_amount,
+            self.journal_max_entries,
+        );
+        trace!(
+            "Processed prepare with incoming amount: {}. Account {} has balance (including prepaid amount): {} ",
+            incoming_amount, from_account.id(), balance.total()
+        );
+        Box::new(ok(()))
+    }
+
+    fn update_balances_for_fulfill(
+        &self,
+        to_account: Account,
+        outgoing_amount: u64,
+        reference: &str,
+    ) -> Box<dyn Future<Item = (i64, u64), Error = ()> + Send> {
+        if outgoing_amount == 0 {
+            return Box::new(ok((0, 0)));
+        }
+        let mut balances = self.balances.lock();
+        let balance = balances.entry(to_account.id()).or_default();
+        balance.balance += outgoing_amount as i64;
+        balance.record(
+            JournalEntryKind::Fulfill,
+            Some(reference),
+            outgoing_amount as i64,
+            self.journal_max_entries,
+        );
+
+        // Settle if the balance is over the threshold, and update the balance _before_
+        // sending the settlement so that we don't send multiple settlements for the same
+        // balance. If the settlement fails it is refunded with `refund_settlement`
+        let mut amount_to_settle = 0;
+        if let (Some(settle_threshold), Some(settle_to)) = (
+            to_account.inner.settle_threshold,
+            to_account.inner.settle_to,
+        ) {
+            if balance.balance > settle_threshold && settle_threshold > settle_to {
+                amount_to_settle = (balance.balance - settle_to) as u64;
+                balance.balance = settle_to;
+                balance.record(
+                    JournalEntryKind::OutgoingSettlement,
+                    Some(reference),
+                    -(amount_to_settle as i64),
+                    self.journal_max_entries,
+                );
+            }
+        }
+        trace!(
+            "Processed fulfill for account {} for outgoing amount {}. Balance: {}, amount to settle: {}",
+            to_account.id(),
+            outgoing_amount,
+            balance.total(),
+            amount_to_settle,
+        );
+        Box::new(ok((balance.total(), amount_to_settle)))
+    }
+
+    fn update_balances_for_reject(
+        &self,
+        from_account: Account,
+        incoming_amount: u64,
+        reference: &str,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if incoming_amount > 0 {
+            let mut balances = self.balances.lock();
+            let balance = balances.entry(from_account.id()).or_default();
+            balance.balance += incoming_amount as i64;
+            balance.record(
+                JournalEntryKind::Reject,
+                Some(reference),
+                incoming_amount as i64,
+                self.journal_max_entries,
+            );
+            trace!(
+                "Processed reject for incoming amount: {}. Account {} has balance (including prepaid amount): {}",
+                incoming_amount, from_account.id(), balance.total()
+            );
+        }
+        Box::new(ok(()))
+    }
+
+    fn get_balance_journal(
+        &self,
+        account: Account,
+        from_entry: u64,
+        limit: usize,
+    ) -> Box<dyn Future<Item = Vec<JournalEntry>, Error = ()> + Send> {
+        let entries = self
+            .balances
+            .lock()
+            .get(&account.id())
+            .map(|balance| {
+                balance
+                    .journal
+                    .iter()
+                    .skip(from_entry.saturating_sub(1 + balance.trimmed_entries) as usize)
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::new(ok(entries))
+    }
+}
+
+impl ExchangeRateStore for InMemoryStore {
+    fn get_exchange_rates(&self, asset_codes: &[&str]) -> Result<Vec<f64>, ()> {
+        let exchange_rates = self.exchange_rates.read();
+        let rates: Vec<f64> = asset_codes
+            .iter()
+            .filter_map(|code| exchange_rates.get(*code).cloned())
+            .collect();
+        if rates.len() == asset_codes.len() {
+            Ok(rates)
+        } else {
+            Err(())
+        }
+    }
+}
+
 impl HttpStore for InMemoryStore {
     type Account = Account;
 
@@ -133,7 +474,11 @@ impl HttpStore for InMemoryStore {
         &self,
         auth_header: &str,
     ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
-        if let Some(account_id) = self.http_auth.read().get(auth_header) {
+        if let Some(account_id) = self
+            .http_auth
+            .read()
+            .get(&token_hmac(&self.hmac_key, auth_header))
+        {
             if let Some(account) = self.accounts.read().get(account_id) {
                 return Box::new(ok(account.clone()));
             }
@@ -142,9 +487,59 @@ impl HttpStore for InMemoryStore {
     }
 }
 
+impl HttpForwardingStore for InMemoryStore {
+    type Account = Account;
+
+    fn set_connection_owner(
+        &self,
+        account_id: u64,
+        instance_url: &Url,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        self.connection_owners
+            .write()
+            .insert(account_id, instance_url.clone());
+        Box::new(ok(()))
+    }
+
+    fn remove_connection_owner(
+        &self,
+        account_id: u64,
+        instance_url: &Url,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut connection_owners = self.connection_owners.write();
+        if connection_owners.get(&account_id) == Some(instance_url) {
+            connection_owners.remove(&account_id);
+        }
+        Box::new(ok(()))
+    }
+
+    fn get_connection_owner(
+        &self,
+        account_id: u64,
+    ) -> Box<dyn Future<Item = Option<Url>, Error = ()> + Send> {
+        Box::new(ok(self.connection_owners.read().get(&account_id).cloned()))
+    }
+}
+
 impl RouterStore for InMemoryStore {
     fn routing_table(&self) -> HashMap<Bytes, u64> {
-        self.routing_table.read().clone()
+        let mut routing_table = self.routing_table.read().clone();
+        // Static routes overwrite any other routes for the same prefix
+        routing_table.extend(
+            self.static_routes
+                .read()
+                .iter()
+                .map(|(prefix, account_id)| (prefix.clone(), *account_id)),
+        );
+        routing_table
+    }
+
+    fn alternate_routes(&self, prefix: &[u8]) -> Vec<AlternateRoute<u64>> {
+        self.alternate_routes
+            .read()
+            .get(prefix)
+            .cloned()
+            .unwrap_or_default()
     }
 }
 
@@ -155,7 +550,7 @@ impl BtpStore for InMemoryStore {
         &self,
         token: &str,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
-        if let Some(account_id) = self.btp_auth.read().get(&(token.to_string())) {
+        if let Some(account_id) = self.btp_auth.read().get(&token_hmac(&self.hmac_key, token)) {
             Box::new(ok(self.accounts.read()[account_id].clone()))
         } else {
             Box::new(err(()))
@@ -182,14 +577,12 @@ impl BtpOpenSignupStore for InMemoryStore {
         &self,
         account: BtpOpenSignupAccount<'a>,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
-        let account_id = {
-            let next_id: u64 = *self.next_account_id.lock();
-            *self.next_account_id.lock() += 1;
-            next_id
-        };
+        let mut next_account_id = self.next_account_id.lock();
+        let account_id = *next_account_id;
+        *next_account_id += 1;
+        let auth_token_hmac = token_hmac(&self.hmac_key, account.auth_token);
         let account = AccountBuilder::new(account.ilp_address.clone())
             .id(account_id)
-            .btp_incoming_token(account.auth_token.to_string())
             .asset_code(account.asset_code.to_string())
             .asset_scale(account.asset_scale)
             .build();
@@ -197,12 +590,550 @@ impl BtpOpenSignupStore for InMemoryStore {
         (*self.accounts.write()).insert(account_id, account.clone());
         let ilp_address = account.client_address().clone();
         (*self.routing_table.write()).insert(ilp_address.to_bytes(), account_id);
-        (*self.btp_auth.write()).insert(
-            account.inner.btp_incoming_token.clone().unwrap(),
+        (*self.btp_auth.write()).insert(auth_token_hmac, account_id);
+
+        Box::new(ok(account))
+    }
+}
+
+impl NodeStore for InMemoryStore {
+    type Account = Account;
+
+    fn insert_account(
+        &self,
+        account: AccountDetails,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        // The ID is only used up once the account is added
+        let mut next_account_id = self.next_account_id.lock();
+        let account_id = *next_account_id;
+        let account = match Account::try_from(account_id, account) {
+            Ok(account) => account,
+            Err(_) => return Box::new(err(())),
+        };
+
+        if self.has_conflicting_account(&account) {
+            return Box::new(err(()));
+        }
+
+        self.index_account(account.clone(), &mut next_account_id);
+        debug!("Inserted account {}", account_id);
+        Box::new(ok(account.without_incoming_tokens()))
+    }
+
+    fn get_all_accounts(&self) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send> {
+        let mut accounts: Vec<Account> = self.accounts.read().values().cloned().collect();
+        accounts.sort_unstable_by_key(|account| account.id());
+        Box::new(ok(accounts))
+    }
+
+    fn get_account_id_from_username(
+        &self,
+        username: &str,
+    ) -> Box<dyn Future<Item = u64, Error = ()> + Send> {
+        let username = username.to_lowercase();
+        if let Some(account_id) = self.usernames.read().get(&username) {
+            Box::new(ok(*account_id))
+        } else {
+            debug!("No account found with username: {}", username);
+            Box::new(err(()))
+        }
+    }
+
+    fn set_rates<R>(&self, rates: R) -> Box<dyn Future<Item = (), Error = ()> + Send>
+    where
+        R: IntoIterator<Item = (String, f64)>,
+    {
+        let rates = HashMap::from_iter(rates);
+        trace!("Set exchange rates: {:?}", rates);
+        *self.exchange_rates.write() = rates;
+        Box::new(ok(()))
+    }
+
+    fn set_static_routes<R>(&self, routes: R) -> Box<dyn Future<Item = (), Error = ()> + Send>
+    where
+        R: IntoIterator<Item = (String, u64)>,
+    {
+        let routes: HashMap<Bytes, u64> = HashMap::from_iter(
+            routes
+                .into_iter()
+                .map(|(prefix, account_id)| (Bytes::from(prefix), account_id)),
+        );
+        if !routes
+            .values()
+            .all(|account_id| self.account_exists(*account_id))
+        {
+            error!("Error setting static routes because not all of the given accounts exist");
+            return Box::new(err(()));
+        }
+        *self.static_routes.write() = routes;
+        Box::new(ok(()))
+    }
+
+    fn set_static_route(
+        &self,
+        prefix: String,
+        account_id: u64,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if !self.account_exists(account_id) {
+            error!(
+                "Cannot set static route for prefix: {} because account {} does not exist",
+                prefix, account_id
+            );
+            return Box::new(err(()));
+        }
+        self.static_routes
+            .write()
+            .insert(Bytes::from(prefix), account_id);
+        Box::new(ok(()))
+    }
+
+    fn update_child_addresses(
+        &self,
+        node_address: Address,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut accounts = self.accounts.write();
+        let mut routing_table = self.routing_table.write();
+        for account in accounts
+            .values_mut()
+            .filter(|account| account.inner.routing_relation == RoutingRelation::Child)
+        {
+            let old_address = account.inner.ilp_address.clone();
+            let old_bytes: &[u8] = old_address.as_ref();
+            let is_under_node_address = old_bytes.starts_with(node_address.as_ref())
+                && old_bytes.get(node_address.len()) == Some(&b'.');
+            if is_under_node_address {
+                continue;
+            }
+            let segment = old_address.segments().last().unwrap_or_default();
+            let new_address = match node_address.with_suffix(segment.as_bytes()) {
+                Ok(address) => address,
+                Err(err) => {
+                    error!(
+                        "Unable to put account {} under the node's address: {:?}",
+                        account.id(),
+                        err
+                    );
+                    continue;
+                }
+            };
+            debug!(
+                "Changing address of child account {} from {} to {}",
+                account.id(),
+                str::from_utf8(old_address.as_ref()).unwrap_or("<not utf8>"),
+                str::from_utf8(new_address.as_ref()).unwrap_or("<not utf8>")
+            );
+            routing_table.remove(&old_address.to_bytes());
+            routing_table.insert(new_address.to_bytes(), account.id());
+            let mut details = (*account.inner).clone();
+            details.ilp_address = new_address;
+            *account = details.build();
+        }
+        Box::new(ok(()))
+    }
+
+    fn set_account_asset(
+        &self,
+        account_id: u64,
+        asset_code: String,
+        asset_scale: u8,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut accounts = self.accounts.write();
+        if let Some(account) = accounts.get_mut(&account_id) {
+            let mut details = (*account.inner).clone();
+            details.asset_code = asset_code.to_uppercase();
+            details.asset_scale = asset_scale;
+            *account = details.build();
+            Box::new(ok(()))
+        } else {
+            error!(
+                "Cannot set asset of account {} because it does not exist",
+                account_id
+            );
+            Box::new(err(()))
+        }
+    }
+}
+
+impl RouteManagerStore for InMemoryStore {
+    type Account = Account;
+
+    fn get_accounts_to_send_routes_to(
+        &self,
+    ) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
+        Box::new(ok(self
+            .accounts
+            .read()
+            .values()
+            .filter(|account| account.inner.send_routes)
+            .cloned()
+            .collect()))
+    }
+
+    fn get_accounts_to_receive_routes_from(
+        &self,
+    ) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
+        Box::new(ok(self
+            .accounts
+            .read()
+            .values()
+            .filter(|account| account.inner.receive_routes)
+            .cloned()
+            .collect()))
+    }
+
+    fn get_local_and_configured_routes(
+        &self,
+    ) -> Box<dyn Future<Item = (RoutingTable<Account>, RoutingTable<Account>), Error = ()> + Send>
+    {
+        let accounts = self.accounts.read();
+        let local_table = HashMap::from_iter(
+            accounts
+                .values()
+                .map(|account| (account.inner.ilp_address.to_bytes(), account.clone())),
+        );
+
+        // The accounts' additional routes are configured along with the accounts
+        let additional_routes = accounts.values().flat_map(|account| {
+            account
+                .inner
+                .additional_routes
+                .iter()
+                .map(move |prefix| (prefix.clone(), account.clone()))
+        });
+        let static_routes = self.static_routes.read();
+        let static_routes = static_routes.iter().filter_map(|(prefix, account_id)| {
+            if let Some(account) = accounts.get(account_id) {
+                Some((prefix.clone(), account.clone()))
+            } else {
+                warn!(
+                    "No account for ID: {}, ignoring configured route for prefix: {:?}",
+                    account_id, prefix
+                );
+                None
+            }
+        });
+        let configured_table = HashMap::from_iter(additional_routes.chain(static_routes));
+
+        Box::new(ok((local_table, configured_table)))
+    }
+
+    fn set_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Account)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let routes = HashMap::from_iter(
+            routes
+                .into_iter()
+                .map(|(prefix, account)| (prefix, account.id())),
+        );
+        trace!("Saved {} routes", routes.len());
+        *self.routing_table.write() = routes;
+        Box::new(ok(()))
+    }
+
+    fn set_alternate_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Vec<(Account, bool)>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let routes = HashMap::from_iter(routes.into_iter().map(|(prefix, alternates)| {
+            let alternates = alternates
+                .into_iter()
+                .map(|(account, equal_cost)| AlternateRoute {
+                    account_id: account.id(),
+                    equal_cost,
+                })
+                .collect();
+            (prefix, alternates)
+        }));
+        *self.alternate_routes.write() = routes;
+        Box::new(ok(()))
+    }
+}
+
+/// Apply the rate limit with the given key, see `CellRateLimit`.
+/// Returns false without using up any of the limit if the `quantity` would exceed it.
+fn throttle(
+    rate_limits: &mut HashMap<String, Instant>,
+    key: String,
+    limit: u64,
+    quantity: u64,
+) -> bool {
+    let now = Instant::now();
+    let remaining = rate_limits
+        .get(&key)
+        .filter(|tat| **tat > now)
+        .map(|tat| (*tat - now).as_nanos())
+        .unwrap_or(0);
+    if let Some(new_remaining) = CellRateLimit::per_minute(limit).apply(remaining, quantity) {
+        rate_limits.insert(key, now + Duration::from_nanos(new_remaining as u64));
+        true
+    } else {
+        false
+    }
+}
+
+impl RateLimitStore for InMemoryStore {
+    type Account = Account;
+
+    /// Apply rate limits for number of packets per minute and amount of money per minute
+    fn apply_rate_limits(
+        &self,
+        account: Account,
+        prepare_amount: u64,
+    ) -> Box<dyn Future<Item = (), Error = RateLimitError> + Send> {
+        let mut rate_limits = self.rate_limits.lock();
+        if let Some(limit) = account.inner.packets_per_minute_limit {
+            let key = format!("limit:packets:{}", account.id());
+            if !throttle(&mut rate_limits, key, u64::from(limit), 1) {
+                return Box::new(err(RateLimitError::PacketLimitExceeded));
+            }
+        }
+        if let Some(limit) = account.inner.amount_per_minute_limit {
+            let key = format!("limit:throughput:{}", account.id());
+            if !throttle(&mut rate_limits, key, limit, prepare_amount) {
+                return Box::new(err(RateLimitError::ThroughputLimitExceeded));
+            }
+        }
+        Box::new(ok(()))
+    }
+
+    fn refund_throughput_limit(
+        &self,
+        account: Account,
+        prepare_amount: u64,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if let Some(limit) = account.inner.amount_per_minute_limit {
+            let now = Instant::now();
+            let key = format!("limit:throughput:{}", account.id());
+            if let Some(tat) = self.rate_limits.lock().get_mut(&key) {
+                let remaining = if *tat > now {
+                    (*tat - now).as_nanos()
+                } else {
+                    0
+                };
+                let new_remaining =
+                    CellRateLimit::per_minute(limit).refund(remaining, prepare_amount);
+                *tat = now + Duration::from_nanos(new_remaining as u64);
+            }
+        }
+        Box::new(ok(()))
+    }
+}
+
+impl IdempotentStore for InMemoryStore {
+    fn load_idempotent_data(
+        &self,
+        idempotency_key: String,
+    ) -> Box<dyn Future<Item = IdempotentData, Error = ()> + Send> {
+        match self.idempotent_data.read().get(&idempotency_key) {
+            Some((data, saved_at)) if saved_at.elapsed() < IDEMPOTENCY_KEY_EXPIRY => {
+                trace!("Loaded idempotency key {:?} - {:?}", idempotency_key, data);
+                Box::new(ok(data.clone()))
+            }
+            _ => Box::new(err(())),
+        }
+    }
+
+    fn save_idempotent_data(
+        &self,
+        idempotency_key: String,
+        input_hash: [u8; 32],
+        status_code: StatusCode,
+        data: Bytes,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut idempotent_data = self.idempotent_data.write();
+        idempotent_data.retain(|_, (_, saved_at)| saved_at.elapsed() < IDEMPOTENCY_KEY_EXPIRY);
+        trace!(
+            "Cached {:?}: {:?}, {:?}",
+            idempotency_key,
+            status_code,
+            data,
+        );
+        idempotent_data.insert(
+            idempotency_key,
+            ((status_code, data, input_hash), Instant::now()),
+        );
+        Box::new(ok(()))
+    }
+}
+
+impl NodeStateStore for InMemoryStore {
+    fn export_state(&self) -> Box<dyn Future<Item = NodeState, Error = ()> + Send> {
+        let mut state = NodeState::new();
+        let balances = self.balances.lock();
+        let mut accounts: Vec<Account> = self.accounts.read().values().cloned().collect();
+        accounts.sort_unstable_by_key(|account| account.id());
+        let with_incoming_tokens: HashSet<u64> = self
+            .http_auth
+            .read()
+            .values()
+            .chain(self.btp_auth.read().values())
+            .cloned()
+            .collect();
+        state.accounts = accounts
+            .iter()
+            .map(|account| {
+                let balance = balances.get(&account.id()).cloned().unwrap_or_default();
+                AccountState {
+                    id: account.id(),
+                    details: account.to_node_details(),
+                    balance: balance.balance,
+                    prepaid_amount: balance.prepaid_amount,
+                    has_incoming_tokens: with_incoming_tokens.contains(&account.id()),
+                }
+            })
+            .collect();
+        state.static_routes = self
+            .static_routes
+            .read()
+            .iter()
+            .map(|(prefix, account_id)| (String::from_utf8_lossy(prefix).to_string(), *account_id))
+            .collect();
+        state.rates = self
+            .exchange_rates
+            .read()
+            .iter()
+            .map(|(asset_code, rate)| (asset_code.clone(), *rate))
+            .collect();
+        state.idempotent_data = self
+            .idempotent_data
+            .read()
+            .iter()
+            .filter(|(_, (_, saved_at))| saved_at.elapsed() < IDEMPOTENCY_KEY_EXPIRY)
+            .map(|(idempotency_key, ((status_code, data, input_hash), _))| {
+                IdempotentDataState::new(idempotency_key.clone(), *status_code, data, input_hash)
+            })
+            .collect();
+        Box::new(ok(state))
+    }
+
+    fn import_state(&self, state: NodeState) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        // Check every account before adding any, so the import is all or nothing
+        let mut next_account_id = self.next_account_id.lock();
+        let mut imported = Vec::with_capacity(state.accounts.len());
+        for account in state.accounts {
+            let AccountState {
+                id,
+                details,
+                balance,
+                prepaid_amount,
+                ..
+            } = account;
+            let account = match Account::try_from(id, details) {
+                Ok(account) => account,
+                Err(_) => return Box::new(err(())),
+            };
+            if self.account_exists(id) {
+                error!("Cannot import account {} because it already exists", id);
+                return Box::new(err(()));
+            }
+            if self.has_conflicting_account(&account) {
+                return Box::new(err(()));
+            }
+            imported.push((account, balance, prepaid_amount));
+        }
+
+        for (account, balance, prepaid_amount) in imported {
+            let id = account.id();
+            self.index_account(account, &mut next_account_id);
+            let mut balances = self.balances.lock();
+            let entry = balances.entry(id).or_default();
+            entry.balance = balance;
+            entry.prepaid_amount = prepaid_amount;
+            entry.record(
+                JournalEntryKind::Import,
+                None,
+                balance + prepaid_amount,
+                self.journal_max_entries,
+            );
+            debug!("Imported account {}", id);
+        }
+
+        *self.static_routes.write() = state
+            .static_routes
+            .into_iter()
+            .map(|(prefix, account_id)| (Bytes::from(prefix), account_id))
+            .collect();
+        *self.exchange_rates.write() = state.rates.into_iter().collect();
+        let mut idempotent_data = self.idempotent_data.write();
+        for entry in state.idempotent_data {
+            if let Some(data) = entry.decode() {
+                idempotent_data.insert(entry.idempotency_key, (data, Instant::now()));
+            }
+        }
+        Box::new(ok(()))
+    }
+}
+
+impl SettlementStore for InMemoryStore {
+    type Account = Account;
+
+    fn update_balance_for_incoming_settlement(
+        &self,
+        account_id: u64,
+        amount: u64,
+        idempotency_key: Option<String>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut idempotency_keys = self.settlement_idempotency_keys.lock();
+        let mut balances = self.balances.lock();
+
+        // If the idempotency key has been used, then do not perform any operations
+        if let Some(ref idempotency_key) = idempotency_key {
+            idempotency_keys.retain(|_, used_at| used_at.elapsed() < IDEMPOTENCY_KEY_EXPIRY);
+            if idempotency_keys.contains_key(idempotency_key) {
+                return Box::new(ok(()));
+            }
+            idempotency_keys.insert(idempotency_key.clone(), Instant::now());
+        }
+
+        // Credit the incoming settlement to the balance and/or prepaid amount,
+        // depending on whether that account currently owes money or not
+        let amount = amount as i64;
+        let balance = balances.entry(account_id).or_default();
+        if balance.balance >= 0 {
+            balance.prepaid_amount += amount;
+        } else if -balance.balance >= amount {
+            balance.balance += amount;
+        } else {
+            balance.prepaid_amount += amount + balance.balance;
+            balance.balance = 0;
+        }
+        balance.record(
+            JournalEntryKind::IncomingSettlement,
+            idempotency_key.as_ref().map(String::as_str),
+            amount,
+            self.journal_max_entries,
+        );
+        trace!(
+            "Processed incoming settlement from account: {} for amount: {}. Balance is now: {}",
             account_id,
+            amount,
+            balance.total()
         );
+        Box::new(ok(()))
+    }
 
-        Box::new(ok(account))
+    fn refund_settlement(
+        &self,
+        account_id: u64,
+        settle_amount: u64,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut balances = self.balances.lock();
+        let balance = balances.entry(account_id).or_default();
+        balance.balance += settle_amount as i64;
+        balance.record(
+            JournalEntryKind::SettlementRefund,
+            None,
+            settle_amount as i64,
+            self.journal_max_entries,
+        );
+        trace!(
+            "Refunded settlement for account: {} of amount: {}. Balance is now: {}",
+            account_id,
+            settle_amount,
+            balance.total()
+        );
+        Box::new(ok(()))
     }
 }
 
@@ -210,6 +1141,7 @@ impl BtpOpenSignupStore for InMemoryStore {
 mod tests {
     use super::*;
 
+    use interledger_api::import_node_state;
     use interledger_packet::Address;
     use std::str::FromStr;
     #[test]
@@ -242,6 +1174,136 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn keeps_only_hmacs_of_incoming_tokens() {
+        let account = AccountBuilder::new(Address::from_str("example.zero").unwrap())
+            .http_incoming_token("test_token".to_string())
+            .btp_incoming_token("btp_token".to_string())
+            .build();
+        let store = InMemoryStore::from_accounts(vec![account]);
+        let account = store.get_accounts(vec![0]).wait().unwrap().pop().unwrap();
+        assert_eq!(account.inner.http_incoming_token, None);
+        assert_eq!(account.inner.btp_incoming_token, None);
+        assert!(!store
+            .http_auth
+            .read()
+            .contains_key(&Bytes::from("test_token")));
+        assert_eq!(
+            store
+                .get_account_from_btp_token("btp_token")
+                .wait()
+                .unwrap()
+                .id(),
+            0
+        );
+    }
+
+    #[test]
+    fn exports_and_imports_state() {
+        let store = InMemoryStore::new(vec![
+            AccountBuilder::new(Address::from_str("example.zero").unwrap())
+                .id(0)
+                .asset_code("XYZ".to_string())
+                .http_outgoing_token("outgoing".to_string()),
+            AccountBuilder::new(Address::from_str("example.three").unwrap())
+                .id(3)
+                .asset_code("XYZ".to_string()),
+        ]);
+        let accounts = store.get_accounts(vec![0, 3]).wait().unwrap();
+        store
+            .update_balances_for_prepare(accounts[0].clone(), 100, "a")
+            .wait()
+            .unwrap();
+        store
+            .set_static_route("example.route".to_string(), 3)
+            .wait()
+            .unwrap();
+        store
+            .set_rates(vec![("XYZ".to_string(), 1.5)])
+            .wait()
+            .unwrap();
+        store
+            .save_idempotent_data(
+                "key".to_string(),
+                [1; 32],
+                StatusCode::OK,
+                Bytes::from("OK"),
+            )
+            .wait()
+            .unwrap();
+
+        let state = store.export_state().wait().unwrap();
+        assert_eq!(state.accounts.len(), 2);
+        assert_eq!(state.accounts[0].balance, -100);
+        assert_eq!(
+            state.accounts[0].details.http_outgoing_token,
+            Some("outgoing".to_string())
+        );
+
+        let imported = InMemoryStore::default();
+        import_node_state(imported.clone(), state).wait().unwrap();
+        let accounts = imported.get_accounts(vec![0, 3]).wait().unwrap();
+        assert_eq!(
+            imported.get_balance(accounts[0].clone()).wait().unwrap(),
+            -100
+        );
+        assert_eq!(
+            accounts[0].inner.http_outgoing_token,
+            Some("outgoing".to_string())
+        );
+        assert_eq!(
+            imported
+                .static_routes
+                .read()
+                .get(&Bytes::from("example.route")),
+            Some(&3)
+        );
+        assert_eq!(imported.get_exchange_rates(&["XYZ"]).unwrap(), vec![1.5]);
+        assert_eq!(
+            imported
+                .load_idempotent_data("key".to_string())
+                .wait()
+                .unwrap()
+                .2,
+            [1; 32]
+        );
+        let journal = imported
+            .get_balance_journal(accounts[0].clone(), 1, 10)
+            .wait()
+            .unwrap();
+        assert_eq!(journal[0].kind, JournalEntryKind::Import);
+
+        // New accounts get IDs after the imported ones and
+        // state cannot be imported twice
+        let next = imported
+            .insert_account(accounts[1].to_node_details())
+            .wait()
+            .unwrap();
+        assert_eq!(next.id(), 4);
+        let state = store.export_state().wait().unwrap();
+        assert!(import_node_state(imported, state).wait().is_err());
+    }
+
+    #[test]
+    fn imports_all_accounts_or_none() {
+        let store = InMemoryStore::new(vec![
+            AccountBuilder::new(Address::from_str("example.zero").unwrap()).id(0),
+            AccountBuilder::new(Address::from_str("example.one").unwrap()).id(1),
+        ]);
+        let mut state = store.export_state().wait().unwrap();
+        state.accounts[1].details.http_endpoint = Some("not a url".to_string());
+
+        let imported = InMemoryStore::default();
+        assert!(import_node_state(imported.clone(), state.clone())
+            .wait()
+            .is_err());
+        assert!(imported.get_all_accounts().wait().unwrap().is_empty());
+
+        state.accounts[1].details.http_endpoint = None;
+        import_node_state(imported.clone(), state).wait().unwrap();
+        assert_eq!(imported.get_all_accounts().wait().unwrap().len(), 2);
+    }
+
     #[test]
     fn query_by_btp() {
         let account = AccountBuilder::new(Address::from_str("example.zero").unwrap())
@@ -292,4 +1354,358 @@ mod tests {
             .unwrap();
         assert_eq!(account.id(), 1);
     }
+
+    fn account_details(ilp_address: &str) -> AccountDetails {
+        AccountDetails {
+            ilp_address: Address::from_str(ilp_address).unwrap(),
+            username: None,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: u64::max_value(),
+            min_balance: None,
+            http_endpoint: None,
+            http_incoming_token: None,
+            http_outgoing_token: None,
+            btp_uri: None,
+            btp_incoming_token: None,
+            settle_threshold: None,
+            settle_to: None,
+            send_routes: false,
+            receive_routes: false,
+            routing_relation: None,
+            allowed_route_prefixes: None,
+            route_import_deny: None,
+            route_import_max_path_length: None,
+            route_export_allow: None,
+            route_export_deny: None,
+            route_export_max_path_length: None,
+            advertise_parent_routes: None,
+            route_cost: None,
+            round_trip_time: None,
+            amount_per_minute_limit: None,
+            packets_per_minute_limit: None,
+            settlement_engine_url: None,
+            settlement_engine_asset_scale: None,
+        }
+    }
+
+    #[test]
+    fn insert_and_look_up_accounts() {
+        let store = InMemoryStore::default();
+        let mut details = account_details("example.alice");
+        details.username = Some("Alice".to_string());
+        details.http_incoming_token = Some("Bearer token".to_string());
+        let account = store.insert_account(details.clone()).wait().unwrap();
+        assert_eq!(account.id(), 1);
+        assert_eq!(
+            store.get_account_id_from_username("alice").wait().unwrap(),
+            1
+        );
+        assert_eq!(
+            store
+                .get_account_from_http_token("Bearer token")
+                .wait()
+                .unwrap()
+                .id(),
+            1
+        );
+        assert_eq!(store.routing_table()[&Bytes::from("example.alice")], 1);
+
+        // Usernames and incoming tokens must be unique
+        assert!(store.insert_account(details).wait().is_err());
+        assert_eq!(store.get_all_accounts().wait().unwrap().len(), 1);
+
+        // The rejected insert did not use up an ID
+        let account = store
+            .insert_account(account_details("example.bob"))
+            .wait()
+            .unwrap();
+        assert_eq!(account.id(), 2);
+    }
+
+    #[test]
+    fn rejects_usernames_used_by_the_api() {
+        let store = InMemoryStore::default();
+        for username in &["accounts", "Routes", "ping"] {
+            let mut details = account_details("example.alice");
+            details.username = Some(username.to_string());
+            assert!(store.insert_account(details).wait().is_err());
+        }
+        assert!(store.get_all_accounts().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_inserts_cannot_share_a_username() {
+        let store = InMemoryStore::default();
+        let inserts: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    let mut details = account_details(&format!("example.carol{}", i));
+                    details.username = Some("carol".to_string());
+                    store.insert_account(details).wait().is_ok()
+                })
+            })
+            .collect();
+        let inserted = inserts
+            .into_iter()
+            .map(|insert| insert.join().unwrap())
+            .filter(|inserted| *inserted)
+            .count();
+        assert_eq!(inserted, 1);
+        assert_eq!(store.get_all_accounts().wait().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_prepare_under_min_balance() {
+        let account = AccountBuilder::new(Address::from_str("example.alice").unwrap())
+            .min_balance(-100)
+            .build();
+        let store = InMemoryStore::from_accounts(vec![account.clone()]);
+        store
+            .update_balances_for_prepare(account.clone(), 100, "condition")
+            .wait()
+            .unwrap();
+        assert!(store
+            .update_balances_for_prepare(account.clone(), 1, "condition")
+            .wait()
+            .is_err());
+        assert_eq!(store.get_balance(account.clone()).wait().unwrap(), -100);
+
+        store
+            .update_balances_for_reject(account.clone(), 100, "condition")
+            .wait()
+            .unwrap();
+        assert_eq!(store.get_balance(account).wait().unwrap(), 0);
+    }
+
+    #[test]
+    fn settles_when_fulfill_goes_over_threshold() {
+        let account = AccountBuilder::new(Address::from_str("example.alice").unwrap())
+            .settlement(100, 10)
+            .build();
+        let store = InMemoryStore::from_accounts(vec![account.clone()]);
+        assert_eq!(
+            store
+                .update_balances_for_fulfill(account.clone(), 100, "condition")
+                .wait()
+                .unwrap(),
+            (100, 0)
+        );
+        assert_eq!(
+            store
+                .update_balances_for_fulfill(account.clone(), 1, "condition")
+                .wait()
+                .unwrap(),
+            (10, 91)
+        );
+        store.refund_settlement(account.id(), 91).wait().unwrap();
+        assert_eq!(store.get_balance(account).wait().unwrap(), 101);
+    }
+
+    #[test]
+    fn trims_journal_to_max_entries() {
+        let account = AccountBuilder::new(Address::from_str("example.alice").unwrap()).build();
+        let mut store = InMemoryStore::from_accounts(vec![account.clone()]);
+        store.journal_max_entries(2);
+        for _ in 0..3 {
+            store
+                .update_balances_for_prepare(account.clone(), 10, "aa")
+                .wait()
+                .unwrap();
+        }
+
+        let journal = store
+            .get_balance_journal(account.clone(), 1, 10)
+            .wait()
+            .unwrap();
+        let ids: Vec<u64> = journal.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(journal[1].balance, -30);
+
+        let page = store.get_balance_journal(account, 3, 10).wait().unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, 3);
+    }
+
+    #[test]
+    fn records_balance_changes_in_journal() {
+        let account = AccountBuilder::new(Address::from_str("example.alice").unwrap())
+            .settlement(100, 10)
+            .build();
+        let store = InMemoryStore::from_accounts(vec![account.clone()]);
+        store
+            .update_balances_for_prepare(account.clone(), 30, "aa")
+            .wait()
+            .unwrap();
+        store
+            .update_balances_for_reject(account.clone(), 30, "aa")
+            .wait()
+            .unwrap();
+        store
+            .update_balances_for_fulfill(account.clone(), 120, "bb")
+            .wait()
+            .unwrap();
+        store.refund_settlement(account.id(), 110).wait().unwrap();
+        store
+            .update_balance_for_incoming_settlement(account.id(), 5, Some("key".to_string()))
+            .wait()
+            .unwrap();
+
+        let journal = store
+            .get_balance_journal(account.clone(), 1, 10)
+            .wait()
+            .unwrap();
+        let summary: Vec<(u64, JournalEntryKind, Option<&str>, i64, i64, i64)> = journal
+            .iter()
+            .map(|entry| {
+                (
+                    entry.id,
+                    entry.kind,
+                    entry.reference.as_ref().map(String::as_str),
+                    entry.amount,
+                    entry.balance,
+                    entry.prepaid_amount,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, JournalEntryKind::Prepare, Some("aa"), -30, -30, 0),
+                (2, JournalEntryKind::Reject, Some("aa"), 30, 0, 0),
+                (3, JournalEntryKind::Fulfill, Some("bb"), 120, 120, 0),
+                (
+                    4,
+                    JournalEntryKind::OutgoingSettlement,
+                    Some("bb"),
+                    -110,
+                    10,
+                    0
+                ),
+                (5, JournalEntryKind::SettlementRefund, None, 110, 120, 0),
+                (
+                    6,
+                    JournalEntryKind::IncomingSettlement,
+                    Some("key"),
+                    5,
+                    120,
+                    5
+                ),
+            ]
+        );
+
+        // Pages start at the given entry
+        let page = store.get_balance_journal(account, 5, 10).wait().unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].id, 5);
+    }
+
+    #[test]
+    fn incoming_settlements_pay_off_balance_then_prepay() {
+        let account = AccountBuilder::new(Address::from_str("example.alice").unwrap()).build();
+        let store = InMemoryStore::from_accounts(vec![account.clone()]);
+        store
+            .update_balances_for_prepare(account.clone(), 100, "condition")
+            .wait()
+            .unwrap();
+        store
+            .update_balance_for_incoming_settlement(account.id(), 150, Some("key".to_string()))
+            .wait()
+            .unwrap();
+        // The same idempotency key is only credited once
+        store
+            .update_balance_for_incoming_settlement(account.id(), 150, Some("key".to_string()))
+            .wait()
+            .unwrap();
+        assert_eq!(store.get_balance(account.clone()).wait().unwrap(), 50);
+
+        // Prepares use up the prepaid amount before the balance
+        store
+            .update_balances_for_prepare(account.clone(), 70, "condition")
+            .wait()
+            .unwrap();
+        assert_eq!(store.get_balance(account).wait().unwrap(), -20);
+    }
+
+    #[test]
+    fn applies_rate_limits() {
+        let account = AccountBuilder::new(Address::from_str("example.alice").unwrap())
+            .packets_per_minute_limit(2)
+            .amount_per_minute_limit(100)
+            .build();
+        let store = InMemoryStore::from_accounts(vec![account.clone()]);
+        store.apply_rate_limits(account.clone(), 60).wait().unwrap();
+        assert_eq!(
+            store.apply_rate_limits(account.clone(), 60).wait(),
+            Err(RateLimitError::ThroughputLimitExceeded)
+        );
+        store
+            .refund_throughput_limit(account.clone(), 60)
+            .wait()
+            .unwrap();
+        assert_eq!(
+            store.apply_rate_limits(account.clone(), 60).wait(),
+            Err(RateLimitError::PacketLimitExceeded)
+        );
+    }
+
+    #[test]
+    fn only_removes_own_connections() {
+        let store = InMemoryStore::default();
+        let one = Url::parse("http://instance-1:7771").unwrap();
+        let two = Url::parse("http://instance-2:7771").unwrap();
+        store.set_connection_owner(1, &one).wait().unwrap();
+        store.set_connection_owner(1, &two).wait().unwrap();
+        // The connection moved to the second instance before the first one noticed it closed
+        store.remove_connection_owner(1, &one).wait().unwrap();
+        assert_eq!(
+            store.get_connection_owner(1).wait().unwrap(),
+            Some(two.clone())
+        );
+        store.remove_connection_owner(1, &two).wait().unwrap();
+        assert_eq!(store.get_connection_owner(1).wait().unwrap(), None);
+    }
+
+    #[test]
+    fn static_routes_overwrite_other_routes() {
+        let store = InMemoryStore::new(vec![
+            AccountBuilder::new(Address::from_str("example.one").unwrap()).id(1),
+            AccountBuilder::new(Address::from_str("example.two").unwrap()).id(2),
+        ]);
+        store
+            .set_static_route("example.one".to_string(), 2)
+            .wait()
+            .unwrap();
+        assert!(store
+            .set_static_route("example.three".to_string(), 3)
+            .wait()
+            .is_err());
+        assert_eq!(store.routing_table()[&Bytes::from("example.one")], 2);
+
+        let (local, configured) = store.get_local_and_configured_routes().wait().unwrap();
+        assert_eq!(local[&Bytes::from("example.one")].id(), 1);
+        assert_eq!(configured[&Bytes::from("example.one")].id(), 2);
+    }
+
+    #[test]
+    fn moves_child_addresses_under_node_address() {
+        let store = InMemoryStore::new(vec![
+            AccountBuilder::new(Address::from_str("local.alice").unwrap()).id(1),
+            AccountBuilder::new(Address::from_str("example.parent").unwrap())
+                .id(2)
+                .routing_relation(RoutingRelation::Parent),
+        ]);
+        store
+            .update_child_addresses(Address::from_str("example.node").unwrap())
+            .wait()
+            .unwrap();
+        let accounts = store.get_accounts(vec![1, 2]).wait().unwrap();
+        assert_eq!(accounts[0].client_address(), &b"example.node.alice"[..]);
+        assert_eq!(accounts[1].client_address(), &b"example.parent"[..]);
+        assert_eq!(store.routing_table()[&Bytes::from("example.node.alice")], 1);
+        assert!(!store
+            .routing_table()
+            .contains_key(&Bytes::from("local.alice")));
+    }
 }
@@ -1,8 +1,7 @@
-use super::crypto::{decrypt_token, encrypt_token};
 use bytes::Bytes;
-use interledger_api::AccountDetails;
+use interledger_api::{AccountDetails, ExportableAccount};
 use interledger_btp::BtpAccount;
-use interledger_ccp::{CcpRoutingAccount, RoutingRelation};
+use interledger_ccp::{CcpRoutingAccount, RoutePolicy, RoutingRelation};
 use interledger_http::HttpAccount;
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::Address;
@@ -11,10 +10,10 @@ use interledger_service_util::{
     MaxPacketAmountAccount, RateLimitAccount, RoundTripTimeAccount, DEFAULT_ROUND_TRIP_TIME,
 };
 use interledger_settlement::{SettlementAccount, SettlementEngineDetails};
+use interledger_store_crypto::KeyRing;
 use log::error;
 use redis::{from_redis_value, ErrorKind, FromRedisValue, RedisError, ToRedisArgs, Value};
 
-use ring::aead;
 use serde::Serialize;
 use serde::Serializer;
 use std::{
@@ -24,13 +23,14 @@ use std::{
 };
 
 use url::Url;
-const ACCOUNT_DETAILS_FIELDS: usize = 21;
+const ACCOUNT_DETAILS_FIELDS: usize = 30;
 
 #[derive(Clone, Debug, Serialize)]
 pub struct Account {
     pub(crate) id: u64,
     #[serde(serialize_with = "address_to_string")]
     pub(crate) ilp_address: Address,
+    pub(crate) username: Option<String>,
     // TODO add additional routes
     pub(crate) asset_code: String,
     pub(crate) asset_scale: u8,
@@ -50,6 +50,14 @@ pub struct Account {
     pub(crate) routing_relation: RoutingRelation,
     pub(crate) send_routes: bool,
     pub(crate) receive_routes: bool,
+    pub(crate) allowed_route_prefixes: Option<Vec<String>>,
+    pub(crate) route_import_deny: Option<Vec<String>>,
+    pub(crate) route_import_max_path_length: Option<u32>,
+    pub(crate) route_export_allow: Option<Vec<String>>,
+    pub(crate) route_export_deny: Option<Vec<String>>,
+    pub(crate) route_export_max_path_length: Option<u32>,
+    pub(crate) advertise_parent_routes: bool,
+    pub(crate) route_cost: u32,
     pub(crate) round_trip_time: u64,
     pub(crate) packets_per_minute_limit: Option<u32>,
     pub(crate) amount_per_minute_limit: Option<u64>,
@@ -119,6 +127,18 @@ impl Account {
         } else {
             RoutingRelation::Child
         };
+        let username = details.validated_username()?;
+        for prefixes in &[
+            &details.allowed_route_prefixes,
+            &details.route_import_deny,
+            &details.route_export_allow,
+            &details.route_export_deny,
+        ] {
+            for prefix in prefixes.iter().flat_map(|prefixes| prefixes.iter()) {
+                Address::from_str(prefix)
+                    .map_err(|err| error!("Invalid route prefix {}: {:?}", prefix, err))?;
+            }
+        }
         let settlement_engine_url =
             if let Some(settlement_engine_url) = details.settlement_engine_url {
                 Url::parse(&settlement_engine_url).ok()
@@ -130,6 +150,7 @@ impl Account {
             ilp_address: Address::try_from(details.ilp_address.as_ref()).map_err(|err| {
                 error!("Invalid ILP Address when creating Redis account: {:?}", err)
             })?,
+            username,
             asset_code: details.asset_code.to_uppercase(),
             asset_scale: details.asset_scale,
             max_packet_amount: details.max_packet_amount,
@@ -142,6 +163,14 @@ impl Account {
             settle_to: details.settle_to,
             send_routes: details.send_routes,
             receive_routes: details.receive_routes,
+            allowed_route_prefixes: details.allowed_route_prefixes,
+            route_import_deny: details.route_import_deny,
+            route_import_max_path_length: details.route_import_max_path_length,
+            route_export_allow: details.route_export_allow,
+            route_export_deny: details.route_export_deny,
+            route_export_max_path_length: details.route_export_max_path_length,
+            advertise_parent_routes: details.advertise_parent_routes.unwrap_or(true),
+            route_cost: details.route_cost.unwrap_or(0),
             routing_relation,
             round_trip_time: details.round_trip_time.unwrap_or(DEFAULT_ROUND_TRIP_TIME),
             packets_per_minute_limit: details.packets_per_minute_limit,
@@ -151,15 +180,12 @@ impl Account {
         })
     }
 
-    pub fn encrypt_tokens(
-        mut self,
-        encryption_key: &aead::SealingKey,
-    ) -> AccountWithEncryptedTokens {
+    pub fn encrypt_tokens(mut self, keys: &KeyRing) -> AccountWithEncryptedTokens {
         if let Some(ref token) = self.btp_outgoing_token {
-            self.btp_outgoing_token = Some(encrypt_token(encryption_key, token));
+            self.btp_outgoing_token = Some(keys.encrypt(token));
         }
         if let Some(ref token) = self.http_outgoing_token {
-            self.http_outgoing_token = Some(encrypt_token(encryption_key, token));
+            self.http_outgoing_token = Some(keys.encrypt(token));
         }
         AccountWithEncryptedTokens { account: self }
     }
@@ -170,16 +196,42 @@ pub struct AccountWithEncryptedTokens {
 }
 
 impl AccountWithEncryptedTokens {
-    pub fn decrypt_tokens(mut self, decryption_key: &aead::OpeningKey) -> Account {
+    pub fn decrypt_tokens(mut self, keys: &KeyRing) -> Account {
         if let Some(ref encrypted) = self.account.btp_outgoing_token {
-            self.account.btp_outgoing_token = decrypt_token(decryption_key, encrypted);
+            self.account.btp_outgoing_token = keys.decrypt(encrypted).map(|(token, _)| token);
         }
         if let Some(ref encrypted) = self.account.http_outgoing_token {
-            self.account.http_outgoing_token = decrypt_token(decryption_key, encrypted);
+            self.account.http_outgoing_token = keys.decrypt(encrypted).map(|(token, _)| token);
         }
 
         self.account
     }
+
+    pub fn id(&self) -> u64 {
+        self.account.id
+    }
+
+    /// Re-encrypt the tokens that were not encrypted with the current key and return
+    /// them with the names of their fields and the ciphertexts they replace
+    pub fn reencrypt_stale_tokens(&self, keys: &KeyRing) -> Vec<(&'static str, Bytes, Bytes)> {
+        let tokens = [
+            ("btp_outgoing_token", &self.account.btp_outgoing_token),
+            ("http_outgoing_token", &self.account.http_outgoing_token),
+        ];
+        tokens
+            .iter()
+            .filter_map(|(field, encrypted)| {
+                encrypted.as_ref().and_then(|encrypted| {
+                    let (token, stale) = keys.decrypt(encrypted)?;
+                    if stale {
+                        Some((*field, encrypted.clone(), keys.encrypt(&token)))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect()
+    }
 }
 
 impl ToRedisArgs for AccountWithEncryptedTokens {
@@ -193,6 +245,10 @@ impl ToRedisArgs for AccountWithEncryptedTokens {
             "ilp_address".write_redis_args(&mut rv);
             rv.push(account.ilp_address.to_bytes().to_vec());
         }
+        if let Some(ref username) = account.username {
+            "username".write_redis_args(&mut rv);
+            username.write_redis_args(&mut rv);
+        }
         if !account.asset_code.is_empty() {
             "asset_code".write_redis_args(&mut rv);
             account.asset_code.write_redis_args(&mut rv);
@@ -242,6 +298,39 @@ impl ToRedisArgs for AccountWithEncryptedTokens {
             "receive_routes".write_redis_args(&mut rv);
             account.receive_routes.write_redis_args(&mut rv);
         }
+        if let Some(ref prefixes) = account.allowed_route_prefixes {
+            "allowed_route_prefixes".write_redis_args(&mut rv);
+            prefixes.join(",").write_redis_args(&mut rv);
+        }
+        if let Some(ref prefixes) = account.route_import_deny {
+            "route_import_deny".write_redis_args(&mut rv);
+            prefixes.join(",").write_redis_args(&mut rv);
+        }
+        if let Some(length) = account.route_import_max_path_length {
+            "route_import_max_path_length".write_redis_args(&mut rv);
+            length.write_redis_args(&mut rv);
+        }
+        if let Some(ref prefixes) = account.route_export_allow {
+            "route_export_allow".write_redis_args(&mut rv);
+            prefixes.join(",").write_redis_args(&mut rv);
+        }
+        if let Some(ref prefixes) = account.route_export_deny {
+            "route_export_deny".write_redis_args(&mut rv);
+            prefixes.join(",").write_redis_args(&mut rv);
+        }
+        if let Some(length) = account.route_export_max_path_length {
+            "route_export_max_path_length".write_redis_args(&mut rv);
+            length.write_redis_args(&mut rv);
+        }
+        // Parent routes are advertised unless this is set
+        if !account.advertise_parent_routes {
+            "advertise_parent_routes".write_redis_args(&mut rv);
+            account.advertise_parent_routes.write_redis_args(&mut rv);
+        }
+        if account.route_cost > 0 {
+            "route_cost".write_redis_args(&mut rv);
+            account.route_cost.write_redis_args(&mut rv);
+        }
         if let Some(limit) = account.packets_per_minute_limit {
             "packets_per_minute_limit".write_redis_args(&mut rv);
             limit.write_redis_args(&mut rv);
@@ -290,6 +379,7 @@ impl FromRedisValue for AccountWithEncryptedTokens {
             account: Account {
                 id: get_value("id", &hash)?,
                 ilp_address,
+                username: get_value_option("username", &hash)?,
                 asset_code: get_value("asset_code", &hash)?,
                 asset_scale: get_value("asset_scale", &hash)?,
                 http_endpoint: get_url_option("http_endpoint", &hash)?,
@@ -303,6 +393,21 @@ impl FromRedisValue for AccountWithEncryptedTokens {
                 routing_relation,
                 send_routes: get_bool("send_routes", &hash),
                 receive_routes: get_bool("receive_routes", &hash),
+                allowed_route_prefixes: get_list_option("allowed_route_prefixes", &hash)?,
+                route_import_deny: get_list_option("route_import_deny", &hash)?,
+                route_import_max_path_length: get_value_option(
+                    "route_import_max_path_length",
+                    &hash,
+                )?,
+                route_export_allow: get_list_option("route_export_allow", &hash)?,
+                route_export_deny: get_list_option("route_export_deny", &hash)?,
+                route_export_max_path_length: get_value_option(
+                    "route_export_max_path_length",
+                    &hash,
+                )?,
+                advertise_parent_routes: !hash.contains_key("advertise_parent_routes")
+                    || get_bool("advertise_parent_routes", &hash),
+                route_cost: get_value_option("route_cost", &hash)?.unwrap_or(0),
                 round_trip_time,
                 packets_per_minute_limit: get_value_option("packets_per_minute_limit", &hash)?,
                 amount_per_minute_limit: get_value_option("amount_per_minute_limit", &hash)?,
@@ -364,6 +469,21 @@ fn get_url_option(key: &str, map: &HashMap<String, Value>) -> Result<Option<Url>
     }
 }
 
+/// Lists are stored as comma-separated strings
+fn get_list_option(
+    key: &str,
+    map: &HashMap<String, Value>,
+) -> Result<Option<Vec<String>>, RedisError> {
+    let value: Option<String> = get_value_option(key, map)?;
+    Ok(value.map(|value| {
+        value
+            .split(',')
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect()
+    }))
+}
+
 fn get_bool(key: &str, map: &HashMap<String, Value>) -> bool {
     if let Some(ref value) = map.get(key) {
         if let Ok(value) = from_redis_value(value) as Result<String, RedisError> {
@@ -441,6 +561,47 @@ impl CcpRoutingAccount for Account {
     fn should_receive_routes(&self) -> bool {
         self.receive_routes
     }
+
+    fn allowed_route_prefixes(&self) -> Option<Vec<Bytes>> {
+        to_prefixes(&self.allowed_route_prefixes)
+    }
+
+    fn route_import_policy(&self) -> RoutePolicy {
+        RoutePolicy {
+            allow: self.allowed_route_prefixes(),
+            deny: to_prefixes(&self.route_import_deny).unwrap_or_default(),
+            max_path_length: self
+                .route_import_max_path_length
+                .map(|length| length as usize),
+        }
+    }
+
+    fn route_export_policy(&self) -> RoutePolicy {
+        RoutePolicy {
+            allow: to_prefixes(&self.route_export_allow),
+            deny: to_prefixes(&self.route_export_deny).unwrap_or_default(),
+            max_path_length: self
+                .route_export_max_path_length
+                .map(|length| length as usize),
+        }
+    }
+
+    fn should_advertise_parent_routes(&self) -> bool {
+        self.advertise_parent_routes
+    }
+
+    fn route_cost(&self) -> u32 {
+        self.route_cost
+    }
+}
+
+fn to_prefixes(prefixes: &Option<Vec<String>>) -> Option<Vec<Bytes>> {
+    prefixes.as_ref().map(|prefixes| {
+        prefixes
+            .iter()
+            .map(|prefix| Bytes::from(prefix.as_str()))
+            .collect()
+    })
 }
 
 impl RoundTripTimeAccount for Account {
@@ -459,6 +620,32 @@ impl RateLimitAccount for Account {
     }
 }
 
+impl ExportableAccount for Account {
+    fn username(&self) -> Option<&str> {
+        self.username.as_ref().map(String::as_str)
+    }
+
+    fn min_balance(&self) -> Option<i64> {
+        self.min_balance
+    }
+
+    fn settle_threshold(&self) -> Option<i64> {
+        self.settle_threshold
+    }
+
+    fn settle_to(&self) -> Option<i64> {
+        self.settle_to
+    }
+
+    fn settlement_engine_url(&self) -> Option<&Url> {
+        self.settlement_engine_url.as_ref()
+    }
+
+    fn settlement_engine_asset_scale(&self) -> Option<u8> {
+        self.settlement_engine_asset_scale
+    }
+}
+
 impl SettlementAccount for Account {
     fn settlement_engine_details(&self) -> Option<SettlementEngineDetails> {
         match (
@@ -482,6 +669,7 @@ mod redis_account {
     lazy_static! {
         static ref ACCOUNT_DETAILS: AccountDetails = AccountDetails {
             ilp_address: Address::from_str("example.alice").unwrap(),
+            username: Some("Alice".to_string()),
             asset_scale: 6,
             asset_code: "XYZ".to_string(),
             max_packet_amount: 1000,
@@ -496,6 +684,14 @@ mod redis_account {
             send_routes: true,
             receive_routes: true,
             routing_relation: Some("Peer".to_string()),
+            allowed_route_prefixes: None,
+            route_import_deny: None,
+            route_import_max_path_length: None,
+            route_export_allow: None,
+            route_export_deny: None,
+            route_export_max_path_length: None,
+            advertise_parent_routes: None,
+            route_cost: None,
             round_trip_time: Some(600),
             amount_per_minute_limit: None,
             packets_per_minute_limit: None,
@@ -514,5 +710,46 @@ mod redis_account {
         );
         assert_eq!(account.get_btp_token().unwrap(), b"btp_token");
         assert_eq!(account.routing_relation(), RoutingRelation::Peer);
+        assert_eq!(account.username, Some("alice".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_username() {
+        let mut details = ACCOUNT_DETAILS.clone();
+        details.username = Some("alice/bob".to_string());
+        assert!(Account::try_from(10, details).is_err());
+    }
+
+    #[test]
+    fn builds_route_policies() {
+        let mut details = ACCOUNT_DETAILS.clone();
+        details.allowed_route_prefixes = Some(vec!["example.alice".to_string()]);
+        details.route_import_max_path_length = Some(3);
+        details.route_export_deny = Some(vec!["example.private".to_string()]);
+        let account = Account::try_from(10, details).unwrap();
+        assert_eq!(
+            account.route_import_policy(),
+            RoutePolicy {
+                allow: Some(vec![Bytes::from("example.alice")]),
+                deny: Vec::new(),
+                max_path_length: Some(3),
+            }
+        );
+        assert_eq!(
+            account.route_export_policy(),
+            RoutePolicy {
+                allow: None,
+                deny: vec![Bytes::from("example.private")],
+                max_path_length: None,
+            }
+        );
+        assert!(account.should_advertise_parent_routes());
+    }
+
+    #[test]
+    fn rejects_invalid_route_prefixes() {
+        let mut details = ACCOUNT_DETAILS.clone();
+        details.route_export_allow = Some(vec!["not a prefix".to_string()]);
+        assert!(Account::try_from(10, details).is_err());
     }
 }
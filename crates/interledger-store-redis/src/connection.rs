@@ -0,0 +1,1239 @@
+use futures::{
+    future::{err, join_all, loop_fn, ok, result, Either, Loop, Shared},
+    Future,
+};
+use log::{debug, error, warn};
+use parking_lot::{Mutex, RwLock};
+use redis::{
+    cmd,
+    r#async::{ConnectionLike, SharedConnection},
+    Client, ConnectionAddr, ConnectionInfo, ErrorKind, RedisError, RedisFuture, Value,
+};
+use std::{collections::HashMap, ops::Range, str, sync::Arc};
+
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// A reconnect (or reload of the cluster's slots) that every request that failed on the
+/// old connection waits for, so they do not each start their own
+type Reconnect<T> = Shared<Box<dyn Future<Item = T, Error = RedisError> + Send>>;
+
+/// The Redis servers the store uses
+#[derive(Clone, Debug)]
+pub enum Servers {
+    Single(ConnectionInfo),
+    /// The master that Redis Sentinel knows by `master_name`. The database and password
+    /// used for the master are the ones in `master`
+    Sentinel {
+        sentinels: Vec<ConnectionInfo>,
+        master_name: String,
+        master: ConnectionInfo,
+    },
+    /// A Redis Cluster, discovered through any of these nodes
+    Cluster(Vec<ConnectionInfo>),
+}
+
+/// A connection that can be cloned and shared like redis' `SharedConnection`, but which
+/// reconnects when it is lost instead of failing every request from then on.
+///
+/// Requests that fail because the connection was lost are not sent again, because
+/// Redis may have executed them already. Requests that Redis rejected without executing
+/// (because a Sentinel failover turned the master into a replica, or because a Cluster
+/// slot moved to another node) are retried once on the new connection. Requests that
+/// fail while a reconnect is underway wait for it instead of starting another one.
+#[derive(Clone)]
+pub enum RedisConnection {
+    Direct(Arc<DirectConnection>),
+    Cluster(Arc<ClusterConnection>),
+}
+
+impl RedisConnection {
+    pub fn connect(servers: Servers) -> impl Future<Item = Self, Error = RedisError> {
+        match servers {
+            Servers::Cluster(nodes) => Either::A(ClusterConnection::load_state(nodes.clone()).map(
+                move |state| {
+                    RedisConnection::Cluster(Arc::new(ClusterConnection {
+                        nodes,
+                        state: RwLock::new((0, state)),
+                        reload: Mutex::new(None),
+                    }))
+                },
+            )),
+            servers => Either::B(open(&servers).map(move |connection| {
+                RedisConnection::Direct(Arc::new(DirectConnection {
+                    servers,
+                    connection: RwLock::new((0, connection)),
+                    reconnect: Mutex::new(None),
+                }))
+            })),
+        }
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command(self, packed: Vec<u8>) -> RedisFuture<(Self, Value)> {
+        let future = match self {
+            RedisConnection::Direct(ref direct) => {
+                DirectConnection::request(direct.clone(), move |connection| {
+                    connection.req_packed_command(packed.clone())
+                })
+            }
+            RedisConnection::Cluster(ref cluster) => {
+                ClusterConnection::request(cluster.clone(), move |cluster| {
+                    ClusterConnection::send_command(cluster, packed.clone())
+                })
+            }
+        };
+        Box::new(future.map(move |value| (self, value)))
+    }
+
+    fn req_packed_commands(
+        self,
+        packed: Vec<u8>,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<(Self, Vec<Value>)> {
+        let future = match self {
+            RedisConnection::Direct(ref direct) => {
+                DirectConnection::request(direct.clone(), move |connection| {
+                    connection.req_packed_commands(packed.clone(), offset, count)
+                })
+            }
+            RedisConnection::Cluster(ref cluster) => {
+                ClusterConnection::request(cluster.clone(), move |cluster| {
+                    ClusterConnection::send_commands(cluster, packed.clone(), offset, count)
+                })
+            }
+        };
+        Box::new(future.map(move |values| (self, values)))
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Direct(ref direct) => match direct.servers {
+                Servers::Single(ref info) => info.db,
+                Servers::Sentinel { ref master, .. } => master.db,
+                Servers::Cluster(_) => 0,
+            },
+            // Redis Cluster only has database 0
+            RedisConnection::Cluster(_) => 0,
+        }
+    }
+}
+
+/// A connection to a single server or to the master found through Sentinel
+pub struct DirectConnection {
+    servers: Servers,
+    /// The current connection and the number of times it was replaced
+    connection: RwLock<(u64, SharedConnection)>,
+    reconnect: Mutex<Option<Reconnect<SharedConnection>>>,
+}
+
+impl DirectConnection {
+    fn request<T, F, R>(direct: Arc<DirectConnection>, request: F) -> RedisFuture<T>
+    where
+        F: Fn(SharedConnection) -> R + Send + 'static,
+        R: Future<Item = (SharedConnection, T), Error = RedisError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (generation, connection) = direct.connection.read().clone();
+        Box::new(
+            request(connection)
+                .map(|(_connection, value)| value)
+                .or_else(move |error| {
+                    let retry = is_readonly_error(&error);
+                    if !retry && !is_connection_error(&error) {
+                        return Either::A(err(error));
+                    }
+                    Either::B(
+                        DirectConnection::reconnect(&direct, generation, &error).then(
+                            move |result| match result {
+                                Ok(connection) if retry => {
+                                    Either::A(request(connection).map(|(_connection, value)| value))
+                                }
+                                _ => Either::B(err(error)),
+                            },
+                        ),
+                    )
+                }),
+        )
+    }
+
+    /// Replace the connection that the failed request used, unless that already happened,
+    /// joining the reconnect that is underway if there is one
+    fn reconnect(
+        direct: &Arc<DirectConnection>,
+        failed_generation: u64,
+        error: &RedisError,
+    ) -> impl Future<Item = SharedConnection, Error = ()> {
+        let mut reconnect = direct.reconnect.lock();
+        {
+            let current = direct.connection.read();
+            if current.0 != failed_generation {
+                return Either::A(ok(current.1.clone()));
+            }
+        }
+        let shared = match *reconnect {
+            Some(ref shared) => shared.clone(),
+            None => {
+                warn!("Reconnecting to Redis after error: {:?}", error);
+                let direct_clone = direct.clone();
+                let future: Box<dyn Future<Item = SharedConnection, Error = RedisError> + Send> =
+                    Box::new(open(&direct.servers).then(move |result| {
+                        *direct_clone.reconnect.lock() = None;
+                        match result {
+                            Ok(connection) => {
+                                debug!("Reconnected to Redis");
+                                let mut current = direct_clone.connection.write();
+                                *current = (current.0 + 1, connection.clone());
+                                Ok(connection)
+                            }
+                            Err(error) => {
+                                error!("Error reconnecting to Redis: {:?}", error);
+                                Err(error)
+                            }
+                        }
+                    }));
+                let shared = future.shared();
+                *reconnect = Some(shared.clone());
+                shared
+            }
+        };
+        Either::B(
+            shared
+                .map(|connection| (*connection).clone())
+                .map_err(|_| ()),
+        )
+    }
+}
+
+/// A connection to each master of a Redis Cluster. Commands are sent to the node that
+/// serves the slot of their first key, and pipelines with keys on several nodes are
+/// split into one pipeline per node. Transactions are never split: they fail unless all
+/// of their keys are in one slot. Commands that a node redirects with ASK, because their
+/// slot is being migrated, are sent to the node the slot is migrated to
+pub struct ClusterConnection {
+    nodes: Vec<ConnectionInfo>,
+    /// The current slots and connections, and the number of times they were reloaded
+    state: RwLock<(u64, ClusterState)>,
+    reload: Mutex<Option<Reconnect<()>>>,
+}
+
+struct ClusterState {
+    /// The node serving each range of slots
+    slots: Vec<(Range<u16>, String)>,
+    connections: HashMap<String, SharedConnection>,
+}
+
+impl ClusterConnection {
+    fn request<T, F, R>(cluster: Arc<ClusterConnection>, request: F) -> RedisFuture<T>
+    where
+        F: Fn(&Arc<ClusterConnection>) -> R + Send + 'static,
+        R: Future<Item = T, Error = RedisError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let generation = cluster.state.read().0;
+        Box::new(request(&cluster).or_else(move |error| {
+            let retry = is_moved_error(&error);
+            if !retry && !is_connection_error(&error) {
+                return Either::A(err(error));
+            }
+            Either::B(
+                ClusterConnection::reload(&cluster, generation, &error).then(move |result| {
+                    match result {
+                        Ok(_) if retry => Either::A(request(&cluster)),
+                        _ => Either::B(err(error)),
+                    }
+                }),
+            )
+        }))
+    }
+
+    /// Reload the slots and connections that the failed request used, unless that already
+    /// happened, joining the reload that is underway if there is one
+    fn reload(
+        cluster: &Arc<ClusterConnection>,
+        failed_generation: u64,
+        error: &RedisError,
+    ) -> impl Future<Item = (), Error = ()> {
+        let mut reload = cluster.reload.lock();
+        if cluster.state.read().0 != failed_generation {
+            return Either::A(ok(()));
+        }
+        let shared = match *reload {
+            Some(ref shared) => shared.clone(),
+            None => {
+                warn!("Reloading Redis Cluster slots after error: {:?}", error);
+                let cluster_clone = cluster.clone();
+                let future: Box<dyn Future<Item = (), Error = RedisError> + Send> = Box::new(
+                    ClusterConnection::load_state(cluster.nodes.clone()).then(move |result| {
+                        *cluster_clone.reload.lock() = None;
+                        match result {
+                            Ok(state) => {
+                                let mut current = cluster_clone.state.write();
+                                *current = (current.0 + 1, state);
+                                Ok(())
+                            }
+                            Err(error) => {
+                                error!("Error reloading Redis Cluster slots: {:?}", error);
+                                Err(error)
+                            }
+                        }
+                    }),
+                );
+                let shared = future.shared();
+                *reload = Some(shared.clone());
+                shared
+            }
+        };
+        Either::B(shared.map(|_| ()).map_err(|_| ()))
+    }
+
+    /// Ask the nodes in turn which node serves each slot, and connect to all of them
+    fn load_state(
+        nodes: Vec<ConnectionInfo>,
+    ) -> impl Future<Item = ClusterState, Error = RedisError> {
+        let passwd = nodes.first().and_then(|node| node.passwd.clone());
+        loop_fn(0, move |i| {
+            let node = match nodes.get(i) {
+                Some(node) => node.clone(),
+                None => {
+                    return Either::A(err(RedisError::from((
+                        ErrorKind::IoError,
+                        "No Redis Cluster node returned the cluster slots",
+                    ))))
+                }
+            };
+            Either::B(
+                connect_to(node.clone())
+                    .and_then(|connection| cmd("CLUSTER").arg("SLOTS").query_async(connection))
+                    .then(
+                        move |result: Result<(SharedConnection, Value), RedisError>| match result
+                            .map(|(_connection, slots)| parse_slots(slots))
+                        {
+                            Ok(Some(slots)) => Ok(Loop::Break(slots)),
+                            Ok(None) => {
+                                warn!("Invalid CLUSTER SLOTS reply from Redis node {:?}", node);
+                                Ok(Loop::Continue(i + 1))
+                            }
+                            Err(error) => {
+                                warn!(
+                                    "Error getting slots from Redis node {:?}: {:?}",
+                                    node, error
+                                );
+                                Ok(Loop::Continue(i + 1))
+                            }
+                        },
+                    ),
+            )
+        })
+        .and_then(move |slots: Vec<(Range<u16>, String, u16)>| {
+            let mut nodes: HashMap<String, ConnectionInfo> = HashMap::new();
+            for (_, host, port) in slots.iter() {
+                nodes
+                    .entry(format!("{}:{}", host, port))
+                    .or_insert_with(|| ConnectionInfo {
+                        addr: Box::new(ConnectionAddr::Tcp(host.clone(), *port)),
+                        db: 0,
+                        passwd: passwd.clone(),
+                    });
+            }
+            join_all(
+                nodes
+                    .into_iter()
+                    .map(|(name, node)| connect_to(node).map(move |connection| (name, connection))),
+            )
+            .map(move |connections| ClusterState {
+                slots: slots
+                    .into_iter()
+                    .map(|(range, host, port)| (range, format!("{}:{}", host, port)))
+                    .collect(),
+                connections: connections.into_iter().collect(),
+            })
+        })
+    }
+
+    /// The connection to the node serving the key's slot, or to any node for commands
+    /// without a key
+    fn connection_for(&self, key: Option<&[u8]>) -> Result<(String, SharedConnection), RedisError> {
+        let guard = self.state.read();
+        let state = &guard.1;
+        let node = match key {
+            Some(key) => {
+                let slot = hash_slot(key);
+                state
+                    .slots
+                    .iter()
+                    .find(|(range, _)| range.contains(&slot))
+                    .map(|(_, node)| node)
+            }
+            None => state.slots.first().map(|(_, node)| node),
+        };
+        node.and_then(|node| {
+            state
+                .connections
+                .get(node)
+                .map(|connection| (node.clone(), connection.clone()))
+        })
+        .ok_or_else(|| {
+            RedisError::from((
+                ErrorKind::IoError,
+                "No Redis Cluster node serves the slot of the key",
+            ))
+        })
+    }
+
+    /// The connection to the node with the given `<host>:<port>`, which is opened if the
+    /// node does not serve any slots yet
+    fn connection_to(
+        cluster: &Arc<ClusterConnection>,
+        node: &str,
+    ) -> impl Future<Item = SharedConnection, Error = RedisError> {
+        if let Some(connection) = cluster.state.read().1.connections.get(node) {
+            return Either::A(ok(connection.clone()));
+        }
+        let mut parts = node.rsplitn(2, ':');
+        let (port, host) = match (
+            parts.next().and_then(|port| port.parse().ok()),
+            parts.next(),
+        ) {
+            (Some(port), Some(host)) => (port, host.to_string()),
+            _ => {
+                return Either::A(err(RedisError::from((
+                    ErrorKind::TypeError,
+                    "Invalid Redis Cluster node address",
+                ))))
+            }
+        };
+        let info = ConnectionInfo {
+            addr: Box::new(ConnectionAddr::Tcp(host, port)),
+            db: 0,
+            passwd: cluster.nodes.first().and_then(|node| node.passwd.clone()),
+        };
+        let cluster = cluster.clone();
+        let node = node.to_string();
+        Either::B(connect_to(info).map(move |connection| {
+            cluster
+                .state
+                .write()
+                .1
+                .connections
+                .insert(node, connection.clone());
+            connection
+        }))
+    }
+
+    /// Send the commands to one node. If the slot of their keys is being migrated and the
+    /// keys were already moved, the node redirects them with ASK and they are sent to the
+    /// node the slot is migrated to, preceded by ASKING. The slots are not reloaded,
+    /// because the old node keeps serving the slot until the migration is done
+    fn send_to_node(
+        cluster: &Arc<ClusterConnection>,
+        connection: SharedConnection,
+        packed: Vec<u8>,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<Vec<Value>> {
+        let cluster = cluster.clone();
+        Box::new(
+            connection
+                .req_packed_commands(packed.clone(), offset, count)
+                .map(|(_connection, values)| values)
+                .or_else(move |error| {
+                    let node = match ask_redirect(&error) {
+                        Some(node) => node,
+                        None => return Either::A(err(error)),
+                    };
+                    let (asking, replies) = match with_asking(&packed) {
+                        Some(asking) => asking,
+                        None => return Either::A(err(error)),
+                    };
+                    debug!("Following ASK redirect to Redis Cluster node {}", node);
+                    let num_replies = replies.len();
+                    Either::B(
+                        ClusterConnection::connection_to(&cluster, &node)
+                            .and_then(move |connection| {
+                                connection.req_packed_commands(asking, 0, num_replies)
+                            })
+                            .map(move |(_connection, values)| {
+                                values
+                                    .into_iter()
+                                    .zip(replies)
+                                    .filter(|(_value, original)| *original)
+                                    .map(|(value, _original)| value)
+                                    .skip(offset)
+                                    .take(count)
+                                    .collect()
+                            }),
+                    )
+                }),
+        )
+    }
+
+    fn send_command(cluster: &Arc<ClusterConnection>, packed: Vec<u8>) -> RedisFuture<Value> {
+        let key = parse_commands(&packed)
+            .and_then(|commands| commands.into_iter().next())
+            .and_then(|command| command.key);
+        match cluster.connection_for(key.as_ref().map(|key| &key[..])) {
+            Ok((_node, connection)) => Box::new(
+                ClusterConnection::send_to_node(cluster, connection, packed, 0, 1).and_then(
+                    |values| {
+                        values.into_iter().next().ok_or_else(|| {
+                            RedisError::from((ErrorKind::TypeError, "Missing reply from Redis"))
+                        })
+                    },
+                ),
+            ),
+            Err(error) => Box::new(err(error)),
+        }
+    }
+
+    fn send_commands(
+        cluster: &Arc<ClusterConnection>,
+        packed: Vec<u8>,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<Vec<Value>> {
+        let commands = match parse_commands(&packed) {
+            Some(commands) => commands,
+            None => {
+                return Box::new(err(RedisError::from((
+                    ErrorKind::TypeError,
+                    "Unable to parse the commands of the pipeline",
+                ))))
+            }
+        };
+        // Transactions are sent as MULTI, the commands and EXEC, and only EXEC's reply is read
+        let transaction = commands.len() >= 2
+            && commands[0].name == b"MULTI"
+            && commands[commands.len() - 1].name == b"EXEC"
+            && offset == commands.len() - 1
+            && count == 1;
+        if transaction {
+            // Splitting a transaction between nodes would make it only atomic on each node,
+            // so all of its keys have to be in one slot
+            let mut keys = commands.iter().filter_map(|command| command.key.as_ref());
+            let key = keys.next().cloned();
+            if let Some(ref key) = key {
+                let slot = hash_slot(key);
+                if keys.any(|other| hash_slot(other) != slot) {
+                    return Box::new(err(RedisError::from((
+                        ErrorKind::InvalidClientConfig,
+                        "Transaction uses keys in several Redis Cluster slots",
+                    ))));
+                }
+            }
+            return match cluster.connection_for(key.as_ref().map(|key| &key[..])) {
+                Ok((_node, connection)) => {
+                    ClusterConnection::send_to_node(cluster, connection, packed, offset, count)
+                }
+                Err(error) => Box::new(err(error)),
+            };
+        }
+
+        let mut groups: Vec<(String, SharedConnection, Vec<usize>)> = Vec::new();
+        for (i, command) in commands.iter().enumerate() {
+            let (node, connection) =
+                match cluster.connection_for(command.key.as_ref().map(|key| &key[..])) {
+                    Ok(node) => node,
+                    Err(error) => return Box::new(err(error)),
+                };
+            match groups.iter_mut().find(|group| group.0 == node) {
+                Some(group) => group.2.push(i),
+                None => groups.push((node, connection, vec![i])),
+            }
+        }
+
+        if groups.len() <= 1 {
+            let connection = match groups.pop() {
+                Some((_node, connection, _)) => connection,
+                None => match cluster.connection_for(None) {
+                    Ok((_node, connection)) => connection,
+                    Err(error) => return Box::new(err(error)),
+                },
+            };
+            return ClusterConnection::send_to_node(cluster, connection, packed, offset, count);
+        }
+        if offset != 0 {
+            return Box::new(err(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "Unable to split the pipeline between the Redis Cluster nodes",
+            ))));
+        }
+
+        // Pipelines that are not transactions are not atomic anyway, so their commands
+        // can be sent to each node separately
+        let num_commands = commands.len();
+        let requests: Vec<_> = groups
+            .into_iter()
+            .map(|(_node, connection, indices)| {
+                let mut group_packed = Vec::new();
+                for i in indices.iter() {
+                    group_packed.extend_from_slice(&packed[commands[*i].range.clone()]);
+                }
+                let num_group_commands = indices.len();
+                ClusterConnection::send_to_node(
+                    cluster,
+                    connection,
+                    group_packed,
+                    0,
+                    num_group_commands,
+                )
+                .map(move |values| (indices, values))
+            })
+            .collect();
+        Box::new(join_all(requests).map(move |groups| {
+            let mut values = vec![Value::Nil; num_commands];
+            for (indices, group_values) in groups {
+                for (i, value) in indices.into_iter().zip(group_values) {
+                    values[i] = value;
+                }
+            }
+            values
+        }))
+    }
+}
+
+fn open(servers: &Servers) -> Box<dyn Future<Item = SharedConnection, Error = RedisError> + Send> {
+    match servers {
+        Servers::Single(info) => Box::new(connect_to(info.clone())),
+        Servers::Sentinel {
+            sentinels,
+            master_name,
+            master,
+        } => Box::new(
+            discover_master(sentinels.clone(), master_name.clone(), master.clone())
+                .and_then(connect_to),
+        ),
+        Servers::Cluster(_) => Box::new(err(RedisError::from((
+            ErrorKind::InvalidClientConfig,
+            "Redis Cluster nodes cannot be used as a single server",
+        )))),
+    }
+}
+
+fn connect_to(info: ConnectionInfo) -> impl Future<Item = SharedConnection, Error = RedisError> {
+    result(Client::open(info)).and_then(|client| {
+        debug!("Connecting to Redis: {:?}", client);
+        client.get_shared_async_connection()
+    })
+}
+
+/// Ask the sentinels in turn for the address of the master
+fn discover_master(
+    sentinels: Vec<ConnectionInfo>,
+    master_name: String,
+    master: ConnectionInfo,
+) -> impl Future<Item = ConnectionInfo, Error = RedisError> {
+    loop_fn(0, move |i| {
+        let sentinel = match sentinels.get(i) {
+            // Sentinels do not have databases to select
+            Some(sentinel) => ConnectionInfo {
+                addr: sentinel.addr.clone(),
+                db: 0,
+                passwd: sentinel.passwd.clone(),
+            },
+            None => {
+                return Either::A(err(RedisError::from((
+                    ErrorKind::IoError,
+                    "No Redis Sentinel returned the address of the master",
+                ))))
+            }
+        };
+        let master_name = master_name.clone();
+        let master = master.clone();
+        Either::B(
+            connect_to(sentinel.clone())
+                .and_then(move |connection| {
+                    cmd("SENTINEL")
+                        .arg("get-master-addr-by-name")
+                        .arg(master_name)
+                        .query_async(connection)
+                })
+                .then(
+                    move |result: Result<(SharedConnection, Option<(String, u16)>), RedisError>| {
+                        match result {
+                            Ok((_connection, Some((host, port)))) => {
+                                debug!(
+                                    "Redis Sentinel {:?} returned master {}:{}",
+                                    sentinel.addr, host, port
+                                );
+                                Ok(Loop::Break(ConnectionInfo {
+                                    addr: Box::new(ConnectionAddr::Tcp(host, port)),
+                                    db: master.db,
+                                    passwd: master.passwd,
+                                }))
+                            }
+                            Ok((_connection, None)) => {
+                                warn!(
+                                    "Redis Sentinel {:?} does not know the master",
+                                    sentinel.addr
+                                );
+                                Ok(Loop::Continue(i + 1))
+                            }
+                            Err(error) => {
+                                warn!(
+                                    "Error asking Redis Sentinel {:?} for the master: {:?}",
+                                    sentinel.addr, error
+                                );
+                                Ok(Loop::Continue(i + 1))
+                            }
+                        }
+                    },
+                ),
+        )
+    })
+}
+
+fn is_connection_error(error: &RedisError) -> bool {
+    error.kind() == ErrorKind::IoError
+}
+
+/// The server is a replica, which happens to the old master after a Sentinel failover
+fn is_readonly_error(error: &RedisError) -> bool {
+    error.extension_error_code() == Some("READONLY")
+}
+
+/// The key's slot is served by another node. Transactions with such keys are aborted
+fn is_moved_error(error: &RedisError) -> bool {
+    match error.extension_error_code() {
+        Some("MOVED") => true,
+        _ => error.kind() == ErrorKind::ExecAbortError,
+    }
+}
+
+/// The `<host>:<port>` of the node that the key's slot is being migrated to, if the key
+/// was already moved there and the node redirected the command with ASK
+fn ask_redirect(error: &RedisError) -> Option<String> {
+    if error.extension_error_code() != Some("ASK") {
+        return None;
+    }
+    // The detail is "<slot> <host>:<port>"
+    error
+        .detail()?
+        .split_whitespace()
+        .nth(1)
+        .map(|node| node.to_string())
+}
+
+/// Put ASKING before the packed commands, so that the node a slot is being migrated to
+/// accepts them. Within a transaction, the ASKING before MULTI covers every command.
+/// Also returns whether each reply is one of the commands' replies, or ASKING's
+fn with_asking(packed: &[u8]) -> Option<(Vec<u8>, Vec<bool>)> {
+    let mut asking = Vec::with_capacity(packed.len());
+    let mut replies = Vec::new();
+    let mut in_transaction = false;
+    for command in parse_commands(packed)? {
+        if !in_transaction {
+            asking.extend_from_slice(b"*1\r\n$6\r\nASKING\r\n");
+            replies.push(false);
+        }
+        match command.name.as_slice() {
+            b"MULTI" => in_transaction = true,
+            b"EXEC" | b"DISCARD" => in_transaction = false,
+            _ => {}
+        }
+        asking.extend_from_slice(&packed[command.range]);
+        replies.push(true);
+    }
+    Some((asking, replies))
+}
+
+/// Parse the `(start, end, [host, port, ...], replicas...)` entries of CLUSTER SLOTS
+fn parse_slots(value: Value) -> Option<Vec<(Range<u16>, String, u16)>> {
+    let ranges = match value {
+        Value::Bulk(ranges) => ranges,
+        _ => return None,
+    };
+    ranges
+        .into_iter()
+        .map(|range| {
+            let mut items = match range {
+                Value::Bulk(items) => items.into_iter(),
+                _ => return None,
+            };
+            let start = match items.next()? {
+                Value::Int(start) => start as u16,
+                _ => return None,
+            };
+            let end = match items.next()? {
+                Value::Int(end) => end as u16,
+                _ => return None,
+            };
+            let mut master = match items.next()? {
+                Value::Bulk(master) => master.into_iter(),
+                _ => return None,
+            };
+            let host = match master.next()? {
+                Value::Data(host) => String::from_utf8(host).ok()?,
+                _ => return None,
+            };
+            let port = match master.next()? {
+                Value::Int(port) => port as u16,
+                _ => return None,
+            };
+            Some((start..end + 1, host, port))
+        })
+        .collect()
+}
+
+struct Command {
+    /// Where the command is in the packed commands
+    range: Range<usize>,
+    name: Vec<u8>,
+    key: Option<Vec<u8>>,
+}
+
+/// Split packed commands, which are arrays of bulk strings, and find their keys
+fn parse_commands(packed: &[u8]) -> Option<Vec<Command>> {
+    let mut commands = Vec::new();
+    let mut position = 0;
+    while position < packed.len() {
+        let start = position;
+        let (num_args, next) = parse_header(packed, position, b'*')?;
+        position = next;
+        let mut args: Vec<&[u8]> = Vec::with_capacity(num_args);
+        for _ in 0..num_args {
+            let (len, next) = parse_header(packed, position, b'$')?;
+            let end = next.checked_add(len)?;
+            args.push(packed.get(next..end)?);
+            if packed.get(end..end + 2)? != b"\r\n" {
+                return None;
+            }
+            position = end + 2;
+        }
+        let name = args.first()?.to_ascii_uppercase();
+        let key = command_key(&name, &args).map(|key| key.to_vec());
+        commands.push(Command {
+            range: start..position,
+            name,
+            key,
+        });
+    }
+    Some(commands)
+}
+
+/// Parse a `<marker><number>\r\n` header, returning the number and the position after it
+fn parse_header(packed: &[u8], position: usize, marker: u8) -> Option<(usize, usize)> {
+    if *packed.get(position)? != marker {
+        return None;
+    }
+    let end = position
+        + 1
+        + packed
+            .get(position + 1..)?
+            .iter()
+            .position(|b| *b == b'\r')?;
+    let number = str::from_utf8(&packed[position + 1..end])
+        .ok()?
+        .parse()
+        .ok()?;
+    if packed.get(end + 1) != Some(&b'\n') {
+        return None;
+    }
+    Some((number, end + 2))
+}
+
+fn command_key<'a>(name: &[u8], args: &[&'a [u8]]) -> Option<&'a [u8]> {
+    match name {
+        b"EVAL" | b"EVALSHA" => {
+            let num_keys: usize = str::from_utf8(args.get(2)?).ok()?.parse().ok()?;
+            if num_keys > 0 {
+                args.get(3).cloned()
+            } else {
+                None
+            }
+        }
+        // The keys the store scans for share a hash tag, which is in the pattern too
+        b"SCAN" => args
+            .iter()
+            .position(|arg| arg.eq_ignore_ascii_case(b"MATCH"))
+            .and_then(|i| args.get(i + 1))
+            .cloned(),
+        b"MULTI" | b"EXEC" | b"DISCARD" | b"PING" | b"INFO" | b"CLUSTER" | b"SCRIPT" => None,
+        _ => args.get(1).cloned(),
+    }
+}
+
+/// The Redis Cluster slot of the key, which only hashes the key's hash tag if it has one
+fn hash_slot(key: &[u8]) -> u16 {
+    let key = key
+        .iter()
+        .position(|b| *b == b'{')
+        .and_then(|open| {
+            let len = key[open + 1..].iter().position(|b| *b == b'}')?;
+            if len > 0 {
+                Some(&key[open + 1..open + 1 + len])
+            } else {
+                None
+            }
+        })
+        .unwrap_or(key);
+    crc16(key) % CLUSTER_SLOTS
+}
+
+/// CRC16-CCITT (XMODEM), as used by Redis Cluster
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+    use tokio::runtime::Runtime;
+
+    /// A Redis server that answers each command with the reply the handler returns for
+    /// it, or closes the connection if the handler returns None. The handler also gets
+    /// the number of the connection the command came on
+    struct FakeServer {
+        info: ConnectionInfo,
+        commands: Arc<Mutex<Vec<Vec<u8>>>>,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl FakeServer {
+        fn start<F>(handler: F) -> Self
+        where
+            F: Fn(usize, &Command) -> Option<String> + Send + Sync + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let commands = Arc::new(Mutex::new(Vec::new()));
+            let connections = Arc::new(AtomicUsize::new(0));
+            let handler = Arc::new(handler);
+            let commands_clone = commands.clone();
+            let connections_clone = connections.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let connection = connections_clone.fetch_add(1, Ordering::SeqCst);
+                    let handler = handler.clone();
+                    let commands = commands_clone.clone();
+                    thread::spawn(move || {
+                        let mut buffer = Vec::new();
+                        let mut chunk = [0; 4096];
+                        loop {
+                            match stream.read(&mut chunk) {
+                                Ok(0) | Err(_) => return,
+                                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                            }
+                            // Wait for the rest of the commands if they are incomplete
+                            let parsed = match parse_commands(&buffer) {
+                                Some(parsed) => parsed,
+                                None => continue,
+                            };
+                            buffer.clear();
+                            for command in parsed {
+                                commands.lock().push(command.name.clone());
+                                match handler(connection, &command) {
+                                    Some(reply) => {
+                                        let _ = stream.write_all(reply.as_bytes());
+                                    }
+                                    None => return,
+                                }
+                            }
+                        }
+                    });
+                }
+            });
+            FakeServer {
+                info: ConnectionInfo {
+                    addr: Box::new(ConnectionAddr::Tcp("127.0.0.1".to_string(), port)),
+                    db: 0,
+                    passwd: None,
+                },
+                commands,
+                connections,
+            }
+        }
+
+        fn port(&self) -> u16 {
+            match *self.info.addr {
+                ConnectionAddr::Tcp(_, port) => port,
+                _ => unreachable!(),
+            }
+        }
+
+        fn received(&self, name: &[u8]) -> usize {
+            self.commands
+                .lock()
+                .iter()
+                .filter(|command| command.as_slice() == name)
+                .count()
+        }
+    }
+
+    #[test]
+    fn concurrent_failures_share_one_reconnect() {
+        // The first connection is closed as soon as a command arrives
+        let server = FakeServer::start(|connection, _command| {
+            if connection == 0 {
+                None
+            } else {
+                Some("+PONG\r\n".to_string())
+            }
+        });
+        let mut runtime = Runtime::new().unwrap();
+        let connection = runtime
+            .block_on(RedisConnection::connect(Servers::Single(
+                server.info.clone(),
+            )))
+            .unwrap();
+
+        let pings: Vec<_> = (0..3)
+            .map(|_| {
+                cmd("PING").query_async(connection.clone()).then(
+                    |result: Result<(RedisConnection, String), RedisError>| Ok::<_, ()>(result),
+                )
+            })
+            .collect();
+        let results = runtime.block_on(join_all(pings)).unwrap();
+        // The requests may have been executed, so they are not sent again
+        assert!(results.iter().all(|result| result.is_err()));
+
+        let (_connection, pong): (_, String) = runtime
+            .block_on(cmd("PING").query_async(connection))
+            .unwrap();
+        assert_eq!(pong, "PONG");
+        assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn retries_on_the_new_master_after_a_sentinel_failover() {
+        let old_master = FakeServer::start(|_connection, command| {
+            Some(if command.name == b"SET" {
+                "-READONLY You can't write against a read only replica.\r\n".to_string()
+            } else {
+                "+OK\r\n".to_string()
+            })
+        });
+        let new_master = FakeServer::start(|_connection, _command| Some("+OK\r\n".to_string()));
+        let ports = (old_master.port(), new_master.port());
+        let asked = Arc::new(AtomicUsize::new(0));
+        let asked_clone = asked.clone();
+        // The sentinel returns the new master once the old one has been demoted
+        let sentinel = FakeServer::start(move |_connection, _command| {
+            let port = if asked_clone.fetch_add(1, Ordering::SeqCst) == 0 {
+                ports.0
+            } else {
+                ports.1
+            };
+            let port = port.to_string();
+            Some(format!(
+                "*2\r\n$9\r\n127.0.0.1\r\n${}\r\n{}\r\n",
+                port.len(),
+                port
+            ))
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let connection = runtime
+            .block_on(RedisConnection::connect(Servers::Sentinel {
+                sentinels: vec![sentinel.info.clone()],
+                master_name: "master".to_string(),
+                master: old_master.info.clone(),
+            }))
+            .unwrap();
+        let (_connection, reply): (_, String) = runtime
+            .block_on(cmd("SET").arg("key").arg("value").query_async(connection))
+            .unwrap();
+        assert_eq!(reply, "OK");
+        assert_eq!(old_master.received(b"SET"), 1);
+        assert_eq!(new_master.received(b"SET"), 1);
+        assert_eq!(asked.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn follows_cluster_slots_that_moved() {
+        let new_node = FakeServer::start(|_connection, _command| Some("$-1\r\n".to_string()));
+        let new_port = new_node.port();
+        let old_port = Arc::new(AtomicUsize::new(0));
+        let old_port_clone = old_port.clone();
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let reloads_clone = reloads.clone();
+        let old_node = FakeServer::start(move |_connection, command| {
+            if command.name == b"CLUSTER" {
+                // The slots move to the new node after the first CLUSTER SLOTS
+                let port = if reloads_clone.fetch_add(1, Ordering::SeqCst) == 0 {
+                    old_port_clone.load(Ordering::SeqCst)
+                } else {
+                    new_port as usize
+                };
+                Some(format!(
+                    "*1\r\n*3\r\n:0\r\n:16383\r\n*2\r\n$9\r\n127.0.0.1\r\n:{}\r\n",
+                    port
+                ))
+            } else {
+                Some(format!(
+                    "-MOVED {} 127.0.0.1:{}\r\n",
+                    hash_slot(b"key"),
+                    new_port
+                ))
+            }
+        });
+        old_port.store(old_node.port() as usize, Ordering::SeqCst);
+
+        let mut runtime = Runtime::new().unwrap();
+        let connection = runtime
+            .block_on(RedisConnection::connect(Servers::Cluster(vec![old_node
+                .info
+                .clone()])))
+            .unwrap();
+        let (_connection, value): (_, Option<String>) = runtime
+            .block_on(cmd("GET").arg("key").query_async(connection))
+            .unwrap();
+        assert_eq!(value, None);
+        assert_eq!(old_node.received(b"GET"), 1);
+        assert_eq!(new_node.received(b"GET"), 1);
+        assert_eq!(reloads.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn follows_ask_redirects_without_reloading_slots() {
+        let new_node = FakeServer::start(|_connection, command| {
+            Some(if command.name == b"ASKING" {
+                "+OK\r\n".to_string()
+            } else {
+                "$-1\r\n".to_string()
+            })
+        });
+        let new_port = new_node.port();
+        let old_port = Arc::new(AtomicUsize::new(0));
+        let old_port_clone = old_port.clone();
+        let old_node = FakeServer::start(move |_connection, command| {
+            if command.name == b"CLUSTER" {
+                Some(format!(
+                    "*1\r\n*3\r\n:0\r\n:16383\r\n*2\r\n$9\r\n127.0.0.1\r\n:{}\r\n",
+                    old_port_clone.load(Ordering::SeqCst)
+                ))
+            } else {
+                // The key was already moved to the node the slot is being migrated to
+                Some(format!(
+                    "-ASK {} 127.0.0.1:{}\r\n",
+                    hash_slot(b"key"),
+                    new_port
+                ))
+            }
+        });
+        old_port.store(old_node.port() as usize, Ordering::SeqCst);
+
+        let mut runtime = Runtime::new().unwrap();
+        let connection = runtime
+            .block_on(RedisConnection::connect(Servers::Cluster(vec![old_node
+                .info
+                .clone()])))
+            .unwrap();
+        let (_connection, value): (_, Option<String>) = runtime
+            .block_on(cmd("GET").arg("key").query_async(connection))
+            .unwrap();
+        assert_eq!(value, None);
+        assert_eq!(old_node.received(b"GET"), 1);
+        assert_eq!(new_node.received(b"ASKING"), 1);
+        assert_eq!(new_node.received(b"GET"), 1);
+        assert_eq!(old_node.received(b"CLUSTER"), 1);
+    }
+
+    #[test]
+    fn hash_slots_match_redis_cluster() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(hash_slot(b"foo"), 12182);
+        assert_eq!(hash_slot(b"bar"), 5061);
+        assert_eq!(hash_slot(b"accounts:{1}"), hash_slot(b"1"));
+        assert_eq!(hash_slot(b"prefix:journal:{1}"), hash_slot(b"accounts:{1}"));
+        // Empty hash tags are not used
+        assert_eq!(
+            hash_slot(b"foo{}{bar}"),
+            crc16(b"foo{}{bar}") % CLUSTER_SLOTS
+        );
+    }
+
+    #[test]
+    fn parses_keys_of_packed_commands() {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("HGETALL")
+            .arg("accounts:{1}")
+            .cmd("EVAL")
+            .arg("return 1")
+            .arg(2)
+            .arg("accounts:{2}")
+            .arg("journal:{2}")
+            .cmd("EVAL")
+            .arg("return 1")
+            .arg(0)
+            .cmd("SCAN")
+            .arg(0)
+            .arg("MATCH")
+            .arg("idempotency-key:{idempotency}:*");
+        let packed = pipe.get_packed_pipeline(true);
+        let commands = parse_commands(&packed).unwrap();
+        assert_eq!(commands.len(), 6);
+        assert_eq!(commands[0].name, b"MULTI");
+        assert_eq!(commands[0].key, None);
+        assert_eq!(commands[1].key, Some(b"accounts:{1}".to_vec()));
+        assert_eq!(commands[2].key, Some(b"accounts:{2}".to_vec()));
+        assert_eq!(commands[3].key, None);
+        assert_eq!(
+            commands[4].key,
+            Some(b"idempotency-key:{idempotency}:*".to_vec())
+        );
+        assert_eq!(commands[5].name, b"EXEC");
+        assert_eq!(commands[5].range.end, packed.len());
+        for command in commands.iter() {
+            assert_eq!(packed[command.range.start], b'*');
+        }
+
+        assert!(parse_commands(b"*1\r\n$4\r\nPIN").is_none());
+    }
+
+    #[test]
+    fn refuses_transactions_across_slots() {
+        let cluster = Arc::new(ClusterConnection {
+            nodes: Vec::new(),
+            state: RwLock::new((
+                0,
+                ClusterState {
+                    slots: Vec::new(),
+                    connections: HashMap::new(),
+                },
+            )),
+            reload: Mutex::new(None),
+        });
+        // An account's keys and the keys all accounts share have different hash tags
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("HSET")
+            .arg("{node:1}:accounts")
+            .arg("balance")
+            .arg(0)
+            .cmd("HSET")
+            .arg("{node}:usernames")
+            .arg("alice")
+            .arg(1);
+        let packed = pipe.get_packed_pipeline(true);
+        let error = ClusterConnection::send_commands(&cluster, packed, 3, 1)
+            .wait()
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidClientConfig);
+    }
+}
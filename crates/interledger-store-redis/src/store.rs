@@ -1,27 +1,31 @@
 use super::account::*;
-use super::crypto::generate_keys;
+use super::connection::{RedisConnection, Servers};
 use bytes::Bytes;
 use futures::{
-    future::{err, ok, result, Either},
+    future::{err, join_all, loop_fn, ok, result, Either, Loop},
     Future, Stream,
 };
 use log::{debug, error, trace, warn};
 use std::collections::{HashMap, HashSet};
 
 use http::StatusCode;
-use interledger_api::{AccountDetails, NodeStore};
+use interledger_api::{
+    AccountDetails, AccountState, IdempotentDataState, NodeState, NodeStateStore, NodeStore,
+};
 use interledger_btp::BtpStore;
-use interledger_ccp::RouteManagerStore;
-use interledger_http::HttpStore;
-use interledger_router::RouterStore;
+use interledger_ccp::{RouteManagerStore, RoutingRelation};
+use interledger_http::{HttpForwardingStore, HttpStore};
+use interledger_packet::Address;
+use interledger_router::{AlternateRoute, RouterStore};
 use interledger_service::{Account as AccountTrait, AccountStore};
-use interledger_service_util::{BalanceStore, ExchangeRateStore, RateLimitError, RateLimitStore};
+use interledger_service_util::{
+    journal_timestamp, BalanceStore, ExchangeRateStore, JournalEntry, JournalEntryKind,
+    RateLimitError, RateLimitStore,
+};
 use interledger_settlement::{IdempotentData, IdempotentStore, SettlementStore};
+use interledger_store_crypto::KeyRing;
 use parking_lot::RwLock;
-use redis::{
-    self, cmd, r#async::SharedConnection, Client, ConnectionInfo, PipelineCommands, Value,
-};
-use ring::{aead, hmac};
+use redis::{self, cmd, ConnectionInfo, PipelineCommands, Value};
 use std::{
     iter::FromIterator,
     str,
@@ -31,6 +35,7 @@ use std::{
 };
 use tokio_executor::spawn;
 use tokio_timer::Interval;
+use url::Url;
 
 const DEFAULT_POLL_INTERVAL: u64 = 30000; // 30 seconds
 
@@ -39,16 +44,52 @@ const DEFAULT_POLL_INTERVAL: u64 = 30000; // 30 seconds
 // trips for messages to be sent to and from Redis, as well as locks to ensure no other
 // process is accessing Redis at the same time.
 // For more information on scripting in Redis, see https://redis.io/commands/eval
-static ACCOUNT_FROM_INDEX: &str = "
-local id = redis.call('HGET', KEYS[1], ARGV[1])
-if not id then
-    return nil
+// ARGV[1] is the field the current keys index the token under and the rest are the
+// fields older keys may have indexed it under. Entries found under an older field are
+// moved to the current one. The script only returns the account ID, because the account
+// details may be in another Redis Cluster slot
+static ACCOUNT_ID_FROM_INDEX: &str = "
+for i = 1, #ARGV do
+    local field = ARGV[i]
+    local id = redis.call('HGET', KEYS[1], field)
+    if id then
+        if i > 1 then
+            redis.call('HDEL', KEYS[1], field)
+            redis.call('HSET', KEYS[1], ARGV[1], id)
+        end
+        return id
+    end
 end
-return redis.call('HGETALL', 'accounts:' .. id)";
+return nil";
+
+// Balance-changing scripts take the account's details and journal keys as KEYS[1] and
+// KEYS[2] and append an entry to the journal in the same atomic step, encoded as
+// `JournalEntry::to_record` does.
+// The last ARGV is the number of entries to keep (0 keeps all of them). The oldest ones
+// are trimmed and counted in the account's journal_offset so entry IDs stay the same.
+// Lua numbers are doubles, so the amounts are formatted with %d to avoid exponents
+macro_rules! with_journal {
+    ($script:expr) => {
+        concat!(
+            "
+local function record_journal_entry(kind, amount, balance, prepaid_amount, timestamp, reference)
+    local length = redis.call('RPUSH', KEYS[2], string.format('%s|%d|%d|%d|%s|%s', kind, amount, balance, prepaid_amount, timestamp, reference))
+    local max_entries = tonumber(ARGV[#ARGV])
+    if max_entries > 0 and length > max_entries then
+        redis.call('LTRIM', KEYS[2], length - max_entries, -1)
+        redis.call('HINCRBY', KEYS[1], 'journal_offset', length - max_entries)
+    end
+end
+",
+            $script
+        )
+    };
+}
 
-static PROCESS_PREPARE: &str = "
+static PROCESS_PREPARE: &str = with_journal!(
+    "
 local from_id = ARGV[1]
-local from_account = 'accounts:' .. ARGV[1]
+local from_account = KEYS[1]
 local from_amount = tonumber(ARGV[2])
 local min_balance, balance, prepaid_amount = unpack(redis.call('HMGET', from_account, 'min_balance', 'balance', 'prepaid_amount'))
 balance = tonumber(balance)
@@ -74,13 +115,19 @@ else
     balance = redis.call('HINCRBY', from_account, 'balance', 0 - from_amount)
 end
 
-return balance + prepaid_amount";
-static PROCESS_FULFILL: &str = "
-local to_account = 'accounts:' .. ARGV[1]
+record_journal_entry('prepare', 0 - from_amount, balance, prepaid_amount, ARGV[4], ARGV[3])
+
+return balance + prepaid_amount"
+);
+static PROCESS_FULFILL: &str = with_journal!(
+    "
+local to_account = KEYS[1]
 local to_amount = tonumber(ARGV[2])
 
 local balance = redis.call('HINCRBY', to_account, 'balance', to_amount)
 local prepaid_amount, settle_threshold, settle_to = unpack(redis.call('HMGET', to_account, 'prepaid_amount', 'settle_threshold', 'settle_to'))
+prepaid_amount = tonumber(prepaid_amount)
+record_journal_entry('fulfill', to_amount, balance, prepaid_amount, ARGV[4], ARGV[3])
 
 -- The logic for trigerring settlement is as follows:
 --  1. settle_threshold must be non-nil (if it's nil, then settlement was perhaps disabled on the account).
@@ -93,28 +140,38 @@ if (settle_threshold and settle_to) and (balance > tonumber(settle_threshold)) a
     -- Update the balance _before_ sending the settlement so that we don't accidentally send
     -- multiple settlements for the same balance. If the settlement fails we'll roll back
     -- the balance change by re-adding the amount back to the balance
-    balance = settle_to
+    balance = tonumber(settle_to)
     redis.call('HSET', to_account, 'balance', balance)
+    record_journal_entry('outgoing_settlement', 0 - settle_amount, balance, prepaid_amount, ARGV[4], ARGV[3])
 end
 
-return {balance + prepaid_amount, settle_amount}";
-static PROCESS_REJECT: &str = "
-local from_account = 'accounts:' .. ARGV[1]
+return {balance + prepaid_amount, settle_amount}"
+);
+static PROCESS_REJECT: &str = with_journal!(
+    "
+local from_account = KEYS[1]
 local from_amount = tonumber(ARGV[2])
 
-local prepaid_amount = redis.call('HGET', from_account, 'prepaid_amount')
+local prepaid_amount = tonumber(redis.call('HGET', from_account, 'prepaid_amount'))
 local balance = redis.call('HINCRBY', from_account, 'balance', from_amount)
-return balance + prepaid_amount";
-static REFUND_SETTLEMENT: &str = "
-local account = 'accounts:' .. ARGV[1]
+record_journal_entry('reject', from_amount, balance, prepaid_amount, ARGV[4], ARGV[3])
+return balance + prepaid_amount"
+);
+static REFUND_SETTLEMENT: &str = with_journal!(
+    "
+local account = KEYS[1]
 local settle_amount = tonumber(ARGV[2])
 
+local prepaid_amount = tonumber(redis.call('HGET', account, 'prepaid_amount'))
 local balance = redis.call('HINCRBY', account, 'balance', settle_amount)
-return balance";
-static PROCESS_INCOMING_SETTLEMENT: &str = "
-local account = 'accounts:' .. ARGV[1]
+record_journal_entry('settlement_refund', settle_amount, balance, prepaid_amount, ARGV[3], '')
+return balance"
+);
+static PROCESS_INCOMING_SETTLEMENT: &str = with_journal!(
+    "
+local account = KEYS[1]
 local amount = tonumber(ARGV[2])
-local idempotency_key = ARGV[3]
+local idempotency_key = KEYS[3]
 
 local balance, prepaid_amount = unpack(redis.call('HMGET', account, 'balance', 'prepaid_amount'))
 
@@ -138,67 +195,288 @@ else
     redis.call('HSET', account, 'balance', 0)
 end
 
-return balance + prepaid_amount";
+record_journal_entry('incoming_settlement', amount, tonumber(balance), tonumber(prepaid_amount), ARGV[4], ARGV[3])
+
+return balance + prepaid_amount"
+);
+
+// Read up to ARGV[2] journal entries starting with entry ID ARGV[1], along with the ID
+// of the first one returned. Entries before the account's journal_offset were trimmed
+static GET_JOURNAL_ENTRIES: &str = "
+local offset = tonumber(redis.call('HGET', KEYS[1], 'journal_offset') or '0')
+local start = math.max(tonumber(ARGV[1]) - 1 - offset, 0)
+return {offset + start + 1, redis.call('LRANGE', KEYS[2], start, start + tonumber(ARGV[2]) - 1)}";
+
+// Raise the next account ID to at least ARGV[1], for accounts inserted with their own ID
+static RAISE_NEXT_ACCOUNT_ID: &str = "
+if tonumber(redis.call('GET', KEYS[1]) or '0') < tonumber(ARGV[1]) then
+    redis.call('SET', KEYS[1], ARGV[1])
+end";
+
+// Set each field ARGV[i] to ARGV[i + 2] only if it still holds ARGV[i + 1], so that a
+// token that was changed since it was read is not overwritten. Returns how many were set
+static HSET_IF_EQUAL: &str = "
+local replaced = 0
+for i = 1, #ARGV, 3 do
+    if redis.call('HGET', KEYS[1], ARGV[i]) == ARGV[i + 1] then
+        redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 2])
+        replaced = replaced + 1
+    end
+end
+return replaced";
+
+// Remove the field ARGV[1] only if it is still set to ARGV[2], so that a value someone
+// else set since is kept (for example, the instance that took over a connection)
+static HDEL_IF_EQUAL: &str = "
+if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+    return redis.call('HDEL', KEYS[1], ARGV[1])
+end
+return 0";
 
 static ROUTES_KEY: &str = "routes:current";
 static RATES_KEY: &str = "rates:current";
 static STATIC_ROUTES_KEY: &str = "routes:static";
+static ALTERNATE_ROUTES_KEY: &str = "routes:alternate";
 static NEXT_ACCOUNT_ID_KEY: &str = "next_account_id";
-
-fn prefixed_idempotency_key(idempotency_key: String) -> String {
-    format!("idempotency-key:{}", idempotency_key)
+static USERNAMES_KEY: &str = "usernames";
+static BTP_AUTH_KEY: &str = "btp_auth";
+static HTTP_AUTH_KEY: &str = "http_auth";
+static SEND_ROUTES_TO_KEY: &str = "send_routes_to";
+static RECEIVE_ROUTES_FROM_KEY: &str = "receive_routes_from";
+static BTP_OUTGOING_KEY: &str = "btp_outgoing";
+static CONNECTION_OWNERS_KEY: &str = "connection_owners";
+
+/// The names of the keys the store uses. They all start with the configured prefix,
+/// so several nodes can share one Redis database without seeing each other's data.
+///
+/// With hash tags, the keys that all accounts share (the indexes, routes and rates) have
+/// the hash tag `{<prefix>}` (or `{interledger}` without a prefix), and each account's
+/// keys have their own, `{<prefix>:<account ID>}`, so Redis Cluster spreads the accounts
+/// over its nodes. Redis can only apply transactions and scripts atomically if their keys
+/// are in one slot, so they either use the keys of one account or only the shared keys
+#[derive(Clone, Debug, Default)]
+struct KeyNames {
+    prefix: String,
+    /// The start of each account's hash tag, which is followed by the account ID
+    account_tag: Option<String>,
 }
 
-fn account_details_key(account_id: u64) -> String {
-    format!("accounts:{}", account_id)
+impl KeyNames {
+    fn new(prefix: &str, hash_tags: bool) -> Self {
+        if hash_tags {
+            let tag = if prefix.is_empty() {
+                "interledger"
+            } else {
+                prefix
+            };
+            KeyNames {
+                prefix: format!("{{{}}}:", tag),
+                account_tag: Some(tag.to_string()),
+            }
+        } else {
+            KeyNames {
+                prefix: if prefix.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}:", prefix)
+                },
+                account_tag: None,
+            }
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    fn account_key(&self, name: &str, account_id: u64) -> String {
+        match self.account_tag {
+            Some(ref tag) => format!("{{{}:{}}}:{}", tag, account_id, name),
+            None => format!("{}{}:{}", self.prefix, name, account_id),
+        }
+    }
+
+    fn account_details(&self, account_id: u64) -> String {
+        self.account_key("accounts", account_id)
+    }
+
+    fn journal(&self, account_id: u64) -> String {
+        self.account_key("journal", account_id)
+    }
+
+    /// The key that marks an incoming settlement as processed. It is in the account's slot,
+    /// because the script that credits the settlement uses both
+    fn incoming_settlement(&self, account_id: u64, idempotency_key: &str) -> String {
+        match self.account_tag {
+            Some(ref tag) => format!("{{{}:{}}}:{}", tag, account_id, idempotency_key),
+            None => self.key(idempotency_key),
+        }
+    }
+
+    fn idempotency_key(&self, idempotency_key: &str) -> String {
+        format!("{}idempotency-key:{}", self.prefix, idempotency_key)
+    }
+
+    /// SCAN pattern matching all of the saved idempotent responses
+    fn idempotency_key_pattern(&self) -> String {
+        let mut pattern = String::with_capacity(self.prefix.len());
+        for c in self.prefix.chars() {
+            if c == '*' || c == '?' || c == '[' || c == ']' || c == '\\' {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push_str(&self.idempotency_key("*")[self.prefix.len()..]);
+        pattern
+    }
+
+    fn packets_limit(&self, account_id: u64) -> String {
+        self.account_key("limit:packets", account_id)
+    }
+
+    fn throughput_limit(&self, account_id: u64) -> String {
+        self.account_key("limit:throughput", account_id)
+    }
 }
 
 pub struct RedisStoreBuilder {
     redis_uri: ConnectionInfo,
+    sentinel: Option<(Vec<ConnectionInfo>, String)>,
+    cluster_nodes: Option<Vec<ConnectionInfo>>,
     secret: [u8; 32],
+    previous_secrets: Vec<[u8; 32]>,
     poll_interval: u64,
+    key_prefix: String,
+    journal_max_entries: u64,
+}
+
+/// The result of migrating the store's data to the keys derived from its current secret
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyMigration {
+    /// Outgoing tokens that were re-encrypted with the current key
+    pub reencrypted_tokens: usize,
+    /// Incoming token index entries that still use previous keys. They are moved to the
+    /// current keys the next time the account holder authenticates, because the store
+    /// only has the HMACs of incoming tokens
+    pub stale_index_entries: usize,
+    /// IDs of the accounts with stale index entries
+    pub stale_accounts: Vec<u64>,
 }
 
 impl RedisStoreBuilder {
     pub fn new(redis_uri: ConnectionInfo, secret: [u8; 32]) -> Self {
         RedisStoreBuilder {
             redis_uri,
+            sentinel: None,
+            cluster_nodes: None,
             secret,
+            previous_secrets: Vec::new(),
             poll_interval: DEFAULT_POLL_INTERVAL,
+            key_prefix: String::new(),
+            journal_max_entries: 0,
         }
     }
 
+    /// Secrets the store used before it was rotated to the current one. Tokens are
+    /// still read with the keys derived from them until `RedisStore::migrate_keys`
+    /// reports that nothing uses them, after which they can be removed
+    pub fn previous_secrets(&mut self, previous_secrets: Vec<[u8; 32]>) -> &mut Self {
+        self.previous_secrets = previous_secrets;
+        self
+    }
+
     pub fn poll_interval(&mut self, poll_interval: u64) -> &mut Self {
         self.poll_interval = poll_interval;
         self
     }
 
+    /// Prefix for all of the keys the store uses, so that several nodes can share one
+    /// Redis database. Keys are named `<prefix>:<key>`. Defaults to no prefix.
+    /// With Redis Cluster the prefix goes into the hash tags, `{<prefix>}:` for the keys
+    /// all accounts share and `{<prefix>:<account ID>}:` for each account's keys, so it
+    /// must not contain `{` or `}`
+    pub fn key_prefix(&mut self, key_prefix: &str) -> &mut Self {
+        self.key_prefix = key_prefix.to_string();
+        self
+    }
+
+    /// Trim each account's journal list to its latest `max_entries` entries whenever the
+    /// balance scripts add one. Defaults to 0, which keeps every entry
+    pub fn journal_max_entries(&mut self, max_entries: u64) -> &mut Self {
+        self.journal_max_entries = max_entries;
+        self
+    }
+
+    /// Ask these Redis Sentinels for the address of the master named `master_name`
+    /// instead of connecting to the `redis_uri` directly, and ask them again when the
+    /// connection is lost or the master turns out to be a replica after a failover.
+    /// The database and password of the `redis_uri` are used for the master
+    pub fn sentinel(&mut self, sentinels: Vec<ConnectionInfo>, master_name: &str) -> &mut Self {
+        self.sentinel = Some((sentinels, master_name.to_string()));
+        self.cluster_nodes = None;
+        self
+    }
+
+    /// Use a Redis Cluster, which is discovered through the `redis_uri` and these other
+    /// nodes. Each account's keys get their own hash tag, so the accounts are spread over
+    /// the nodes, while the keys all accounts share (the indexes, routes and rates) are
+    /// kept in one slot. Inserting accounts takes one transaction per account and one for
+    /// the shared keys, which are undone if any of them fails
+    pub fn cluster(&mut self, nodes: Vec<ConnectionInfo>) -> &mut Self {
+        self.cluster_nodes = Some(nodes);
+        self.sentinel = None;
+        self
+    }
+
     pub fn connect(&self) -> impl Future<Item = RedisStore, Error = ()> {
-        let (hmac_key, encryption_key, decryption_key) = generate_keys(&self.secret[..]);
+        let keys = KeyRing::new("redis", &self.secret[..], &self.previous_secrets);
+        let key_names = KeyNames::new(&self.key_prefix, self.cluster_nodes.is_some());
         let poll_interval = self.poll_interval;
+        let journal_max_entries = self.journal_max_entries;
+        let servers = if let Some(ref nodes) = self.cluster_nodes {
+            let mut nodes = nodes.clone();
+            nodes.insert(0, self.redis_uri.clone());
+            if self.redis_uri.db != 0 {
+                error!("Redis Cluster only supports database 0");
+                Err(())
+            } else if self.key_prefix.contains(|c| c == '{' || c == '}') {
+                error!("With Redis Cluster, the key prefix must not contain {{ or }}");
+                Err(())
+            } else {
+                Ok(Servers::Cluster(nodes))
+            }
+        } else if let Some((ref sentinels, ref master_name)) = self.sentinel {
+            Ok(Servers::Sentinel {
+                sentinels: sentinels.clone(),
+                master_name: master_name.clone(),
+                master: self.redis_uri.clone(),
+            })
+        } else {
+            Ok(Servers::Single(self.redis_uri.clone()))
+        };
 
-        result(Client::open(self.redis_uri.clone()))
-            .map_err(|err| error!("Error creating Redis client: {:?}", err))
-            .and_then(|client| {
-                debug!("Connected to redis: {:?}", client);
-                client
-                    .get_shared_async_connection()
+        result(servers)
+            .and_then(|servers| {
+                RedisConnection::connect(servers)
                     .map_err(|err| error!("Error connecting to Redis: {:?}", err))
             })
             .and_then(move |connection| {
+                debug!("Connected to Redis");
                 let store = RedisStore {
                     connection: Arc::new(connection),
                     exchange_rates: Arc::new(RwLock::new(HashMap::new())),
                     routes: Arc::new(RwLock::new(HashMap::new())),
-                    hmac_key: Arc::new(hmac_key),
-                    encryption_key: Arc::new(encryption_key),
-                    decryption_key: Arc::new(decryption_key),
+                    alternate_routes: Arc::new(RwLock::new(HashMap::new())),
+                    keys: Arc::new(keys),
+                    key_names: Arc::new(key_names),
+                    journal_max_entries,
                 };
 
                 // Start polling for rate updates
                 // Note: if this behavior changes, make sure to update the Drop implementation
                 let connection_clone = Arc::downgrade(&store.connection);
                 let exchange_rates = store.exchange_rates.clone();
+                let key_names = store.key_names.clone();
                 let poll_rates =
                     Interval::new(Instant::now(), Duration::from_millis(poll_interval))
                         .map_err(|err| error!("Interval error: {:?}", err))
@@ -206,6 +484,7 @@ impl RedisStoreBuilder {
                             if let Some(connection) = connection_clone.upgrade() {
                                 Either::A(update_rates(
                                     connection.as_ref().clone(),
+                                    &key_names,
                                     exchange_rates.clone(),
                                 ))
                             } else {
@@ -220,15 +499,26 @@ impl RedisStoreBuilder {
                 // Note: if this behavior changes, make sure to update the Drop implementation
                 let connection_clone = Arc::downgrade(&store.connection);
                 let routing_table = store.routes.clone();
+                let alternate_routes = store.alternate_routes.clone();
+                let key_names = store.key_names.clone();
                 let poll_routes =
                     Interval::new(Instant::now(), Duration::from_millis(poll_interval))
                         .map_err(|err| error!("Interval error: {:?}", err))
                         .for_each(move |_| {
                             if let Some(connection) = connection_clone.upgrade() {
-                                Either::A(update_routes(
-                                    connection.as_ref().clone(),
-                                    routing_table.clone(),
-                                ))
+                                Either::A(
+                                    update_routes(
+                                        connection.as_ref().clone(),
+                                        &key_names,
+                                        routing_table.clone(),
+                                    )
+                                    .join(update_alternate_routes(
+                                        connection.as_ref().clone(),
+                                        &key_names,
+                                        alternate_routes.clone(),
+                                    ))
+                                    .map(|_| ()),
+                                )
                             } else {
                                 debug!("Not polling routes anymore because connection was closed");
                                 // TODO make sure the interval stops
@@ -250,134 +540,636 @@ impl RedisStoreBuilder {
 /// future versions of it will use PubSub to subscribe to updates.
 #[derive(Clone)]
 pub struct RedisStore {
-    connection: Arc<SharedConnection>,
+    connection: Arc<RedisConnection>,
     exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
     routes: Arc<RwLock<HashMap<Bytes, u64>>>,
-    hmac_key: Arc<hmac::SigningKey>, // redisstore stores a key, this must be protected
-    encryption_key: Arc<aead::SealingKey>,
-    decryption_key: Arc<aead::OpeningKey>,
+    alternate_routes: Arc<RwLock<HashMap<Bytes, Vec<AlternateRoute<u64>>>>>,
+    keys: Arc<KeyRing>, // redisstore stores keys, these must be protected
+    key_names: Arc<KeyNames>,
+    journal_max_entries: u64,
+}
+
+/// An account about to be inserted, with the index fields of its incoming tokens
+struct NewAccount {
+    account: Account,
+    /// Every field the tokens may already be indexed under, starting with the current one
+    btp_incoming_token_hmacs: Option<Vec<Vec<u8>>>,
+    http_incoming_token_hmacs: Option<Vec<Vec<u8>>>,
+    imported_balance: Option<(i64, i64)>,
+}
+
+impl NewAccount {
+    /// Write the account's own keys, which are all in the account's slot
+    fn add_to_pipeline(
+        &self,
+        pipe: &mut redis::Pipeline,
+        key_names: &KeyNames,
+        store_keys: &KeyRing,
+    ) {
+        let account = &self.account;
+
+        // Set account details
+        pipe.cmd("HMSET")
+            .arg(key_names.account_details(account.id))
+            .arg(account.clone().encrypt_tokens(store_keys))
+            .ignore();
+
+        // Set balance-related details
+        let (balance, prepaid_amount) = self.imported_balance.unwrap_or((0, 0));
+        pipe.hset_multiple(
+            key_names.account_details(account.id),
+            &[("balance", balance), ("prepaid_amount", prepaid_amount)],
+        )
+        .ignore();
+        if self.imported_balance.is_some() {
+            let entry = JournalEntry {
+                id: 1,
+                kind: JournalEntryKind::Import,
+                reference: None,
+                amount: balance + prepaid_amount,
+                balance,
+                prepaid_amount,
+                timestamp: journal_timestamp(),
+            };
+            pipe.rpush(key_names.journal(account.id), entry.to_record())
+                .ignore();
+        }
+    }
+
+    /// Add the account to the indexes and the routing table, which all accounts share.
+    /// The username claim is the only command that returns a result
+    fn add_indexes_to_pipeline(&self, pipe: &mut redis::Pipeline, key_names: &KeyNames) {
+        let account = &self.account;
+
+        if self.imported_balance.is_some() {
+            // Accounts are loaded by iterating up to the next account ID
+            pipe.cmd("EVAL")
+                .arg(RAISE_NEXT_ACCOUNT_ID)
+                .arg(1)
+                .arg(key_names.key(NEXT_ACCOUNT_ID_KEY))
+                .arg(account.id + 1)
+                .ignore();
+        }
+
+        // Set incoming auth details, indexed with the current keys
+        if let Some(auth) = self
+            .btp_incoming_token_hmacs
+            .as_ref()
+            .and_then(|hmacs| hmacs.first())
+        {
+            pipe.hset(key_names.key(BTP_AUTH_KEY), auth.as_slice(), account.id)
+                .ignore();
+        }
+
+        if let Some(auth) = self
+            .http_incoming_token_hmacs
+            .as_ref()
+            .and_then(|hmacs| hmacs.first())
+        {
+            pipe.hset(key_names.key(HTTP_AUTH_KEY), auth.as_slice(), account.id)
+                .ignore();
+        }
+
+        // The username is claimed in the same transaction, so an account that another
+        // client inserted since the usernames were checked keeps its username
+        if let Some(ref username) = account.username {
+            pipe.hsetnx(key_names.key(USERNAMES_KEY), username, account.id);
+        }
+
+        if account.send_routes {
+            pipe.sadd(key_names.key(SEND_ROUTES_TO_KEY), account.id)
+                .ignore();
+        }
+
+        if account.receive_routes {
+            pipe.sadd(key_names.key(RECEIVE_ROUTES_FROM_KEY), account.id)
+                .ignore();
+        }
+
+        if account.btp_uri.is_some() {
+            pipe.sadd(key_names.key(BTP_OUTGOING_KEY), account.id)
+                .ignore();
+        }
+
+        // Add route to routing table
+        pipe.hset(
+            key_names.key(ROUTES_KEY),
+            account.ilp_address.to_bytes().to_vec(),
+            account.id,
+        )
+        .ignore();
+    }
+
+    /// Undo `add_to_pipeline`
+    fn add_removal_to_pipeline(&self, pipe: &mut redis::Pipeline, key_names: &KeyNames) {
+        let account = &self.account;
+        pipe.del(key_names.account_details(account.id)).ignore();
+        pipe.del(key_names.journal(account.id)).ignore();
+    }
+
+    /// Undo `add_indexes_to_pipeline`, leaving the index entries that belong to other accounts
+    fn add_index_removal_to_pipeline(&self, pipe: &mut redis::Pipeline, key_names: &KeyNames) {
+        let account = &self.account;
+        let mut index_entries: Vec<(String, Vec<u8>)> = Vec::new();
+        if let Some(auth) = self
+            .btp_incoming_token_hmacs
+            .as_ref()
+            .and_then(|hmacs| hmacs.first())
+        {
+            index_entries.push((key_names.key(BTP_AUTH_KEY), auth.clone()));
+        }
+        if let Some(auth) = self
+            .http_incoming_token_hmacs
+            .as_ref()
+            .and_then(|hmacs| hmacs.first())
+        {
+            index_entries.push((key_names.key(HTTP_AUTH_KEY), auth.clone()));
+        }
+        if let Some(ref username) = account.username {
+            index_entries.push((key_names.key(USERNAMES_KEY), username.as_bytes().to_vec()));
+        }
+        index_entries.push((
+            key_names.key(ROUTES_KEY),
+            account.ilp_address.to_bytes().to_vec(),
+        ));
+        for (index, field) in index_entries {
+            pipe.cmd("EVAL")
+                .arg(HDEL_IF_EQUAL)
+                .arg(1)
+                .arg(index)
+                .arg(field)
+                .arg(account.id)
+                .ignore();
+        }
+
+        for set in &[
+            SEND_ROUTES_TO_KEY,
+            RECEIVE_ROUTES_FROM_KEY,
+            BTP_OUTGOING_KEY,
+        ] {
+            pipe.srem(key_names.key(set), account.id).ignore();
+        }
+    }
+}
+
+/// The rest of an imported node state, which is written in the same transaction as the
+/// index entries of its accounts. The static routes and rates it replaces are kept so
+/// they can be restored
+struct ImportedData {
+    static_routes: Vec<(String, u64)>,
+    rates: Vec<(String, f64)>,
+    idempotent_data: Vec<(String, IdempotentData)>,
+    previous_static_routes: Vec<(String, u64)>,
+    previous_rates: Vec<(String, f64)>,
+}
+
+impl ImportedData {
+    fn add_to_pipeline(&self, pipe: &mut redis::Pipeline, key_names: &KeyNames) {
+        replace_hash(pipe, key_names.key(STATIC_ROUTES_KEY), &self.static_routes);
+        replace_hash(pipe, key_names.key(RATES_KEY), &self.rates);
+        for (idempotency_key, (status_code, data, input_hash)) in self.idempotent_data.iter() {
+            pipe.cmd("HMSET")
+                .arg(key_names.idempotency_key(idempotency_key))
+                .arg("status_code")
+                .arg(status_code.as_u16())
+                .arg("data")
+                .arg(data.as_ref())
+                .arg("input_hash")
+                .arg(&input_hash[..])
+                .ignore()
+                .expire(key_names.idempotency_key(idempotency_key), 86400)
+                .ignore();
+        }
+    }
+
+    /// Undo `add_to_pipeline`
+    fn add_removal_to_pipeline(&self, pipe: &mut redis::Pipeline, key_names: &KeyNames) {
+        replace_hash(
+            pipe,
+            key_names.key(STATIC_ROUTES_KEY),
+            &self.previous_static_routes,
+        );
+        replace_hash(pipe, key_names.key(RATES_KEY), &self.previous_rates);
+        for (idempotency_key, _) in self.idempotent_data.iter() {
+            pipe.del(key_names.idempotency_key(idempotency_key))
+                .ignore();
+        }
+    }
+}
+
+/// Remove the accounts that `create_new_accounts` may have partly inserted, along with the
+/// rest of the imported state. The index entries are removed first, in one transaction,
+/// and then each account's keys in a transaction of their own
+fn remove_new_accounts(
+    connection: RedisConnection,
+    key_names: &KeyNames,
+    new_accounts: &[NewAccount],
+    imported: Option<&ImportedData>,
+) -> impl Future<Item = RedisConnection, Error = ()> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for new_account in new_accounts.iter() {
+        new_account.add_index_removal_to_pipeline(&mut pipe, key_names);
+    }
+    if let Some(imported) = imported {
+        imported.add_removal_to_pipeline(&mut pipe, key_names);
+    }
+    let account_removals: Vec<redis::Pipeline> = new_accounts
+        .iter()
+        .map(|new_account| {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            new_account.add_removal_to_pipeline(&mut pipe, key_names);
+            pipe
+        })
+        .collect();
+    pipe.query_async(connection)
+        .and_then(move |(connection, _): (RedisConnection, Value)| {
+            let removals: Vec<_> = account_removals
+                .into_iter()
+                .map(|pipe| {
+                    pipe.query_async(connection.clone())
+                        .map(|(_connection, _): (RedisConnection, Value)| ())
+                })
+                .collect();
+            join_all(removals).map(move |_| connection)
+        })
+        .map_err(|err| error!("Error removing accounts that were not inserted: {:?}", err))
+}
+
+/// Replace the contents of a hash. HMSET fails without any fields, so it is only sent with some
+fn replace_hash<V: redis::ToRedisArgs>(
+    pipe: &mut redis::Pipeline,
+    key: String,
+    fields: &[(String, V)],
+) {
+    pipe.del(&key).ignore();
+    if !fields.is_empty() {
+        pipe.hset_multiple(key, fields).ignore();
+    }
 }
 
 impl RedisStore {
     fn get_next_account_id(&self) -> impl Future<Item = u64, Error = ()> {
         cmd("INCR")
-            .arg(NEXT_ACCOUNT_ID_KEY)
+            .arg(self.key_names.key(NEXT_ACCOUNT_ID_KEY))
             .query_async(self.connection.as_ref().clone())
             .map_err(|err| error!("Error incrementing account ID: {:?}", err))
             .and_then(|(_conn, next_account_id): (_, u64)| Ok(next_account_id - 1))
     }
 
+    /// Insert an account under the given ID
     fn create_new_account(
         &self,
+        id: u64,
         account: AccountDetails,
-    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+    ) -> impl Future<Item = Account, Error = ()> {
+        self.create_new_accounts(vec![(id, account, None)], None)
+            .map(|mut accounts| accounts.pop().unwrap())
+    }
+
+    /// Insert accounts under the given IDs, so either all of them are inserted or none
+    /// are. Imported accounts bring their balance and prepaid amount with them, which
+    /// are recorded in the account's journal, and the rest of the imported state is
+    /// written in the same transaction as the accounts' index entries
+    fn create_new_accounts(
+        &self,
+        accounts: Vec<(u64, AccountDetails, Option<(i64, i64)>)>,
+        imported: Option<ImportedData>,
+    ) -> impl Future<Item = Vec<Account>, Error = ()> {
         let connection = self.connection.clone();
         let routing_table = self.routes.clone();
-        let encryption_key = self.encryption_key.clone();
+        let store_keys = self.keys.clone();
+        let key_names = self.key_names.clone();
 
         // Instead of storing the incoming secrets, we store the HMAC digest of them
         // (This is better than encrypting because the output is deterministic so we can look
         // up the account by the HMAC of the auth details submitted by the account holder over the wire)
-        let btp_incoming_token_hmac = account
-            .btp_incoming_token
-            .clone()
-            .map(|token| hmac::sign(&self.hmac_key, token.as_bytes()));
-        let btp_incoming_token_hmac_clone = btp_incoming_token_hmac;
-        let http_incoming_token_hmac = account
-            .http_incoming_token
-            .clone()
-            .map(|token| hmac::sign(&self.hmac_key, token.as_bytes()));
-        let http_incoming_token_hmac_clone = http_incoming_token_hmac;
+        // The token must not be in the index under any of the keys, in case it was added
+        // before the secret was rotated
+        let mut new_accounts = Vec::with_capacity(accounts.len());
+        for (id, details, imported_balance) in accounts {
+            let btp_incoming_token = details.btp_incoming_token.clone();
+            let http_incoming_token = details.http_incoming_token.clone();
+            match Account::try_from(id, details) {
+                Ok(account) => new_accounts.push(NewAccount {
+                    account,
+                    btp_incoming_token_hmacs: btp_incoming_token
+                        .map(|token| self.keys.index_fields(token.as_bytes())),
+                    http_incoming_token_hmacs: http_incoming_token
+                        .map(|token| self.keys.index_fields(token.as_bytes())),
+                    imported_balance,
+                }),
+                Err(_) => return Either::A(err(())),
+            }
+        }
 
-        Box::new(
-            self.get_next_account_id()
-                .and_then(|id| {
-                    debug!("Next account id is: {}", id);
-                    Account::try_from(id, account)
-                })
-                .and_then(move |account| {
-                    // Check that there isn't already an account with values that must be unique
-                    let mut keys: Vec<String> = vec!["ID".to_string()];
+        // Check that there isn't already an account with values that must be unique
+        let mut keys: Vec<(u64, &'static str)> = Vec::new();
+        let mut pipe = redis::pipe();
+        for new_account in new_accounts.iter() {
+            let id = new_account.account.id;
+            keys.push((id, "ID"));
+            pipe.exists(key_names.account_details(id));
+            for auth in new_account.btp_incoming_token_hmacs.iter().flatten() {
+                keys.push((id, "BTP auth"));
+                pipe.hexists(key_names.key(BTP_AUTH_KEY), auth.as_slice());
+            }
+            for auth in new_account.http_incoming_token_hmacs.iter().flatten() {
+                keys.push((id, "HTTP auth"));
+                pipe.hexists(key_names.key(HTTP_AUTH_KEY), auth.as_slice());
+            }
+            if let Some(ref username) = new_account.account.username {
+                keys.push((id, "username"));
+                pipe.hexists(key_names.key(USERNAMES_KEY), username);
+            }
+        }
 
+        Either::B(
+            pipe.query_async(connection.as_ref().clone())
+                .map_err(|err| {
+                    error!(
+                        "Error checking whether account details already exist: {:?}",
+                        err
+                    )
+                })
+                .and_then(move |(connection, results): (RedisConnection, Vec<bool>)| {
+                    if let Some(index) = results.iter().position(|val| *val) {
+                        let (id, key) = keys[index];
+                        warn!(
+                            "An account already exists with the same {}. Cannot insert account {}",
+                            key, id
+                        );
+                        Err(())
+                    } else {
+                        Ok(connection)
+                    }
+                })
+                .and_then(move |connection| {
+                    // Each account's keys are in the account's own slot, so every account is
+                    // written in its own transaction before the indexes are, in one more
+                    let account_writes: Vec<_> = new_accounts
+                        .iter()
+                        .map(|new_account| {
+                            let mut pipe = redis::pipe();
+                            pipe.atomic();
+                            new_account.add_to_pipeline(&mut pipe, &key_names, &store_keys);
+                            pipe.query_async(connection.clone())
+                                .map(|(_connection, _): (RedisConnection, Value)| ())
+                        })
+                        .collect();
                     let mut pipe = redis::pipe();
-                    pipe.exists(account_details_key(account.id));
-
-                    if let Some(auth) = btp_incoming_token_hmac {
-                        keys.push("BTP auth".to_string());
-                        pipe.hexists("btp_auth", auth.as_ref());
+                    pipe.atomic();
+                    for new_account in new_accounts.iter() {
+                        new_account.add_indexes_to_pipeline(&mut pipe, &key_names);
                     }
-                    if let Some(auth) = http_incoming_token_hmac {
-                        keys.push("HTTP auth".to_string());
-                        pipe.hexists("http_auth", auth.as_ref());
+                    if let Some(ref imported) = imported {
+                        imported.add_to_pipeline(&mut pipe, &key_names);
                     }
-                    pipe.query_async(connection.as_ref().clone())
-                        .map_err(|err| {
-                            error!(
-                                "Error checking whether account details already exist: {:?}",
-                                err
-                            )
-                        })
-                        .and_then(
-                            move |(connection, results): (SharedConnection, Vec<bool>)| {
-                                if let Some(index) = results.iter().position(|val| *val) {
-                                    warn!("An account already exists with the same {}. Cannot insert account: {:?}", keys[index], account);
-                                    Err(())
-                                } else {
-                                    Ok((connection, account))
+                    let connection_clone = connection.clone();
+                    join_all(account_writes)
+                        .and_then(move |_| pipe.query_async(connection))
+                        .map_err(|err| error!("Error inserting accounts into DB: {:?}", err))
+                        .then(
+                            move |result: Result<(RedisConnection, Vec<bool>), ()>| {
+                                if let Ok((connection, claimed_usernames)) = result {
+                                    if claimed_usernames.iter().all(|claimed| *claimed) {
+                                        return Either::A(
+                                            update_routes(connection, &key_names, routing_table)
+                                                .map(move |_| new_accounts),
+                                        );
+                                    }
+                                    // Another account took one of the usernames after they
+                                    // were checked
+                                    warn!("An account already exists with the same username. Cannot insert accounts");
                                 }
+                                // Remove the accounts again, so either all of them are
+                                // inserted or none are
+                                Either::B(
+                                    remove_new_accounts(
+                                        connection_clone,
+                                        &key_names,
+                                        &new_accounts,
+                                        imported.as_ref(),
+                                    )
+                                    .and_then(move |connection| {
+                                        update_routes(connection, &key_names, routing_table)
+                                    })
+                                    .and_then(|_| Err(())),
+                                )
                             },
                         )
-                })
-                .and_then(move |(connection, account)| {
-                    let mut pipe = redis::pipe();
-                    pipe.atomic();
-
-                    // Set account details
-                    pipe.cmd("HMSET").arg(account_details_key(account.id)).arg(account.clone().encrypt_tokens(&encryption_key))
-                        .ignore();
-
-                    // Set balance-related details
-                    pipe.hset_multiple(account_details_key(account.id), &[("balance", 0), ("prepaid_amount", 0)]).ignore();
+                        .and_then(move |new_accounts| {
+                            Ok(new_accounts
+                                .into_iter()
+                                .map(|new_account| {
+                                    let account = new_account.account;
+                                    debug!(
+                                        "Inserted account {} (ILP address: {})",
+                                        account.id,
+                                        str::from_utf8(account.ilp_address.as_ref())
+                                            .unwrap_or("<not utf8>")
+                                    );
+                                    account
+                                })
+                                .collect())
+                        })
+                }),
+        )
+    }
 
-                    // Set incoming auth details
-                    if let Some(auth) = btp_incoming_token_hmac_clone {
-                        pipe.hset("btp_auth", auth.as_ref(), account.id).ignore();
+    /// Look up the account that an incoming token belongs to in the given token index
+    fn get_account_from_index(
+        &self,
+        index: &str,
+        token: &[u8],
+    ) -> impl Future<Item = Option<Account>, Error = redis::RedisError> {
+        let keys = self.keys.clone();
+        let key_names = self.key_names.clone();
+        cmd("EVAL")
+            .arg(ACCOUNT_ID_FROM_INDEX)
+            .arg(1)
+            .arg(self.key_names.key(index))
+            .arg(self.keys.index_fields(token))
+            .query_async(self.connection.as_ref().clone())
+            .and_then(
+                move |(connection, account_id): (RedisConnection, Option<u64>)| {
+                    if let Some(account_id) = account_id {
+                        Either::A(
+                            cmd("HGETALL")
+                                .arg(key_names.account_details(account_id))
+                                .query_async(connection)
+                                .map(
+                                    move |(_connection, account): (
+                                        _,
+                                        AccountWithEncryptedTokens,
+                                    )| {
+                                        Some(account.decrypt_tokens(&keys))
+                                    },
+                                ),
+                        )
+                    } else {
+                        Either::B(ok(None))
                     }
+                },
+            )
+    }
 
-                    if let Some(auth) = http_incoming_token_hmac_clone {
-                        pipe.hset("http_auth", auth.as_ref(), account.id).ignore();
+    fn get_all_accounts_with_encrypted_tokens(
+        &self,
+    ) -> impl Future<Item = Vec<AccountWithEncryptedTokens>, Error = ()> {
+        let key_names = self.key_names.clone();
+        cmd("GET")
+            .arg(self.key_names.key(NEXT_ACCOUNT_ID_KEY))
+            .query_async(self.connection.as_ref().clone())
+            .and_then(
+                move |(connection, next_account_id): (RedisConnection, Option<u64>)| {
+                    if let Some(next_account_id) = next_account_id {
+                        if next_account_id > 0 {
+                            trace!("Getting accounts up to id: {}", next_account_id);
+                            let mut pipe = redis::pipe();
+                            for i in 0..next_account_id {
+                                pipe.hgetall(key_names.account_details(i));
+                            }
+                            return Either::A(pipe.query_async(connection).and_then(
+                                move |(_, accounts): (
+                                    _,
+                                    Vec<Option<AccountWithEncryptedTokens>>,
+                                )| {
+                                    Ok(accounts.into_iter().filter_map(|a| a).collect())
+                                },
+                            ));
+                        }
                     }
+                    Either::B(ok(Vec::new()))
+                },
+            )
+            .map_err(|err| error!("Error getting all accounts: {:?}", err))
+    }
 
-                    if account.send_routes {
-                        pipe.sadd("send_routes_to", account.id).ignore();
+    /// Re-encrypt the outgoing tokens that were encrypted with keys derived from the
+    /// previous secrets and find the incoming token index entries that still use them.
+    /// Once there are none left, the previous secrets are no longer needed
+    pub fn migrate_keys(&self) -> impl Future<Item = KeyMigration, Error = ()> {
+        let keys = self.keys.clone();
+        let connection = self.connection.clone();
+        let key_names = self.key_names.clone();
+        let store = self.clone();
+        self.get_all_accounts_with_encrypted_tokens()
+            .and_then(move |accounts| {
+                // Each account's tokens are only replaced if they still hold the ciphertexts
+                // that were read, so tokens that were changed in the meantime are kept
+                let mut pipe = redis::pipe();
+                let mut stale_accounts = 0;
+                for account in accounts.iter() {
+                    let tokens = account.reencrypt_stale_tokens(&keys);
+                    if tokens.is_empty() {
+                        continue;
                     }
-
-                    if account.receive_routes {
-                        pipe.sadd("receive_routes_from", account.id).ignore();
+                    pipe.cmd("EVAL")
+                        .arg(HSET_IF_EQUAL)
+                        .arg(1)
+                        .arg(key_names.account_details(account.id()));
+                    for (field, stale, reencrypted) in tokens.iter() {
+                        pipe.arg(*field)
+                            .arg(stale.as_ref())
+                            .arg(reencrypted.as_ref());
                     }
-
-                    if account.btp_uri.is_some() {
-                        pipe.sadd("btp_outgoing", account.id).ignore();
+                    stale_accounts += 1;
+                }
+                if stale_accounts == 0 {
+                    return Either::A(ok(0));
+                }
+                Either::B(
+                    pipe.query_async(connection.as_ref().clone())
+                        .map_err(|err| error!("Error saving re-encrypted tokens: {:?}", err))
+                        .map(|(_connection, replaced): (_, Vec<usize>)| replaced.iter().sum()),
+                )
+            })
+            .and_then(move |reencrypted_tokens| {
+                store.get_stale_index_entries().map(move |entries| {
+                    debug!(
+                        "Re-encrypted {} tokens with the current key. {} incoming token index entries still use previous keys",
+                        reencrypted_tokens,
+                        entries.len()
+                    );
+                    KeyMigration {
+                        reencrypted_tokens,
+                        stale_index_entries: entries.len(),
+                        stale_accounts: account_ids_of_entries(&entries),
                     }
+                })
+            })
+    }
 
-                    // Add route to routing table
-                    pipe.hset(ROUTES_KEY, account.ilp_address.to_bytes().to_vec(), account.id)
-                        .ignore();
+    /// Delete the incoming token index entries that still use previous keys, so the
+    /// previous secrets can be removed before every account holder has authenticated
+    /// again. Returns the IDs of the accounts whose incoming tokens no longer work and
+    /// who need new ones
+    pub fn remove_stale_index_entries(&self) -> impl Future<Item = Vec<u64>, Error = ()> {
+        let connection = self.connection.clone();
+        self.get_stale_index_entries().and_then(move |entries| {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for (key, field, _account_id) in entries.iter() {
+                pipe.hdel(key, field.as_slice()).ignore();
+            }
+            pipe.query_async(connection.as_ref().clone())
+                .map_err(|err| {
+                    error!(
+                        "Error removing stale incoming token index entries: {:?}",
+                        err
+                    )
+                })
+                .map(move |(_connection, _): (_, Value)| {
+                    warn!(
+                        "Removed {} incoming token index entries that used previous keys",
+                        entries.len()
+                    );
+                    account_ids_of_entries(&entries)
+                })
+        })
+    }
 
-                    pipe.query_async(connection)
-                        .map_err(|err| error!("Error inserting account into DB: {:?}", err))
-                        .and_then(move |(connection, _ret): (SharedConnection, Value)| {
-                            update_routes(connection, routing_table)
-                        })
-                        .and_then(move |_| {
-                            debug!("Inserted account {} (ILP address: {})", account.id, str::from_utf8(account.ilp_address.as_ref()).unwrap_or("<not utf8>"));
-                            Ok(account)
-                        })
-                }),
-        )
+    /// Get the incoming token index entries that were not written with the current keys,
+    /// as the index's key, the field and the account ID
+    fn get_stale_index_entries(
+        &self,
+    ) -> impl Future<Item = Vec<(String, Vec<u8>, u64)>, Error = ()> {
+        let keys = self.keys.clone();
+        let http_auth_key = self.key_names.key(HTTP_AUTH_KEY);
+        let btp_auth_key = self.key_names.key(BTP_AUTH_KEY);
+        let mut pipe = redis::pipe();
+        pipe.hgetall(&http_auth_key).hgetall(&btp_auth_key);
+        pipe.query_async(self.connection.as_ref().clone())
+            .map_err(|err| error!("Error getting incoming token indexes: {:?}", err))
+            .map(
+                move |(_connection, (http_auth, btp_auth)): (
+                    _,
+                    (HashMap<Vec<u8>, u64>, HashMap<Vec<u8>, u64>),
+                )| {
+                    http_auth
+                        .into_iter()
+                        .map(|(field, id)| (http_auth_key.clone(), field, id))
+                        .chain(
+                            btp_auth
+                                .into_iter()
+                                .map(|(field, id)| (btp_auth_key.clone(), field, id)),
+                        )
+                        .filter(|(_, field, _)| !keys.is_current_index_field(field))
+                        .collect()
+                },
+            )
     }
 }
 
+fn account_ids_of_entries(entries: &[(String, Vec<u8>, u64)]) -> Vec<u64> {
+    let mut account_ids: Vec<u64> = entries.iter().map(|(_, _, id)| *id).collect();
+    account_ids.sort();
+    account_ids.dedup();
+    account_ids
+}
+
 impl AccountStore for RedisStore {
     type Account = Account;
 
@@ -386,11 +1178,11 @@ impl AccountStore for RedisStore {
         &self,
         account_ids: Vec<<Self::Account as AccountTrait>::AccountId>,
     ) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
-        let decryption_key = self.decryption_key.clone();
+        let keys = self.keys.clone();
         let num_accounts = account_ids.len();
         let mut pipe = redis::pipe();
         for account_id in account_ids.iter() {
-            pipe.hgetall(account_details_key(*account_id));
+            pipe.hgetall(self.key_names.account_details(*account_id));
         }
         Box::new(
             pipe.query_async(self.connection.as_ref().clone())
@@ -405,7 +1197,7 @@ impl AccountStore for RedisStore {
                         if accounts.len() == num_accounts {
                             let accounts = accounts
                                 .into_iter()
-                                .map(|account| account.decrypt_tokens(&decryption_key))
+                                .map(|account| account.decrypt_tokens(&keys))
                                 .collect();
                             Ok(accounts)
                         } else {
@@ -423,7 +1215,7 @@ impl BalanceStore for RedisStore {
     fn get_balance(&self, account: Account) -> Box<dyn Future<Item = i64, Error = ()> + Send> {
         Box::new(
             cmd("HMGET")
-                .arg(account_details_key(account.id))
+                .arg(self.key_names.account_details(account.id))
                 .arg(&["balance", "prepaid_amount"])
                 .query_async(self.connection.as_ref().clone())
                 .map_err(move |err| {
@@ -444,15 +1236,21 @@ impl BalanceStore for RedisStore {
         &self,
         from_account: Account,
         incoming_amount: u64,
+        reference: &str,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
         if incoming_amount > 0 {
             let from_account_id = from_account.id;
             Box::new(
                 cmd("EVAL")
                     .arg(PROCESS_PREPARE)
-                    .arg(0)
+                    .arg(2)
+                    .arg(self.key_names.account_details(from_account_id))
+                    .arg(self.key_names.journal(from_account_id))
                     .arg(from_account_id)
                     .arg(incoming_amount)
+                    .arg(reference)
+                    .arg(journal_timestamp())
+                    .arg(self.journal_max_entries)
                     .query_async(self.connection.as_ref().clone())
                     .map_err(move |err| {
                         warn!(
@@ -477,6 +1275,7 @@ impl BalanceStore for RedisStore {
         &self,
         to_account: Account,
         outgoing_amount: u64,
+        reference: &str,
     ) -> Box<dyn Future<Item = (i64, u64), Error = ()> + Send> {
         if outgoing_amount > 0 {
             debug!(
@@ -487,9 +1286,14 @@ impl BalanceStore for RedisStore {
             Box::new(
                 cmd("EVAL")
                     .arg(PROCESS_FULFILL)
-                    .arg(0)
+                    .arg(2)
+                    .arg(self.key_names.account_details(to_account_id))
+                    .arg(self.key_names.journal(to_account_id))
                     .arg(to_account_id)
                     .arg(outgoing_amount)
+                    .arg(reference)
+                    .arg(journal_timestamp())
+                    .arg(self.journal_max_entries)
                     .query_async(self.connection.as_ref().clone())
                     .map_err(move |err| {
                         error!(
@@ -516,15 +1320,21 @@ impl BalanceStore for RedisStore {
         &self,
         from_account: Account,
         incoming_amount: u64,
+        reference: &str,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
         if incoming_amount > 0 {
             let from_account_id = from_account.id;
             Box::new(
                 cmd("EVAL")
                     .arg(PROCESS_REJECT)
-                    .arg(0)
+                    .arg(2)
+                    .arg(self.key_names.account_details(from_account_id))
+                    .arg(self.key_names.journal(from_account_id))
                     .arg(from_account_id)
                     .arg(incoming_amount)
+                    .arg(reference)
+                    .arg(journal_timestamp())
+                    .arg(self.journal_max_entries)
                     .query_async(self.connection.as_ref().clone())
                     .map_err(move |err| {
                         warn!(
@@ -544,6 +1354,52 @@ impl BalanceStore for RedisStore {
             Box::new(ok(()))
         }
     }
+
+    fn get_balance_journal(
+        &self,
+        account: Account,
+        from_entry: u64,
+        limit: usize,
+    ) -> Box<dyn Future<Item = Vec<JournalEntry>, Error = ()> + Send> {
+        if limit == 0 {
+            return Box::new(ok(Vec::new()));
+        }
+        let account_id = account.id;
+        Box::new(
+            cmd("EVAL")
+                .arg(GET_JOURNAL_ENTRIES)
+                .arg(2)
+                .arg(self.key_names.account_details(account_id))
+                .arg(self.key_names.journal(account_id))
+                .arg(from_entry.max(1))
+                .arg(limit)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error getting balance journal for account: {} {:?}",
+                        account_id, err
+                    )
+                })
+                .and_then(
+                    move |(_connection, (first_entry, records)): (_, (u64, Vec<String>))| {
+                        records
+                            .iter()
+                            .enumerate()
+                            .map(|(i, record)| {
+                                JournalEntry::from_record(first_entry + i as u64, record).map_err(
+                                    |_| {
+                                        error!(
+                                            "Invalid journal entry for account {}: {}",
+                                            account_id, record
+                                        )
+                                    },
+                                )
+                            })
+                            .collect()
+                    },
+                ),
+        )
+    }
 }
 
 impl ExchangeRateStore for RedisStore {
@@ -573,69 +1429,63 @@ impl BtpStore for RedisStore {
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
         // TODO make sure it can't do script injection!
         // TODO cache the result so we don't hit redis for every packet (is that necessary if redis is often used as a cache?)
-        let decryption_key = self.decryption_key.clone();
         Box::new(
-            cmd("EVAL")
-                .arg(ACCOUNT_FROM_INDEX)
-                .arg(1)
-                .arg("btp_auth")
-                .arg(hmac::sign(&self.hmac_key, token.as_bytes()).as_ref())
-                .query_async(self.connection.as_ref().clone())
+            self.get_account_from_index(BTP_AUTH_KEY, token.as_bytes())
                 .map_err(|err| error!("Error getting account from BTP token: {:?}", err))
-                .and_then(
-                    move |(_connection, account): (_, Option<AccountWithEncryptedTokens>)| {
-                        if let Some(account) = account {
-                            let account = account.decrypt_tokens(&decryption_key);
-                            Ok(account)
-                        } else {
-                            warn!("No account found with BTP token");
-                            Err(())
-                        }
-                    },
-                ),
+                .and_then(|account| {
+                    if let Some(account) = account {
+                        Ok(account)
+                    } else {
+                        warn!("No account found with BTP token");
+                        Err(())
+                    }
+                }),
         )
     }
 
     fn get_btp_outgoing_accounts(
         &self,
     ) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send> {
-        let decryption_key = self.decryption_key.clone();
+        let keys = self.keys.clone();
+        let key_names = self.key_names.clone();
         Box::new(
             cmd("SMEMBERS")
-                .arg("btp_outgoing")
+                .arg(self.key_names.key(BTP_OUTGOING_KEY))
                 .query_async(self.connection.as_ref().clone())
                 .map_err(|err| error!("Error getting members of set btp_outgoing: {:?}", err))
-                .and_then(|(connection, account_ids): (SharedConnection, Vec<u64>)| {
-                    if account_ids.is_empty() {
-                        Either::A(ok(Vec::new()))
-                    } else {
-                        let mut pipe = redis::pipe();
-                        for id in account_ids {
-                            pipe.hgetall(account_details_key(id));
-                        }
-                        Either::B(
-                            pipe.query_async(connection)
-                                .map_err(|err| {
-                                    error!(
+                .and_then(
+                    move |(connection, account_ids): (RedisConnection, Vec<u64>)| {
+                        if account_ids.is_empty() {
+                            Either::A(ok(Vec::new()))
+                        } else {
+                            let mut pipe = redis::pipe();
+                            for id in account_ids {
+                                pipe.hgetall(key_names.account_details(id));
+                            }
+                            Either::B(
+                                pipe.query_async(connection)
+                                    .map_err(|err| {
+                                        error!(
                                         "Error getting accounts with outgoing BTP details: {:?}",
                                         err
                                     )
-                                })
-                                .and_then(
-                                    move |(_connection, accounts): (
-                                        SharedConnection,
-                                        Vec<AccountWithEncryptedTokens>,
-                                    )| {
-                                        let accounts: Vec<Account> = accounts
-                                            .into_iter()
-                                            .map(|account| account.decrypt_tokens(&decryption_key))
-                                            .collect();
-                                        Ok(accounts)
-                                    },
-                                ),
-                        )
-                    }
-                }),
+                                    })
+                                    .and_then(
+                                        move |(_connection, accounts): (
+                                            RedisConnection,
+                                            Vec<AccountWithEncryptedTokens>,
+                                        )| {
+                                            let accounts: Vec<Account> = accounts
+                                                .into_iter()
+                                                .map(|account| account.decrypt_tokens(&keys))
+                                                .collect();
+                                            Ok(accounts)
+                                        },
+                                    ),
+                            )
+                        }
+                    },
+                ),
         )
     }
 }
@@ -648,29 +1498,82 @@ impl HttpStore for RedisStore {
         token: &str,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
         // TODO make sure it can't do script injection!
-        let decryption_key = self.decryption_key.clone();
         let token = token.to_string();
+        Box::new(
+            self.get_account_from_index(HTTP_AUTH_KEY, token.as_bytes())
+                .map_err(|err| error!("Error getting account from HTTP auth: {:?}", err))
+                .and_then(move |account| {
+                    if let Some(account) = account {
+                        Ok(account)
+                    } else {
+                        warn!("No account found with given HTTP auth");
+                        // TODO remove this log line (not safe to log auth token)
+                        trace!("Unknown HTTP auth token: {}", token);
+                        Err(())
+                    }
+                }),
+        )
+    }
+}
+
+impl HttpForwardingStore for RedisStore {
+    type Account = Account;
+
+    fn set_connection_owner(
+        &self,
+        account_id: u64,
+        instance_url: &Url,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(
+            cmd("HSET")
+                .arg(self.key_names.key(CONNECTION_OWNERS_KEY))
+                .arg(account_id)
+                .arg(instance_url.as_str())
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error setting connection owner: {:?}", err))
+                .map(|(_connection, _): (_, Value)| ()),
+        )
+    }
+
+    fn remove_connection_owner(
+        &self,
+        account_id: u64,
+        instance_url: &Url,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
         Box::new(
             cmd("EVAL")
-                .arg(ACCOUNT_FROM_INDEX)
+                .arg(HDEL_IF_EQUAL)
                 .arg(1)
-                .arg("http_auth")
-                .arg(hmac::sign(&self.hmac_key, token.as_bytes()).as_ref())
+                .arg(self.key_names.key(CONNECTION_OWNERS_KEY))
+                .arg(account_id)
+                .arg(instance_url.as_str())
                 .query_async(self.connection.as_ref().clone())
-                .map_err(|err| error!("Error getting account from HTTP auth: {:?}", err))
-                .and_then(
-                    move |(_connection, account): (_, Option<AccountWithEncryptedTokens>)| {
-                        if let Some(account) = account {
-                            let account = account.decrypt_tokens(&decryption_key);
-                            Ok(account)
-                        } else {
-                            warn!("No account found with given HTTP auth");
-                            // TODO remove this log line (not safe to log auth token)
-                            trace!("Unknown HTTP auth token: {}", token);
-                            Err(())
-                        }
-                    },
-                ),
+                .map_err(|err| error!("Error removing connection owner: {:?}", err))
+                .map(|(_connection, _): (_, Value)| ()),
+        )
+    }
+
+    fn get_connection_owner(
+        &self,
+        account_id: u64,
+    ) -> Box<dyn Future<Item = Option<Url>, Error = ()> + Send> {
+        Box::new(
+            cmd("HGET")
+                .arg(self.key_names.key(CONNECTION_OWNERS_KEY))
+                .arg(account_id)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error getting connection owner: {:?}", err))
+                .and_then(|(_connection, instance_url): (_, Option<String>)| {
+                    instance_url
+                        .map(|instance_url| Url::parse(&instance_url))
+                        .transpose()
+                        .map_err(|err| {
+                            error!(
+                                "Invalid instance URL stored for connection owner: {:?}",
+                                err
+                            )
+                        })
+                }),
         )
     }
 }
@@ -679,6 +1582,14 @@ impl RouterStore for RedisStore {
     fn routing_table(&self) -> HashMap<Bytes, u64> {
         self.routes.read().clone()
     }
+
+    fn alternate_routes(&self, prefix: &[u8]) -> Vec<AlternateRoute<u64>> {
+        self.alternate_routes
+            .read()
+            .get(prefix)
+            .cloned()
+            .unwrap_or_default()
+    }
 }
 
 impl NodeStore for RedisStore {
@@ -688,44 +1599,24 @@ impl NodeStore for RedisStore {
         &self,
         account: AccountDetails,
     ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
-        self.create_new_account(account)
+        let store = self.clone();
+        Box::new(self.get_next_account_id().and_then(move |id| {
+            debug!("Next account id is: {}", id);
+            store.create_new_account(id, account)
+        }))
     }
 
     // TODO limit the number of results and page through them
     fn get_all_accounts(&self) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send> {
-        let decryption_key = self.decryption_key.clone();
+        let keys = self.keys.clone();
         Box::new(
-            cmd("GET")
-                .arg(NEXT_ACCOUNT_ID_KEY)
-                .query_async(self.connection.as_ref().clone())
-                .and_then(
-                    move |(connection, next_account_id): (SharedConnection, Option<u64>)| {
-                        if let Some(next_account_id) = next_account_id {
-                            if next_account_id > 0 {
-                                trace!("Getting accounts up to id: {}", next_account_id);
-                                let mut pipe = redis::pipe();
-                                for i in 0..next_account_id {
-                                    pipe.hgetall(account_details_key(i));
-                                }
-                                return Either::A(pipe.query_async(connection).and_then(
-                                    move |(_, accounts): (
-                                        _,
-                                        Vec<Option<AccountWithEncryptedTokens>>,
-                                    )| {
-                                        let accounts: Vec<Account> = accounts
-                                            .into_iter()
-                                            .filter_map(|a| a)
-                                            .map(|account| account.decrypt_tokens(&decryption_key))
-                                            .collect();
-                                        Ok(accounts)
-                                    },
-                                ));
-                            }
-                        }
-                        Either::B(ok(Vec::new()))
-                    },
-                )
-                .map_err(|err| error!("Error getting all accounts: {:?}", err)),
+            self.get_all_accounts_with_encrypted_tokens()
+                .and_then(move |accounts| {
+                    Ok(accounts
+                        .into_iter()
+                        .map(|account| account.decrypt_tokens(&keys))
+                        .collect())
+                }),
         )
     }
 
@@ -735,18 +1626,41 @@ impl NodeStore for RedisStore {
     {
         let rates: Vec<(String, f64)> = rates.into_iter().collect();
         let exchange_rates = self.exchange_rates.clone();
+        let key_names = self.key_names.clone();
         let mut pipe = redis::pipe();
         pipe.atomic()
-            .del(RATES_KEY)
+            .del(key_names.key(RATES_KEY))
             .ignore()
-            .hset_multiple(RATES_KEY, &rates)
+            .hset_multiple(key_names.key(RATES_KEY), &rates)
             .ignore();
         Box::new(
             pipe.query_async(self.connection.as_ref().clone())
                 .map_err(|err| error!("Error setting rates: {:?}", err))
-                .and_then(move |(connection, _): (SharedConnection, Value)| {
+                .and_then(move |(connection, _): (RedisConnection, Value)| {
                     trace!("Set exchange rates: {:?}", exchange_rates);
-                    update_rates(connection, exchange_rates)
+                    update_rates(connection, &key_names, exchange_rates)
+                }),
+        )
+    }
+
+    fn get_account_id_from_username(
+        &self,
+        username: &str,
+    ) -> Box<dyn Future<Item = u64, Error = ()> + Send> {
+        let username = username.to_lowercase();
+        Box::new(
+            cmd("HGET")
+                .arg(self.key_names.key(USERNAMES_KEY))
+                .arg(&username)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error getting account ID from username: {:?}", err))
+                .and_then(move |(_connection, id): (_, Option<u64>)| {
+                    if let Some(id) = id {
+                        Ok(id)
+                    } else {
+                        debug!("No account found with username: {}", username);
+                        Err(())
+                    }
                 }),
         )
     }
@@ -762,13 +1676,14 @@ impl NodeStore for RedisStore {
             HashSet::from_iter(routes.iter().map(|(_prefix, account_id)| *account_id));
         let mut pipe = redis::pipe();
         for account_id in accounts {
-            pipe.exists(account_details_key(account_id));
+            pipe.exists(self.key_names.account_details(account_id));
         }
 
         let routing_table = self.routes.clone();
+        let key_names = self.key_names.clone();
         Box::new(pipe.query_async(self.connection.as_ref().clone())
             .map_err(|err| error!("Error checking if accounts exist while setting static routes: {:?}", err))
-            .and_then(|(connection, accounts_exist): (SharedConnection, Vec<bool>)| {
+            .and_then(|(connection, accounts_exist): (RedisConnection, Vec<bool>)| {
                 if accounts_exist.iter().all(|a| *a) {
                     Ok(connection)
                 } else {
@@ -779,14 +1694,14 @@ impl NodeStore for RedisStore {
             .and_then(move |connection| {
         let mut pipe = redis::pipe();
         pipe.atomic()
-            .del(STATIC_ROUTES_KEY)
+            .del(key_names.key(STATIC_ROUTES_KEY))
             .ignore()
-            .hset_multiple(STATIC_ROUTES_KEY, &routes)
+            .hset_multiple(key_names.key(STATIC_ROUTES_KEY), &routes)
             .ignore();
             pipe.query_async(connection)
                 .map_err(|err| error!("Error setting static routes: {:?}", err))
-                .and_then(move |(connection, _): (SharedConnection, Value)| {
-                    update_routes(connection, routing_table)
+                .and_then(move |(connection, _): (RedisConnection, Value)| {
+                    update_routes(connection, &key_names, routing_table)
                 })
             }))
     }
@@ -797,13 +1712,14 @@ impl NodeStore for RedisStore {
         account_id: u64,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
         let routing_table = self.routes.clone();
+        let key_names = self.key_names.clone();
         let prefix_clone = prefix.clone();
         Box::new(
         cmd("EXISTS")
-            .arg(account_details_key(account_id))
+            .arg(self.key_names.account_details(account_id))
             .query_async(self.connection.as_ref().clone())
             .map_err(|err| error!("Error checking if account exists before setting static route: {:?}", err))
-            .and_then(move |(connection, exists): (SharedConnection, bool)| {
+            .and_then(move |(connection, exists): (RedisConnection, bool)| {
                 if exists {
                     Ok(connection)
                 } else {
@@ -813,17 +1729,133 @@ impl NodeStore for RedisStore {
             })
             .and_then(move |connection| {
                 cmd("HSET")
-                    .arg(STATIC_ROUTES_KEY)
+                    .arg(key_names.key(STATIC_ROUTES_KEY))
                     .arg(prefix)
                     .arg(account_id)
                     .query_async(connection)
                     .map_err(|err| error!("Error setting static route: {:?}", err))
-                    .and_then(move |(connection, _): (SharedConnection, Value)| {
-                        update_routes(connection, routing_table)
+                    .and_then(move |(connection, _): (RedisConnection, Value)| {
+                        update_routes(connection, &key_names, routing_table)
                     })
             })
         )
     }
+
+    fn update_child_addresses(
+        &self,
+        node_address: Address,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let connection = self.connection.clone();
+        let routing_table = self.routes.clone();
+        let key_names = self.key_names.clone();
+        Box::new(self.get_all_accounts().and_then(move |accounts| {
+            // The accounts' details are each in their account's slot, so only the changes
+            // to the routing table are made in one transaction
+            let mut accounts_pipe = redis::pipe();
+            let mut routes_pipe = redis::pipe();
+            routes_pipe.atomic();
+            let mut changed_accounts = 0;
+            for account in accounts
+                .iter()
+                .filter(|account| account.routing_relation == RoutingRelation::Child)
+            {
+                let old_address = account.ilp_address.clone();
+                let old_bytes: &[u8] = old_address.as_ref();
+                let is_under_node_address = old_bytes.starts_with(node_address.as_ref())
+                    && old_bytes.get(node_address.len()) == Some(&b'.');
+                if is_under_node_address {
+                    continue;
+                }
+                let segment = old_address.segments().last().unwrap_or_default();
+                let new_address = match node_address.with_suffix(segment.as_bytes()) {
+                    Ok(address) => address,
+                    Err(err) => {
+                        error!(
+                            "Unable to put account {} under the node's address: {:?}",
+                            account.id, err
+                        );
+                        continue;
+                    }
+                };
+                debug!(
+                    "Changing address of child account {} from {} to {}",
+                    account.id,
+                    str::from_utf8(old_address.as_ref()).unwrap_or("<not utf8>"),
+                    str::from_utf8(new_address.as_ref()).unwrap_or("<not utf8>")
+                );
+                accounts_pipe
+                    .hset(
+                        key_names.account_details(account.id),
+                        "ilp_address",
+                        new_address.to_bytes().to_vec(),
+                    )
+                    .ignore();
+                routes_pipe
+                    .hdel(key_names.key(ROUTES_KEY), old_address.to_bytes().to_vec())
+                    .ignore();
+                routes_pipe
+                    .hset(
+                        key_names.key(ROUTES_KEY),
+                        new_address.to_bytes().to_vec(),
+                        account.id,
+                    )
+                    .ignore();
+                changed_accounts += 1;
+            }
+            if changed_accounts == 0 {
+                return Either::A(ok(()));
+            }
+            Either::B(
+                accounts_pipe
+                    .query_async(connection.as_ref().clone())
+                    .and_then(move |(connection, _ret): (RedisConnection, Value)| {
+                        routes_pipe.query_async(connection)
+                    })
+                    .map_err(|err| error!("Error updating child account addresses: {:?}", err))
+                    .and_then(move |(connection, _ret): (RedisConnection, Value)| {
+                        update_routes(connection, &key_names, routing_table)
+                    }),
+            )
+        }))
+    }
+
+    fn set_account_asset(
+        &self,
+        account_id: u64,
+        asset_code: String,
+        asset_scale: u8,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let connection = self.connection.as_ref().clone();
+        let key_names = self.key_names.clone();
+        Box::new(
+            cmd("EXISTS")
+                .arg(key_names.account_details(account_id))
+                .query_async(connection.clone())
+                .map_err(|err| error!("Error checking whether account exists: {:?}", err))
+                .and_then(move |(_connection, exists): (RedisConnection, bool)| {
+                    if exists {
+                        Ok(())
+                    } else {
+                        error!(
+                            "Cannot set asset of account {} because it does not exist",
+                            account_id
+                        );
+                        Err(())
+                    }
+                })
+                .and_then(move |_| {
+                    cmd("HMSET")
+                        .arg(key_names.account_details(account_id))
+                        .arg("asset_code")
+                        .arg(asset_code.to_uppercase())
+                        .arg("asset_scale")
+                        .arg(asset_scale)
+                        .query_async(connection)
+                        .map_err(|err| error!("Error setting account asset: {:?}", err))
+                        .map(|(_connection, _): (RedisConnection, Value)| ())
+                }),
+        )
+    }
 }
 
 type RoutingTable<A> = HashMap<Bytes, A>;
@@ -834,50 +1866,57 @@ impl RouteManagerStore for RedisStore {
     fn get_accounts_to_send_routes_to(
         &self,
     ) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
-        let decryption_key = self.decryption_key.clone();
+        let keys = self.keys.clone();
+        let key_names = self.key_names.clone();
         Box::new(
             cmd("SMEMBERS")
-                .arg("send_routes_to")
+                .arg(self.key_names.key(SEND_ROUTES_TO_KEY))
                 .query_async(self.connection.as_ref().clone())
                 .map_err(|err| error!("Error getting members of set send_routes_to: {:?}", err))
-                .and_then(|(connection, account_ids): (SharedConnection, Vec<u64>)| {
-                    if account_ids.is_empty() {
-                        Either::A(ok(Vec::new()))
-                    } else {
-                        let mut pipe = redis::pipe();
-                        for id in account_ids {
-                            pipe.hgetall(account_details_key(id));
+                .and_then(
+                    move |(connection, account_ids): (RedisConnection, Vec<u64>)| {
+                        if account_ids.is_empty() {
+                            Either::A(ok(Vec::new()))
+                        } else {
+                            let mut pipe = redis::pipe();
+                            for id in account_ids {
+                                pipe.hgetall(key_names.account_details(id));
+                            }
+                            Either::B(
+                                pipe.query_async(connection)
+                                    .map_err(|err| {
+                                        error!(
+                                            "Error getting accounts to send routes to: {:?}",
+                                            err
+                                        )
+                                    })
+                                    .and_then(
+                                        move |(_connection, accounts): (
+                                            RedisConnection,
+                                            Vec<AccountWithEncryptedTokens>,
+                                        )| {
+                                            let accounts: Vec<Account> = accounts
+                                                .into_iter()
+                                                .map(|account| account.decrypt_tokens(&keys))
+                                                .collect();
+                                            Ok(accounts)
+                                        },
+                                    ),
+                            )
                         }
-                        Either::B(
-                            pipe.query_async(connection)
-                                .map_err(|err| {
-                                    error!("Error getting accounts to send routes to: {:?}", err)
-                                })
-                                .and_then(
-                                    move |(_connection, accounts): (
-                                        SharedConnection,
-                                        Vec<AccountWithEncryptedTokens>,
-                                    )| {
-                                        let accounts: Vec<Account> = accounts
-                                            .into_iter()
-                                            .map(|account| account.decrypt_tokens(&decryption_key))
-                                            .collect();
-                                        Ok(accounts)
-                                    },
-                                ),
-                        )
-                    }
-                }),
+                    },
+                ),
         )
     }
 
     fn get_accounts_to_receive_routes_from(
         &self,
     ) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
-        let decryption_key = self.decryption_key.clone();
+        let keys = self.keys.clone();
+        let key_names = self.key_names.clone();
         Box::new(
             cmd("SMEMBERS")
-                .arg("receive_routes_from")
+                .arg(self.key_names.key(RECEIVE_ROUTES_FROM_KEY))
                 .query_async(self.connection.as_ref().clone())
                 .map_err(|err| {
                     error!(
@@ -885,37 +1924,39 @@ impl RouteManagerStore for RedisStore {
                         err
                     )
                 })
-                .and_then(|(connection, account_ids): (SharedConnection, Vec<u64>)| {
-                    if account_ids.is_empty() {
-                        Either::A(ok(Vec::new()))
-                    } else {
-                        let mut pipe = redis::pipe();
-                        for id in account_ids {
-                            pipe.hgetall(account_details_key(id));
+                .and_then(
+                    move |(connection, account_ids): (RedisConnection, Vec<u64>)| {
+                        if account_ids.is_empty() {
+                            Either::A(ok(Vec::new()))
+                        } else {
+                            let mut pipe = redis::pipe();
+                            for id in account_ids {
+                                pipe.hgetall(key_names.account_details(id));
+                            }
+                            Either::B(
+                                pipe.query_async(connection)
+                                    .map_err(|err| {
+                                        error!(
+                                            "Error getting accounts to receive routes from: {:?}",
+                                            err
+                                        )
+                                    })
+                                    .and_then(
+                                        move |(_connection, accounts): (
+                                            RedisConnection,
+                                            Vec<AccountWithEncryptedTokens>,
+                                        )| {
+                                            let accounts: Vec<Account> = accounts
+                                                .into_iter()
+                                                .map(|account| account.decrypt_tokens(&keys))
+                                                .collect();
+                                            Ok(accounts)
+                                        },
+                                    ),
+                            )
                         }
-                        Either::B(
-                            pipe.query_async(connection)
-                                .map_err(|err| {
-                                    error!(
-                                        "Error getting accounts to receive routes from: {:?}",
-                                        err
-                                    )
-                                })
-                                .and_then(
-                                    move |(_connection, accounts): (
-                                        SharedConnection,
-                                        Vec<AccountWithEncryptedTokens>,
-                                    )| {
-                                        let accounts: Vec<Account> = accounts
-                                            .into_iter()
-                                            .map(|account| account.decrypt_tokens(&decryption_key))
-                                            .collect();
-                                        Ok(accounts)
-                                    },
-                                ),
-                        )
-                    }
-                }),
+                    },
+                ),
         )
     }
 
@@ -924,11 +1965,11 @@ impl RouteManagerStore for RedisStore {
     ) -> Box<dyn Future<Item = (RoutingTable<Account>, RoutingTable<Account>), Error = ()> + Send>
     {
         let get_static_routes = cmd("HGETALL")
-            .arg(STATIC_ROUTES_KEY)
+            .arg(self.key_names.key(STATIC_ROUTES_KEY))
             .query_async(self.connection.as_ref().clone())
             .map_err(|err| error!("Error getting static routes: {:?}", err))
             .and_then(
-                |(_, static_routes): (SharedConnection, Vec<(String, u64)>)| Ok(static_routes),
+                |(_, static_routes): (RedisConnection, Vec<(String, u64)>)| Ok(static_routes),
             );
         Box::new(self.get_all_accounts().join(get_static_routes).and_then(
             |(accounts, static_routes)| {
@@ -972,18 +2013,56 @@ impl RouteManagerStore for RedisStore {
 
         // Save routes to Redis
         let routing_tale = self.routes.clone();
+        let key_names = self.key_names.clone();
         let mut pipe = redis::pipe();
         pipe.atomic()
-            .del(ROUTES_KEY)
+            .del(key_names.key(ROUTES_KEY))
             .ignore()
-            .hset_multiple(ROUTES_KEY, &routes)
+            .hset_multiple(key_names.key(ROUTES_KEY), &routes)
             .ignore();
         Box::new(
             pipe.query_async(self.connection.as_ref().clone())
                 .map_err(|err| error!("Error setting routes: {:?}", err))
-                .and_then(move |(connection, _): (SharedConnection, Value)| {
+                .and_then(move |(connection, _): (RedisConnection, Value)| {
                     trace!("Saved {} routes to Redis", num_routes);
-                    update_routes(connection, routing_tale)
+                    update_routes(connection, &key_names, routing_tale)
+                }),
+        )
+    }
+
+    fn set_alternate_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Vec<(Account, bool)>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let routes: Vec<(String, String)> = routes
+            .into_iter()
+            .filter_map(|(prefix, alternates)| {
+                let prefix = String::from_utf8(prefix.to_vec()).ok()?;
+                let alternates: Vec<String> = alternates
+                    .into_iter()
+                    .map(|(account, equal_cost)| {
+                        format!("{}:{}", account.id, if equal_cost { 1 } else { 0 })
+                    })
+                    .collect();
+                Some((prefix, alternates.join(",")))
+            })
+            .collect();
+
+        let alternate_routes = self.alternate_routes.clone();
+        let key_names = self.key_names.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .del(key_names.key(ALTERNATE_ROUTES_KEY))
+            .ignore();
+        if !routes.is_empty() {
+            pipe.hset_multiple(key_names.key(ALTERNATE_ROUTES_KEY), &routes)
+                .ignore();
+        }
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error setting alternate routes: {:?}", err))
+                .and_then(move |(connection, _): (RedisConnection, Value)| {
+                    update_alternate_routes(connection, &key_names, alternate_routes)
                 }),
         )
     }
@@ -1008,7 +2087,7 @@ impl RateLimitStore for RedisStore {
             if let Some(limit) = account.packets_per_minute_limit {
                 let limit = limit - 1;
                 pipe.cmd("CL.THROTTLE")
-                    .arg(format!("limit:packets:{}", account.id))
+                    .arg(self.key_names.packets_limit(account.id))
                     .arg(limit)
                     .arg(limit)
                     .arg(60)
@@ -1018,7 +2097,7 @@ impl RateLimitStore for RedisStore {
             if let Some(limit) = account.amount_per_minute_limit {
                 let limit = limit - 1;
                 pipe.cmd("CL.THROTTLE")
-                    .arg(format!("limit:throughput:{}", account.id))
+                    .arg(self.key_names.throughput_limit(account.id))
                     // TODO allow separate configuration for burst limit
                     .arg(limit)
                     .arg(limit)
@@ -1063,7 +2142,7 @@ impl RateLimitStore for RedisStore {
             let limit = limit - 1;
             Box::new(
                 cmd("CL.THROTTLE")
-                    .arg(format!("limit:throughput:{}", account.id))
+                    .arg(self.key_names.throughput_limit(account.id))
                     .arg(limit)
                     .arg(limit)
                     .arg(60)
@@ -1087,7 +2166,7 @@ impl IdempotentStore for RedisStore {
         let idempotency_key_clone = idempotency_key.clone();
         Box::new(
             cmd("HGETALL")
-                .arg(prefixed_idempotency_key(idempotency_key.clone()))
+                .arg(self.key_names.idempotency_key(&idempotency_key))
                 .query_async(self.connection.as_ref().clone())
                 .map_err(move |err| {
                     error!(
@@ -1126,7 +2205,7 @@ impl IdempotentStore for RedisStore {
         let mut pipe = redis::pipe();
         pipe.atomic()
             .cmd("HMSET") // cannot use hset_multiple since data and status_code have different types
-            .arg(&prefixed_idempotency_key(idempotency_key.clone()))
+            .arg(self.key_names.idempotency_key(&idempotency_key))
             .arg("status_code")
             .arg(status_code.as_u16())
             .arg("data")
@@ -1134,7 +2213,7 @@ impl IdempotentStore for RedisStore {
             .arg("input_hash")
             .arg(&input_hash)
             .ignore()
-            .expire(&prefixed_idempotency_key(idempotency_key.clone()), 86400)
+            .expire(self.key_names.idempotency_key(&idempotency_key), 86400)
             .ignore();
         Box::new(
             pipe.query_async(self.connection.as_ref().clone())
@@ -1152,6 +2231,173 @@ impl IdempotentStore for RedisStore {
     }
 }
 
+impl NodeStateStore for RedisStore {
+    fn export_state(&self) -> Box<dyn Future<Item = NodeState, Error = ()> + Send> {
+        let connection = self.connection.as_ref().clone();
+        let key_names = self.key_names.clone();
+        let key_names_clone = self.key_names.clone();
+        let idempotency_key_pattern = self.key_names.idempotency_key_pattern();
+        let idempotency_key_prefix_len = self.key_names.idempotency_key("").len();
+        Box::new(
+            self.get_all_accounts()
+                .and_then(move |accounts| {
+                    // Each account's balance is in its own slot, so they cannot be read in
+                    // one transaction
+                    let mut pipe = redis::pipe();
+                    for account in accounts.iter() {
+                        pipe.cmd("HMGET")
+                            .arg(key_names.account_details(account.id))
+                            .arg(&["balance", "prepaid_amount"]);
+                    }
+                    let balances = if accounts.is_empty() {
+                        Either::A(ok((connection, Vec::new())))
+                    } else {
+                        Either::B(pipe.query_async(connection))
+                    };
+                    balances
+                        .map_err(|err| error!("Error exporting balances: {:?}", err))
+                        .and_then(
+                            move |(connection, balances): (RedisConnection, Vec<(i64, i64)>)| {
+                                let mut state = NodeState::new();
+                                state.accounts = accounts
+                                    .iter()
+                                    .zip(balances.into_iter())
+                                    .map(|(account, (balance, prepaid_amount))| AccountState {
+                                        id: account.id,
+                                        details: AccountDetails::from_account(account),
+                                        balance,
+                                        prepaid_amount,
+                                        has_incoming_tokens: false,
+                                    })
+                                    .collect();
+                                Ok((connection, state))
+                            },
+                        )
+                })
+                .and_then(move |(connection, mut state)| {
+                    let mut pipe = redis::pipe();
+                    pipe.hgetall(key_names_clone.key(STATIC_ROUTES_KEY))
+                        .hgetall(key_names_clone.key(RATES_KEY))
+                        .hvals(key_names_clone.key(HTTP_AUTH_KEY))
+                        .hvals(key_names_clone.key(BTP_AUTH_KEY));
+                    pipe.query_async(connection)
+                        .map_err(|err| error!("Error exporting routes and rates: {:?}", err))
+                        .and_then(
+                            move |(connection, (static_routes, rates, http_auth, btp_auth)): (
+                                RedisConnection,
+                                (Vec<(String, u64)>, Vec<(String, f64)>, Vec<u64>, Vec<u64>),
+                            )| {
+                                let with_incoming_tokens: HashSet<u64> =
+                                    http_auth.into_iter().chain(btp_auth).collect();
+                                for account in state.accounts.iter_mut() {
+                                    account.has_incoming_tokens =
+                                        with_incoming_tokens.contains(&account.id);
+                                }
+                                state.static_routes = static_routes.into_iter().collect();
+                                state.rates = rates.into_iter().collect();
+                                Ok((connection, state))
+                            },
+                        )
+                })
+                .and_then(move |(connection, state)| {
+                    scan_keys(connection, idempotency_key_pattern)
+                        .map(move |(connection, keys)| (connection, keys, state))
+                })
+                .and_then(move |(connection, keys, mut state)| {
+                    let mut pipe = redis::pipe();
+                    pipe.atomic();
+                    for key in keys.iter() {
+                        pipe.hgetall(key);
+                    }
+                    pipe.query_async(connection)
+                        .map_err(|err| error!("Error exporting idempotency keys: {:?}", err))
+                        .and_then(
+                            move |(_connection, data): (_, Vec<HashMap<String, Vec<u8>>>)| {
+                                // Keys may have expired since they were scanned
+                                state.idempotent_data = keys
+                                    .into_iter()
+                                    .zip(data.into_iter())
+                                    .filter_map(|(key, data)| {
+                                        let status_code = str::from_utf8(data.get("status_code")?)
+                                            .ok()
+                                            .and_then(|code| StatusCode::from_str(code).ok())?;
+                                        let input_hash = data.get("input_hash")?;
+                                        if input_hash.len() != 32 {
+                                            return None;
+                                        }
+                                        let mut hash = [0; 32];
+                                        hash.copy_from_slice(input_hash);
+                                        Some(IdempotentDataState::new(
+                                            key[idempotency_key_prefix_len..].to_string(),
+                                            status_code,
+                                            data.get("data")?,
+                                            &hash,
+                                        ))
+                                    })
+                                    .collect();
+                                Ok(state)
+                            },
+                        )
+                }),
+        )
+    }
+
+    fn import_state(&self, state: NodeState) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let NodeState {
+            accounts,
+            static_routes,
+            rates,
+            idempotent_data,
+            ..
+        } = state;
+        let accounts: Vec<(u64, AccountDetails, Option<(i64, i64)>)> = accounts
+            .into_iter()
+            .map(|account| {
+                (
+                    account.id,
+                    account.details,
+                    Some((account.balance, account.prepaid_amount)),
+                )
+            })
+            .collect();
+        let idempotent_data = idempotent_data
+            .iter()
+            .filter_map(|entry| Some((entry.idempotency_key.clone(), entry.decode()?)))
+            .collect();
+        let store = self.clone();
+        let mut pipe = redis::pipe();
+        pipe.hgetall(self.key_names.key(STATIC_ROUTES_KEY))
+            .hgetall(self.key_names.key(RATES_KEY));
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error loading the routes and rates to replace: {:?}", err))
+                .and_then(
+                    move |(_connection, (previous_static_routes, previous_rates)): (
+                        RedisConnection,
+                        (Vec<(String, u64)>, Vec<(String, f64)>),
+                    )| {
+                        let imported = ImportedData {
+                            static_routes: static_routes.into_iter().collect(),
+                            rates: rates.into_iter().collect(),
+                            idempotent_data,
+                            previous_static_routes,
+                            previous_rates,
+                        };
+                        store
+                            .create_new_accounts(accounts, Some(imported))
+                            .and_then(move |_| {
+                                update_rates(
+                                    store.connection.as_ref().clone(),
+                                    &store.key_names,
+                                    store.exchange_rates.clone(),
+                                )
+                            })
+                    },
+                ),
+        )
+    }
+}
+
 impl SettlementStore for RedisStore {
     type Account = Account;
 
@@ -1164,10 +2410,18 @@ impl SettlementStore for RedisStore {
         let idempotency_key = idempotency_key.unwrap();
         Box::new(cmd("EVAL")
             .arg(PROCESS_INCOMING_SETTLEMENT)
-            .arg(0)
+            .arg(3)
+            .arg(self.key_names.account_details(account_id))
+            .arg(self.key_names.journal(account_id))
+            .arg(
+                self.key_names
+                    .incoming_settlement(account_id, &idempotency_key),
+            )
             .arg(account_id)
             .arg(amount)
             .arg(idempotency_key)
+            .arg(journal_timestamp())
+            .arg(self.journal_max_entries)
             .query_async(self.connection.as_ref().clone())
             .map_err(move |err| error!("Error processing incoming settlement from account: {} for amount: {}: {:?}", account_id, amount, err))
             .and_then(move |(_connection, balance): (_, i64)| {
@@ -1189,9 +2443,13 @@ impl SettlementStore for RedisStore {
         Box::new(
             cmd("EVAL")
                 .arg(REFUND_SETTLEMENT)
-                .arg(0)
+                .arg(2)
+                .arg(self.key_names.account_details(account_id))
+                .arg(self.key_names.journal(account_id))
                 .arg(account_id)
                 .arg(settle_amount)
+                .arg(journal_timestamp())
+                .arg(self.journal_max_entries)
                 .query_async(self.connection.as_ref().clone())
                 .map_err(move |err| {
                     error!(
@@ -1213,12 +2471,46 @@ impl SettlementStore for RedisStore {
 }
 
 // TODO replace this with pubsub when async pubsub is added upstream: https://github.com/mitsuhiko/redis-rs/issues/183
+/// Get all of the keys matching the pattern, without blocking the server like KEYS would
+fn scan_keys(
+    connection: RedisConnection,
+    pattern: String,
+) -> impl Future<Item = (RedisConnection, Vec<String>), Error = ()> {
+    loop_fn(
+        (connection, 0, Vec::new()),
+        move |(connection, cursor, mut keys): (RedisConnection, u64, Vec<String>)| {
+            cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(1000)
+                .query_async(connection)
+                .map_err(|err| error!("Error scanning keys: {:?}", err))
+                .map(
+                    move |(connection, (cursor, mut batch)): (
+                        RedisConnection,
+                        (u64, Vec<String>),
+                    )| {
+                        keys.append(&mut batch);
+                        if cursor == 0 {
+                            Loop::Break((connection, keys))
+                        } else {
+                            Loop::Continue((connection, cursor, keys))
+                        }
+                    },
+                )
+        },
+    )
+}
+
 fn update_rates(
-    connection: SharedConnection,
+    connection: RedisConnection,
+    key_names: &KeyNames,
     exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
 ) -> impl Future<Item = (), Error = ()> {
     cmd("HGETALL")
-        .arg(RATES_KEY)
+        .arg(key_names.key(RATES_KEY))
         .query_async(connection)
         .map_err(|err| error!("Error polling for exchange rates: {:?}", err))
         .and_then(move |(_connection, rates): (_, Vec<(String, f64)>)| {
@@ -1234,11 +2526,13 @@ fn update_rates(
 type RouteVec = Vec<(String, u64)>;
 
 fn update_routes(
-    connection: SharedConnection,
+    connection: RedisConnection,
+    key_names: &KeyNames,
     routing_table: Arc<RwLock<HashMap<Bytes, u64>>>,
 ) -> impl Future<Item = (), Error = ()> {
     let mut pipe = redis::pipe();
-    pipe.hgetall(ROUTES_KEY).hgetall(STATIC_ROUTES_KEY);
+    pipe.hgetall(key_names.key(ROUTES_KEY))
+        .hgetall(key_names.key(STATIC_ROUTES_KEY));
     pipe.query_async(connection)
         .map_err(|err| error!("Error polling for routing table updates: {:?}", err))
         .and_then(
@@ -1265,6 +2559,37 @@ fn update_routes(
         )
 }
 
+/// Alternate routes are stored as comma-separated lists of "<account ID>:<1 if equal cost, else 0>"
+fn update_alternate_routes(
+    connection: RedisConnection,
+    key_names: &KeyNames,
+    alternate_routes: Arc<RwLock<HashMap<Bytes, Vec<AlternateRoute<u64>>>>>,
+) -> impl Future<Item = (), Error = ()> {
+    cmd("HGETALL")
+        .arg(key_names.key(ALTERNATE_ROUTES_KEY))
+        .query_async(connection)
+        .map_err(|err| error!("Error polling for alternate routes: {:?}", err))
+        .and_then(move |(_connection, routes): (_, Vec<(String, String)>)| {
+            let routes = HashMap::from_iter(routes.into_iter().map(|(prefix, alternates)| {
+                let alternates = alternates
+                    .split(',')
+                    .filter_map(|alternate| {
+                        let mut parts = alternate.split(':');
+                        let account_id = parts.next()?.parse().ok()?;
+                        let equal_cost = parts.next() == Some("1");
+                        Some(AlternateRoute {
+                            account_id,
+                            equal_cost,
+                        })
+                    })
+                    .collect();
+                (Bytes::from(prefix), alternates)
+            }));
+            *alternate_routes.write() = routes;
+            Ok(())
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1293,4 +2618,27 @@ mod tests {
             ))
             .unwrap();
     }
+
+    #[test]
+    fn hash_tags_give_each_account_its_own_slot() {
+        let key_names = KeyNames::new("node", true);
+        assert_eq!(key_names.account_details(1), "{node:1}:accounts");
+        assert_eq!(key_names.journal(1), "{node:1}:journal");
+        assert_eq!(key_names.packets_limit(1), "{node:1}:limit:packets");
+        assert_eq!(key_names.incoming_settlement(1, "abc"), "{node:1}:abc");
+        assert_eq!(key_names.key(USERNAMES_KEY), "{node}:usernames");
+        assert_eq!(
+            key_names.idempotency_key_pattern(),
+            "{node}:idempotency-key:*"
+        );
+        assert_eq!(
+            KeyNames::new("", true).account_details(1),
+            "{interledger:1}:accounts"
+        );
+
+        let key_names = KeyNames::new("", false);
+        assert_eq!(key_names.account_details(1), "accounts:1");
+        assert_eq!(key_names.incoming_settlement(1, "abc"), "abc");
+        assert_eq!(key_names.idempotency_key_pattern(), "idempotency-key:*");
+    }
 }
@@ -4,138 +4,187 @@ use common::*;
 
 use interledger_api::NodeStore;
 use interledger_btp::BtpAccount;
-use interledger_http::HttpAccount;
-use interledger_ildcp::IldcpAccount;
-use interledger_packet::Address;
+use interledger_http::{HttpAccount, HttpStore};
 use interledger_service::Account as AccontTrait;
 use interledger_service::AccountStore;
 use interledger_service_util::BalanceStore;
-use std::str::FromStr;
+use std::collections::HashMap;
 
 #[test]
-fn insert_accounts() {
-    block_on(test_store().and_then(|(store, context)| {
-        store
-            .insert_account(ACCOUNT_DETAILS_2.clone())
-            .and_then(move |account| {
-                assert_eq!(account.id(), 2);
-                let _ = context;
-                Ok(())
+fn reads_and_migrates_tokens_after_secret_rotation() {
+    block_on(test_store().and_then(|(_store, context)| {
+        let connection_info = context.get_client_connection_info();
+        RedisStoreBuilder::new(connection_info.clone(), [1; 32])
+            .previous_secrets(vec![[0; 32]])
+            .connect()
+            .and_then(|store| {
+                let store_clone = store.clone();
+                let store_clone_2 = store.clone();
+                store
+                    .get_accounts(vec![0])
+                    .and_then(move |accounts| {
+                        assert_eq!(
+                            accounts[0].get_http_auth_token().unwrap(),
+                            "outgoing_auth_token"
+                        );
+                        assert_eq!(accounts[0].get_btp_token().unwrap(), b"btp_token");
+                        // Looking the account up moves its index entry to the current keys
+                        store_clone.get_account_from_http_token("incoming_auth_token")
+                    })
+                    .and_then(move |account| {
+                        assert_eq!(account.id(), 0);
+                        store_clone_2.migrate_keys()
+                    })
+            })
+            .and_then(move |migration| {
+                assert_eq!(migration.reencrypted_tokens, 4);
+                assert_eq!(migration.stale_index_entries, 3);
+                assert_eq!(migration.stale_accounts, vec![0, 1]);
+                RedisStoreBuilder::new(connection_info, [1; 32]).connect()
+            })
+            .and_then(|store| {
+                let store_clone = store.clone();
+                store
+                    .get_accounts(vec![0])
+                    .and_then(move |accounts| {
+                        assert_eq!(
+                            accounts[0].get_http_auth_token().unwrap(),
+                            "outgoing_auth_token"
+                        );
+                        store_clone.get_account_from_http_token("incoming_auth_token")
+                    })
+                    .and_then(move |account| {
+                        assert_eq!(account.id(), 0);
+                        let _ = context;
+                        Ok(())
+                    })
             })
     }))
-    .unwrap();
-}
-
-#[test]
-fn starts_with_zero_balance() {
-    block_on(test_store().and_then(|(store, context)| {
-        let account0 = Account::try_from(0, ACCOUNT_DETAILS_0.clone()).unwrap();
-        store.get_balance(account0).and_then(move |balance| {
-            assert_eq!(balance, 0);
-            let _ = context;
-            Ok(())
-        })
-    }))
-    .unwrap();
-}
-
-#[test]
-fn fails_on_duplicate_http_incoming_auth() {
-    let mut account = ACCOUNT_DETAILS_2.clone();
-    account.http_incoming_token = Some("incoming_auth_token".to_string());
-    let result = block_on(test_store().and_then(|(store, context)| {
-        store.insert_account(account).then(move |result| {
-            let _ = context;
-            result
-        })
-    }));
-    assert!(result.is_err());
-}
-
-#[test]
-fn fails_on_duplicate_btp_incoming_auth() {
-    let mut account = ACCOUNT_DETAILS_2.clone();
-    account.btp_incoming_token = Some("btp_token".to_string());
-    let result = block_on(test_store().and_then(|(store, context)| {
-        store.insert_account(account).then(move |result| {
-            let _ = context;
-            result
-        })
-    }));
-    assert!(result.is_err());
-}
-
-#[test]
-fn get_all_accounts() {
-    block_on(test_store().and_then(|(store, context)| {
-        store.get_all_accounts().and_then(move |accounts| {
-            assert_eq!(accounts.len(), 2);
-            let _ = context;
-            Ok(())
-        })
-    }))
-    .unwrap();
+    .unwrap()
 }
 
 #[test]
-fn gets_single_account() {
-    block_on(test_store().and_then(|(store, context)| {
-        store.get_accounts(vec![1]).and_then(move |accounts| {
-            assert_eq!(
-                accounts[0].client_address(),
-                &Address::from_str("example.bob").unwrap()
-            );
-            let _ = context;
-            Ok(())
-        })
+fn removes_stale_index_entries() {
+    block_on(test_store().and_then(|(_store, context)| {
+        RedisStoreBuilder::new(context.get_client_connection_info(), [1; 32])
+            .previous_secrets(vec![[0; 32]])
+            .connect()
+            .and_then(|store| {
+                let store_clone = store.clone();
+                let store_clone_2 = store.clone();
+                store
+                    .get_account_from_http_token("incoming_auth_token")
+                    .and_then(move |_| store_clone.remove_stale_index_entries())
+                    .and_then(move |stale_accounts| {
+                        assert_eq!(stale_accounts, vec![0, 1]);
+                        store_clone_2.migrate_keys()
+                    })
+                    .and_then(move |migration| {
+                        assert_eq!(migration.stale_index_entries, 0);
+                        // The entry that was moved to the current keys still works
+                        store
+                            .get_account_from_http_token("incoming_auth_token")
+                            .and_then(move |account| {
+                                assert_eq!(account.id(), 0);
+                                store.get_account_from_btp_token("btp_token")
+                            })
+                            .then(move |result| {
+                                assert!(result.is_err());
+                                let _ = context;
+                                Ok(())
+                            })
+                    })
+            })
     }))
-    .unwrap();
+    .unwrap()
 }
 
 #[test]
-fn gets_multiple() {
-    block_on(test_store().and_then(|(store, context)| {
-        store.get_accounts(vec![1, 0]).and_then(move |accounts| {
-            // note reverse order is intentional
-            assert_eq!(
-                accounts[0].client_address(),
-                &Address::from_str("example.bob").unwrap()
-            );
-            assert_eq!(
-                accounts[1].client_address(),
-                &Address::from_str("example.alice").unwrap()
-            );
-            let _ = context;
-            Ok(())
-        })
+fn stores_only_hmacs_of_incoming_tokens() {
+    block_on(test_store().and_then(|(_store, context)| {
+        context
+            .async_connection()
+            .map_err(|err| panic!(err))
+            .and_then(|connection| {
+                let mut pipe = redis::pipe();
+                pipe.hgetall("accounts:0").hkeys("http_auth");
+                pipe.query_async(connection)
+                    .map_err(|err| panic!(err))
+                    .and_then(
+                        |(_connection, (account, http_auth)): (
+                            _,
+                            (HashMap<String, Vec<u8>>, Vec<Vec<u8>>),
+                        )| {
+                            let token = &b"incoming_auth_token"[..];
+                            assert!(!account.values().any(|value| value.as_slice() == token));
+                            assert_eq!(http_auth.len(), 2);
+                            assert!(!http_auth.iter().any(|field| field.as_slice() == token));
+                            let _ = context;
+                            Ok(())
+                        },
+                    )
+            })
     }))
-    .unwrap();
+    .unwrap()
 }
 
 #[test]
-fn decrypts_outgoing_tokens() {
+fn separates_stores_with_different_key_prefixes() {
     block_on(test_store().and_then(|(store, context)| {
-        store.get_accounts(vec![0]).and_then(move |accounts| {
-            let account = &accounts[0];
-            assert_eq!(
-                account.get_http_auth_token().unwrap(),
-                "outgoing_auth_token"
-            );
-            assert_eq!(account.get_btp_token().unwrap(), b"btp_token");
-            let _ = context;
-            Ok(())
-        })
+        RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+            .key_prefix("other")
+            .connect()
+            .and_then(|other| {
+                let other_clone = other.clone();
+                other
+                    .get_all_accounts()
+                    .and_then(move |accounts| {
+                        assert!(accounts.is_empty());
+                        // The same details do not conflict with the unprefixed store's account
+                        other_clone
+                            .insert_account(ACCOUNT_DETAILS_0.clone())
+                            .map(move |account| (other_clone, account))
+                    })
+                    .and_then(|(other, account)| {
+                        assert_eq!(account.id(), 0);
+                        let other_clone = other.clone();
+                        other
+                            .update_balances_for_prepare(account, 100, "condition")
+                            .and_then(move |_| {
+                                other_clone.get_account_from_http_token("incoming_auth_token")
+                            })
+                            .map(move |account| (other, account))
+                    })
+                    .and_then(|(other, account)| {
+                        other
+                            .get_balance(account.clone())
+                            .join(other.get_balance_journal(account, 1, 10))
+                    })
+            })
+            .and_then(move |(balance, journal)| {
+                assert_eq!(balance, -100);
+                assert_eq!(journal.len(), 1);
+                store.get_all_accounts().and_then(move |accounts| {
+                    assert_eq!(accounts.len(), 2);
+                    store.get_balance(accounts[0].clone())
+                })
+            })
+            .and_then(move |balance| {
+                assert_eq!(balance, 0);
+                context
+                    .async_connection()
+                    .and_then(|connection| {
+                        redis::cmd("EXISTS")
+                            .arg("other:accounts:0")
+                            .query_async(connection)
+                            .map_err(|err| panic!(err))
+                    })
+                    .and_then(move |(_connection, exists): (_, bool)| {
+                        assert!(exists);
+                        let _ = context;
+                        Ok(())
+                    })
+            })
     }))
     .unwrap()
 }
-
-#[test]
-fn errors_for_unknown_accounts() {
-    let result = block_on(test_store().and_then(|(store, context)| {
-        store.get_accounts(vec![0, 5]).then(move |result| {
-            let _ = context;
-            result
-        })
-    }));
-    assert!(result.is_err());
-}
@@ -1,12 +1,9 @@
 mod common;
 
 use common::*;
-use futures::future::{self, Either};
+use futures::future;
 use interledger_api::NodeStore;
-use interledger_packet::Address;
-use interledger_service::AccountStore;
 use interledger_service_util::BalanceStore;
-use std::str::FromStr;
 
 #[test]
 fn get_balance() {
@@ -37,282 +34,35 @@ fn get_balance() {
 }
 
 #[test]
-fn prepare_then_fulfill_with_settlement() {
-    block_on(test_store().and_then(|(store, context)| {
-        let store_clone_1 = store.clone();
-        let store_clone_2 = store.clone();
-        store
-            .clone()
-            .get_accounts(vec![0, 1])
-            .map_err(|_err| panic!("Unable to get accounts"))
-            .and_then(move |accounts| {
-                let account0 = accounts[0].clone();
-                let account1 = accounts[1].clone();
+fn trims_journal_to_max_entries() {
+    let context = TestContext::new();
+    block_on(
+        RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+            .journal_max_entries(2)
+            .connect()
+            .and_then(|store| {
+                let store_clone = store.clone();
                 store
-                    // reduce account 0's balance by 100
-                    .update_balances_for_prepare(accounts[0].clone(), 100)
-                    .and_then(move |_| {
-                        store_clone_1
-                            .clone()
-                            .get_balance(accounts[0].clone())
-                            .join(store_clone_1.clone().get_balance(accounts[1].clone()))
-                            .and_then(|(balance0, balance1)| {
-                                assert_eq!(balance0, -100);
-                                assert_eq!(balance1, 0);
-                                Ok(())
-                            })
-                    })
-                    .and_then(move |_| {
-                        store_clone_2
-                            .clone()
-                            .update_balances_for_fulfill(account1.clone(), 100)
-                            .and_then(move |_| {
-                                store_clone_2
-                                    .clone()
-                                    .get_balance(account0.clone())
-                                    .join(store_clone_2.clone().get_balance(account1.clone()))
-                                    .and_then(move |(balance0, balance1)| {
-                                        assert_eq!(balance0, -100);
-                                        assert_eq!(balance1, -1000); // the account must be settled down to -1000
-                                        let _ = context;
-                                        Ok(())
-                                    })
-                            })
-                    })
-            })
-    }))
-    .unwrap();
-}
-
-#[test]
-fn process_fulfill_no_settle_to() {
-    // account without a settle_to
-    let acc = {
-        let mut acc = ACCOUNT_DETAILS_1.clone();
-        acc.ilp_address = Address::from_str("example.charlie").unwrap();
-        acc.http_incoming_token = None;
-        acc.http_outgoing_token = None;
-        acc.btp_incoming_token = None;
-        acc.settle_to = None;
-        acc
-    };
-    block_on(test_store().and_then(|(store, context)| {
-        let store_clone = store.clone();
-        store.clone().insert_account(acc).and_then(move |_| {
-            store_clone.get_accounts(vec![2]).and_then(move |accounts| {
-                let acc = accounts[0].clone();
-                store_clone
-                    .clone()
-                    .update_balances_for_fulfill(acc.clone(), 100)
-                    .and_then(move |(balance, amount_to_settle)| {
-                        assert_eq!(balance, 100);
-                        assert_eq!(amount_to_settle, 0);
-                        let _ = context;
-                        Ok(())
-                    })
-            })
-        })
-    }))
-    .unwrap();
-}
-
-#[test]
-fn process_fulfill_settle_to_over_threshold() {
-    // account misconfigured with settle_to >= settle_threshold does not get settlements
-    let acc = {
-        let mut acc = ACCOUNT_DETAILS_1.clone();
-        acc.ilp_address = Address::from_str("example.b").unwrap();
-        acc.settle_to = Some(101);
-        acc.settle_threshold = Some(100);
-        acc.http_incoming_token = None;
-        acc.http_outgoing_token = None;
-        acc.btp_incoming_token = None;
-        acc
-    };
-    block_on(test_store().and_then(|(store, context)| {
-        let store_clone = store.clone();
-        store.clone().insert_account(acc).and_then(move |_| {
-            store_clone.get_accounts(vec![2]).and_then(move |accounts| {
-                let acc = accounts[0].clone();
-                store_clone
-                    .clone()
-                    .update_balances_for_fulfill(acc.clone(), 1000)
-                    .and_then(move |(balance, amount_to_settle)| {
-                        assert_eq!(balance, 1000);
-                        assert_eq!(amount_to_settle, 0);
-                        let _ = context;
-                        Ok(())
-                    })
-            })
-        })
-    }))
-    .unwrap();
-}
-
-#[test]
-fn process_fulfill_ok() {
-    // account with settle to = 0 (not falsy) with settle_threshold > 0, gets settlements
-    let acc = {
-        let mut acc = ACCOUNT_DETAILS_1.clone();
-        acc.ilp_address = Address::from_str("example.c").unwrap();
-        acc.settle_to = Some(0);
-        acc.settle_threshold = Some(100);
-        acc.http_incoming_token = None;
-        acc.http_outgoing_token = None;
-        acc.btp_incoming_token = None;
-        acc
-    };
-    block_on(test_store().and_then(|(store, context)| {
-        let store_clone = store.clone();
-        store.clone().insert_account(acc).and_then(move |_| {
-            store_clone.get_accounts(vec![2]).and_then(move |accounts| {
-                let acc = accounts[0].clone();
-                store_clone
-                    .clone()
-                    .update_balances_for_fulfill(acc.clone(), 101)
-                    .and_then(move |(balance, amount_to_settle)| {
-                        assert_eq!(balance, 0);
-                        assert_eq!(amount_to_settle, 101);
-                        let _ = context;
-                        Ok(())
-                    })
-            })
-        })
-    }))
-    .unwrap();
-}
-
-#[test]
-fn prepare_then_reject() {
-    block_on(test_store().and_then(|(store, context)| {
-        let store_clone_1 = store.clone();
-        let store_clone_2 = store.clone();
-        store
-            .clone()
-            .get_accounts(vec![0, 1])
-            .map_err(|_err| panic!("Unable to get accounts"))
-            .and_then(move |accounts| {
-                let account0 = accounts[0].clone();
-                let account1 = accounts[1].clone();
-                store
-                    .update_balances_for_prepare(accounts[0].clone(), 100)
-                    .and_then(move |_| {
-                        store_clone_1
-                            .clone()
-                            .get_balance(accounts[0].clone())
-                            .join(store_clone_1.clone().get_balance(accounts[1].clone()))
-                            .and_then(|(balance0, balance1)| {
-                                assert_eq!(balance0, -100);
-                                assert_eq!(balance1, 0);
-                                Ok(())
-                            })
-                    })
-                    .and_then(move |_| {
-                        store_clone_2
-                            .clone()
-                            .update_balances_for_reject(account0.clone(), 100)
-                            .and_then(move |_| {
-                                store_clone_2
-                                    .clone()
-                                    .get_balance(account0.clone())
-                                    .join(store_clone_2.clone().get_balance(account1.clone()))
-                                    .and_then(move |(balance0, balance1)| {
-                                        assert_eq!(balance0, 0);
-                                        assert_eq!(balance1, 0);
-                                        let _ = context;
-                                        Ok(())
-                                    })
-                            })
-                    })
-            })
-    }))
-    .unwrap();
-}
-
-#[test]
-fn enforces_minimum_balance() {
-    block_on(test_store().and_then(|(store, context)| {
-        store
-            .clone()
-            .get_accounts(vec![0, 1])
-            .map_err(|_err| panic!("Unable to get accounts"))
-            .and_then(move |accounts| {
-                store
-                    .update_balances_for_prepare(accounts[0].clone(), 10000)
-                    .then(move |result| {
-                        assert!(result.is_err());
-                        let _ = context;
-                        Ok(())
-                    })
-            })
-    }))
-    .unwrap()
-}
-
-#[test]
-// Prepare and Fulfill a packet for 100 units from Account 0 to Account 1
-// Then, Prepare and Fulfill a packet for 80 units from Account 1 to Account 0
-fn netting_fulfilled_balances() {
-    block_on(test_store().and_then(|(store, context)| {
-        let store_clone1 = store.clone();
-        let store_clone2 = store.clone();
-        store
-            .clone()
-            .insert_account(ACCOUNT_DETAILS_2.clone())
-            .and_then(move |_| {
-                store
-                    .clone()
-                    .get_accounts(vec![0, 2])
-                    .map_err(|_err| panic!("Unable to get accounts"))
-                    .and_then(move |accounts| {
-                        let account0 = accounts[0].clone();
-                        let account1 = accounts[1].clone();
-                        let account0_clone = account0.clone();
-                        let account1_clone = account1.clone();
+                    .insert_account(ACCOUNT_DETAILS_0.clone())
+                    .and_then(move |account| {
+                        let store = store_clone.clone();
                         future::join_all(vec![
-                            Either::A(store.clone().update_balances_for_prepare(
-                                account0.clone(),
-                                100, // decrement account 0 by 100
-                            )),
-                            Either::B(
-                                store
-                                    .clone()
-                                    .update_balances_for_fulfill(
-                                        account1.clone(), // increment account 1 by 100
-                                        100,
-                                    )
-                                    .and_then(|_| Ok(())),
-                            ),
+                            store_clone.update_balances_for_prepare(account.clone(), 10, "aa"),
+                            store_clone.update_balances_for_prepare(account.clone(), 10, "bb"),
+                            store_clone.update_balances_for_prepare(account.clone(), 10, "cc"),
                         ])
-                        .and_then(move |_| {
-                            future::join_all(vec![
-                                Either::A(
-                                    store_clone1
-                                        .clone()
-                                        .update_balances_for_prepare(account1.clone(), 80),
-                                ),
-                                Either::B(
-                                    store_clone1
-                                        .clone()
-                                        .update_balances_for_fulfill(account0.clone(), 80)
-                                        .and_then(|_| Ok(())),
-                                ),
-                            ])
-                        })
-                        .and_then(move |_| {
-                            store_clone2
-                                .clone()
-                                .get_balance(account0_clone)
-                                .join(store_clone2.get_balance(account1_clone))
-                                .and_then(move |(balance0, balance1)| {
-                                    assert_eq!(balance0, -20);
-                                    assert_eq!(balance1, 20);
-                                    let _ = context;
-                                    Ok(())
-                                })
-                        })
+                        .and_then(move |_| store.get_balance_journal(account, 1, 10))
                     })
             })
-    }))
-    .unwrap();
+            .and_then(move |journal| {
+                // The oldest entry was removed but the others keep their IDs
+                assert_eq!(journal.len(), 2);
+                assert_eq!(journal[0].id, 2);
+                assert_eq!(journal[1].id, 3);
+                assert_eq!(journal[1].balance, -30);
+                let _ = context;
+                Ok(())
+            }),
+    )
+    .unwrap()
 }
@@ -1,9 +1,8 @@
-mod fixtures;
 mod redis_helpers;
 mod store_helpers;
 
-pub use fixtures::*;
 pub use futures::Future;
 pub use interledger_store_redis::*;
+pub use interledger_store_tests::fixtures::*;
 pub use redis_helpers::*;
 pub use store_helpers::*;
@@ -1,5 +1,6 @@
-use super::fixtures::*;
-use super::redis_helpers::*;
+#![allow(dead_code)]
+
+use super::*;
 use env_logger;
 use futures::Future;
 use interledger_api::NodeStore;
@@ -40,3 +41,42 @@ where
     drop(lock);
     result
 }
+
+/// Run one of the tests shared by all stores with a new test store
+pub fn run_store_test<F, R>(test: F)
+where
+    F: FnOnce(RedisStore) -> R + Send + 'static,
+    R: Future<Item = (), Error = ()> + Send + 'static,
+{
+    block_on(test_store().and_then(|(store, context)| {
+        test(store).then(move |result| {
+            let _ = context;
+            result
+        })
+    }))
+    .unwrap()
+}
+
+/// Run one of the tests shared by all stores with a new test store and an empty store
+/// using a different database and secret
+pub fn run_import_test<F, R>(test: F)
+where
+    F: FnOnce(RedisStore, RedisStore) -> R + Send + 'static,
+    R: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let other_context = TestContext::new();
+    let empty_store =
+        RedisStoreBuilder::new(other_context.get_client_connection_info(), [1; 32]).connect();
+    block_on(
+        test_store()
+            .join(empty_store)
+            .and_then(|((store, context), empty_store)| {
+                test(store, empty_store).then(move |result| {
+                    let _ = context;
+                    let _ = other_context;
+                    result
+                })
+            }),
+    )
+    .unwrap()
+}
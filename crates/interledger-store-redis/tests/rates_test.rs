@@ -6,24 +6,6 @@ use interledger_service_util::ExchangeRateStore;
 use std::time::Duration;
 use tokio_timer::sleep;
 
-#[test]
-fn set_rates() {
-    block_on(test_store().and_then(|(store, context)| {
-        let store_clone = store.clone();
-        let rates = store.get_exchange_rates(&["ABC", "XYZ"]);
-        assert!(rates.is_err());
-        store
-            .set_rates(vec![("ABC".to_string(), 500.0), ("XYZ".to_string(), 0.005)])
-            .and_then(move |_| {
-                let rates = store_clone.get_exchange_rates(&["XYZ", "ABC"]).unwrap();
-                assert_eq!(rates[0].to_string(), "0.005");
-                assert_eq!(rates[1].to_string(), "500");
-                let _ = context;
-                Ok(())
-            })
-    }))
-    .unwrap();
-}
 #[test]
 fn polls_for_rate_updates() {
     let context = TestContext::new();
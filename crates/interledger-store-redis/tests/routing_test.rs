@@ -6,7 +6,6 @@ use interledger_api::{AccountDetails, NodeStore};
 use interledger_ccp::RouteManagerStore;
 use interledger_packet::Address;
 use interledger_router::RouterStore;
-use interledger_service::Account as AccountTrait;
 use std::str::FromStr;
 use std::{collections::HashMap, time::Duration};
 use tokio_timer::sleep;
@@ -35,6 +34,7 @@ fn polls_for_route_updates() {
                         );
                         store_clone_1.insert_account(AccountDetails {
                             ilp_address: Address::from_str("example.bob").unwrap(),
+                            username: None,
                             asset_scale: 6,
                             asset_code: "XYZ".to_string(),
                             max_packet_amount: 1000,
@@ -49,6 +49,14 @@ fn polls_for_route_updates() {
                             send_routes: false,
                             receive_routes: false,
                             routing_relation: None,
+                            allowed_route_prefixes: None,
+                            route_import_deny: None,
+                            route_import_max_path_length: None,
+                            route_export_allow: None,
+                            route_export_deny: None,
+                            route_export_max_path_length: None,
+                            advertise_parent_routes: None,
+                            route_cost: None,
                             round_trip_time: None,
                             amount_per_minute_limit: None,
                             packets_per_minute_limit: None,
@@ -99,51 +107,6 @@ fn polls_for_route_updates() {
     .unwrap();
 }
 
-#[test]
-fn gets_accounts_to_send_routes_to() {
-    block_on(test_store().and_then(|(store, context)| {
-        store
-            .get_accounts_to_send_routes_to()
-            .and_then(move |accounts| {
-                assert_eq!(accounts[0].id(), 1);
-                assert_eq!(accounts.len(), 1);
-                let _ = context;
-                Ok(())
-            })
-    }))
-    .unwrap()
-}
-
-#[test]
-fn gets_accounts_to_receive_routes_from() {
-    block_on(test_store().and_then(|(store, context)| {
-        store
-            .get_accounts_to_receive_routes_from()
-            .and_then(move |accounts| {
-                assert_eq!(accounts[0].id(), 0);
-                assert_eq!(accounts.len(), 1);
-                let _ = context;
-                Ok(())
-            })
-    }))
-    .unwrap()
-}
-
-#[test]
-fn gets_local_and_configured_routes() {
-    block_on(test_store().and_then(|(store, context)| {
-        store
-            .get_local_and_configured_routes()
-            .and_then(move |(local, configured)| {
-                assert_eq!(local.len(), 2);
-                assert!(configured.is_empty());
-                let _ = context;
-                Ok(())
-            })
-    }))
-    .unwrap()
-}
-
 #[test]
 fn saves_routes_to_db() {
     block_on(test_store().and_then(|(mut store, context)| {
@@ -179,34 +142,6 @@ fn saves_routes_to_db() {
     .unwrap()
 }
 
-#[test]
-fn updates_local_routes() {
-    block_on(test_store().and_then(|(store, context)| {
-        let account0 = Account::try_from(0, ACCOUNT_DETAILS_0.clone()).unwrap();
-        let account1 = Account::try_from(1, ACCOUNT_DETAILS_1.clone()).unwrap();
-        store
-            .clone()
-            .set_routes(vec![
-                (Bytes::from("example.a"), account0.clone()),
-                (Bytes::from("example.b"), account0.clone()),
-                (Bytes::from("example.c"), account1.clone()),
-            ])
-            .and_then(move |_| {
-                let routes = store.routing_table();
-                assert_eq!(routes[&b"example.a"[..]], 0);
-                assert_eq!(routes[&b"example.b"[..]], 0);
-                assert_eq!(routes[&b"example.c"[..]], 1);
-                assert_eq!(routes.len(), 3);
-                Ok(())
-            })
-            .and_then(move |_| {
-                let _ = context;
-                Ok(())
-            })
-    }))
-    .unwrap()
-}
-
 #[test]
 fn adds_static_routes_to_redis() {
     block_on(test_store().and_then(|(store, context)| {
@@ -237,55 +172,3 @@ fn adds_static_routes_to_redis() {
     }))
     .unwrap()
 }
-
-#[test]
-fn static_routes_override_others() {
-    block_on(test_store().and_then(|(store, context)| {
-        let mut store_clone = store.clone();
-        store
-            .clone()
-            .set_static_routes(vec![
-                ("example.a".to_string(), 0),
-                ("example.b".to_string(), 0),
-            ])
-            .and_then(move |_| {
-                let account1 = Account::try_from(1, ACCOUNT_DETAILS_1.clone()).unwrap();
-                store_clone.set_routes(vec![
-                    (Bytes::from("example.a"), account1.clone()),
-                    (Bytes::from("example.b"), account1.clone()),
-                    (Bytes::from("example.c"), account1),
-                ])
-            })
-            .and_then(move |_| {
-                let routes = store.routing_table();
-                assert_eq!(routes[&b"example.a"[..]], 0);
-                assert_eq!(routes[&b"example.b"[..]], 0);
-                assert_eq!(routes[&b"example.c"[..]], 1);
-                assert_eq!(routes.len(), 3);
-                let _ = context;
-                Ok(())
-            })
-    }))
-    .unwrap()
-}
-
-#[test]
-fn returns_configured_routes_for_route_manager() {
-    block_on(test_store().and_then(|(store, context)| {
-        store
-            .clone()
-            .set_static_routes(vec![
-                ("example.a".to_string(), 0),
-                ("example.b".to_string(), 1),
-            ])
-            .and_then(move |_| store.get_local_and_configured_routes())
-            .and_then(move |(_local, configured)| {
-                assert_eq!(configured.len(), 2);
-                assert_eq!(configured[&b"example.a"[..]].id(), 0);
-                assert_eq!(configured[&b"example.b"[..]].id(), 1);
-                let _ = context;
-                Ok(())
-            })
-    }))
-    .unwrap()
-}
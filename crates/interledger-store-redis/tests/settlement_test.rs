@@ -1,9 +1,10 @@
 mod common;
 
-use bytes::Bytes;
+// These check how incoming settlements are split between the balance and prepaid_amount
+// fields. The behavior shared with the other stores is tested in store_test.rs
+
 use common::*;
-use http::StatusCode;
-use interledger_settlement::{IdempotentStore, SettlementStore};
+use interledger_settlement::SettlementStore;
 use lazy_static::lazy_static;
 use redis::{cmd, r#async::SharedConnection};
 
@@ -36,39 +37,6 @@ fn credits_prepaid_amount() {
     .unwrap()
 }
 
-#[test]
-fn saves_and_loads_idempotency_key_data_properly() {
-    block_on(test_store().and_then(|(store, context)| {
-        let input_hash: [u8; 32] = Default::default();
-        store
-            .save_idempotent_data(
-                IDEMPOTENCY_KEY.clone(),
-                input_hash,
-                StatusCode::OK,
-                Bytes::from("TEST"),
-            )
-            .map_err(|err| eprintln!("Redis error: {:?}", err))
-            .and_then(move |_| {
-                store
-                    .load_idempotent_data(IDEMPOTENCY_KEY.clone())
-                    .map_err(|err| eprintln!("Redis error: {:?}", err))
-                    .and_then(move |data1| {
-                        assert_eq!(data1, (StatusCode::OK, Bytes::from("TEST"), input_hash));
-                        let _ = context;
-
-                        store
-                            .load_idempotent_data("asdf".to_string())
-                            .map_err(|err| eprintln!("Redis error: {:?}", err))
-                            .and_then(move |_data2| {
-                                let _ = context;
-                                Ok(())
-                            })
-                    })
-            })
-    }))
-    .unwrap_err() // second idempotent load fails
-}
-
 #[test]
 fn idempotent_settlement_calls() {
     block_on(test_store().and_then(|(store, context)| {
@@ -0,0 +1,6 @@
+#[allow(unused_imports)]
+mod common;
+
+use common::*;
+
+interledger_store_tests::store_tests!();
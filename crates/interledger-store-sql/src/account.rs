@@ -0,0 +1,620 @@
+use super::schema::accounts;
+use bytes::Bytes;
+use interledger_api::{AccountDetails, ExportableAccount};
+use interledger_btp::BtpAccount;
+use interledger_ccp::{CcpRoutingAccount, RoutePolicy, RoutingRelation};
+use interledger_http::HttpAccount;
+use interledger_ildcp::IldcpAccount;
+use interledger_packet::Address;
+use interledger_service::Account as AccountTrait;
+use interledger_service_util::{
+    MaxPacketAmountAccount, RateLimitAccount, RoundTripTimeAccount, DEFAULT_ROUND_TRIP_TIME,
+};
+use interledger_settlement::{SettlementAccount, SettlementEngineDetails};
+use interledger_store_crypto::StoreKeys;
+use log::error;
+use serde::Serialize;
+use serde::Serializer;
+use std::{
+    convert::TryFrom,
+    str::{self, FromStr},
+};
+use url::Url;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Account {
+    pub(crate) id: u64,
+    #[serde(serialize_with = "address_to_string")]
+    pub(crate) ilp_address: Address,
+    pub(crate) username: Option<String>,
+    // TODO add additional routes
+    pub(crate) asset_code: String,
+    pub(crate) asset_scale: u8,
+    pub(crate) max_packet_amount: u64,
+    pub(crate) min_balance: Option<i64>,
+    #[serde(serialize_with = "optional_url_to_string")]
+    pub(crate) http_endpoint: Option<Url>,
+    #[serde(serialize_with = "optional_bytes_to_utf8")]
+    pub(crate) http_outgoing_token: Option<Bytes>,
+    #[serde(serialize_with = "optional_url_to_string")]
+    pub(crate) btp_uri: Option<Url>,
+    #[serde(serialize_with = "optional_bytes_to_utf8")]
+    pub(crate) btp_outgoing_token: Option<Bytes>,
+    pub(crate) settle_threshold: Option<i64>,
+    pub(crate) settle_to: Option<i64>,
+    #[serde(serialize_with = "routing_relation_to_string")]
+    pub(crate) routing_relation: RoutingRelation,
+    pub(crate) send_routes: bool,
+    pub(crate) receive_routes: bool,
+    pub(crate) allowed_route_prefixes: Option<Vec<String>>,
+    pub(crate) route_import_deny: Option<Vec<String>>,
+    pub(crate) route_import_max_path_length: Option<u32>,
+    pub(crate) route_export_allow: Option<Vec<String>>,
+    pub(crate) route_export_deny: Option<Vec<String>>,
+    pub(crate) route_export_max_path_length: Option<u32>,
+    pub(crate) advertise_parent_routes: bool,
+    pub(crate) route_cost: u32,
+    pub(crate) round_trip_time: u64,
+    pub(crate) packets_per_minute_limit: Option<u32>,
+    pub(crate) amount_per_minute_limit: Option<u64>,
+    #[serde(serialize_with = "optional_url_to_string")]
+    pub(crate) settlement_engine_url: Option<Url>,
+    pub(crate) settlement_engine_asset_scale: Option<u8>,
+}
+
+fn address_to_string<S>(address: &Address, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(str::from_utf8(address.as_ref()).unwrap_or(""))
+}
+
+fn optional_bytes_to_utf8<S>(bytes: &Option<Bytes>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if let Some(bytes) = bytes {
+        serializer.serialize_some(str::from_utf8(bytes.as_ref()).unwrap_or(""))
+    } else {
+        serializer.serialize_none()
+    }
+}
+
+fn optional_url_to_string<S>(url: &Option<Url>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if let Some(ref url) = url {
+        serializer.serialize_str(url.as_ref())
+    } else {
+        serializer.serialize_none()
+    }
+}
+
+// This needs to be pass by ref because serde expects this function to take a ref
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn routing_relation_to_string<S>(
+    relation: &RoutingRelation,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(relation.to_string().as_str())
+}
+
+impl Account {
+    pub fn try_from(id: u64, details: AccountDetails) -> Result<Account, ()> {
+        let http_endpoint = if let Some(ref url) = details.http_endpoint {
+            Some(Url::parse(url).map_err(|err| error!("Invalid URL: {:?}", err))?)
+        } else {
+            None
+        };
+        let (btp_uri, btp_outgoing_token) = if let Some(ref url) = details.btp_uri {
+            let mut btp_uri = Url::parse(url).map_err(|err| error!("Invalid URL: {:?}", err))?;
+            let btp_outgoing_token = btp_uri.password().map(Bytes::from);
+            btp_uri.set_password(None).unwrap();
+            (Some(btp_uri), btp_outgoing_token)
+        } else {
+            (None, None)
+        };
+        let routing_relation = if let Some(ref relation) = details.routing_relation {
+            RoutingRelation::from_str(relation)?
+        } else {
+            RoutingRelation::Child
+        };
+        let username = details.validated_username()?;
+        for prefixes in &[
+            &details.allowed_route_prefixes,
+            &details.route_import_deny,
+            &details.route_export_allow,
+            &details.route_export_deny,
+        ] {
+            for prefix in prefixes.iter().flat_map(|prefixes| prefixes.iter()) {
+                Address::from_str(prefix)
+                    .map_err(|err| error!("Invalid route prefix {}: {:?}", prefix, err))?;
+            }
+        }
+        let settlement_engine_url =
+            if let Some(settlement_engine_url) = details.settlement_engine_url {
+                Url::parse(&settlement_engine_url).ok()
+            } else {
+                None
+            };
+        Ok(Account {
+            id,
+            ilp_address: Address::try_from(details.ilp_address.as_ref()).map_err(|err| {
+                error!("Invalid ILP Address when creating SQL account: {:?}", err)
+            })?,
+            username,
+            asset_code: details.asset_code.to_uppercase(),
+            asset_scale: details.asset_scale,
+            max_packet_amount: details.max_packet_amount,
+            min_balance: details.min_balance,
+            http_endpoint,
+            http_outgoing_token: details.http_outgoing_token.map(Bytes::from),
+            btp_uri,
+            btp_outgoing_token,
+            settle_threshold: details.settle_threshold,
+            settle_to: details.settle_to,
+            send_routes: details.send_routes,
+            receive_routes: details.receive_routes,
+            allowed_route_prefixes: details.allowed_route_prefixes,
+            route_import_deny: details.route_import_deny,
+            route_import_max_path_length: details.route_import_max_path_length,
+            route_export_allow: details.route_export_allow,
+            route_export_deny: details.route_export_deny,
+            route_export_max_path_length: details.route_export_max_path_length,
+            advertise_parent_routes: details.advertise_parent_routes.unwrap_or(true),
+            route_cost: details.route_cost.unwrap_or(0),
+            routing_relation,
+            round_trip_time: details.round_trip_time.unwrap_or(DEFAULT_ROUND_TRIP_TIME),
+            packets_per_minute_limit: details.packets_per_minute_limit,
+            amount_per_minute_limit: details.amount_per_minute_limit,
+            settlement_engine_url,
+            settlement_engine_asset_scale: details.settlement_engine_asset_scale,
+        })
+    }
+
+    /// Convert the account into a row for the accounts table, encrypting the outgoing tokens
+    /// and storing only the HMACs of the incoming ones
+    pub(crate) fn to_row(
+        &self,
+        keys: &StoreKeys,
+        http_incoming_token_hmac: Option<String>,
+        btp_incoming_token_hmac: Option<String>,
+    ) -> AccountRow {
+        AccountRow {
+            id: self.id as i64,
+            ilp_address: str::from_utf8(self.ilp_address.as_ref())
+                .unwrap_or_default()
+                .to_string(),
+            username: self.username.clone(),
+            asset_code: self.asset_code.clone(),
+            asset_scale: i32::from(self.asset_scale),
+            max_packet_amount: self.max_packet_amount as i64,
+            min_balance: self.min_balance,
+            http_endpoint: self.http_endpoint.as_ref().map(Url::to_string),
+            http_incoming_token_hmac,
+            http_outgoing_token: self
+                .http_outgoing_token
+                .as_ref()
+                .map(|token| hex::encode(keys.encrypt(token))),
+            btp_uri: self.btp_uri.as_ref().map(Url::to_string),
+            btp_incoming_token_hmac,
+            btp_outgoing_token: self
+                .btp_outgoing_token
+                .as_ref()
+                .map(|token| hex::encode(keys.encrypt(token))),
+            settle_threshold: self.settle_threshold,
+            settle_to: self.settle_to,
+            routing_relation: self.routing_relation.to_string(),
+            send_routes: self.send_routes,
+            receive_routes: self.receive_routes,
+            allowed_route_prefixes: self.allowed_route_prefixes.as_ref().map(|p| p.join(",")),
+            route_import_deny: self.route_import_deny.as_ref().map(|p| p.join(",")),
+            route_import_max_path_length: self.route_import_max_path_length.map(i64::from),
+            route_export_allow: self.route_export_allow.as_ref().map(|p| p.join(",")),
+            route_export_deny: self.route_export_deny.as_ref().map(|p| p.join(",")),
+            route_export_max_path_length: self.route_export_max_path_length.map(i64::from),
+            advertise_parent_routes: self.advertise_parent_routes,
+            route_cost: i64::from(self.route_cost),
+            round_trip_time: self.round_trip_time as i64,
+            packets_per_minute_limit: self.packets_per_minute_limit.map(i64::from),
+            amount_per_minute_limit: self.amount_per_minute_limit.map(|limit| limit as i64),
+            settlement_engine_url: self.settlement_engine_url.as_ref().map(Url::to_string),
+            settlement_engine_asset_scale: self.settlement_engine_asset_scale.map(i32::from),
+        }
+    }
+}
+
+/// A row of the accounts table. The outgoing tokens are encrypted and hex-encoded, and
+/// unsigned values are stored as signed integers because SQL has no unsigned types
+#[derive(Debug, Queryable, Insertable)]
+#[table_name = "accounts"]
+pub(crate) struct AccountRow {
+    pub(crate) id: i64,
+    pub(crate) ilp_address: String,
+    pub(crate) username: Option<String>,
+    pub(crate) asset_code: String,
+    pub(crate) asset_scale: i32,
+    pub(crate) max_packet_amount: i64,
+    pub(crate) min_balance: Option<i64>,
+    pub(crate) http_endpoint: Option<String>,
+    pub(crate) http_incoming_token_hmac: Option<String>,
+    pub(crate) http_outgoing_token: Option<String>,
+    pub(crate) btp_uri: Option<String>,
+    pub(crate) btp_incoming_token_hmac: Option<String>,
+    pub(crate) btp_outgoing_token: Option<String>,
+    pub(crate) settle_threshold: Option<i64>,
+    pub(crate) settle_to: Option<i64>,
+    pub(crate) routing_relation: String,
+    pub(crate) send_routes: bool,
+    pub(crate) receive_routes: bool,
+    pub(crate) allowed_route_prefixes: Option<String>,
+    pub(crate) route_import_deny: Option<String>,
+    pub(crate) route_import_max_path_length: Option<i64>,
+    pub(crate) route_export_allow: Option<String>,
+    pub(crate) route_export_deny: Option<String>,
+    pub(crate) route_export_max_path_length: Option<i64>,
+    pub(crate) advertise_parent_routes: bool,
+    pub(crate) route_cost: i64,
+    pub(crate) round_trip_time: i64,
+    pub(crate) packets_per_minute_limit: Option<i64>,
+    pub(crate) amount_per_minute_limit: Option<i64>,
+    pub(crate) settlement_engine_url: Option<String>,
+    pub(crate) settlement_engine_asset_scale: Option<i32>,
+}
+
+impl AccountRow {
+    pub(crate) fn decrypt_tokens(self, keys: &StoreKeys) -> Result<Account, ()> {
+        let id = self.id as u64;
+        Ok(Account {
+            id,
+            ilp_address: Address::from_str(&self.ilp_address)
+                .map_err(|err| error!("Account {} has an invalid ILP address: {:?}", id, err))?,
+            username: self.username,
+            asset_code: self.asset_code,
+            asset_scale: self.asset_scale as u8,
+            max_packet_amount: self.max_packet_amount as u64,
+            min_balance: self.min_balance,
+            http_endpoint: parse_url_option(id, self.http_endpoint)?,
+            http_outgoing_token: decrypt_token_option(keys, self.http_outgoing_token),
+            btp_uri: parse_url_option(id, self.btp_uri)?,
+            btp_outgoing_token: decrypt_token_option(keys, self.btp_outgoing_token),
+            settle_threshold: self.settle_threshold,
+            settle_to: self.settle_to,
+            routing_relation: RoutingRelation::from_str(&self.routing_relation)
+                .map_err(|_| error!("Account {} has an invalid routing relation", id))?,
+            send_routes: self.send_routes,
+            receive_routes: self.receive_routes,
+            allowed_route_prefixes: split_list_option(self.allowed_route_prefixes),
+            route_import_deny: split_list_option(self.route_import_deny),
+            route_import_max_path_length: self.route_import_max_path_length.map(|l| l as u32),
+            route_export_allow: split_list_option(self.route_export_allow),
+            route_export_deny: split_list_option(self.route_export_deny),
+            route_export_max_path_length: self.route_export_max_path_length.map(|l| l as u32),
+            advertise_parent_routes: self.advertise_parent_routes,
+            route_cost: self.route_cost as u32,
+            round_trip_time: self.round_trip_time as u64,
+            packets_per_minute_limit: self.packets_per_minute_limit.map(|limit| limit as u32),
+            amount_per_minute_limit: self.amount_per_minute_limit.map(|limit| limit as u64),
+            settlement_engine_url: parse_url_option(id, self.settlement_engine_url)?,
+            settlement_engine_asset_scale: self
+                .settlement_engine_asset_scale
+                .map(|scale| scale as u8),
+        })
+    }
+}
+
+fn parse_url_option(account_id: u64, url: Option<String>) -> Result<Option<Url>, ()> {
+    if let Some(url) = url {
+        Url::parse(&url)
+            .map(Some)
+            .map_err(|err| error!("Account {} has an invalid URL: {:?}", account_id, err))
+    } else {
+        Ok(None)
+    }
+}
+
+fn decrypt_token_option(keys: &StoreKeys, encrypted: Option<String>) -> Option<Bytes> {
+    let encrypted = hex::decode(encrypted?)
+        .map_err(|err| error!("Encrypted token is not valid hex: {:?}", err))
+        .ok()?;
+    let token = keys.decrypt(&encrypted);
+    if token.is_none() {
+        error!("Unable to decrypt token");
+    }
+    token
+}
+
+/// Lists are stored as comma-separated strings
+fn split_list_option(value: Option<String>) -> Option<Vec<String>> {
+    value.map(|value| {
+        value
+            .split(',')
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect()
+    })
+}
+
+impl AccountTrait for Account {
+    type AccountId = u64;
+
+    fn id(&self) -> Self::AccountId {
+        self.id
+    }
+}
+
+impl IldcpAccount for Account {
+    fn client_address(&self) -> &Address {
+        &self.ilp_address
+    }
+
+    fn asset_code(&self) -> &str {
+        self.asset_code.as_str()
+    }
+
+    fn asset_scale(&self) -> u8 {
+        self.asset_scale
+    }
+}
+
+impl HttpAccount for Account {
+    fn get_http_url(&self) -> Option<&Url> {
+        self.http_endpoint.as_ref()
+    }
+
+    fn get_http_auth_token(&self) -> Option<&str> {
+        self.http_outgoing_token
+            .as_ref()
+            .map(|s| str::from_utf8(s.as_ref()).unwrap_or_default())
+    }
+}
+
+impl BtpAccount for Account {
+    fn get_btp_uri(&self) -> Option<&Url> {
+        self.btp_uri.as_ref()
+    }
+
+    fn get_btp_token(&self) -> Option<&[u8]> {
+        if let Some(ref token) = self.btp_outgoing_token {
+            Some(token)
+        } else {
+            None
+        }
+    }
+}
+
+impl MaxPacketAmountAccount for Account {
+    fn max_packet_amount(&self) -> u64 {
+        self.max_packet_amount
+    }
+}
+
+impl CcpRoutingAccount for Account {
+    fn routing_relation(&self) -> RoutingRelation {
+        self.routing_relation
+    }
+
+    fn should_send_routes(&self) -> bool {
+        self.send_routes
+    }
+
+    fn should_receive_routes(&self) -> bool {
+        self.receive_routes
+    }
+
+    fn allowed_route_prefixes(&self) -> Option<Vec<Bytes>> {
+        to_prefixes(&self.allowed_route_prefixes)
+    }
+
+    fn route_import_policy(&self) -> RoutePolicy {
+        RoutePolicy {
+            allow: self.allowed_route_prefixes(),
+            deny: to_prefixes(&self.route_import_deny).unwrap_or_default(),
+            max_path_length: self
+                .route_import_max_path_length
+                .map(|length| length as usize),
+        }
+    }
+
+    fn route_export_policy(&self) -> RoutePolicy {
+        RoutePolicy {
+            allow: to_prefixes(&self.route_export_allow),
+            deny: to_prefixes(&self.route_export_deny).unwrap_or_default(),
+            max_path_length: self
+                .route_export_max_path_length
+                .map(|length| length as usize),
+        }
+    }
+
+    fn should_advertise_parent_routes(&self) -> bool {
+        self.advertise_parent_routes
+    }
+
+    fn route_cost(&self) -> u32 {
+        self.route_cost
+    }
+}
+
+fn to_prefixes(prefixes: &Option<Vec<String>>) -> Option<Vec<Bytes>> {
+    prefixes.as_ref().map(|prefixes| {
+        prefixes
+            .iter()
+            .map(|prefix| Bytes::from(prefix.as_str()))
+            .collect()
+    })
+}
+
+impl RoundTripTimeAccount for Account {
+    fn round_trip_time(&self) -> u64 {
+        self.round_trip_time
+    }
+}
+
+impl RateLimitAccount for Account {
+    fn amount_per_minute_limit(&self) -> Option<u64> {
+        self.amount_per_minute_limit
+    }
+
+    fn packets_per_minute_limit(&self) -> Option<u32> {
+        self.packets_per_minute_limit
+    }
+}
+
+impl ExportableAccount for Account {
+    fn username(&self) -> Option<&str> {
+        self.username.as_ref().map(String::as_str)
+    }
+
+    fn min_balance(&self) -> Option<i64> {
+        self.min_balance
+    }
+
+    fn settle_threshold(&self) -> Option<i64> {
+        self.settle_threshold
+    }
+
+    fn settle_to(&self) -> Option<i64> {
+        self.settle_to
+    }
+
+    fn settlement_engine_url(&self) -> Option<&Url> {
+        self.settlement_engine_url.as_ref()
+    }
+
+    fn settlement_engine_asset_scale(&self) -> Option<u8> {
+        self.settlement_engine_asset_scale
+    }
+}
+
+impl SettlementAccount for Account {
+    fn settlement_engine_details(&self) -> Option<SettlementEngineDetails> {
+        match (
+            &self.settlement_engine_url,
+            self.settlement_engine_asset_scale,
+        ) {
+            (Some(url), Some(asset_scale)) => Some(SettlementEngineDetails {
+                url: url.clone(),
+                asset_scale,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod sql_account {
+    use super::*;
+    use lazy_static::lazy_static;
+
+    lazy_static! {
+        static ref ACCOUNT_DETAILS: AccountDetails = AccountDetails {
+            ilp_address: Address::from_str("example.alice").unwrap(),
+            username: Some("Alice".to_string()),
+            asset_scale: 6,
+            asset_code: "XYZ".to_string(),
+            max_packet_amount: 1000,
+            min_balance: Some(-1000),
+            http_endpoint: Some("http://example.com/ilp".to_string()),
+            http_incoming_token: Some("incoming_auth_token".to_string()),
+            http_outgoing_token: Some("outgoing_auth_token".to_string()),
+            btp_uri: Some("btp+ws://:btp_token@example.com/btp".to_string()),
+            btp_incoming_token: Some("btp_token".to_string()),
+            settle_threshold: Some(0),
+            settle_to: Some(-1000),
+            send_routes: true,
+            receive_routes: true,
+            routing_relation: Some("Peer".to_string()),
+            allowed_route_prefixes: None,
+            route_import_deny: None,
+            route_import_max_path_length: None,
+            route_export_allow: None,
+            route_export_deny: None,
+            route_export_max_path_length: None,
+            advertise_parent_routes: None,
+            route_cost: None,
+            round_trip_time: Some(600),
+            amount_per_minute_limit: None,
+            packets_per_minute_limit: None,
+            settlement_engine_asset_scale: None,
+            settlement_engine_url: None,
+        };
+    }
+
+    #[test]
+    fn from_account_details() {
+        let account = Account::try_from(10, ACCOUNT_DETAILS.clone()).unwrap();
+        assert_eq!(account.id(), 10);
+        assert_eq!(
+            account.get_http_auth_token().unwrap(),
+            "outgoing_auth_token"
+        );
+        assert_eq!(account.get_btp_token().unwrap(), b"btp_token");
+        assert_eq!(account.routing_relation(), RoutingRelation::Peer);
+        assert_eq!(account.username, Some("alice".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_username() {
+        let mut details = ACCOUNT_DETAILS.clone();
+        details.username = Some("alice/bob".to_string());
+        assert!(Account::try_from(10, details).is_err());
+    }
+
+    #[test]
+    fn builds_route_policies() {
+        let mut details = ACCOUNT_DETAILS.clone();
+        details.allowed_route_prefixes = Some(vec!["example.alice".to_string()]);
+        details.route_import_max_path_length = Some(3);
+        details.route_export_deny = Some(vec!["example.private".to_string()]);
+        let account = Account::try_from(10, details).unwrap();
+        assert_eq!(
+            account.route_import_policy(),
+            RoutePolicy {
+                allow: Some(vec![Bytes::from("example.alice")]),
+                deny: Vec::new(),
+                max_path_length: Some(3),
+            }
+        );
+        assert_eq!(
+            account.route_export_policy(),
+            RoutePolicy {
+                allow: None,
+                deny: vec![Bytes::from("example.private")],
+                max_path_length: None,
+            }
+        );
+        assert!(account.should_advertise_parent_routes());
+    }
+
+    #[test]
+    fn rejects_invalid_route_prefixes() {
+        let mut details = ACCOUNT_DETAILS.clone();
+        details.route_export_allow = Some(vec!["not a prefix".to_string()]);
+        assert!(Account::try_from(10, details).is_err());
+    }
+
+    #[test]
+    fn round_trips_through_row() {
+        let keys = StoreKeys::new("sql", &[0; 32]);
+        let account = Account::try_from(10, ACCOUNT_DETAILS.clone()).unwrap();
+        let row = account.to_row(&keys, None, None);
+        assert_ne!(
+            row.http_outgoing_token.as_ref().unwrap(),
+            "outgoing_auth_token"
+        );
+        let account = row.decrypt_tokens(&keys).unwrap();
+        assert_eq!(account.id(), 10);
+        assert_eq!(
+            account.get_http_auth_token().unwrap(),
+            "outgoing_auth_token"
+        );
+        assert_eq!(account.get_btp_token().unwrap(), b"btp_token");
+        assert_eq!(account.routing_relation(), RoutingRelation::Peer);
+        assert_eq!(account.round_trip_time(), 600);
+        assert_eq!(account.max_packet_amount(), 1000);
+    }
+}
@@ -0,0 +1,17 @@
+//! # interledger-store-sql
+//!
+//! A Store that uses a SQL database ([SQLite](https://sqlite.org/) or [PostgreSQL](https://www.postgresql.org/))
+//! for storing account details, balances, the routing table, etc.
+//!
+//! Balance updates run in database transactions, so the balances can be backed up and
+//! audited with the database's standard tools.
+
+#[macro_use]
+extern crate diesel;
+
+mod account;
+mod schema;
+mod store;
+
+pub use account::Account;
+pub use store::{SqlStore, SqlStoreBuilder};
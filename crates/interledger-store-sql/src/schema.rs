@@ -0,0 +1,210 @@
+// The tables are created with types that both SQLite and PostgreSQL understand, so the
+// same statements work for both. Unsigned values such as account IDs and amounts are
+// stored in signed BIGINT columns, and binary values are hex-encoded.
+pub(crate) static CREATE_TABLES: &str = "
+CREATE TABLE IF NOT EXISTS accounts (
+    id BIGINT PRIMARY KEY,
+    ilp_address TEXT NOT NULL,
+    username TEXT UNIQUE,
+    asset_code TEXT NOT NULL,
+    asset_scale INTEGER NOT NULL,
+    max_packet_amount BIGINT NOT NULL,
+    min_balance BIGINT,
+    http_endpoint TEXT,
+    http_incoming_token_hmac TEXT UNIQUE,
+    http_outgoing_token TEXT,
+    btp_uri TEXT,
+    btp_incoming_token_hmac TEXT UNIQUE,
+    btp_outgoing_token TEXT,
+    settle_threshold BIGINT,
+    settle_to BIGINT,
+    routing_relation TEXT NOT NULL,
+    send_routes BOOLEAN NOT NULL,
+    receive_routes BOOLEAN NOT NULL,
+    allowed_route_prefixes TEXT,
+    route_import_deny TEXT,
+    route_import_max_path_length BIGINT,
+    route_export_allow TEXT,
+    route_export_deny TEXT,
+    route_export_max_path_length BIGINT,
+    advertise_parent_routes BOOLEAN NOT NULL,
+    route_cost BIGINT NOT NULL,
+    round_trip_time BIGINT NOT NULL,
+    packets_per_minute_limit BIGINT,
+    amount_per_minute_limit BIGINT,
+    settlement_engine_url TEXT,
+    settlement_engine_asset_scale INTEGER
+);
+CREATE TABLE IF NOT EXISTS balances (
+    account_id BIGINT PRIMARY KEY REFERENCES accounts (id),
+    balance BIGINT NOT NULL,
+    prepaid_amount BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS balance_journal (
+    account_id BIGINT NOT NULL REFERENCES accounts (id),
+    entry_id BIGINT NOT NULL,
+    kind TEXT NOT NULL,
+    reference TEXT,
+    amount BIGINT NOT NULL,
+    balance BIGINT NOT NULL,
+    prepaid_amount BIGINT NOT NULL,
+    created_at BIGINT NOT NULL,
+    PRIMARY KEY (account_id, entry_id)
+);
+CREATE TABLE IF NOT EXISTS routes (
+    prefix TEXT PRIMARY KEY,
+    account_id BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS static_routes (
+    prefix TEXT PRIMARY KEY,
+    account_id BIGINT NOT NULL REFERENCES accounts (id)
+);
+CREATE TABLE IF NOT EXISTS alternate_routes (
+    prefix TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    account_id BIGINT NOT NULL,
+    equal_cost BOOLEAN NOT NULL,
+    PRIMARY KEY (prefix, position)
+);
+CREATE TABLE IF NOT EXISTS rates (
+    asset_code TEXT PRIMARY KEY,
+    rate DOUBLE PRECISION NOT NULL
+);
+CREATE TABLE IF NOT EXISTS rate_limits (
+    limit_key TEXT PRIMARY KEY,
+    theoretical_arrival BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS idempotent_data (
+    idempotency_key TEXT PRIMARY KEY,
+    status_code INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    input_hash TEXT NOT NULL,
+    created_at BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS settlement_idempotency_keys (
+    idempotency_key TEXT PRIMARY KEY,
+    created_at BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS connection_owners (
+    account_id BIGINT PRIMARY KEY,
+    instance_url TEXT NOT NULL
+);
+";
+
+table! {
+    accounts (id) {
+        id -> BigInt,
+        ilp_address -> Text,
+        username -> Nullable<Text>,
+        asset_code -> Text,
+        asset_scale -> Integer,
+        max_packet_amount -> BigInt,
+        min_balance -> Nullable<BigInt>,
+        http_endpoint -> Nullable<Text>,
+        http_incoming_token_hmac -> Nullable<Text>,
+        http_outgoing_token -> Nullable<Text>,
+        btp_uri -> Nullable<Text>,
+        btp_incoming_token_hmac -> Nullable<Text>,
+        btp_outgoing_token -> Nullable<Text>,
+        settle_threshold -> Nullable<BigInt>,
+        settle_to -> Nullable<BigInt>,
+        routing_relation -> Text,
+        send_routes -> Bool,
+        receive_routes -> Bool,
+        allowed_route_prefixes -> Nullable<Text>,
+        route_import_deny -> Nullable<Text>,
+        route_import_max_path_length -> Nullable<BigInt>,
+        route_export_allow -> Nullable<Text>,
+        route_export_deny -> Nullable<Text>,
+        route_export_max_path_length -> Nullable<BigInt>,
+        advertise_parent_routes -> Bool,
+        route_cost -> BigInt,
+        round_trip_time -> BigInt,
+        packets_per_minute_limit -> Nullable<BigInt>,
+        amount_per_minute_limit -> Nullable<BigInt>,
+        settlement_engine_url -> Nullable<Text>,
+        settlement_engine_asset_scale -> Nullable<Integer>,
+    }
+}
+
+table! {
+    balances (account_id) {
+        account_id -> BigInt,
+        balance -> BigInt,
+        prepaid_amount -> BigInt,
+    }
+}
+
+table! {
+    balance_journal (account_id, entry_id) {
+        account_id -> BigInt,
+        entry_id -> BigInt,
+        kind -> Text,
+        reference -> Nullable<Text>,
+        amount -> BigInt,
+        balance -> BigInt,
+        prepaid_amount -> BigInt,
+        created_at -> BigInt,
+    }
+}
+
+table! {
+    routes (prefix) {
+        prefix -> Text,
+        account_id -> BigInt,
+    }
+}
+
+table! {
+    static_routes (prefix) {
+        prefix -> Text,
+        account_id -> BigInt,
+    }
+}
+
+table! {
+    alternate_routes (prefix, position) {
+        prefix -> Text,
+        position -> Integer,
+        account_id -> BigInt,
+        equal_cost -> Bool,
+    }
+}
+
+table! {
+    rates (asset_code) {
+        asset_code -> Text,
+        rate -> Double,
+    }
+}
+
+table! {
+    rate_limits (limit_key) {
+        limit_key -> Text,
+        theoretical_arrival -> BigInt,
+    }
+}
+
+table! {
+    idempotent_data (idempotency_key) {
+        idempotency_key -> Text,
+        status_code -> Integer,
+        data -> Text,
+        input_hash -> Text,
+        created_at -> BigInt,
+    }
+}
+
+table! {
+    settlement_idempotency_keys (idempotency_key) {
+        idempotency_key -> Text,
+        created_at -> BigInt,
+    }
+}
+
+table! {
+    connection_owners (account_id) {
+        account_id -> BigInt,
+        instance_url -> Text,
+    }
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use common::*;
+use futures::future;
+use interledger_api::NodeStore;
+use interledger_service_util::BalanceStore;
+
+#[test]
+fn trims_journal_to_max_entries() {
+    block_on(
+        SqlStoreBuilder::new("sqlite::memory:", [0; 32])
+            .journal_max_entries(2)
+            .connect()
+            .and_then(|store| {
+                let store_clone = store.clone();
+                store
+                    .insert_account(ACCOUNT_DETAILS_0.clone())
+                    .and_then(move |account| {
+                        let store = store_clone.clone();
+                        future::join_all(vec![
+                            store_clone.update_balances_for_prepare(account.clone(), 10, "aa"),
+                            store_clone.update_balances_for_prepare(account.clone(), 10, "bb"),
+                            store_clone.update_balances_for_prepare(account.clone(), 10, "cc"),
+                        ])
+                        .and_then(move |_| store.get_balance_journal(account, 1, 10))
+                    })
+            })
+            .and_then(|journal| {
+                // The oldest entry was deleted but the others keep their IDs
+                assert_eq!(journal.len(), 2);
+                assert_eq!(journal[0].id, 2);
+                assert_eq!(journal[1].id, 3);
+                assert_eq!(journal[1].balance, -30);
+                Ok(())
+            }),
+    )
+    .unwrap()
+}
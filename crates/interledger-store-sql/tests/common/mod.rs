@@ -0,0 +1,6 @@
+mod store_helpers;
+
+pub use futures::Future;
+pub use interledger_store_sql::*;
+pub use interledger_store_tests::fixtures::*;
+pub use store_helpers::*;
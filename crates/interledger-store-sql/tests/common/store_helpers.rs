@@ -0,0 +1,57 @@
+#![allow(dead_code)]
+
+use super::*;
+use futures::Future;
+use interledger_api::NodeStore;
+use interledger_store_sql::{SqlStore, SqlStoreBuilder};
+use tokio::runtime::Runtime;
+
+/// Each store gets its own in-memory SQLite database so the tests can run in parallel
+pub fn test_store() -> impl Future<Item = SqlStore, Error = ()> {
+    SqlStoreBuilder::new("sqlite::memory:", [0; 32])
+        .connect()
+        .and_then(|store| {
+            let store_clone = store.clone();
+            store
+                .clone()
+                .insert_account(ACCOUNT_DETAILS_0.clone())
+                .and_then(move |_| store_clone.insert_account(ACCOUNT_DETAILS_1.clone()))
+                .and_then(|_| Ok(store))
+        })
+}
+
+pub fn block_on<F>(f: F) -> Result<F::Item, F::Error>
+where
+    F: Future + Send + 'static,
+    F::Item: Send,
+    F::Error: Send,
+{
+    let _ = env_logger::try_init();
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(f)
+}
+
+/// Run one of the tests shared by all stores with a new test store
+pub fn run_store_test<F, R>(test: F)
+where
+    F: FnOnce(SqlStore) -> R + Send + 'static,
+    R: Future<Item = (), Error = ()> + Send + 'static,
+{
+    block_on(test_store().and_then(test)).unwrap()
+}
+
+/// Run one of the tests shared by all stores with a new test store and an empty store
+/// using a different secret
+pub fn run_import_test<F, R>(test: F)
+where
+    F: FnOnce(SqlStore, SqlStore) -> R + Send + 'static,
+    R: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let empty_store = SqlStoreBuilder::new("sqlite::memory:", [1; 32]).connect();
+    block_on(
+        test_store()
+            .join(empty_store)
+            .and_then(|(store, empty_store)| test(store, empty_store)),
+    )
+    .unwrap()
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use common::*;
+use interledger_api::NodeStore;
+use interledger_service_util::ExchangeRateStore;
+use std::{env, process, time::Duration};
+use tokio_timer::sleep;
+
+#[test]
+fn polls_for_rate_updates() {
+    // Two stores using the same database file, like two nodes sharing a database
+    let path = env::temp_dir().join(format!("ilp-store-sql-rates-{}.db", process::id()));
+    let database_url = format!("sqlite://{}", path.display());
+    let _ = std::fs::remove_file(&path);
+    let connect = SqlStoreBuilder::new(&database_url, [0; 32]).connect().join(
+        SqlStoreBuilder::new(&database_url, [0; 32])
+            .poll_interval(1)
+            .connect(),
+    );
+    block_on(connect.and_then(|(store, polling_store)| {
+        store
+            .set_rates(vec![
+                ("ABC".to_string(), 0.5f64),
+                ("DEF".to_string(), 9_999.0),
+            ])
+            .and_then(|_| sleep(Duration::from_millis(100)).then(|_| Ok(())))
+            .and_then(move |_| {
+                let rates = polling_store.get_exchange_rates(&["ABC", "DEF"]).unwrap();
+                assert_eq!(rates[0].to_string(), "0.5");
+                assert_eq!(rates[1].to_string(), "9999");
+                Ok(())
+            })
+    }))
+    .unwrap();
+    let _ = std::fs::remove_file(&path);
+}
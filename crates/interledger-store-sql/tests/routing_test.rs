@@ -0,0 +1,51 @@
+mod common;
+
+use bytes::Bytes;
+use common::*;
+use interledger_api::NodeStore;
+use interledger_ccp::RouteManagerStore;
+use interledger_router::RouterStore;
+use interledger_service::AccountStore;
+use std::{env, process, time::Duration};
+use tokio_timer::sleep;
+
+#[test]
+fn polls_for_route_updates() {
+    // Two stores using the same database file, like two nodes sharing a database
+    let path = env::temp_dir().join(format!("ilp-store-sql-routes-{}.db", process::id()));
+    let database_url = format!("sqlite://{}", path.display());
+    let _ = std::fs::remove_file(&path);
+    let connect = SqlStoreBuilder::new(&database_url, [0; 32]).connect().join(
+        SqlStoreBuilder::new(&database_url, [0; 32])
+            .poll_interval(1)
+            .connect(),
+    );
+    block_on(connect.and_then(|(store, polling_store)| {
+        let mut store_clone = store.clone();
+        store
+            .insert_account(ACCOUNT_DETAILS_0.clone())
+            .and_then(move |_| store.insert_account(ACCOUNT_DETAILS_1.clone()))
+            .and_then(move |_| {
+                store_clone
+                    .get_accounts(vec![0, 1])
+                    .and_then(move |accounts| {
+                        store_clone.set_routes(vec![
+                            (Bytes::from("example.a"), accounts[0].clone()),
+                            (Bytes::from("example.b"), accounts[0].clone()),
+                            (Bytes::from("example.c"), accounts[1].clone()),
+                        ])
+                    })
+            })
+            .and_then(|_| sleep(Duration::from_millis(100)).then(|_| Ok(())))
+            .and_then(move |_| {
+                let routes = polling_store.routing_table();
+                assert_eq!(routes[&Bytes::from("example.a")], 0);
+                assert_eq!(routes[&Bytes::from("example.b")], 0);
+                assert_eq!(routes[&Bytes::from("example.c")], 1);
+                assert_eq!(routes.len(), 3);
+                Ok(())
+            })
+    }))
+    .unwrap();
+    let _ = std::fs::remove_file(&path);
+}
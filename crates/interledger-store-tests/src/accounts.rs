@@ -0,0 +1,158 @@
+use crate::fixtures::ACCOUNT_DETAILS_2;
+use crate::{TestAccount, TestStore};
+use futures::Future;
+use interledger_packet::Address;
+use std::str::FromStr;
+
+pub fn insert_accounts<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .insert_account(ACCOUNT_DETAILS_2.clone())
+        .and_then(move |account| {
+            assert_eq!(account.id(), 2);
+            Ok(())
+        })
+}
+
+pub fn starts_with_zero_balance<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_accounts(vec![0])
+        .and_then(move |accounts| store.get_balance(accounts[0].clone()))
+        .and_then(move |balance| {
+            assert_eq!(balance, 0);
+            Ok(())
+        })
+}
+
+pub fn fails_on_duplicate_http_incoming_auth<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let mut account = ACCOUNT_DETAILS_2.clone();
+    account.http_incoming_token = Some("incoming_auth_token".to_string());
+    store.insert_account(account).then(move |result| {
+        assert!(result.is_err());
+        Ok(())
+    })
+}
+
+pub fn fails_on_duplicate_btp_incoming_auth<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let mut account = ACCOUNT_DETAILS_2.clone();
+    account.btp_incoming_token = Some("btp_token".to_string());
+    store.insert_account(account).then(move |result| {
+        assert!(result.is_err());
+        Ok(())
+    })
+}
+
+pub fn fails_on_duplicate_username<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let mut account = ACCOUNT_DETAILS_2.clone();
+    account.username = Some("Alice".to_string());
+    store.insert_account(account).then(move |result| {
+        assert!(result.is_err());
+        Ok(())
+    })
+}
+
+pub fn gets_account_id_from_username<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_account_id_from_username("alice")
+        .and_then(move |id| {
+            assert_eq!(id, 0);
+            Ok(())
+        })
+}
+
+pub fn get_all_accounts<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store.get_all_accounts().and_then(move |accounts| {
+        assert_eq!(accounts.len(), 2);
+        Ok(())
+    })
+}
+
+pub fn gets_single_account<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store.get_accounts(vec![1]).and_then(move |accounts| {
+        assert_eq!(
+            accounts[0].client_address(),
+            &Address::from_str("example.bob").unwrap()
+        );
+        Ok(())
+    })
+}
+
+pub fn gets_multiple<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store.get_accounts(vec![1, 0]).and_then(move |accounts| {
+        // note reverse order is intentional
+        assert_eq!(
+            accounts[0].client_address(),
+            &Address::from_str("example.bob").unwrap()
+        );
+        assert_eq!(
+            accounts[1].client_address(),
+            &Address::from_str("example.alice").unwrap()
+        );
+        Ok(())
+    })
+}
+
+pub fn decrypts_outgoing_tokens<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store.get_accounts(vec![0]).and_then(move |accounts| {
+        let account = &accounts[0];
+        assert_eq!(
+            account.get_http_auth_token().unwrap(),
+            "outgoing_auth_token"
+        );
+        assert_eq!(account.get_btp_token().unwrap(), b"btp_token");
+        Ok(())
+    })
+}
+
+pub fn errors_for_unknown_accounts<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store.get_accounts(vec![0, 5]).then(move |result| {
+        assert!(result.is_err());
+        Ok(())
+    })
+}
+
+pub fn sets_account_asset<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let store_clone = store.clone();
+    store
+        .set_account_asset(0, "abc".to_string(), 4)
+        .and_then(move |_| store_clone.get_accounts(vec![0]))
+        .and_then(|accounts| {
+            assert_eq!(accounts[0].asset_code(), "ABC");
+            assert_eq!(accounts[0].asset_scale(), 4);
+            Ok(())
+        })
+}
+
+pub fn cannot_set_asset_of_unknown_account<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .set_account_asset(5, "ABC".to_string(), 4)
+        .then(move |result| {
+            assert!(result.is_err());
+            Ok(())
+        })
+}
@@ -0,0 +1,315 @@
+use crate::fixtures::{ACCOUNT_DETAILS_1, ACCOUNT_DETAILS_2};
+use crate::{TestAccount, TestStore};
+use futures::future::{self, Either};
+use futures::Future;
+use interledger_api::AccountDetails;
+use interledger_packet::Address;
+use interledger_service_util::JournalEntryKind;
+use std::str::FromStr;
+
+pub fn prepare_then_fulfill_with_settlement<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let store_clone_1 = store.clone();
+    let store_clone_2 = store.clone();
+    store
+        .clone()
+        .get_accounts(vec![0, 1])
+        .map_err(|_err| panic!("Unable to get accounts"))
+        .and_then(move |accounts| {
+            let account0 = accounts[0].clone();
+            let account1 = accounts[1].clone();
+            store
+                // reduce account 0's balance by 100
+                .update_balances_for_prepare(accounts[0].clone(), 100, "condition")
+                .and_then(move |_| {
+                    store_clone_1
+                        .clone()
+                        .get_balance(accounts[0].clone())
+                        .join(store_clone_1.clone().get_balance(accounts[1].clone()))
+                        .and_then(|(balance0, balance1)| {
+                            assert_eq!(balance0, -100);
+                            assert_eq!(balance1, 0);
+                            Ok(())
+                        })
+                })
+                .and_then(move |_| {
+                    store_clone_2
+                        .clone()
+                        .update_balances_for_fulfill(account1.clone(), 100, "condition")
+                        .and_then(move |_| {
+                            store_clone_2
+                                .clone()
+                                .get_balance(account0.clone())
+                                .join(store_clone_2.clone().get_balance(account1.clone()))
+                                .and_then(move |(balance0, balance1)| {
+                                    assert_eq!(balance0, -100);
+                                    assert_eq!(balance1, -1000); // the account must be settled down to -1000
+                                    Ok(())
+                                })
+                        })
+                })
+        })
+}
+
+/// Insert a third account, fulfill a packet for `amount` to it and return the new
+/// balance and the amount to settle
+fn fulfill_to_new_account<S: TestStore<A>, A: TestAccount>(
+    store: S,
+    details: AccountDetails,
+    amount: u64,
+) -> impl Future<Item = (i64, u64), Error = ()> + Send {
+    let store_clone = store.clone();
+    store
+        .insert_account(details)
+        .and_then(move |_| store_clone.get_accounts(vec![2]))
+        .and_then(move |accounts| {
+            store.update_balances_for_fulfill(accounts[0].clone(), amount, "condition")
+        })
+}
+
+fn details_without_tokens(ilp_address: &str) -> AccountDetails {
+    let mut details = ACCOUNT_DETAILS_1.clone();
+    details.ilp_address = Address::from_str(ilp_address).unwrap();
+    details.http_incoming_token = None;
+    details.http_outgoing_token = None;
+    details.btp_incoming_token = None;
+    details
+}
+
+pub fn process_fulfill_no_settle_to<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    // account without a settle_to
+    let mut details = details_without_tokens("example.charlie");
+    details.settle_to = None;
+    fulfill_to_new_account(store, details, 100).and_then(|(balance, amount_to_settle)| {
+        assert_eq!(balance, 100);
+        assert_eq!(amount_to_settle, 0);
+        Ok(())
+    })
+}
+
+pub fn process_fulfill_settle_to_over_threshold<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    // account misconfigured with settle_to >= settle_threshold does not get settlements
+    let mut details = details_without_tokens("example.b");
+    details.settle_to = Some(101);
+    details.settle_threshold = Some(100);
+    fulfill_to_new_account(store, details, 1000).and_then(|(balance, amount_to_settle)| {
+        assert_eq!(balance, 1000);
+        assert_eq!(amount_to_settle, 0);
+        Ok(())
+    })
+}
+
+pub fn process_fulfill_ok<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    // account with settle to = 0 (not falsy) with settle_threshold > 0, gets settlements
+    let mut details = details_without_tokens("example.c");
+    details.settle_to = Some(0);
+    details.settle_threshold = Some(100);
+    fulfill_to_new_account(store, details, 101).and_then(|(balance, amount_to_settle)| {
+        assert_eq!(balance, 0);
+        assert_eq!(amount_to_settle, 101);
+        Ok(())
+    })
+}
+
+pub fn prepare_then_reject<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let store_clone_1 = store.clone();
+    let store_clone_2 = store.clone();
+    store
+        .clone()
+        .get_accounts(vec![0, 1])
+        .map_err(|_err| panic!("Unable to get accounts"))
+        .and_then(move |accounts| {
+            let account0 = accounts[0].clone();
+            let account1 = accounts[1].clone();
+            store
+                .update_balances_for_prepare(accounts[0].clone(), 100, "condition")
+                .and_then(move |_| {
+                    store_clone_1
+                        .clone()
+                        .get_balance(accounts[0].clone())
+                        .join(store_clone_1.clone().get_balance(accounts[1].clone()))
+                        .and_then(|(balance0, balance1)| {
+                            assert_eq!(balance0, -100);
+                            assert_eq!(balance1, 0);
+                            Ok(())
+                        })
+                })
+                .and_then(move |_| {
+                    store_clone_2
+                        .clone()
+                        .update_balances_for_reject(account0.clone(), 100, "condition")
+                        .and_then(move |_| {
+                            store_clone_2
+                                .clone()
+                                .get_balance(account0.clone())
+                                .join(store_clone_2.clone().get_balance(account1.clone()))
+                                .and_then(move |(balance0, balance1)| {
+                                    assert_eq!(balance0, 0);
+                                    assert_eq!(balance1, 0);
+                                    Ok(())
+                                })
+                        })
+                })
+        })
+}
+
+pub fn enforces_minimum_balance<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .clone()
+        .get_accounts(vec![0, 1])
+        .map_err(|_err| panic!("Unable to get accounts"))
+        .and_then(move |accounts| {
+            store
+                .update_balances_for_prepare(accounts[0].clone(), 10000, "condition")
+                .then(move |result| {
+                    assert!(result.is_err());
+                    Ok(())
+                })
+        })
+}
+
+// Prepare and Fulfill a packet for 100 units from Account 0 to Account 1
+// Then, Prepare and Fulfill a packet for 80 units from Account 1 to Account 0
+pub fn netting_fulfilled_balances<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let store_clone1 = store.clone();
+    let store_clone2 = store.clone();
+    store
+        .clone()
+        .insert_account(ACCOUNT_DETAILS_2.clone())
+        .and_then(move |_| {
+            store
+                .clone()
+                .get_accounts(vec![0, 2])
+                .map_err(|_err| panic!("Unable to get accounts"))
+                .and_then(move |accounts| {
+                    let account0 = accounts[0].clone();
+                    let account1 = accounts[1].clone();
+                    let account0_clone = account0.clone();
+                    let account1_clone = account1.clone();
+                    future::join_all(vec![
+                        Either::A(store.clone().update_balances_for_prepare(
+                            account0.clone(),
+                            100, // decrement account 0 by 100
+                            "condition",
+                        )),
+                        Either::B(
+                            store
+                                .clone()
+                                .update_balances_for_fulfill(
+                                    account1.clone(), // increment account 1 by 100
+                                    100,
+                                    "condition",
+                                )
+                                .and_then(|_| Ok(())),
+                        ),
+                    ])
+                    .and_then(move |_| {
+                        future::join_all(vec![
+                            Either::A(store_clone1.clone().update_balances_for_prepare(
+                                account1.clone(),
+                                80,
+                                "condition",
+                            )),
+                            Either::B(
+                                store_clone1
+                                    .clone()
+                                    .update_balances_for_fulfill(account0.clone(), 80, "condition")
+                                    .and_then(|_| Ok(())),
+                            ),
+                        ])
+                    })
+                    .and_then(move |_| {
+                        store_clone2
+                            .clone()
+                            .get_balance(account0_clone)
+                            .join(store_clone2.get_balance(account1_clone))
+                            .and_then(move |(balance0, balance1)| {
+                                assert_eq!(balance0, -20);
+                                assert_eq!(balance1, 20);
+                                Ok(())
+                            })
+                    })
+                })
+        })
+}
+
+pub fn records_balance_changes_in_journal<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_accounts(vec![0])
+        .and_then(move |accounts| {
+            let account0 = accounts[0].clone();
+            let store_clone = store.clone();
+            store
+                .update_balances_for_prepare(account0.clone(), 100, "aa")
+                .and_then(move |_| {
+                    store_clone
+                        .update_balances_for_reject(account0.clone(), 40, "aa")
+                        .map(move |_| (store_clone, account0))
+                })
+        })
+        .and_then(|(store, account0)| {
+            let store_clone = store.clone();
+            store
+                .get_balance_journal(account0.clone(), 1, 10)
+                .and_then(move |journal| {
+                    assert_eq!(journal.len(), 2);
+                    assert_eq!(journal[0].id, 1);
+                    assert_eq!(journal[0].kind, JournalEntryKind::Prepare);
+                    assert_eq!(journal[0].reference, Some("aa".to_string()));
+                    assert_eq!(journal[0].amount, -100);
+                    assert_eq!(journal[0].balance, -100);
+                    assert_eq!(journal[1].kind, JournalEntryKind::Reject);
+                    assert_eq!(journal[1].amount, 40);
+                    assert_eq!(journal[1].balance, -60);
+                    store_clone.get_balance_journal(account0, 2, 10)
+                })
+        })
+        .and_then(|page| {
+            assert_eq!(page.len(), 1);
+            assert_eq!(page[0].id, 2);
+            Ok(())
+        })
+}
+
+pub fn records_outgoing_settlements_in_journal<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_accounts(vec![0])
+        .and_then(move |accounts| {
+            let account0 = accounts[0].clone();
+            let store_clone = store.clone();
+            store
+                .update_balances_for_fulfill(account0.clone(), 100, "bb")
+                .and_then(move |(balance, amount_to_settle)| {
+                    assert_eq!(balance, -1000);
+                    assert_eq!(amount_to_settle, 1100);
+                    store_clone.get_balance_journal(account0, 1, 10)
+                })
+        })
+        .and_then(|journal| {
+            assert_eq!(journal.len(), 2);
+            assert_eq!(journal[0].kind, JournalEntryKind::Fulfill);
+            assert_eq!(journal[0].balance, 100);
+            assert_eq!(journal[1].kind, JournalEntryKind::OutgoingSettlement);
+            assert_eq!(journal[1].reference, Some("bb".to_string()));
+            assert_eq!(journal[1].amount, -1100);
+            assert_eq!(journal[1].balance, -1000);
+            Ok(())
+        })
+}
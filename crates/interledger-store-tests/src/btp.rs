@@ -0,0 +1,42 @@
+use crate::{TestAccount, TestStore};
+use futures::Future;
+
+pub fn gets_account_from_btp_token<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_account_from_btp_token("other_btp_token")
+        .and_then(move |account| {
+            assert_eq!(account.id(), 1);
+            Ok(())
+        })
+}
+
+pub fn decrypts_outgoing_tokens<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_account_from_btp_token("other_btp_token")
+        .and_then(move |account| {
+            assert_eq!(
+                account.get_http_auth_token().unwrap(),
+                "outgoing_auth_token"
+            );
+            assert_eq!(
+                account.get_btp_token().unwrap(),
+                b"other_outgoing_btp_token"
+            );
+            Ok(())
+        })
+}
+
+pub fn errors_on_unknown_btp_token<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_account_from_btp_token("unknown_btp_token")
+        .then(move |result| {
+            assert!(result.is_err());
+            Ok(())
+        })
+}
@@ -6,6 +6,7 @@ use std::str::FromStr;
 lazy_static! {
     pub static ref ACCOUNT_DETAILS_0: AccountDetails = AccountDetails {
         ilp_address: Address::from_str("example.alice").unwrap(),
+        username: Some("alice".to_string()),
         asset_scale: 6,
         asset_code: "XYZ".to_string(),
         max_packet_amount: 1000,
@@ -20,6 +21,14 @@ lazy_static! {
         send_routes: false,
         receive_routes: true,
         routing_relation: None,
+        allowed_route_prefixes: None,
+        route_import_deny: None,
+        route_import_max_path_length: None,
+        route_export_allow: None,
+        route_export_deny: None,
+        route_export_max_path_length: None,
+        advertise_parent_routes: None,
+        route_cost: None,
         round_trip_time: None,
         amount_per_minute_limit: Some(1000),
         packets_per_minute_limit: Some(2),
@@ -28,6 +37,7 @@ lazy_static! {
     };
     pub static ref ACCOUNT_DETAILS_1: AccountDetails = AccountDetails {
         ilp_address: Address::from_str("example.bob").unwrap(),
+        username: None,
         asset_scale: 9,
         asset_code: "ABC".to_string(),
         max_packet_amount: 1_000_000,
@@ -42,6 +52,14 @@ lazy_static! {
         send_routes: true,
         receive_routes: false,
         routing_relation: None,
+        allowed_route_prefixes: None,
+        route_import_deny: None,
+        route_import_max_path_length: None,
+        route_export_allow: None,
+        route_export_deny: None,
+        route_export_max_path_length: None,
+        advertise_parent_routes: None,
+        route_cost: None,
         round_trip_time: None,
         amount_per_minute_limit: Some(1000),
         packets_per_minute_limit: Some(20),
@@ -50,6 +68,7 @@ lazy_static! {
     };
     pub static ref ACCOUNT_DETAILS_2: AccountDetails = AccountDetails {
         ilp_address: Address::from_str("example.charlie").unwrap(),
+        username: None,
         asset_scale: 9,
         asset_code: "XRP".to_string(),
         max_packet_amount: 1000,
@@ -64,6 +83,14 @@ lazy_static! {
         send_routes: false,
         receive_routes: false,
         routing_relation: None,
+        allowed_route_prefixes: None,
+        route_import_deny: None,
+        route_import_max_path_length: None,
+        route_export_allow: None,
+        route_export_deny: None,
+        route_export_max_path_length: None,
+        advertise_parent_routes: None,
+        route_cost: None,
         round_trip_time: None,
         amount_per_minute_limit: None,
         packets_per_minute_limit: None,
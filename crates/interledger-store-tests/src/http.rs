@@ -0,0 +1,76 @@
+use crate::{TestAccount, TestStore};
+use futures::Future;
+use url::Url;
+
+pub fn gets_account_from_http_bearer_token<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_account_from_http_token("incoming_auth_token")
+        .and_then(move |account| {
+            assert_eq!(account.id(), 0);
+            assert_eq!(
+                account.get_http_auth_token().unwrap(),
+                "outgoing_auth_token"
+            );
+            assert_eq!(account.get_btp_token().unwrap(), b"btp_token");
+            Ok(())
+        })
+}
+
+pub fn decrypts_outgoing_tokens<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_account_from_http_token("incoming_auth_token")
+        .and_then(move |account| {
+            assert_eq!(
+                account.get_http_auth_token().unwrap(),
+                "outgoing_auth_token"
+            );
+            assert_eq!(account.get_btp_token().unwrap(), b"btp_token");
+            Ok(())
+        })
+}
+
+pub fn errors_on_unknown_http_auth<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_account_from_http_token("unknown_token")
+        .then(move |result| {
+            assert!(result.is_err());
+            Ok(())
+        })
+}
+
+pub fn only_removes_own_connection_owner<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let one = Url::parse("http://instance-1:7771").unwrap();
+    let two = Url::parse("http://instance-2:7771").unwrap();
+    let store_clone = store.clone();
+    store
+        .set_connection_owner(0, &one)
+        .and_then(move |_| {
+            store
+                .set_connection_owner(0, &two)
+                .map(|_| (store, one, two))
+        })
+        .and_then(|(store, one, two)| {
+            // The connection moved to the second instance before the first one noticed it closed
+            store
+                .remove_connection_owner(0, &one)
+                .and_then(move |_| store.get_connection_owner(0))
+                .and_then(move |owner| {
+                    assert_eq!(owner, Some(two.clone()));
+                    store_clone
+                        .remove_connection_owner(0, &two)
+                        .and_then(move |_| store_clone.get_connection_owner(0))
+                })
+        })
+        .and_then(|owner| {
+            assert_eq!(owner, None);
+            Ok(())
+        })
+}
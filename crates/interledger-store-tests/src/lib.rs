@@ -0,0 +1,180 @@
+//! # interledger-store-tests
+//!
+//! Tests that every store must pass, whatever it keeps its data in, and the account
+//! fixtures they use.
+//!
+//! Each store's test crate defines two functions and calls `store_tests!()`:
+//!
+//! - `run_store_test` runs a test with a store that holds `ACCOUNT_DETAILS_0` and
+//!   `ACCOUNT_DETAILS_1` (with the IDs 0 and 1)
+//! - `run_import_test` runs a test with a store like that and an empty one that uses
+//!   a different secret
+
+use interledger_api::NodeStateStore;
+use interledger_btp::{BtpAccount, BtpStore};
+use interledger_ccp::{CcpRoutingAccount, RouteManagerStore};
+use interledger_http::{HttpAccount, HttpForwardingStore, HttpStore};
+use interledger_ildcp::IldcpAccount;
+use interledger_router::RouterStore;
+use interledger_service::{Account as AccountTrait, AccountStore};
+use interledger_service_util::{BalanceStore, ExchangeRateStore, RateLimitAccount, RateLimitStore};
+use interledger_settlement::{IdempotentStore, SettlementAccount, SettlementStore};
+
+pub mod accounts;
+pub mod balances;
+pub mod btp;
+pub mod fixtures;
+pub mod http;
+pub mod node_state;
+pub mod rate_limiting;
+pub mod rates;
+pub mod routing;
+pub mod settlement;
+
+/// The store traits the shared tests use, all for the same account type
+pub trait TestStore<A: TestAccount>:
+    AccountStore<Account = A>
+    + NodeStateStore<Account = A>
+    + BalanceStore
+    + BtpStore<Account = A>
+    + HttpStore<Account = A>
+    + HttpForwardingStore<Account = A>
+    + RouterStore
+    + RouteManagerStore<Account = A>
+    + RateLimitStore<Account = A>
+    + ExchangeRateStore
+    + SettlementStore<Account = A>
+    + IdempotentStore
+{
+}
+
+impl<S, A> TestStore<A> for S
+where
+    A: TestAccount,
+    S: AccountStore<Account = A>
+        + NodeStateStore<Account = A>
+        + BalanceStore
+        + BtpStore<Account = A>
+        + HttpStore<Account = A>
+        + HttpForwardingStore<Account = A>
+        + RouterStore
+        + RouteManagerStore<Account = A>
+        + RateLimitStore<Account = A>
+        + ExchangeRateStore
+        + SettlementStore<Account = A>
+        + IdempotentStore,
+{
+}
+
+/// The account traits the shared tests use. The tests refer to accounts by their IDs
+pub trait TestAccount:
+    AccountTrait<AccountId = u64>
+    + IldcpAccount
+    + HttpAccount
+    + BtpAccount
+    + CcpRoutingAccount
+    + RateLimitAccount
+    + SettlementAccount
+    + Sync
+    + 'static
+{
+}
+
+impl<A> TestAccount for A where
+    A: AccountTrait<AccountId = u64>
+        + IldcpAccount
+        + HttpAccount
+        + BtpAccount
+        + CcpRoutingAccount
+        + RateLimitAccount
+        + SettlementAccount
+        + Sync
+        + 'static
+{
+}
+
+/// Define a `#[test]` for each of the shared tests, grouped in modules like the
+/// ones of this crate
+#[macro_export]
+macro_rules! store_tests {
+    () => {
+        $crate::store_tests! {
+            accounts:
+                insert_accounts,
+                starts_with_zero_balance,
+                fails_on_duplicate_http_incoming_auth,
+                fails_on_duplicate_btp_incoming_auth,
+                fails_on_duplicate_username,
+                gets_account_id_from_username,
+                get_all_accounts,
+                gets_single_account,
+                gets_multiple,
+                decrypts_outgoing_tokens,
+                errors_for_unknown_accounts,
+                sets_account_asset,
+                cannot_set_asset_of_unknown_account;
+            balances:
+                prepare_then_fulfill_with_settlement,
+                process_fulfill_no_settle_to,
+                process_fulfill_settle_to_over_threshold,
+                process_fulfill_ok,
+                prepare_then_reject,
+                enforces_minimum_balance,
+                netting_fulfilled_balances,
+                records_balance_changes_in_journal,
+                records_outgoing_settlements_in_journal;
+            btp:
+                gets_account_from_btp_token,
+                decrypts_outgoing_tokens,
+                errors_on_unknown_btp_token;
+            http:
+                gets_account_from_http_bearer_token,
+                decrypts_outgoing_tokens,
+                errors_on_unknown_http_auth,
+                only_removes_own_connection_owner;
+            node_state:
+                exports_and_imports_state(run_import_test),
+                imports_all_of_the_state_or_none;
+            rate_limiting:
+                rate_limits_number_of_packets,
+                limits_amount_throughput,
+                refunds_throughput_limit_for_rejected_packets;
+            rates:
+                set_rates;
+            routing:
+                gets_accounts_to_send_routes_to,
+                gets_accounts_to_receive_routes_from,
+                gets_local_and_configured_routes,
+                saves_alternate_routes,
+                updates_local_routes,
+                static_routes_override_others,
+                returns_configured_routes_for_route_manager,
+                moves_child_addresses_under_node_address;
+            settlement:
+                saves_and_loads_idempotency_key_data_properly,
+                credits_prepaid_amount,
+                idempotent_settlement_calls,
+                credits_balance_owed,
+                clears_balance_owed,
+                clears_balance_owed_and_puts_remainder_as_prepaid;
+        }
+    };
+    ($($module:ident: $($test:ident $(($run:ident))?),+;)+) => {
+        $(
+            mod $module {
+                use super::*;
+
+                $($crate::store_tests!(@test $module $test $($run)?);)+
+            }
+        )+
+    };
+    (@test $module:ident $test:ident) => {
+        $crate::store_tests!(@test $module $test run_store_test);
+    };
+    (@test $module:ident $test:ident $run:ident) => {
+        #[test]
+        fn $test() {
+            $run($crate::$module::$test);
+        }
+    };
+}
@@ -0,0 +1,120 @@
+use crate::fixtures::ACCOUNT_DETAILS_2;
+use crate::{TestAccount, TestStore};
+use bytes::Bytes;
+use futures::Future;
+use http::StatusCode;
+use interledger_api::import_node_state;
+use interledger_service_util::JournalEntryKind;
+
+pub fn exports_and_imports_state<S: TestStore<A>, A: TestAccount>(
+    store: S,
+    empty_store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let store_clone = store.clone();
+    store
+        .get_accounts(vec![0, 1])
+        .and_then(move |accounts| {
+            store_clone
+                .update_balances_for_prepare(accounts[0].clone(), 100, "condition")
+                .map(move |_| store_clone)
+        })
+        .and_then(|store| {
+            store
+                .set_static_route("example.route".to_string(), 1)
+                .map(move |_| store)
+        })
+        .and_then(|store| {
+            store
+                .set_rates(vec![("XYZ".to_string(), 2.0)])
+                .map(move |_| store)
+        })
+        .and_then(|store| {
+            store
+                .save_idempotent_data(
+                    "key".to_string(),
+                    [1; 32],
+                    StatusCode::OK,
+                    Bytes::from("OK"),
+                )
+                .map(move |_| store)
+        })
+        .and_then(|store| store.export_state())
+        .and_then(move |state| {
+            assert_eq!(state.accounts.len(), 2);
+            assert_eq!(state.accounts[0].balance, -100);
+            let details = &state.accounts[0].details;
+            // Only the HMACs of the incoming tokens are stored
+            assert_eq!(details.http_incoming_token, None);
+            assert!(state.accounts[0].has_incoming_tokens);
+            assert!(state.check_incoming_tokens(false).is_err());
+            assert_eq!(
+                details.http_outgoing_token,
+                Some("outgoing_auth_token".to_string())
+            );
+            assert_eq!(
+                details.btp_uri,
+                Some("btp+ws://:btp_token@example.com/btp".to_string())
+            );
+            assert_eq!(state.static_routes["example.route"], 1);
+            assert_eq!(state.idempotent_data.len(), 1);
+
+            let imported = empty_store.clone();
+            import_node_state(empty_store.clone(), state.clone())
+                .and_then(move |_| empty_store.get_accounts(vec![0, 1]))
+                .and_then(move |accounts| {
+                    assert_eq!(
+                        accounts[0].get_http_auth_token(),
+                        Some("outgoing_auth_token")
+                    );
+                    assert_eq!(imported.routing_table()[&Bytes::from("example.route")], 1);
+                    assert_eq!(imported.get_exchange_rates(&["XYZ"]).unwrap(), vec![2.0]);
+                    let store = imported.clone();
+                    imported
+                        .get_balance(accounts[0].clone())
+                        .join(store.get_balance_journal(accounts[0].clone(), 1, 10))
+                        .join(store.load_idempotent_data("key".to_string()))
+                        .map(move |result| (store, result))
+                })
+                .and_then(
+                    move |(store, ((balance, journal), (status_code, data, hash)))| {
+                        assert_eq!(balance, -100);
+                        assert_eq!(journal.len(), 1);
+                        assert_eq!(journal[0].kind, JournalEntryKind::Import);
+                        assert_eq!(status_code, StatusCode::OK);
+                        assert_eq!(data, Bytes::from("OK"));
+                        assert_eq!(hash, [1; 32]);
+
+                        // State can only be imported into an empty store
+                        import_node_state(store.clone(), state).then(move |result| {
+                            assert!(result.is_err());
+                            // New accounts get IDs after the imported ones
+                            store.insert_account(ACCOUNT_DETAILS_2.clone())
+                        })
+                    },
+                )
+                .and_then(|account| {
+                    assert_eq!(account.id(), 2);
+                    Ok(())
+                })
+        })
+}
+
+pub fn imports_all_of_the_state_or_none<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store.export_state().and_then(move |mut state| {
+        // The first account could be inserted but the second one already exists
+        state.accounts[0].id = 2;
+        state.accounts[0].details.username = None;
+        state.rates.insert("ABC".to_string(), 3.0);
+        store.import_state(state).then(move |result| {
+            assert!(result.is_err());
+            // Nothing else from the state was imported either
+            assert!(store.get_exchange_rates(&["ABC"]).is_err());
+            store.get_accounts(vec![2]).then(|result| {
+                assert!(result.is_err());
+                Ok(())
+            })
+        })
+    })
+}
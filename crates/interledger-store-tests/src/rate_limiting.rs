@@ -1,12 +1,13 @@
-mod common;
-use common::*;
+use crate::{TestAccount, TestStore};
 use futures::future::join_all;
-use interledger_service_util::{RateLimitError, RateLimitStore};
+use futures::Future;
+use interledger_service_util::RateLimitError;
 
-#[test]
-fn rate_limits_number_of_packets() {
-    block_on(test_store().and_then(|(store, context)| {
-        let account = Account::try_from(0, ACCOUNT_DETAILS_0.clone()).unwrap();
+pub fn rate_limits_number_of_packets<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store.get_accounts(vec![0]).and_then(move |accounts| {
+        let account = accounts[0].clone();
         join_all(vec![
             store.clone().apply_rate_limits(account.clone(), 10),
             store.clone().apply_rate_limits(account.clone(), 10),
@@ -15,17 +16,16 @@ fn rate_limits_number_of_packets() {
         .then(move |result| {
             assert!(result.is_err());
             assert_eq!(result.unwrap_err(), RateLimitError::PacketLimitExceeded);
-            let _ = context;
             Ok(())
         })
-    }))
-    .unwrap()
+    })
 }
 
-#[test]
-fn limits_amount_throughput() {
-    block_on(test_store().and_then(|(store, context)| {
-        let account = Account::try_from(1, ACCOUNT_DETAILS_1.clone()).unwrap();
+pub fn limits_amount_throughput<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store.get_accounts(vec![1]).and_then(move |accounts| {
+        let account = accounts[0].clone();
         join_all(vec![
             store.clone().apply_rate_limits(account.clone(), 500),
             store.clone().apply_rate_limits(account.clone(), 500),
@@ -34,17 +34,16 @@ fn limits_amount_throughput() {
         .then(move |result| {
             assert!(result.is_err());
             assert_eq!(result.unwrap_err(), RateLimitError::ThroughputLimitExceeded);
-            let _ = context;
             Ok(())
         })
-    }))
-    .unwrap()
+    })
 }
 
-#[test]
-fn refunds_throughput_limit_for_rejected_packets() {
-    block_on(test_store().and_then(|(store, context)| {
-        let account = Account::try_from(1, ACCOUNT_DETAILS_1.clone()).unwrap();
+pub fn refunds_throughput_limit_for_rejected_packets<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store.get_accounts(vec![1]).and_then(move |accounts| {
+        let account = accounts[0].clone();
         join_all(vec![
             store.clone().apply_rate_limits(account.clone(), 500),
             store.clone().apply_rate_limits(account.clone(), 500),
@@ -71,11 +70,9 @@ fn refunds_throughput_limit_for_rejected_packets() {
                                 result.unwrap_err(),
                                 RateLimitError::ThroughputLimitExceeded
                             );
-                            let _ = context;
                             Ok(())
                         })
                 })
         })
-    }))
-    .unwrap()
+    })
 }
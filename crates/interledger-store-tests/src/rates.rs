@@ -0,0 +1,18 @@
+use crate::{TestAccount, TestStore};
+use futures::Future;
+
+pub fn set_rates<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let store_clone = store.clone();
+    let rates = store.get_exchange_rates(&["ABC", "XYZ"]);
+    assert!(rates.is_err());
+    store
+        .set_rates(vec![("ABC".to_string(), 500.0), ("XYZ".to_string(), 0.005)])
+        .and_then(move |_| {
+            let rates = store_clone.get_exchange_rates(&["XYZ", "ABC"]).unwrap();
+            assert_eq!(rates[0].to_string(), "0.005");
+            assert_eq!(rates[1].to_string(), "500");
+            Ok(())
+        })
+}
@@ -0,0 +1,165 @@
+use crate::{TestAccount, TestStore};
+use bytes::Bytes;
+use futures::Future;
+use interledger_packet::Address;
+use interledger_router::AlternateRoute;
+use std::str::FromStr;
+
+pub fn gets_accounts_to_send_routes_to<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_accounts_to_send_routes_to()
+        .and_then(move |accounts| {
+            assert_eq!(accounts[0].id(), 1);
+            assert_eq!(accounts.len(), 1);
+            Ok(())
+        })
+}
+
+pub fn gets_accounts_to_receive_routes_from<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_accounts_to_receive_routes_from()
+        .and_then(move |accounts| {
+            assert_eq!(accounts[0].id(), 0);
+            assert_eq!(accounts.len(), 1);
+            Ok(())
+        })
+}
+
+pub fn gets_local_and_configured_routes<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_local_and_configured_routes()
+        .and_then(move |(local, configured)| {
+            assert_eq!(local.len(), 2);
+            assert!(configured.is_empty());
+            Ok(())
+        })
+}
+
+pub fn saves_alternate_routes<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let mut store_clone = store.clone();
+    store
+        .get_accounts(vec![0, 1])
+        .and_then(move |accounts| {
+            store_clone.set_alternate_routes(vec![(
+                Bytes::from("example.a"),
+                vec![(accounts[0].clone(), true), (accounts[1].clone(), false)],
+            )])
+        })
+        .and_then(move |_| {
+            assert_eq!(
+                store.alternate_routes(b"example.a"),
+                vec![
+                    AlternateRoute {
+                        account_id: 0,
+                        equal_cost: true
+                    },
+                    AlternateRoute {
+                        account_id: 1,
+                        equal_cost: false
+                    },
+                ]
+            );
+            assert!(store.alternate_routes(b"example.b").is_empty());
+            Ok(())
+        })
+}
+
+pub fn updates_local_routes<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let mut store_clone = store.clone();
+    store
+        .get_accounts(vec![0, 1])
+        .and_then(move |accounts| {
+            store_clone.set_routes(vec![
+                (Bytes::from("example.a"), accounts[0].clone()),
+                (Bytes::from("example.b"), accounts[0].clone()),
+                (Bytes::from("example.c"), accounts[1].clone()),
+            ])
+        })
+        .and_then(move |_| {
+            let routes = store.routing_table();
+            assert_eq!(routes[&b"example.a"[..]], 0);
+            assert_eq!(routes[&b"example.b"[..]], 0);
+            assert_eq!(routes[&b"example.c"[..]], 1);
+            assert_eq!(routes.len(), 3);
+            Ok(())
+        })
+}
+
+pub fn static_routes_override_others<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let mut store_clone = store.clone();
+    store
+        .set_static_routes(vec![
+            ("example.a".to_string(), 0),
+            ("example.b".to_string(), 0),
+        ])
+        .and_then(move |_| {
+            store_clone.get_accounts(vec![1]).and_then(move |accounts| {
+                let account1 = accounts[0].clone();
+                store_clone.set_routes(vec![
+                    (Bytes::from("example.a"), account1.clone()),
+                    (Bytes::from("example.b"), account1.clone()),
+                    (Bytes::from("example.c"), account1),
+                ])
+            })
+        })
+        .and_then(move |_| {
+            let routes = store.routing_table();
+            assert_eq!(routes[&b"example.a"[..]], 0);
+            assert_eq!(routes[&b"example.b"[..]], 0);
+            assert_eq!(routes[&b"example.c"[..]], 1);
+            assert_eq!(routes.len(), 3);
+            Ok(())
+        })
+}
+
+pub fn returns_configured_routes_for_route_manager<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .clone()
+        .set_static_routes(vec![
+            ("example.a".to_string(), 0),
+            ("example.b".to_string(), 1),
+        ])
+        .and_then(move |_| store.get_local_and_configured_routes())
+        .and_then(move |(_local, configured)| {
+            assert_eq!(configured.len(), 2);
+            assert_eq!(configured[&b"example.a"[..]].id(), 0);
+            assert_eq!(configured[&b"example.b"[..]].id(), 1);
+            Ok(())
+        })
+}
+
+pub fn moves_child_addresses_under_node_address<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let store_clone = store.clone();
+    store
+        .update_child_addresses(Address::from_str("example.node").unwrap())
+        .and_then(move |_| {
+            let routes = store_clone.routing_table();
+            assert_eq!(routes[&b"example.node.alice"[..]], 0);
+            assert_eq!(routes[&b"example.node.bob"[..]], 1);
+            assert!(!routes.contains_key(&b"example.alice"[..]));
+            store_clone.get_accounts(vec![0])
+        })
+        .and_then(move |accounts| {
+            assert_eq!(
+                *accounts[0].client_address(),
+                Address::from_str("example.node.alice").unwrap()
+            );
+            Ok(())
+        })
+}
@@ -0,0 +1,128 @@
+use crate::{TestAccount, TestStore};
+use bytes::Bytes;
+use futures::Future;
+use http::StatusCode;
+
+const IDEMPOTENCY_KEY: &str = "AJKJNUjM0oyiAN46";
+
+pub fn saves_and_loads_idempotency_key_data_properly<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let input_hash: [u8; 32] = Default::default();
+    let store_clone = store.clone();
+    store
+        .save_idempotent_data(
+            IDEMPOTENCY_KEY.to_string(),
+            input_hash,
+            StatusCode::OK,
+            Bytes::from("TEST"),
+        )
+        .and_then(move |_| store_clone.load_idempotent_data(IDEMPOTENCY_KEY.to_string()))
+        .and_then(move |data1| {
+            assert_eq!(data1, (StatusCode::OK, Bytes::from("TEST"), input_hash));
+            store
+                .load_idempotent_data("asdf".to_string())
+                .then(|result| {
+                    assert!(result.is_err());
+                    Ok(())
+                })
+        })
+}
+
+/// Prepare a packet for `prepared` units from account 0, credit it with an incoming
+/// settlement of `settled` units and return its balance
+fn settle_after_prepare<S: TestStore<A>, A: TestAccount>(
+    store: S,
+    prepared: u64,
+    settled: u64,
+) -> impl Future<Item = i64, Error = ()> + Send {
+    store.get_accounts(vec![0]).and_then(move |accounts| {
+        let account = accounts[0].clone();
+        let store_clone = store.clone();
+        store
+            .update_balances_for_prepare(account.clone(), prepared, "condition")
+            .and_then(move |_| {
+                store_clone
+                    .update_balance_for_incoming_settlement(
+                        0,
+                        settled,
+                        Some(IDEMPOTENCY_KEY.to_string()),
+                    )
+                    .and_then(move |_| store_clone.get_balance(account))
+            })
+    })
+}
+
+pub fn credits_prepaid_amount<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_accounts(vec![0])
+        .and_then(move |accounts| {
+            let account = accounts[0].clone();
+            let store_clone = store.clone();
+            store
+                .update_balance_for_incoming_settlement(0, 100, Some(IDEMPOTENCY_KEY.to_string()))
+                .and_then(move |_| store_clone.get_balance(account))
+        })
+        .and_then(|balance| {
+            assert_eq!(balance, 100);
+            Ok(())
+        })
+}
+
+pub fn idempotent_settlement_calls<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    store
+        .get_accounts(vec![0])
+        .and_then(move |accounts| {
+            let account = accounts[0].clone();
+            let store_clone = store.clone();
+            store
+                .update_balance_for_incoming_settlement(0, 100, Some(IDEMPOTENCY_KEY.to_string()))
+                .and_then(move |_| {
+                    // Reuse key to make idempotent request
+                    store_clone
+                        .update_balance_for_incoming_settlement(
+                            0,
+                            100,
+                            Some(IDEMPOTENCY_KEY.to_string()),
+                        )
+                        .and_then(move |_| store_clone.get_balance(account))
+                })
+        })
+        .and_then(|balance| {
+            // Since it's idempotent there will be no state update.
+            // Otherwise it'd be 200 (100 + 100)
+            assert_eq!(balance, 100);
+            Ok(())
+        })
+}
+
+pub fn credits_balance_owed<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    settle_after_prepare(store, 200, 100).and_then(|balance| {
+        assert_eq!(balance, -100);
+        Ok(())
+    })
+}
+
+pub fn clears_balance_owed<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    settle_after_prepare(store, 100, 100).and_then(|balance| {
+        assert_eq!(balance, 0);
+        Ok(())
+    })
+}
+
+pub fn clears_balance_owed_and_puts_remainder_as_prepaid<S: TestStore<A>, A: TestAccount>(
+    store: S,
+) -> impl Future<Item = (), Error = ()> + Send {
+    settle_after_prepare(store, 40, 100).and_then(|balance| {
+        assert_eq!(balance, 60);
+        Ok(())
+    })
+}
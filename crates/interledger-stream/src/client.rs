@@ -1,9 +1,13 @@
-use super::congestion::CongestionController;
+use super::congestion::{CongestionControl, CongestionController};
 use super::crypto::*;
 use super::error::Error;
 use super::packet::*;
 use bytes::Bytes;
-use futures::{Async, Future, Poll};
+use futures::{
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    task::AtomicTask,
+    Async, Future, Poll, Stream,
+};
 use interledger_ildcp::get_ildcp_info;
 use interledger_packet::{
     Address, ErrorClass, ErrorCode as IlpErrorCode, Fulfill, PacketType as IlpPacketType,
@@ -13,63 +17,249 @@ use interledger_service::*;
 use log::{debug, error, warn};
 use std::{
     cell::Cell,
-    cmp::min,
+    cmp::{max, min},
     str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 
+/// The asset code and scale of one side of a STREAM connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssetDetails {
+    pub asset_code: String,
+    pub asset_scale: u8,
+}
+
+/// The outcome of a payment sent with `send_money`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamDelivery {
+    /// The sender's asset details
+    pub source_asset: AssetDetails,
+    /// The amount fulfilled, in the sender's asset's units
+    pub sent_amount: u64,
+    /// The receiver's asset details, if the receiver sent them
+    pub destination_asset: Option<AssetDetails>,
+    /// The amount delivered, as reported by the receiver and in the receiver's asset's units
+    pub delivered_amount: u64,
+}
+
+/// A snapshot of how far along a payment is, emitted each time packets are sent or answered
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentProgress {
+    /// The amount fulfilled so far, in the sender's asset's units
+    pub sent_amount: u64,
+    /// The amount delivered so far, in the receiver's asset's units
+    pub delivered_amount: u64,
+    /// The amount in Prepare packets that have not been answered yet, in the sender's asset's units
+    pub in_flight_amount: u64,
+    pub rejected_packets: u64,
+    /// The receiver's units delivered per unit sent, once anything has been delivered
+    pub exchange_rate: Option<f64>,
+}
+
+/// Stops a payment started with `send_money_with_progress`.
+///
+/// No more money is sent after `cancel` is called. The payment waits for the packets
+/// already in flight, closes the connection, and resolves with the amounts sent and delivered so far.
+#[derive(Clone)]
+pub struct PaymentCanceller {
+    cancelled: Arc<AtomicBool>,
+    task: Arc<AtomicTask>,
+}
+
+impl PaymentCanceller {
+    fn new() -> Self {
+        PaymentCanceller {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            task: Arc::new(AtomicTask::new()),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.task.notify();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// A handle to a payment started with `send_money_with_progress`.
+///
+/// This is a Stream of the payment's progress, which ends when the payment finishes.
+pub struct PaymentHandle {
+    progress: UnboundedReceiver<PaymentProgress>,
+    canceller: PaymentCanceller,
+}
+
+impl PaymentHandle {
+    /// Get a canceller that can be moved to wherever the payment should be stopped from
+    pub fn canceller(&self) -> PaymentCanceller {
+        self.canceller.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.canceller.cancel()
+    }
+}
+
+impl Stream for PaymentHandle {
+    type Item = PaymentProgress;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.progress.poll()
+    }
+}
+
 /// Send a given amount of money using the STREAM transport protocol.
 ///
-/// This returns the amount delivered, as reported by the receiver and in the receiver's asset's units.
+/// This returns the amounts sent and delivered, along with the sender's and the receiver's asset details.
 pub fn send_money<S, A>(
     service: S,
     from_account: &A,
     destination_account: Address,
     shared_secret: &[u8],
     source_amount: u64,
-) -> impl Future<Item = (u64, S), Error = Error>
+) -> impl Future<Item = (StreamDelivery, S), Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    send_money_with_progress(
+        service,
+        from_account,
+        destination_account,
+        shared_secret,
+        source_amount,
+    )
+    .1
+}
+
+/// Send money like `send_money`, but also return a handle that reports the payment's
+/// progress and can be used to stop the payment early.
+///
+/// Progress events are buffered until they are read, so the handle should either be
+/// polled or dropped.
+pub fn send_money_with_progress<S, A>(
+    service: S,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: &[u8],
+    source_amount: u64,
+) -> (
+    PaymentHandle,
+    impl Future<Item = (StreamDelivery, S), Error = Error>,
+)
 where
     S: IncomingService<A> + Clone,
     A: Account,
 {
+    send_money_with_congestion_control(
+        service,
+        from_account,
+        destination_account,
+        shared_secret,
+        source_amount,
+        CongestionController::default(),
+    )
+}
+
+/// Send money like `send_money_with_progress`, using the given congestion controller
+/// to decide how much to send at once.
+pub fn send_money_with_congestion_control<S, A, C>(
+    service: S,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: &[u8],
+    source_amount: u64,
+    congestion_controller: C,
+) -> (
+    PaymentHandle,
+    impl Future<Item = (StreamDelivery, S), Error = Error>,
+)
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+    C: CongestionControl,
+{
+    let (progress_sender, progress) = unbounded();
+    let canceller = PaymentCanceller::new();
+    let handle = PaymentHandle {
+        progress,
+        canceller: canceller.clone(),
+    };
     let shared_secret = Bytes::from(shared_secret);
     let from_account = from_account.clone();
     // TODO can/should we avoid cloning the account?
-    get_ildcp_info(&mut service.clone(), from_account.clone())
+    let payment = get_ildcp_info(&mut service.clone(), from_account.clone())
         .map_err(|_err| Error::ConnectionError("Unable to get ILDCP info: {:?}".to_string()))
         .and_then(move |account_details| SendMoneyFuture {
             state: SendMoneyFutureState::SendMoney,
             next: Some(service),
             from_account,
             source_account: account_details.client_address(),
+            source_asset: AssetDetails {
+                asset_code: String::from_utf8_lossy(account_details.asset_code()).to_string(),
+                asset_scale: account_details.asset_scale(),
+            },
             destination_account,
             shared_secret,
             source_amount,
-            congestion_controller: CongestionController::default(),
+            congestion_controller,
             pending_requests: Cell::new(Vec::new()),
+            sent_amount: 0,
             delivered_amount: 0,
+            receive_max: None,
+            remote_total_received: 0,
+            remote_close: None,
+            destination_asset: None,
             should_send_source_account: true,
             sequence: 1,
             rejected_packets: 0,
             error: None,
-        })
+            progress: progress_sender,
+            canceller,
+        });
+    (handle, payment)
 }
 
-struct SendMoneyFuture<S: IncomingService<A>, A: Account> {
+struct SendMoneyFuture<S: IncomingService<A>, A: Account, C: CongestionControl> {
     state: SendMoneyFutureState,
     next: Option<S>,
     from_account: A,
     source_account: Address,
+    source_asset: AssetDetails,
     destination_account: Address,
     shared_secret: Bytes,
     source_amount: u64,
-    congestion_controller: CongestionController,
+    congestion_controller: C,
     pending_requests: Cell<Vec<PendingRequest>>,
+    sent_amount: u64,
     delivered_amount: u64,
+    receive_max: Option<u64>,
+    remote_total_received: u64,
+    remote_close: Option<(ErrorCode, String)>,
+    destination_asset: Option<AssetDetails>,
     should_send_source_account: bool,
     sequence: u64,
     rejected_packets: u64,
     error: Option<Error>,
+    progress: UnboundedSender<PaymentProgress>,
+    canceller: PaymentCanceller,
+}
+
+/// Before we know how much the receiver can get, send one packet and wait for its response
+fn probe_amount(amount_in_flight: u64) -> u64 {
+    if amount_in_flight == 0 {
+        u64::max_value()
+    } else {
+        0
+    }
 }
 
 struct PendingRequest {
@@ -82,25 +272,41 @@ struct PendingRequest {
 enum SendMoneyFutureState {
     SendMoney,
     Closing,
-    // RemoteClosed,
+    RemoteClosed,
     Closed,
 }
 
-impl<S, A> SendMoneyFuture<S, A>
+impl<S, A, C> SendMoneyFuture<S, A, C>
 where
     S: IncomingService<A>,
     A: Account,
+    C: CongestionControl,
 {
     fn try_send_money(&mut self) -> Result<bool, Error> {
         // Fire off requests until the congestion controller tells us to stop or we've sent the total amount
         let mut sent_packets = false;
         loop {
             // Determine the amount to send
+            let max_for_receiver = self.max_amount_for_receiver();
             let amount = min(
-                self.source_amount,
-                self.congestion_controller.get_max_amount(),
+                min(
+                    self.source_amount,
+                    self.congestion_controller.get_max_amount(),
+                ),
+                max_for_receiver,
             );
             if amount == 0 {
+                if max_for_receiver == 0
+                    && self.source_amount > 0
+                    && self.pending_requests.get_mut().is_empty()
+                {
+                    return Err(Error::SendMoneyError(format!(
+                        "Receiver cannot receive more money (receive max: {}, delivered: {}, left to send: {})",
+                        self.receive_max.unwrap_or_default(),
+                        self.delivered_amount,
+                        self.source_amount
+                    )));
+                }
                 break;
             }
             self.source_amount -= amount;
@@ -115,6 +321,10 @@ where
                 frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
                     source_account: self.source_account.clone(),
                 }));
+                frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: &self.source_asset.asset_code,
+                    source_asset_scale: self.source_asset.asset_scale,
+                }));
             }
             let stream_packet = StreamPacketBuilder {
                 ilp_packet_type: IlpPacketType::Prepare,
@@ -162,6 +372,36 @@ where
         Ok(sent_packets)
     }
 
+    /// The most we can send without the receiver going over its receive max,
+    /// based on the exchange rate of the packets fulfilled so far.
+    /// Until the receiver has sent its receive max and a packet has been fulfilled,
+    /// only one packet is sent at a time, so at most that one can go over it.
+    fn max_amount_for_receiver(&mut self) -> u64 {
+        let amount_in_flight: u64 = self
+            .pending_requests
+            .get_mut()
+            .iter()
+            .map(|request| request.amount)
+            .sum();
+        let receive_max = match self.receive_max {
+            Some(receive_max) => receive_max,
+            None => return probe_amount(amount_in_flight),
+        };
+        let remaining =
+            receive_max.saturating_sub(max(self.remote_total_received, self.delivered_amount));
+        if remaining == 0 {
+            return 0;
+        }
+        if self.delivered_amount == 0 {
+            // We don't know the exchange rate yet
+            return probe_amount(amount_in_flight);
+        }
+        let source_remaining = u128::from(remaining) * u128::from(self.sent_amount)
+            / u128::from(self.delivered_amount);
+        let source_remaining = min(source_remaining, u128::from(u64::max_value())) as u64;
+        source_remaining.saturating_sub(amount_in_flight)
+    }
+
     fn try_send_connection_close(&mut self) -> Result<(), Error> {
         let sequence = self.next_sequence();
         let stream_packet = StreamPacketBuilder {
@@ -205,7 +445,8 @@ where
 
     fn poll_pending_requests(&mut self) -> Poll<(), Error> {
         let pending_requests = self.pending_requests.take();
-        let pending_requests = pending_requests
+        let num_pending = pending_requests.len();
+        let pending_requests: Vec<PendingRequest> = pending_requests
             .into_iter()
             .filter_map(|mut pending_request| match pending_request.future.poll() {
                 Ok(Async::NotReady) => Some(pending_request),
@@ -219,7 +460,11 @@ where
                 }
             })
             .collect();
+        let answered_any = pending_requests.len() < num_pending;
         self.pending_requests.set(pending_requests);
+        if answered_any {
+            self.report_progress();
+        }
 
         if let Some(error) = self.error.take() {
             error!("Send money stopped because of error: {:?}", error);
@@ -235,17 +480,20 @@ where
         // TODO should we check the fulfillment and expiry or can we assume the plugin does that?
         self.congestion_controller.fulfill(amount);
         self.should_send_source_account = false;
+        self.sent_amount += amount;
 
-        if let Ok(packet) = StreamPacket::from_encrypted(&self.shared_secret, fulfill.into_data()) {
-            if packet.ilp_packet_type() == IlpPacketType::Fulfill {
-                // TODO check that the sequence matches our outgoing packet
+        match StreamPacket::from_encrypted(&self.shared_secret, fulfill.into_data()) {
+            Ok(ref packet)
+                if packet.ilp_packet_type() == IlpPacketType::Fulfill
+                    && packet.sequence() == sequence =>
+            {
                 self.delivered_amount += packet.prepare_amount();
+                self.handle_frames(packet);
             }
-        } else {
-            warn!(
+            _ => warn!(
                 "Unable to parse STREAM packet from fulfill data for sequence {}",
                 sequence
-            );
+            ),
         }
 
         debug!(
@@ -267,23 +515,141 @@ where
         );
 
         match (reject.code().class(), reject.code()) {
+            (_, IlpErrorCode::T04_INSUFFICIENT_LIQUIDITY) if amount <= 1 => {
+                // The congestion controller cannot make the packets any smaller than this
+                self.error = Some(Error::InsufficientLiquidity(format!(
+                    "Packet of {} was rejected: {}",
+                    amount,
+                    str::from_utf8(reject.message()).unwrap_or_default(),
+                )));
+            }
             (ErrorClass::Temporary, _) => {}
             (_, IlpErrorCode::F08_AMOUNT_TOO_LARGE) => {
                 // Handled by the congestion controller
             }
             (_, IlpErrorCode::F99_APPLICATION_ERROR) => {
-                // TODO handle STREAM errors
+                // The receiver includes its STREAM frames in the data of the rejects it sends
+                match StreamPacket::from_encrypted(&self.shared_secret, reject.into_data()) {
+                    Ok(ref packet)
+                        if packet.ilp_packet_type() == IlpPacketType::Reject
+                            && packet.sequence() == sequence =>
+                    {
+                        self.should_send_source_account = false;
+                        self.handle_frames(packet);
+                    }
+                    _ => warn!(
+                        "Unable to parse STREAM packet from reject data for sequence {}",
+                        sequence
+                    ),
+                }
             }
-            _ => {
-                self.error = Some(Error::SendMoneyError(format!(
+            (_, code) => {
+                let message = format!(
                     "Packet was rejected with error: {} {}",
-                    reject.code(),
+                    code,
                     str::from_utf8(reject.message()).unwrap_or_default(),
-                )));
+                );
+                self.error = Some(match code {
+                    IlpErrorCode::F02_UNREACHABLE => Error::ReceiverUnreachable(message),
+                    IlpErrorCode::F04_INSUFFICIENT_DESTINATION_AMOUNT => {
+                        Error::ExchangeRateTooLow(message)
+                    }
+                    IlpErrorCode::R00_TRANSFER_TIMED_OUT
+                    | IlpErrorCode::R02_INSUFFICIENT_TIMEOUT => Error::Timeout(message),
+                    _ => Error::SendMoneyError(message),
+                });
+            }
+        }
+    }
+
+    fn handle_frames(&mut self, packet: &StreamPacket) {
+        for frame in packet.frames() {
+            match frame {
+                Frame::ConnectionClose(frame) => {
+                    self.handle_remote_close(frame.code, frame.message)
+                }
+                Frame::ConnectionAssetDetails(frame) => {
+                    if self.destination_asset.is_none() {
+                        debug!(
+                            "Receiver's asset is {} with scale {}",
+                            frame.source_asset_code, frame.source_asset_scale
+                        );
+                        self.destination_asset = Some(AssetDetails {
+                            asset_code: frame.source_asset_code.to_string(),
+                            asset_scale: frame.source_asset_scale,
+                        });
+                    }
+                }
+                // We only ever send money on stream 1
+                Frame::StreamClose(ref frame) if frame.stream_id == 1 => {
+                    self.handle_remote_close(frame.code.clone(), frame.message)
+                }
+                Frame::StreamMaxMoney(ref frame) if frame.stream_id == 1 => {
+                    self.receive_max = Some(frame.receive_max);
+                    self.remote_total_received =
+                        max(self.remote_total_received, frame.total_received);
+                }
+                _ => {}
             }
         }
     }
 
+    fn handle_remote_close(&mut self, code: ErrorCode, message: &str) {
+        debug!(
+            "Receiver closed the connection with code {:?}: {}",
+            code, message
+        );
+        if self.remote_close.is_none() {
+            self.remote_close = Some((code, message.to_string()));
+        }
+        self.state = SendMoneyFutureState::RemoteClosed;
+    }
+
+    fn report_progress(&mut self) {
+        let in_flight_amount = self
+            .pending_requests
+            .get_mut()
+            .iter()
+            .map(|request| request.amount)
+            .sum();
+        let exchange_rate = if self.delivered_amount > 0 {
+            Some(self.delivered_amount as f64 / self.sent_amount as f64)
+        } else {
+            None
+        };
+        // It's fine if no one is listening for the progress
+        let _ = self.progress.unbounded_send(PaymentProgress {
+            sent_amount: self.sent_amount,
+            delivered_amount: self.delivered_amount,
+            in_flight_amount,
+            rejected_packets: self.rejected_packets,
+            exchange_rate,
+        });
+    }
+
+    fn delivery(&self) -> StreamDelivery {
+        StreamDelivery {
+            source_asset: self.source_asset.clone(),
+            sent_amount: self.sent_amount,
+            destination_asset: self.destination_asset.clone(),
+            delivered_amount: self.delivered_amount,
+        }
+    }
+
+    /// Errors that happen after some money was delivered include the amounts so the caller knows
+    /// the payment was not all-or-nothing
+    fn partial_delivery(&self, error: Error) -> Error {
+        if self.sent_amount > 0 {
+            Error::PartialDelivery {
+                sent_amount: self.sent_amount,
+                delivered_amount: self.delivered_amount,
+                error: Box::new(error),
+            }
+        } else {
+            error
+        }
+    }
+
     fn next_sequence(&mut self) -> u64 {
         let seq = self.sequence;
         self.sequence += 1;
@@ -291,21 +657,62 @@ where
     }
 }
 
-impl<S, A> Future for SendMoneyFuture<S, A>
+impl<S, A, C> Future for SendMoneyFuture<S, A, C>
 where
     S: IncomingService<A>,
     A: Account,
+    C: CongestionControl,
 {
-    type Item = (u64, S);
+    type Item = (StreamDelivery, S);
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Make sure we are woken up if the payment is cancelled
+        self.canceller.task.register();
+
+        self.poll_send_money()
+            .map_err(|error| self.partial_delivery(error))
+    }
+}
+
+impl<S, A, C> SendMoneyFuture<S, A, C>
+where
+    S: IncomingService<A>,
+    A: Account,
+    C: CongestionControl,
+{
+    fn poll_send_money(&mut self) -> Poll<(StreamDelivery, S), Error> {
         // TODO maybe don't have loops here and in try_send_money
         loop {
             self.poll_pending_requests()?;
+            let cancelled = self.canceller.is_cancelled();
 
-            if self.source_amount == 0 && self.pending_requests.get_mut().is_empty() {
+            if self.state == SendMoneyFutureState::RemoteClosed {
+                // The receiver will not accept any more packets so there is no point sending a close
+                if !self.pending_requests.get_mut().is_empty() {
+                    return Ok(Async::NotReady);
+                }
+                if let Some((code, message)) = self.remote_close.take() {
+                    if code != ErrorCode::NoError || self.source_amount > 0 {
+                        return Err(Error::RemoteClosed { code, message });
+                    }
+                }
+                self.state = SendMoneyFutureState::Closed;
+                debug!(
+                    "Send money future finished after the receiver closed the connection. Delivered: {}",
+                    self.delivered_amount
+                );
+                return Ok(Async::Ready((self.delivery(), self.next.take().unwrap())));
+            } else if (self.source_amount == 0 || cancelled)
+                && self.pending_requests.get_mut().is_empty()
+            {
                 if self.state == SendMoneyFutureState::SendMoney {
+                    if cancelled {
+                        debug!(
+                            "Payment cancelled with {} left to send, closing connection",
+                            self.source_amount
+                        );
+                    }
                     self.state = SendMoneyFutureState::Closing;
                     self.try_send_connection_close()?;
                 } else {
@@ -313,12 +720,14 @@ where
                     debug!(
                         "Send money future finished. Delivered: {} ({} packets fulfilled, {} packets rejected)", self.delivered_amount, self.sequence - 1, self.rejected_packets,
                     );
-                    return Ok(Async::Ready((
-                        self.delivered_amount,
-                        self.next.take().unwrap(),
-                    )));
+                    return Ok(Async::Ready((self.delivery(), self.next.take().unwrap())));
                 }
-            } else if !self.try_send_money()? {
+            } else if cancelled {
+                // Wait for the packets in flight before closing the connection
+                return Ok(Async::NotReady);
+            } else if self.try_send_money()? {
+                self.report_progress();
+            } else {
                 return Ok(Async::NotReady);
             }
         }
@@ -328,9 +737,9 @@ where
 #[cfg(test)]
 mod send_money_tests {
     use super::*;
-    use crate::test_helpers::{TestAccount, EXAMPLE_CONNECTOR};
+    use crate::test_helpers::{TestAccount, EXAMPLE_CONNECTOR, EXAMPLE_RECEIVER};
     use interledger_ildcp::IldcpService;
-    use interledger_packet::{ErrorCode as IlpErrorCode, RejectBuilder};
+    use interledger_packet::{ErrorCode as IlpErrorCode, FulfillBuilder, RejectBuilder};
     use interledger_service::incoming_service_fn;
     use parking_lot::Mutex;
     use std::str::FromStr;
@@ -366,4 +775,374 @@ mod send_money_tests {
         assert!(result.is_err());
         assert_eq!(requests.lock().len(), 1);
     }
+
+    #[test]
+    fn stops_when_receiver_closes_connection() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+        };
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let result = send_money(
+            IldcpService::new(incoming_service_fn(move |request| {
+                let sequence = StreamPacket::from_encrypted(&[0; 32], request.prepare.into_data())
+                    .unwrap()
+                    .sequence();
+                requests_clone.lock().push(sequence);
+                let data = StreamPacketBuilder {
+                    ilp_packet_type: IlpPacketType::Reject,
+                    prepare_amount: 0,
+                    sequence,
+                    frames: &[Frame::ConnectionClose(ConnectionCloseFrame {
+                        code: ErrorCode::ApplicationError,
+                        message: "go away",
+                    })],
+                }
+                .build()
+                .into_encrypted(&[0; 32]);
+                Err(RejectBuilder {
+                    code: IlpErrorCode::F99_APPLICATION_ERROR,
+                    message: &[],
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &data[..],
+                }
+                .build())
+            })),
+            &account,
+            Address::from_str("example.destination").unwrap(),
+            &[0; 32][..],
+            100,
+        )
+        .wait();
+        match result {
+            Err(Error::RemoteClosed { code, message }) => {
+                assert_eq!(code, ErrorCode::ApplicationError);
+                assert_eq!(message, "go away");
+            }
+            _ => panic!("Expected the receiver's error"),
+        }
+        assert_eq!(requests.lock().len(), 1);
+    }
+
+    #[test]
+    fn does_not_send_more_than_receive_max() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+        };
+        let total_received = Arc::new(Mutex::new(0));
+        let total_received_clone = total_received.clone();
+        let result = send_money(
+            IldcpService::new(incoming_service_fn(move |request| {
+                // Each unit the sender sends is worth half a unit to the receiver
+                let received = request.prepare.amount() / 2;
+                let sequence = StreamPacket::from_encrypted(&[0; 32], request.prepare.into_data())
+                    .unwrap()
+                    .sequence();
+                let mut total_received = total_received_clone.lock();
+                *total_received += received;
+                let data = StreamPacketBuilder {
+                    ilp_packet_type: IlpPacketType::Fulfill,
+                    prepare_amount: received,
+                    sequence,
+                    frames: &[Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                        stream_id: 1,
+                        receive_max: 1500,
+                        total_received: *total_received,
+                    })],
+                }
+                .build()
+                .into_encrypted(&[0; 32]);
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &data[..],
+                }
+                .build())
+            })),
+            &account,
+            Address::from_str("example.destination").unwrap(),
+            &[0; 32][..],
+            4000,
+        )
+        .wait();
+        assert!(result.is_err());
+        assert_eq!(*total_received.lock(), 1500);
+    }
+
+    /// Lets any number of packets of up to 1000 units be in flight
+    struct FixedPacketAmount;
+
+    impl CongestionControl for FixedPacketAmount {
+        fn get_max_amount(&mut self) -> u64 {
+            1000
+        }
+
+        fn prepare(&mut self, _amount: u64) {}
+
+        fn fulfill(&mut self, _prepare_amount: u64) {}
+
+        fn reject(&mut self, _prepare_amount: u64, _reject: &Reject) {}
+    }
+
+    #[test]
+    fn sends_one_packet_until_receive_max_is_known() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+        };
+        let total_received = Arc::new(Mutex::new(0));
+        let total_received_clone = total_received.clone();
+        let (_handle, payment) = send_money_with_congestion_control(
+            IldcpService::new(incoming_service_fn(move |request| {
+                let received = request.prepare.amount();
+                let sequence = StreamPacket::from_encrypted(&[0; 32], request.prepare.into_data())
+                    .unwrap()
+                    .sequence();
+                let mut total_received = total_received_clone.lock();
+                *total_received += received;
+                let data = StreamPacketBuilder {
+                    ilp_packet_type: IlpPacketType::Fulfill,
+                    prepare_amount: received,
+                    sequence,
+                    frames: &[Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                        stream_id: 1,
+                        receive_max: 1500,
+                        total_received: *total_received,
+                    })],
+                }
+                .build()
+                .into_encrypted(&[0; 32]);
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &data[..],
+                }
+                .build())
+            })),
+            &account,
+            Address::from_str("example.destination").unwrap(),
+            &[0; 32][..],
+            4000,
+            FixedPacketAmount,
+        );
+        assert!(payment.wait().is_err());
+        // Sending all of the packets at once would have delivered the whole 4000
+        assert_eq!(*total_received.lock(), 1500);
+    }
+
+    fn fulfill_everything(request: IncomingRequest<TestAccount>) -> Result<Fulfill, Reject> {
+        let amount = request.prepare.amount();
+        let sequence = StreamPacket::from_encrypted(&[0; 32], request.prepare.into_data())
+            .unwrap()
+            .sequence();
+        let data = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Fulfill,
+            prepare_amount: amount,
+            sequence,
+            frames: &[],
+        }
+        .build()
+        .into_encrypted(&[0; 32]);
+        Ok(FulfillBuilder {
+            fulfillment: &[0; 32],
+            data: &data[..],
+        }
+        .build())
+    }
+
+    #[test]
+    fn reports_progress() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+        };
+        let (handle, payment) = send_money_with_progress(
+            IldcpService::new(incoming_service_fn(fulfill_everything)),
+            &account,
+            Address::from_str("example.destination").unwrap(),
+            &[0; 32][..],
+            3000,
+        );
+        let (delivery, _service) = payment.wait().unwrap();
+        assert_eq!(delivery.sent_amount, 3000);
+
+        let progress = handle.collect().wait().unwrap();
+        assert!(progress.len() > 1);
+        assert!(progress
+            .windows(2)
+            .all(|pair| pair[0].sent_amount <= pair[1].sent_amount));
+        assert_eq!(
+            progress.last(),
+            Some(&PaymentProgress {
+                sent_amount: 3000,
+                delivered_amount: 3000,
+                in_flight_amount: 0,
+                rejected_packets: 0,
+                exchange_rate: Some(1.0),
+            })
+        );
+    }
+
+    #[test]
+    fn cancels_and_closes_connection() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+        };
+        let canceller: Arc<Mutex<Option<PaymentCanceller>>> = Arc::new(Mutex::new(None));
+        let canceller_clone = canceller.clone();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let (handle, payment) = send_money_with_progress(
+            IldcpService::new(incoming_service_fn(move |request| {
+                // Stop the payment as soon as the first packet arrives
+                if let Some(ref canceller) = *canceller_clone.lock() {
+                    canceller.cancel();
+                }
+                requests_clone.lock().push(request.prepare.clone());
+                fulfill_everything(request)
+            })),
+            &account,
+            Address::from_str("example.destination").unwrap(),
+            &[0; 32][..],
+            5000,
+        );
+        *canceller.lock() = Some(handle.canceller());
+
+        let (delivery, _service) = payment.wait().unwrap();
+        assert!(delivery.sent_amount > 0);
+        assert!(delivery.sent_amount < 5000);
+        assert_eq!(delivery.delivered_amount, delivery.sent_amount);
+
+        let requests = requests.lock();
+        let last =
+            StreamPacket::from_encrypted(&[0; 32], requests.last().unwrap().data().into()).unwrap();
+        assert!(last.frames().any(|frame| match frame {
+            Frame::ConnectionClose(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn uses_the_given_congestion_controller() {
+        use crate::congestion::ScalingCongestionController;
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+        };
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let (_handle, payment) = send_money_with_congestion_control(
+            IldcpService::new(incoming_service_fn(move |request| {
+                requests_clone.lock().push(request.prepare.amount());
+                fulfill_everything(request)
+            })),
+            &account,
+            Address::from_str("example.destination").unwrap(),
+            &[0; 32][..],
+            30_000_000,
+            ScalingCongestionController::new(9),
+        );
+        let (delivery, _service) = payment.wait().unwrap();
+        assert_eq!(delivery.sent_amount, 30_000_000);
+        // It starts with a hundredth of a unit and doubles from there
+        assert_eq!(requests.lock()[..2], [10_000_000, 20_000_000]);
+    }
+
+    #[test]
+    fn includes_amounts_in_errors_after_partial_delivery() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+        };
+        let requests = Arc::new(Mutex::new(0));
+        let requests_clone = requests.clone();
+        let (_handle, payment) = send_money_with_congestion_control(
+            IldcpService::new(incoming_service_fn(move |request| {
+                let mut requests = requests_clone.lock();
+                *requests += 1;
+                if *requests == 1 {
+                    fulfill_everything(request)
+                } else {
+                    Err(RejectBuilder {
+                        code: IlpErrorCode::F04_INSUFFICIENT_DESTINATION_AMOUNT,
+                        message: b"rate dropped",
+                        triggered_by: Some(&EXAMPLE_CONNECTOR),
+                        data: &[],
+                    }
+                    .build())
+                }
+            })),
+            &account,
+            Address::from_str("example.destination").unwrap(),
+            &[0; 32][..],
+            100,
+            CongestionController::new(10, 10, 2.0),
+        );
+        match payment.wait() {
+            Err(Error::PartialDelivery {
+                sent_amount,
+                delivered_amount,
+                error,
+            }) => {
+                assert_eq!(sent_amount, 10);
+                assert_eq!(delivered_amount, 10);
+                match *error {
+                    Error::ExchangeRateTooLow(_) => {}
+                    other => panic!("Expected exchange rate error, got: {:?}", other),
+                }
+            }
+            Err(other) => panic!("Expected partial delivery error, got: {:?}", other),
+            Ok(_) => panic!("Expected partial delivery error"),
+        }
+    }
+
+    #[test]
+    fn stops_when_liquidity_runs_out() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+        };
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let (_handle, payment) = send_money_with_congestion_control(
+            IldcpService::new(incoming_service_fn(move |request| {
+                requests_clone.lock().push(request.prepare.amount());
+                Err(RejectBuilder {
+                    code: IlpErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+                    message: &[],
+                    triggered_by: Some(&EXAMPLE_CONNECTOR),
+                    data: &[],
+                }
+                .build())
+            })),
+            &account,
+            Address::from_str("example.destination").unwrap(),
+            &[0; 32][..],
+            100,
+            CongestionController::new(4, 4, 2.0),
+        );
+        match payment.wait() {
+            Err(Error::InsufficientLiquidity(_)) => {}
+            Err(other) => panic!("Expected insufficient liquidity error, got: {:?}", other),
+            Ok(_) => panic!("Expected insufficient liquidity error"),
+        }
+        assert_eq!(*requests.lock(), vec![4, 2, 1]);
+    }
 }
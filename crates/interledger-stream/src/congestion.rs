@@ -10,11 +10,25 @@ use std::cmp::{max, min};
 #[cfg(feature = "metrics_csv")]
 use std::io;
 
+/// Decides how much money a STREAM sender can have in flight and how large each packet can be.
+///
+/// The sender calls `prepare` for each packet it sends and `fulfill` or `reject`
+/// once the packet's outcome is known.
+pub trait CongestionControl {
+    /// The largest amount the next packet can have right now
+    fn get_max_amount(&mut self) -> u64;
+
+    fn prepare(&mut self, amount: u64);
+
+    fn fulfill(&mut self, prepare_amount: u64);
+
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject);
+}
+
 /// A basic congestion controller that implements an
 /// Additive Increase, Multiplicative Decrease (AIMD) algorithm.
 ///
-/// Future implementations of this will use more advanced congestion
-/// control algorithms.
+/// See `ScalingCongestionController` for one that adapts to the asset and the path.
 pub struct CongestionController {
     state: CongestionState,
     increase_amount: u64,
@@ -34,13 +48,6 @@ enum CongestionState {
 
 impl CongestionController {
     pub fn new(start_amount: u64, increase_amount: u64, decrease_factor: f64) -> Self {
-        #[cfg(feature = "metrics_csv")]
-        let mut csv_writer = csv::Writer::from_writer(io::stdout());
-        #[cfg(feature = "metrics_csv")]
-        csv_writer
-            .write_record(&["time", "max_amount_in_flight", "amount_fulfilled"])
-            .unwrap();
-
         CongestionController {
             state: CongestionState::SlowStart,
             increase_amount,
@@ -49,17 +56,24 @@ impl CongestionController {
             amount_in_flight: 0,
             max_in_flight: start_amount,
             #[cfg(feature = "metrics_csv")]
-            csv_writer,
+            csv_writer: new_csv_writer(),
         }
     }
 
     pub fn default() -> Self {
-        // TODO an increase amount of 1000 might be too small if the units are worth very little
-        // should it be adjusted based on something like the max packet amount?
+        // Note an increase amount of 1000 might be too small if the units are worth very little.
+        // The ScalingCongestionController adjusts its amounts based on the asset scale instead
         Self::new(1000, 1000, 2.0)
     }
 
-    pub fn get_max_amount(&mut self) -> u64 {
+    #[cfg(test)]
+    fn set_max_packet_amount(&mut self, max_packet_amount: u64) {
+        self.max_packet_amount = Some(max_packet_amount)
+    }
+}
+
+impl CongestionControl for CongestionController {
+    fn get_max_amount(&mut self) -> u64 {
         let amount_left_in_window = self.max_in_flight - self.amount_in_flight;
         if let Some(max_packet_amount) = self.max_packet_amount {
             min(amount_left_in_window, max_packet_amount)
@@ -68,7 +82,7 @@ impl CongestionController {
         }
     }
 
-    pub fn prepare(&mut self, amount: u64) {
+    fn prepare(&mut self, amount: u64) {
         if amount > 0 {
             self.amount_in_flight += amount;
             debug!(
@@ -78,7 +92,7 @@ impl CongestionController {
         }
     }
 
-    pub fn fulfill(&mut self, prepare_amount: u64) {
+    fn fulfill(&mut self, prepare_amount: u64) {
         self.amount_in_flight -= prepare_amount;
 
         // Before we know how much we should be sending at a time,
@@ -110,10 +124,10 @@ impl CongestionController {
         }
 
         #[cfg(feature = "metrics_csv")]
-        self.log_stats(prepare_amount);
+        log_stats(&mut self.csv_writer, self.max_in_flight, prepare_amount);
     }
 
-    pub fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
         self.amount_in_flight -= prepare_amount;
 
         match reject.code() {
@@ -126,7 +140,7 @@ impl CongestionController {
                 debug!("Rejected packet with T04 error. Amount in flight was: {}, decreasing max in flight to: {}", self.amount_in_flight + prepare_amount, self.max_in_flight);
 
                 #[cfg(feature = "metrics_csv")]
-                self.log_stats(0);
+                log_stats(&mut self.csv_writer, self.max_in_flight, 0);
             }
             ErrorCode::F08_AMOUNT_TOO_LARGE => {
                 if let Ok(details) = MaxPacketAmountDetails::from_bytes(reject.data()) {
@@ -148,23 +162,192 @@ impl CongestionController {
             }
         }
     }
+}
 
-    #[cfg(test)]
-    fn set_max_packet_amount(&mut self, max_packet_amount: u64) {
-        self.max_packet_amount = Some(max_packet_amount)
+/// A congestion controller whose amounts scale with the value of the sender's asset
+/// and that learns the largest packet the path supports.
+///
+/// It starts with, and additively increases by, a hundredth of a whole unit of the asset,
+/// so it ramps up equally fast whether the asset's units are worth a lot or very little.
+///
+/// The max packet amount is taken from the details of F08 errors when connectors include them.
+/// Otherwise, it is found by binary search between the largest packet that was fulfilled and
+/// the smallest one rejected with a T04 or F08 error.
+pub struct ScalingCongestionController {
+    state: CongestionState,
+    increase_amount: u64,
+    decrease_factor: f64,
+    /// The max packet amount reported in F08 errors
+    max_packet_amount: Option<u64>,
+    largest_fulfilled: u64,
+    /// The smallest packet larger than `largest_fulfilled` that was rejected for its size
+    smallest_rejected: Option<u64>,
+    amount_in_flight: u64,
+    max_in_flight: u64,
+    #[cfg(feature = "metrics_csv")]
+    csv_writer: csv::Writer<io::Stdout>,
+}
+
+impl ScalingCongestionController {
+    pub fn new(asset_scale: u8) -> Self {
+        let unit = 10u64
+            .checked_pow(u32::from(asset_scale))
+            .unwrap_or_else(u64::max_value);
+        let amount = max(unit / 100, 1);
+        ScalingCongestionController {
+            state: CongestionState::SlowStart,
+            increase_amount: amount,
+            decrease_factor: 2.0,
+            max_packet_amount: None,
+            largest_fulfilled: 0,
+            smallest_rejected: None,
+            amount_in_flight: 0,
+            max_in_flight: amount,
+            #[cfg(feature = "metrics_csv")]
+            csv_writer: new_csv_writer(),
+        }
     }
 
-    #[cfg(feature = "metrics_csv")]
-    fn log_stats(&mut self, amount_sent: u64) {
-        self.csv_writer
-            .write_record(&[
-                format!("{}", Utc::now().timestamp_millis()),
-                format!("{}", self.max_in_flight),
-                format!("{}", amount_sent),
-            ])
-            .unwrap();
-        self.csv_writer.flush().unwrap();
+    fn max_packet_amount(&self) -> Option<u64> {
+        // Try halfway between the packets that worked and the ones that didn't
+        let search_amount = self.smallest_rejected.map(|rejected| {
+            max(
+                self.largest_fulfilled + (rejected - self.largest_fulfilled) / 2,
+                1,
+            )
+        });
+        match (self.max_packet_amount, search_amount) {
+            (Some(max_packet_amount), Some(search_amount)) => {
+                Some(min(max_packet_amount, search_amount))
+            }
+            (max_packet_amount, None) => max_packet_amount,
+            (None, search_amount) => search_amount,
+        }
+    }
+
+    fn packet_too_large(&mut self, amount: u64) {
+        // A packet no larger than one that was fulfilled was probably
+        // rejected because of liquidity rather than its size
+        if amount > self.largest_fulfilled {
+            self.smallest_rejected = Some(
+                self.smallest_rejected
+                    .map_or(amount, |rejected| min(rejected, amount)),
+            );
+            debug!(
+                "Searching for max packet amount, now trying: {:?}",
+                self.max_packet_amount()
+            );
+        }
+    }
+}
+
+impl CongestionControl for ScalingCongestionController {
+    fn get_max_amount(&mut self) -> u64 {
+        let amount_left_in_window = self.max_in_flight.saturating_sub(self.amount_in_flight);
+        if let Some(max_packet_amount) = self.max_packet_amount() {
+            min(amount_left_in_window, max_packet_amount)
+        } else {
+            amount_left_in_window
+        }
+    }
+
+    fn prepare(&mut self, amount: u64) {
+        self.amount_in_flight += amount;
+    }
+
+    fn fulfill(&mut self, prepare_amount: u64) {
+        self.amount_in_flight -= prepare_amount;
+        self.largest_fulfilled = max(self.largest_fulfilled, prepare_amount);
+        if let Some(rejected) = self.smallest_rejected {
+            if prepare_amount >= rejected {
+                // The path must have changed
+                self.smallest_rejected = None;
+            }
+        }
+
+        if self.state == CongestionState::SlowStart {
+            self.max_in_flight = self.max_in_flight.saturating_mul(2);
+        } else {
+            self.max_in_flight = self.max_in_flight.saturating_add(self.increase_amount);
+        }
+        debug!(
+            "Fulfilled packet of {}, max in flight is now: {}",
+            prepare_amount, self.max_in_flight
+        );
+
+        #[cfg(feature = "metrics_csv")]
+        log_stats(&mut self.csv_writer, self.max_in_flight, prepare_amount);
     }
+
+    fn reject(&mut self, prepare_amount: u64, reject: &Reject) {
+        self.amount_in_flight -= prepare_amount;
+
+        match reject.code() {
+            ErrorCode::T04_INSUFFICIENT_LIQUIDITY => {
+                self.state = CongestionState::AvoidCongestion;
+                self.max_in_flight = max(
+                    (self.max_in_flight as f64 / self.decrease_factor).floor() as u64,
+                    1,
+                );
+                debug!(
+                    "Rejected packet with T04 error, decreasing max in flight to: {}",
+                    self.max_in_flight
+                );
+                self.packet_too_large(prepare_amount);
+
+                #[cfg(feature = "metrics_csv")]
+                log_stats(&mut self.csv_writer, self.max_in_flight, 0);
+            }
+            ErrorCode::F08_AMOUNT_TOO_LARGE => {
+                match MaxPacketAmountDetails::from_bytes(reject.data()) {
+                    Ok(ref details) if details.amount_received() > 0 => {
+                        // The details are in the units of the connector that rejected the packet
+                        let new_max_packet_amount = u128::from(prepare_amount)
+                            * u128::from(details.max_amount())
+                            / u128::from(details.amount_received());
+                        let new_max_packet_amount = max(
+                            min(new_max_packet_amount, u128::from(u64::max_value())) as u64,
+                            1,
+                        );
+                        self.max_packet_amount = Some(
+                            self.max_packet_amount
+                                .map_or(new_max_packet_amount, |max_packet_amount| {
+                                    min(max_packet_amount, new_max_packet_amount)
+                                }),
+                        );
+                    }
+                    _ => {
+                        warn!("Got F08: Amount Too Large Error without max packet amount details attached");
+                        self.packet_too_large(prepare_amount);
+                    }
+                }
+            }
+            _ => {
+                // No special treatment for other errors
+            }
+        }
+    }
+}
+
+#[cfg(feature = "metrics_csv")]
+fn new_csv_writer() -> csv::Writer<io::Stdout> {
+    let mut csv_writer = csv::Writer::from_writer(io::stdout());
+    csv_writer
+        .write_record(&["time", "max_amount_in_flight", "amount_fulfilled"])
+        .unwrap();
+    csv_writer
+}
+
+#[cfg(feature = "metrics_csv")]
+fn log_stats(csv_writer: &mut csv::Writer<io::Stdout>, max_in_flight: u64, amount_sent: u64) {
+    csv_writer
+        .write_record(&[
+            format!("{}", Utc::now().timestamp_millis()),
+            format!("{}", max_in_flight),
+            format!("{}", amount_sent),
+        ])
+        .unwrap();
+    csv_writer.flush().unwrap();
 }
 
 #[cfg(test)]
@@ -348,4 +531,85 @@ mod tests {
             assert_eq!(controller.get_max_amount(), 1000 - 600 - 100);
         }
     }
+
+    mod scaling {
+        use super::*;
+        use interledger_packet::RejectBuilder;
+
+        fn reject(code: ErrorCode, data: &[u8]) -> Reject {
+            RejectBuilder {
+                code,
+                message: &[],
+                triggered_by: None,
+                data,
+            }
+            .build()
+        }
+
+        #[test]
+        fn starts_with_a_hundredth_of_a_unit() {
+            assert_eq!(
+                ScalingCongestionController::new(9).get_max_amount(),
+                10_000_000
+            );
+            assert_eq!(ScalingCongestionController::new(2).get_max_amount(), 1);
+            assert_eq!(ScalingCongestionController::new(0).get_max_amount(), 1);
+        }
+
+        #[test]
+        fn increases_by_a_hundredth_of_a_unit() {
+            let mut controller = ScalingCongestionController::new(6);
+            controller.state = CongestionState::AvoidCongestion;
+            controller.prepare(10_000);
+            controller.fulfill(10_000);
+            assert_eq!(controller.get_max_amount(), 20_000);
+        }
+
+        #[test]
+        fn uses_max_packet_amount_from_f08_details() {
+            let mut controller = ScalingCongestionController::new(6);
+            controller.max_in_flight = 100_000;
+            controller.prepare(10_000);
+            controller.reject(
+                10_000,
+                &reject(
+                    ErrorCode::F08_AMOUNT_TOO_LARGE,
+                    &MaxPacketAmountDetails::new(200, 100).to_bytes(),
+                ),
+            );
+            assert_eq!(controller.get_max_amount(), 5000);
+        }
+
+        #[test]
+        fn binary_searches_max_packet_amount_on_t04() {
+            let mut controller = ScalingCongestionController::new(6);
+            controller.prepare(4000);
+            controller.fulfill(4000);
+            controller.prepare(8000);
+            controller.reject(8000, &reject(ErrorCode::T04_INSUFFICIENT_LIQUIDITY, &[]));
+            assert_eq!(controller.max_packet_amount(), Some(6000));
+
+            controller.prepare(6000);
+            controller.fulfill(6000);
+            assert_eq!(controller.max_packet_amount(), Some(7000));
+
+            controller.prepare(7000);
+            controller.reject(7000, &reject(ErrorCode::T04_INSUFFICIENT_LIQUIDITY, &[]));
+            assert_eq!(controller.max_packet_amount(), Some(6500));
+
+            // Smaller packets being rejected doesn't say anything about the max packet amount
+            controller.prepare(1000);
+            controller.reject(1000, &reject(ErrorCode::T04_INSUFFICIENT_LIQUIDITY, &[]));
+            assert_eq!(controller.max_packet_amount(), Some(6500));
+        }
+
+        #[test]
+        fn binary_searches_on_f08_without_details() {
+            let mut controller = ScalingCongestionController::new(6);
+            controller.max_in_flight = 100_000;
+            controller.prepare(10_000);
+            controller.reject(10_000, &reject(ErrorCode::F08_AMOUNT_TOO_LARGE, &[]));
+            assert_eq!(controller.get_max_amount(), 5000);
+        }
+    }
 }
@@ -0,0 +1,960 @@
+use super::client::AssetDetails;
+use super::congestion::{CongestionControl, CongestionController};
+use super::crypto::*;
+use super::data_money_stream::{DataMoneyStream, StreamState};
+use super::error::Error;
+use super::packet::*;
+use bytes::{Bytes, BytesMut};
+use futures::{
+    task::{self, Task},
+    Async, Future, Poll, Stream,
+};
+use interledger_ildcp::get_ildcp_info;
+use interledger_packet::{
+    Address, ErrorClass, ErrorCode as IlpErrorCode, Fulfill, PacketType as IlpPacketType,
+    PrepareBuilder, Reject,
+};
+use interledger_service::*;
+use log::{debug, error, warn};
+use parking_lot::Mutex;
+use std::{
+    cmp::{max, min},
+    collections::{HashMap, VecDeque},
+    str,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio_executor::spawn;
+use tokio_timer::Delay;
+
+/// The maximum number of bytes of stream data put in a single packet
+const MAX_DATA_PER_PACKET: usize = 16384;
+const MAX_PACKETS_IN_FLIGHT: usize = 10;
+/// How often the sender asks the receiver for data while a reader is waiting.
+/// The receiver can only send data back in its responses to our packets.
+const DATA_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A STREAM connection, which carries any number of streams of money and data.
+///
+/// The Connection is also a Stream of the `DataMoneyStream`s opened by the other side.
+#[derive(Clone)]
+pub struct Connection {
+    pub(crate) state: Arc<Mutex<ConnectionState>>,
+}
+
+impl Connection {
+    pub(crate) fn new(state: ConnectionState) -> Self {
+        Connection {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Open a new stream on this connection
+    pub fn new_stream(&self) -> DataMoneyStream {
+        let id = self.state.lock().open_stream();
+        DataMoneyStream {
+            id,
+            connection: self.state.clone(),
+        }
+    }
+
+    /// Close the connection once all of the streams have finished sending
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closing = true;
+        state.notify_driver();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().is_closed()
+    }
+
+    /// The other side's asset code and scale, once it has sent them
+    pub fn remote_asset_details(&self) -> Option<AssetDetails> {
+        self.state.lock().remote_asset_details.clone()
+    }
+}
+
+impl Stream for Connection {
+    type Item = DataMoneyStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut state = self.state.lock();
+        if let Some(id) = state.new_streams.pop_front() {
+            Ok(Async::Ready(Some(DataMoneyStream {
+                id,
+                connection: self.state.clone(),
+            })))
+        } else if state.closed {
+            match state.close_error {
+                Some((ref code, ref message)) if *code != ErrorCode::NoError => Err(
+                    Error::ConnectionError(format!("Closed with {:?}: {}", code, message)),
+                ),
+                _ => Ok(Async::Ready(None)),
+            }
+        } else {
+            state.accept_task = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// The frames to be sent in a single STREAM packet, before they are serialized
+#[derive(Default)]
+pub(crate) struct OutgoingFrames {
+    pub(crate) connection_close: Option<(ErrorCode, String)>,
+    pub(crate) new_address: Option<Address>,
+    pub(crate) asset_details: Option<AssetDetails>,
+    pub(crate) money: Option<StreamMoneyFrame>,
+    pub(crate) max_money: Vec<StreamMaxMoneyFrame>,
+    pub(crate) max_data: Vec<StreamMaxDataFrame>,
+    pub(crate) data_blocked: Vec<StreamDataBlockedFrame>,
+    pub(crate) data: Vec<(u64, u64, Bytes)>,
+    pub(crate) stream_close: Vec<u64>,
+}
+
+impl OutgoingFrames {
+    pub(crate) fn frames(&self) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        if let Some((ref code, ref message)) = self.connection_close {
+            frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+                code: code.clone(),
+                message: message.as_str(),
+            }));
+        }
+        if let Some(ref source_account) = self.new_address {
+            frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                source_account: source_account.clone(),
+            }));
+        }
+        if let Some(ref asset_details) = self.asset_details {
+            frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                source_asset_code: &asset_details.asset_code,
+                source_asset_scale: asset_details.asset_scale,
+            }));
+        }
+        if let Some(ref frame) = self.money {
+            frames.push(Frame::StreamMoney(frame.clone()));
+        }
+        frames.extend(self.max_money.iter().cloned().map(Frame::StreamMaxMoney));
+        frames.extend(self.max_data.iter().cloned().map(Frame::StreamMaxData));
+        frames.extend(
+            self.data_blocked
+                .iter()
+                .cloned()
+                .map(Frame::StreamDataBlocked),
+        );
+        for (stream_id, offset, data) in &self.data {
+            frames.push(Frame::StreamData(StreamDataFrame {
+                stream_id: *stream_id,
+                offset: *offset,
+                data: &data[..],
+            }));
+        }
+        for stream_id in &self.stream_close {
+            frames.push(Frame::StreamClose(StreamCloseFrame {
+                stream_id: *stream_id,
+                code: ErrorCode::NoError,
+                message: "",
+            }));
+        }
+        frames
+    }
+}
+
+pub(crate) struct OutgoingPacket {
+    sequence: u64,
+    amount: u64,
+    frames: OutgoingFrames,
+}
+
+/// The state of a connection, shared between the `Connection`, its `DataMoneyStream`s,
+/// and either the task sending packets (on the client side) or the `StreamReceiverService`.
+pub(crate) struct ConnectionState {
+    pub(crate) streams: HashMap<u64, StreamState>,
+    is_server: bool,
+    next_stream_id: u64,
+    new_streams: VecDeque<u64>,
+    accept_task: Option<Task>,
+    driver_task: Option<Task>,
+    remote_max_data: u64,
+    closing: bool,
+    close_sent: bool,
+    closed: bool,
+    close_error: Option<(ErrorCode, String)>,
+    remote_asset_details: Option<AssetDetails>,
+}
+
+impl ConnectionState {
+    pub(crate) fn new(is_server: bool) -> Self {
+        ConnectionState {
+            streams: HashMap::new(),
+            is_server,
+            // Streams opened by the client are odd-numbered and those opened by the server are even
+            next_stream_id: if is_server { 2 } else { 1 },
+            new_streams: VecDeque::new(),
+            accept_task: None,
+            driver_task: None,
+            remote_max_data: u64::max_value(),
+            closing: false,
+            close_sent: false,
+            closed: false,
+            close_error: None,
+            remote_asset_details: None,
+        }
+    }
+
+    pub(crate) fn is_server(&self) -> bool {
+        self.is_server
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub(crate) fn notify_driver(&mut self) {
+        if let Some(task) = self.driver_task.take() {
+            task.notify();
+        }
+    }
+
+    fn notify_all(&mut self) {
+        self.notify_driver();
+        if let Some(task) = self.accept_task.take() {
+            task.notify();
+        }
+        for stream in self.streams.values_mut() {
+            stream.notify_all();
+        }
+    }
+
+    fn open_stream(&mut self) -> u64 {
+        let id = self.next_stream_id;
+        self.next_stream_id += 2;
+        self.streams.insert(id, StreamState::new());
+        id
+    }
+
+    /// Get the stream with the given ID, creating it if the other side just opened it
+    pub(crate) fn stream_mut(&mut self, stream_id: u64) -> &mut StreamState {
+        if !self.streams.contains_key(&stream_id) {
+            let opened_by_server = stream_id % 2 == 0;
+            if opened_by_server != self.is_server {
+                debug!("Other side opened stream {}", stream_id);
+                self.new_streams.push_back(stream_id);
+                if let Some(task) = self.accept_task.take() {
+                    task.notify();
+                }
+            }
+            self.streams.insert(stream_id, StreamState::new());
+        }
+        self.streams.get_mut(&stream_id).unwrap()
+    }
+
+    fn sorted_stream_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.streams.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Apply the frames the other side sent, either in a Prepare or in a response.
+    ///
+    /// Money frames are handled separately because the Prepare may still be rejected.
+    pub(crate) fn handle_frames(&mut self, frames: FrameIterator) -> Result<(), ErrorCode> {
+        for frame in frames {
+            match frame {
+                Frame::ConnectionClose(frame) => {
+                    debug!(
+                        "Other side closed connection with code: {:?} {}",
+                        frame.code, frame.message
+                    );
+                    self.close_error = Some((frame.code, frame.message.to_string()));
+                    self.closed = true;
+                }
+                Frame::ConnectionAssetDetails(frame) => {
+                    if self.remote_asset_details.is_none() {
+                        self.remote_asset_details = Some(AssetDetails {
+                            asset_code: frame.source_asset_code.to_string(),
+                            asset_scale: frame.source_asset_scale,
+                        });
+                    }
+                }
+                Frame::ConnectionMaxData(frame) => {
+                    self.remote_max_data = frame.max_offset;
+                }
+                Frame::StreamClose(frame) => {
+                    let stream = self.stream_mut(frame.stream_id);
+                    stream.remote_closed = true;
+                    stream.notify_all();
+                }
+                Frame::StreamMaxMoney(frame) => {
+                    let stream = self.stream_mut(frame.stream_id);
+                    stream.remote_receive_max = frame.receive_max;
+                    stream.remote_total_received =
+                        max(stream.remote_total_received, frame.total_received);
+                }
+                Frame::StreamData(frame) => {
+                    self.stream_mut(frame.stream_id)
+                        .receive_data(frame.offset, frame.data)
+                        .map_err(|_| {
+                            warn!(
+                                "Got data on stream {} that exceeds the window we advertised",
+                                frame.stream_id
+                            );
+                            ErrorCode::FlowControlError
+                        })?;
+                }
+                Frame::StreamMaxData(frame) => {
+                    let stream = self.stream_mut(frame.stream_id);
+                    stream.remote_max_offset = max(stream.remote_max_offset, frame.max_offset);
+                    stream.acknowledge_data();
+                }
+                _ => {}
+            }
+        }
+        if self.closed {
+            self.notify_all();
+        } else {
+            self.notify_driver();
+        }
+        Ok(())
+    }
+
+    /// Split the amount of an incoming Prepare between the streams it is for, according
+    /// to their shares. Returns None if that would exceed any stream's `receive_max`.
+    pub(crate) fn allocate_money(
+        &mut self,
+        frames: FrameIterator,
+        amount: u64,
+    ) -> Option<Vec<(u64, u64)>> {
+        let money_frames: Vec<StreamMoneyFrame> = frames
+            .filter_map(|frame| match frame {
+                Frame::StreamMoney(frame) => Some(frame),
+                _ => None,
+            })
+            .collect();
+        let total_shares: u128 = money_frames
+            .iter()
+            .map(|frame| u128::from(frame.shares))
+            .sum();
+        if total_shares == 0 {
+            return Some(Vec::new());
+        }
+
+        let mut allocations = Vec::with_capacity(money_frames.len());
+        let mut allocated = 0;
+        for (i, frame) in money_frames.iter().enumerate() {
+            let stream_amount = if i == money_frames.len() - 1 {
+                amount - allocated
+            } else {
+                (u128::from(amount) * u128::from(frame.shares) / total_shares) as u64
+            };
+            allocated += stream_amount;
+            let stream = self.stream_mut(frame.stream_id);
+            if stream.total_received.saturating_add(stream_amount) > stream.receive_max {
+                debug!(
+                    "Amount of {} would exceed receive max of stream {}",
+                    stream_amount, frame.stream_id
+                );
+                return None;
+            }
+            allocations.push((frame.stream_id, stream_amount));
+        }
+        Some(allocations)
+    }
+
+    pub(crate) fn credit_money(&mut self, allocations: &[(u64, u64)]) {
+        for (stream_id, amount) in allocations {
+            let stream = self.stream_mut(*stream_id);
+            stream.total_received += amount;
+            stream.notify_reader();
+        }
+    }
+
+    /// Add the flow control frames, and as much pending data as fits, to an outgoing packet
+    fn add_stream_frames(&mut self, frames: &mut OutgoingFrames) {
+        let bytes_sent: u64 = self.streams.values().map(|stream| stream.send_offset).sum();
+        let mut data_budget = min(
+            MAX_DATA_PER_PACKET as u64,
+            self.remote_max_data.saturating_sub(bytes_sent),
+        ) as usize;
+
+        for stream_id in self.sorted_stream_ids() {
+            let stream = self.streams.get_mut(&stream_id).unwrap();
+            if !stream.remote_closed {
+                frames.max_data.push(StreamMaxDataFrame {
+                    stream_id,
+                    max_offset: stream.max_offset(),
+                });
+            }
+            while let Some((offset, data)) = stream.take_data_chunk(data_budget) {
+                data_budget -= data.len();
+                frames.data.push((stream_id, offset, data));
+            }
+            if stream.is_blocked_on_data() {
+                frames.data_blocked.push(StreamDataBlockedFrame {
+                    stream_id,
+                    max_offset: stream.remote_max_offset,
+                });
+            }
+            if stream.should_send_close() {
+                stream.close_sent = true;
+                frames.stream_close.push(stream_id);
+            }
+        }
+    }
+
+    fn add_connection_close(&mut self, frames: &mut OutgoingFrames) {
+        self.close_sent = true;
+        frames.connection_close = Some(
+            self.close_error
+                .clone()
+                .unwrap_or_else(|| (ErrorCode::NoError, String::new())),
+        );
+    }
+
+    /// Give up on a connection the other side stopped sending packets on. Nothing is
+    /// sent to the other side because a server can only reply to packets
+    pub(crate) fn time_out(&mut self) {
+        self.close_error = Some((ErrorCode::NoError, "Idle timeout".to_string()));
+        self.closed = true;
+        self.notify_all();
+    }
+
+    /// Close the connection because the other side did something wrong.
+    /// The error is sent in the next packet or response.
+    pub(crate) fn close_with_error(&mut self, code: ErrorCode, message: &str) {
+        self.close_error = Some((code, message.to_string()));
+        self.closing = true;
+        self.notify_driver();
+    }
+
+    /// Build the frames the server includes in its response to a Prepare.
+    ///
+    /// This is the only way the server can send data back to the client. The data is sent
+    /// again in every response until the client's StreamMaxData frames acknowledge it.
+    pub(crate) fn response_frames(&mut self, money_streams: &[u64]) -> OutgoingFrames {
+        let mut frames = OutgoingFrames::default();
+        for stream in self.streams.values_mut() {
+            stream.retransmit_unacknowledged();
+        }
+        for stream_id in money_streams {
+            let stream = self.stream_mut(*stream_id);
+            frames.max_money.push(StreamMaxMoneyFrame {
+                stream_id: *stream_id,
+                receive_max: stream.receive_max,
+                total_received: stream.total_received,
+            });
+        }
+        self.add_stream_frames(&mut frames);
+        for (stream_id, offset, data) in &frames.data {
+            self.stream_mut(*stream_id)
+                .data_sent_in_response(*offset, data.clone());
+        }
+        if self.closing && !self.close_sent {
+            self.add_connection_close(&mut frames);
+            self.closed = true;
+            self.notify_all();
+        }
+        frames
+    }
+
+    /// Build the next packet the client should send, if there is anything to send.
+    fn next_outgoing_packet(&mut self, max_amount: u64, force: bool) -> Option<OutgoingPacket> {
+        let mut frames = OutgoingFrames::default();
+
+        let mut amount = 0;
+        let money_stream = self.sorted_stream_ids().into_iter().find(|id| {
+            let stream = &self.streams[id];
+            stream.money_to_send() > 0 && !stream.remote_closed
+        });
+        if let Some(stream_id) = money_stream {
+            let stream = self.streams.get_mut(&stream_id).unwrap();
+            amount = min(stream.money_to_send(), max_amount);
+            if amount > 0 {
+                stream.money_in_flight += amount;
+                frames.money = Some(StreamMoneyFrame {
+                    stream_id,
+                    shares: 1,
+                });
+            }
+        }
+
+        self.add_stream_frames(&mut frames);
+
+        let is_finished = self.streams.values().all(|stream| {
+            stream.is_flushed() && stream.money_to_send() == 0 && stream.money_in_flight == 0
+        });
+        if self.closing && !self.close_sent && is_finished {
+            self.add_connection_close(&mut frames);
+        }
+
+        let has_content = amount > 0
+            || !frames.data.is_empty()
+            || !frames.stream_close.is_empty()
+            || frames.connection_close.is_some();
+        if has_content || force {
+            Some(OutgoingPacket {
+                sequence: 0,
+                amount,
+                frames,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Update the state based on the (decrypted) response to a packet the client sent
+    fn handle_response(
+        &mut self,
+        packet: OutgoingPacket,
+        response: Option<StreamPacket>,
+        fulfilled: bool,
+    ) {
+        let delivered = fulfilled || response.is_some();
+        if let Some(ref response) = response {
+            if let Err(code) = self.handle_frames(response.frames()) {
+                self.close_with_error(code, "");
+            }
+        }
+
+        if let Some(money) = packet.frames.money {
+            let stream = self.stream_mut(money.stream_id);
+            stream.money_in_flight -= packet.amount;
+            if fulfilled {
+                stream.total_sent += packet.amount;
+                if let Some(ref response) = response {
+                    stream.total_delivered += response.prepare_amount();
+                }
+            } else if stream.remote_total_received >= stream.remote_receive_max {
+                warn!(
+                    "Receiver will not accept any more money on stream {}",
+                    money.stream_id
+                );
+                stream.send_max = stream.total_sent + stream.money_in_flight;
+            }
+            stream.notify_writer();
+        }
+
+        for (stream_id, offset, data) in packet.frames.data {
+            let stream = self.stream_mut(stream_id);
+            if delivered {
+                stream.data_delivered(data.len());
+            } else {
+                stream.retransmit_data(offset, data);
+            }
+        }
+        if !delivered {
+            for stream_id in packet.frames.stream_close {
+                self.stream_mut(stream_id).close_sent = false;
+            }
+        }
+        if packet.frames.connection_close.is_some() {
+            if delivered {
+                self.closed = true;
+                self.notify_all();
+            } else {
+                self.close_sent = false;
+            }
+        }
+    }
+}
+
+/// Open a STREAM connection to the given destination, using the `destination_account`
+/// and `shared_secret` from an SPSP query or similar.
+///
+/// This spawns a task that sends the packets for all of the connection's streams.
+/// The task finishes when the connection is closed or when the `Connection` and all
+/// of its streams are dropped.
+pub fn connect<S, A>(
+    service: S,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: &[u8],
+) -> impl Future<Item = Connection, Error = Error>
+where
+    S: IncomingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    let shared_secret = Bytes::from(shared_secret);
+    let from_account = from_account.clone();
+    get_ildcp_info(&mut service.clone(), from_account.clone())
+        .map_err(|_err| Error::ConnectionError("Unable to get ILDCP info".to_string()))
+        .and_then(move |account_details| {
+            let connection = Connection::new(ConnectionState::new(false));
+            spawn(ConnectionDriver {
+                connection: connection.state.clone(),
+                next: service,
+                from_account,
+                source_account: account_details.client_address(),
+                source_asset: AssetDetails {
+                    asset_code: String::from_utf8_lossy(account_details.asset_code()).to_string(),
+                    asset_scale: account_details.asset_scale(),
+                },
+                destination_account,
+                shared_secret,
+                sequence: 1,
+                congestion_controller: CongestionController::default(),
+                pending_requests: Vec::new(),
+                should_send_source_account: true,
+                poll_delay: None,
+                pull_data: false,
+            });
+            Ok(connection)
+        })
+}
+
+struct ConnectionDriver<S, A> {
+    connection: Arc<Mutex<ConnectionState>>,
+    next: S,
+    from_account: A,
+    source_account: Address,
+    source_asset: AssetDetails,
+    destination_account: Address,
+    shared_secret: Bytes,
+    sequence: u64,
+    congestion_controller: CongestionController,
+    pending_requests: Vec<(OutgoingPacket, BoxedIlpFuture)>,
+    should_send_source_account: bool,
+    poll_delay: Option<Delay>,
+    pull_data: bool,
+}
+
+enum NextStep {
+    Finished,
+    Send(OutgoingPacket),
+    PollForData,
+    Wait,
+}
+
+impl<S, A> ConnectionDriver<S, A>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    fn send_packet(&mut self, mut packet: OutgoingPacket) {
+        packet.sequence = self.sequence;
+        self.sequence += 1;
+        if self.should_send_source_account {
+            packet.frames.new_address = Some(self.source_account.clone());
+            packet.frames.asset_details = Some(self.source_asset.clone());
+        }
+
+        let stream_packet = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: packet.sequence,
+            frames: &packet.frames.frames(),
+        }
+        .build();
+        debug!(
+            "Sending packet {} with amount: {} and encrypted STREAM packet: {:?}",
+            packet.sequence, packet.amount, stream_packet
+        );
+        let data = stream_packet.into_encrypted(&self.shared_secret);
+        // Packets without money are only used to carry frames, so the receiver should reject them
+        let execution_condition = if packet.amount > 0 {
+            generate_condition(&self.shared_secret, &data)
+        } else {
+            random_condition()
+        };
+        let prepare = PrepareBuilder {
+            destination: self.destination_account.clone(),
+            amount: packet.amount,
+            execution_condition: &execution_condition,
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            data: &data[..],
+        }
+        .build();
+
+        if packet.amount > 0 {
+            self.congestion_controller.prepare(packet.amount);
+        }
+        let future = self.next.handle_request(IncomingRequest {
+            from: self.from_account.clone(),
+            prepare,
+        });
+        self.pending_requests.push((packet, Box::new(future)));
+    }
+
+    fn poll_pending_requests(&mut self) {
+        let mut i = 0;
+        while i < self.pending_requests.len() {
+            let result = match self.pending_requests[i].1.poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Async::Ready(fulfill)) => Ok(fulfill),
+                Err(reject) => Err(reject),
+            };
+            let (packet, _) = self.pending_requests.swap_remove(i);
+            self.handle_result(packet, result);
+        }
+    }
+
+    fn handle_result(&mut self, packet: OutgoingPacket, result: Result<Fulfill, Reject>) {
+        let (response_data, fulfilled) = match result {
+            Ok(fulfill) => {
+                if packet.amount > 0 {
+                    self.congestion_controller.fulfill(packet.amount);
+                }
+                (Some(fulfill.into_data()), true)
+            }
+            Err(reject) => {
+                if packet.amount > 0 {
+                    self.congestion_controller.reject(packet.amount, &reject);
+                }
+                match (reject.code().class(), reject.code()) {
+                    (_, IlpErrorCode::F99_APPLICATION_ERROR) => {
+                        (Some(BytesMut::from(reject.data())), false)
+                    }
+                    (ErrorClass::Temporary, _) | (_, IlpErrorCode::F08_AMOUNT_TOO_LARGE) => {
+                        debug!(
+                            "Packet {} was rejected with error: {}, will retry",
+                            packet.sequence,
+                            reject.code()
+                        );
+                        (None, false)
+                    }
+                    _ => {
+                        let message = format!(
+                            "Packet was rejected with error: {} {}",
+                            reject.code(),
+                            str::from_utf8(reject.message()).unwrap_or_default(),
+                        );
+                        error!("Closing connection: {}", message);
+                        let mut connection = self.connection.lock();
+                        connection.close_error = Some((ErrorCode::InternalError, message));
+                        connection.closed = true;
+                        connection.notify_all();
+                        return;
+                    }
+                }
+            }
+        };
+
+        let response = response_data
+            .and_then(|data| StreamPacket::from_encrypted(&self.shared_secret, data).ok())
+            .filter(|response| {
+                if response.sequence() != packet.sequence
+                    || response.ilp_packet_type() == IlpPacketType::Prepare
+                {
+                    warn!(
+                        "Ignoring STREAM response that does not match packet {}: {:?}",
+                        packet.sequence, response
+                    );
+                    false
+                } else {
+                    true
+                }
+            });
+        if let Some(ref response) = response {
+            self.should_send_source_account = false;
+            // The receiver may have more data waiting. Data it sent again because
+            // we had not acknowledged it yet does not mean that
+            let connection = self.connection.lock();
+            let has_new_data = response.frames().any(|frame| match frame {
+                Frame::StreamData(frame) => connection
+                    .streams
+                    .get(&frame.stream_id)
+                    .map(|stream| !stream.has_received(frame.offset, frame.data.len()))
+                    .unwrap_or(true),
+                _ => false,
+            });
+            if has_new_data {
+                self.pull_data = true;
+            }
+        }
+
+        self.connection
+            .lock()
+            .handle_response(packet, response, fulfilled);
+    }
+
+    fn next_step(&mut self) -> NextStep {
+        let mut connection = self.connection.lock();
+        connection.driver_task = Some(task::current());
+
+        // Close the connection if the user dropped all of the handles to it
+        if Arc::strong_count(&self.connection) == 1 && !connection.closing {
+            debug!("Connection was dropped, closing it");
+            connection.closing = true;
+        }
+
+        if connection.closed {
+            return if self.pending_requests.is_empty() {
+                NextStep::Finished
+            } else {
+                NextStep::Wait
+            };
+        }
+        if self.pending_requests.len() >= MAX_PACKETS_IN_FLIGHT {
+            return NextStep::Wait;
+        }
+
+        let max_amount = self.congestion_controller.get_max_amount();
+        if let Some(packet) = connection.next_outgoing_packet(max_amount, self.pull_data) {
+            self.pull_data = false;
+            return NextStep::Send(packet);
+        }
+
+        let should_poll = self.pending_requests.is_empty()
+            && connection
+                .streams
+                .values()
+                .any(|stream| stream.is_waiting_for_data() || stream.is_blocked_on_data());
+        if should_poll {
+            NextStep::PollForData
+        } else {
+            self.poll_delay = None;
+            NextStep::Wait
+        }
+    }
+}
+
+impl<S, A> Future for ConnectionDriver<S, A>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            self.poll_pending_requests();
+
+            match self.next_step() {
+                NextStep::Finished => {
+                    debug!(
+                        "Connection finished after sending {} packets",
+                        self.sequence - 1
+                    );
+                    return Ok(Async::Ready(()));
+                }
+                NextStep::Send(packet) => self.send_packet(packet),
+                NextStep::PollForData => {
+                    let delay = self
+                        .poll_delay
+                        .get_or_insert_with(|| Delay::new(Instant::now() + DATA_POLL_INTERVAL));
+                    match delay.poll() {
+                        Ok(Async::Ready(_)) => {
+                            self.poll_delay = None;
+                            self.pull_data = true;
+                        }
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(err) => {
+                            warn!("Timer error while waiting to poll for data: {:?}", err);
+                            self.poll_delay = None;
+                            self.pull_data = true;
+                        }
+                    }
+                }
+                NextStep::Wait => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod connection_state {
+    use super::super::data_money_stream::DEFAULT_STREAM_WINDOW;
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn tracks_streams_opened_by_other_side() {
+        let mut server = ConnectionState::new(true);
+        assert_eq!(server.open_stream(), 2);
+        server.stream_mut(2);
+        assert!(server.new_streams.is_empty());
+        server.stream_mut(1);
+        assert_eq!(server.new_streams.pop_front(), Some(1));
+    }
+
+    #[test]
+    fn splits_money_by_shares() {
+        let mut state = ConnectionState::new(true);
+        let packet = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[
+                Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                }),
+                Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 3,
+                    shares: 2,
+                }),
+            ],
+        }
+        .build();
+        let allocations = state.allocate_money(packet.frames(), 100).unwrap();
+        assert_eq!(allocations, vec![(1, 33), (3, 67)]);
+
+        state.stream_mut(3).receive_max = 50;
+        assert!(state.allocate_money(packet.frames(), 100).is_none());
+    }
+
+    #[test]
+    fn retransmits_data_when_packet_is_not_delivered() {
+        let connection = Connection::new(ConnectionState::new(false));
+        let mut stream = connection.new_stream();
+        stream.write_all(b"hello").unwrap();
+
+        let mut state = connection.state.lock();
+        let packet = state.next_outgoing_packet(0, false).unwrap();
+        assert_eq!(packet.frames.data.len(), 1);
+        assert!(state.next_outgoing_packet(0, false).is_none());
+
+        state.handle_response(packet, None, false);
+        let packet = state.next_outgoing_packet(0, false).unwrap();
+        assert_eq!(packet.frames.data[0].1, 0);
+        assert_eq!(&packet.frames.data[0].2[..], b"hello");
+        state.handle_response(packet, None, false);
+    }
+
+    #[test]
+    fn resends_response_data_until_acknowledged() {
+        let connection = Connection::new(ConnectionState::new(true));
+        let mut stream = connection.new_stream();
+        stream.write_all(b"hello").unwrap();
+
+        let mut state = connection.state.lock();
+        let frames = state.response_frames(&[]);
+        assert_eq!(&frames.data[0].2[..], b"hello");
+        let frames = state.response_frames(&[]);
+        assert_eq!(frames.data[0].1, 0);
+        assert_eq!(&frames.data[0].2[..], b"hello");
+
+        let stream = state.stream_mut(stream.id());
+        stream.remote_max_offset = DEFAULT_STREAM_WINDOW + 5;
+        stream.acknowledge_data();
+        assert!(stream.is_flushed());
+        assert!(state.response_frames(&[]).data.is_empty());
+    }
+
+    #[test]
+    fn only_sends_connection_close_after_streams_finish() {
+        let mut state = ConnectionState::new(false);
+        let id = state.open_stream();
+        state.stream_mut(id).send_max = 10;
+        state.closing = true;
+
+        let packet = state.next_outgoing_packet(10, false).unwrap();
+        assert_eq!(packet.amount, 10);
+        assert!(packet.frames.connection_close.is_none());
+
+        state.handle_response(packet, None, true);
+        assert_eq!(state.streams[&id].total_sent, 10);
+        let packet = state.next_outgoing_packet(10, false).unwrap();
+        assert!(packet.frames.connection_close.is_some());
+        assert!(!state.is_closed());
+        state.handle_response(packet, None, true);
+        assert!(state.is_closed());
+    }
+}
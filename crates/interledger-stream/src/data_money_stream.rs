@@ -0,0 +1,589 @@
+use super::connection::ConnectionState;
+use super::error::Error;
+use bytes::{Bytes, BytesMut};
+use futures::{
+    task::{self, Task},
+    Async, Future, Poll, Stream,
+};
+use parking_lot::Mutex;
+use std::{
+    cmp::min,
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The number of bytes each side is initially allowed to send on a stream
+/// before it hears a StreamMaxData frame from the other side.
+pub const DEFAULT_STREAM_WINDOW: u64 = 65536;
+
+/// The maximum number of bytes buffered by `write` before it applies backpressure.
+const MAX_SEND_BUFFER: usize = 65536;
+
+/// The per-stream state shared between the `DataMoneyStream` handle and the connection.
+pub(crate) struct StreamState {
+    // Incoming data
+    recv_chunks: BTreeMap<u64, Bytes>,
+    read_offset: u64,
+    pub(crate) advertised_max_offset: u64,
+
+    // Outgoing data
+    send_buffer: BytesMut,
+    pub(crate) send_offset: u64,
+    retransmit: BTreeMap<u64, Bytes>,
+    // Data the server sent in responses, which it keeps until the client acknowledges it
+    unacknowledged: BTreeMap<u64, Bytes>,
+    pub(crate) data_in_flight: usize,
+    pub(crate) remote_max_offset: u64,
+
+    // Money
+    pub(crate) send_max: u64,
+    pub(crate) total_sent: u64,
+    pub(crate) money_in_flight: u64,
+    pub(crate) total_delivered: u64,
+    pub(crate) receive_max: u64,
+    pub(crate) total_received: u64,
+    pub(crate) remote_receive_max: u64,
+    pub(crate) remote_total_received: u64,
+
+    // Closing
+    pub(crate) local_closed: bool,
+    pub(crate) close_sent: bool,
+    pub(crate) remote_closed: bool,
+
+    read_task: Option<Task>,
+    write_task: Option<Task>,
+    money_tasks: Vec<Task>,
+}
+
+impl StreamState {
+    pub(crate) fn new() -> Self {
+        StreamState {
+            recv_chunks: BTreeMap::new(),
+            read_offset: 0,
+            advertised_max_offset: DEFAULT_STREAM_WINDOW,
+            send_buffer: BytesMut::new(),
+            send_offset: 0,
+            retransmit: BTreeMap::new(),
+            unacknowledged: BTreeMap::new(),
+            data_in_flight: 0,
+            remote_max_offset: DEFAULT_STREAM_WINDOW,
+            send_max: 0,
+            total_sent: 0,
+            money_in_flight: 0,
+            total_delivered: 0,
+            receive_max: u64::max_value(),
+            total_received: 0,
+            remote_receive_max: u64::max_value(),
+            remote_total_received: 0,
+            local_closed: false,
+            close_sent: false,
+            remote_closed: false,
+            read_task: None,
+            write_task: None,
+            money_tasks: Vec::new(),
+        }
+    }
+
+    /// Buffer data received from the other side. Returns an error if the
+    /// data goes beyond the window we have advertised.
+    pub(crate) fn receive_data(&mut self, offset: u64, data: &[u8]) -> Result<(), ()> {
+        let end = offset + data.len() as u64;
+        if end > self.advertised_max_offset {
+            return Err(());
+        }
+        if end > self.read_offset && !data.is_empty() {
+            let is_longer = self
+                .recv_chunks
+                .get(&offset)
+                .map(|existing| existing.len() < data.len())
+                .unwrap_or(true);
+            if is_longer {
+                self.recv_chunks.insert(offset, Bytes::from(data));
+            }
+            self.notify_reader();
+        }
+        Ok(())
+    }
+
+    /// Whether this data was already received, in which case it is ignored
+    pub(crate) fn has_received(&self, offset: u64, len: usize) -> bool {
+        offset + len as u64 <= self.read_offset
+            || self
+                .recv_chunks
+                .get(&offset)
+                .map(|existing| existing.len() >= len)
+                .unwrap_or(false)
+    }
+
+    /// The max offset to advertise to the other side, one window past what the reader
+    /// has consumed. This also tells the other side which data we have received.
+    pub(crate) fn max_offset(&mut self) -> u64 {
+        self.advertised_max_offset = self.read_offset + DEFAULT_STREAM_WINDOW;
+        self.advertised_max_offset
+    }
+
+    pub(crate) fn is_waiting_for_data(&self) -> bool {
+        self.read_task.is_some() && !self.remote_closed
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut read = 0;
+        while read < buf.len() {
+            let (offset, chunk) = match self.recv_chunks.iter().next() {
+                Some((offset, _)) if *offset > self.read_offset => break,
+                Some((offset, chunk)) => (*offset, chunk.clone()),
+                None => break,
+            };
+            self.recv_chunks.remove(&offset);
+            let skip = (self.read_offset - offset) as usize;
+            if skip >= chunk.len() {
+                continue;
+            }
+            let to_copy = min(chunk.len() - skip, buf.len() - read);
+            buf[read..read + to_copy].copy_from_slice(&chunk[skip..skip + to_copy]);
+            read += to_copy;
+            self.read_offset += to_copy as u64;
+            if skip + to_copy < chunk.len() {
+                self.recv_chunks
+                    .insert(self.read_offset, chunk.slice_from(skip + to_copy));
+            }
+        }
+        read
+    }
+
+    /// Take the next chunk of outgoing data, preferring data that needs to be retransmitted.
+    /// The chunk will not exceed `max_len` or the other side's advertised window.
+    pub(crate) fn take_data_chunk(&mut self, max_len: usize) -> Option<(u64, Bytes)> {
+        if max_len == 0 {
+            return None;
+        }
+        let first_retransmit = self.retransmit.keys().next().cloned();
+        if let Some(offset) = first_retransmit {
+            let chunk = self.retransmit.remove(&offset).unwrap();
+            if chunk.len() > max_len {
+                self.retransmit
+                    .insert(offset + max_len as u64, chunk.slice_from(max_len));
+                self.data_in_flight += max_len;
+                return Some((offset, chunk.slice_to(max_len)));
+            }
+            self.data_in_flight += chunk.len();
+            return Some((offset, chunk));
+        }
+
+        let window = self.remote_max_offset.saturating_sub(self.send_offset) as usize;
+        let len = min(min(max_len, window), self.send_buffer.len());
+        if len == 0 {
+            return None;
+        }
+        let offset = self.send_offset;
+        let chunk = self.send_buffer.split_to(len).freeze();
+        self.send_offset += len as u64;
+        self.data_in_flight += len;
+        self.notify_writer();
+        Some((offset, chunk))
+    }
+
+    /// Called when a chunk of data did not reach the other side
+    pub(crate) fn retransmit_data(&mut self, offset: u64, chunk: Bytes) {
+        self.data_in_flight -= chunk.len();
+        self.retransmit.insert(offset, chunk);
+    }
+
+    /// Called when a chunk of data was received by the other side
+    pub(crate) fn data_delivered(&mut self, len: usize) {
+        self.data_in_flight -= len;
+        self.notify_writer();
+    }
+
+    /// Called when the server sent a chunk of data in a response. The server cannot tell
+    /// whether the response arrived, so the chunk stays in flight until it is acknowledged
+    pub(crate) fn data_sent_in_response(&mut self, offset: u64, chunk: Bytes) {
+        self.unacknowledged.insert(offset, chunk);
+    }
+
+    /// Queue the data sent in responses that was not acknowledged yet to be sent again
+    pub(crate) fn retransmit_unacknowledged(&mut self) {
+        let unacknowledged = std::mem::replace(&mut self.unacknowledged, BTreeMap::new());
+        for (offset, chunk) in unacknowledged {
+            self.retransmit_data(offset, chunk);
+        }
+    }
+
+    /// Forget the data sent in responses that the other side has read, which it
+    /// acknowledges by advertising a max offset one window past it
+    pub(crate) fn acknowledge_data(&mut self) {
+        let acknowledged = self.remote_max_offset.saturating_sub(DEFAULT_STREAM_WINDOW);
+        while let Some(offset) = self.unacknowledged.keys().next().cloned() {
+            if offset >= acknowledged {
+                break;
+            }
+            let chunk = self.unacknowledged.remove(&offset).unwrap();
+            let len = min(chunk.len() as u64, acknowledged - offset) as usize;
+            if len < chunk.len() {
+                self.unacknowledged
+                    .insert(offset + len as u64, chunk.slice_from(len));
+            }
+            self.data_delivered(len);
+        }
+    }
+
+    pub(crate) fn is_blocked_on_data(&self) -> bool {
+        self.retransmit.is_empty()
+            && !self.send_buffer.is_empty()
+            && self.send_offset >= self.remote_max_offset
+    }
+
+    pub(crate) fn is_flushed(&self) -> bool {
+        self.send_buffer.is_empty() && self.retransmit.is_empty() && self.data_in_flight == 0
+    }
+
+    /// The amount of money waiting to be sent on this stream
+    pub(crate) fn money_to_send(&self) -> u64 {
+        self.send_max
+            .saturating_sub(self.total_sent + self.money_in_flight)
+    }
+
+    /// Whether a StreamClose frame for this stream should go out now
+    pub(crate) fn should_send_close(&self) -> bool {
+        self.local_closed
+            && !self.close_sent
+            && self.is_flushed()
+            && self.money_to_send() == 0
+            && self.money_in_flight == 0
+    }
+
+    pub(crate) fn notify_reader(&mut self) {
+        if let Some(task) = self.read_task.take() {
+            task.notify();
+        }
+        for task in self.money_tasks.drain(..) {
+            task.notify();
+        }
+    }
+
+    pub(crate) fn notify_writer(&mut self) {
+        if let Some(task) = self.write_task.take() {
+            task.notify();
+        }
+        for task in self.money_tasks.drain(..) {
+            task.notify();
+        }
+    }
+
+    pub(crate) fn notify_all(&mut self) {
+        self.notify_reader();
+        self.notify_writer();
+    }
+}
+
+/// A single stream within a STREAM connection, used to send and receive both data and money.
+///
+/// Data is read and written through the `AsyncRead` and `AsyncWrite` implementations,
+/// with the amount buffered on either side limited by the StreamMaxData frames.
+/// Money is sent with `send_money` and received with `receive_money`.
+#[derive(Clone)]
+pub struct DataMoneyStream {
+    pub(crate) id: u64,
+    pub(crate) connection: Arc<Mutex<ConnectionState>>,
+}
+
+impl DataMoneyStream {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The total amount of money sent and fulfilled on this stream, in the sender's units
+    pub fn total_sent(&self) -> u64 {
+        self.with_state(|_, stream| stream.total_sent)
+    }
+
+    /// The total amount the other side reported receiving on this stream, in its units
+    pub fn total_delivered(&self) -> u64 {
+        self.with_state(|_, stream| stream.total_delivered)
+    }
+
+    /// The total amount of money received on this stream, in our units
+    pub fn total_received(&self) -> u64 {
+        self.with_state(|_, stream| stream.total_received)
+    }
+
+    /// Set the maximum total amount this stream will accept.
+    /// Packets that would exceed it are rejected and the sender is told the limit.
+    pub fn set_receive_max(&self, receive_max: u64) {
+        self.with_state(|_, stream| stream.receive_max = receive_max)
+    }
+
+    /// Send the given amount of money (in addition to anything already queued) on this stream.
+    ///
+    /// The returned future resolves to the stream's `total_sent` once all of it has been fulfilled.
+    /// Note that only the side that opened the connection can send money, because the
+    /// receiver has no way of addressing packets back to the sender.
+    pub fn send_money(&self, amount: u64) -> MoneySent {
+        let target = {
+            let mut connection = self.connection.lock();
+            let target = {
+                let stream = connection.stream_mut(self.id);
+                stream.send_max += amount;
+                stream.send_max
+            };
+            connection.notify_driver();
+            target
+        };
+        MoneySent {
+            stream: self.clone(),
+            target,
+        }
+    }
+
+    /// A Stream of the amounts of money received on this stream, which ends when the
+    /// stream or connection is closed. The first item includes any money that arrived
+    /// before this was called.
+    pub fn receive_money(&self) -> MoneyReceiver {
+        MoneyReceiver {
+            stream: self.clone(),
+            last_total: 0,
+        }
+    }
+
+    fn with_state<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut ConnectionState, &mut StreamState) -> T,
+    {
+        let mut connection = self.connection.lock();
+        let mut stream = connection
+            .streams
+            .remove(&self.id)
+            .unwrap_or_else(StreamState::new);
+        let result = f(&mut connection, &mut stream);
+        connection.streams.insert(self.id, stream);
+        result
+    }
+}
+
+impl Read for DataMoneyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.with_state(|connection, stream| {
+            let read = stream.read(buf);
+            if read > 0 {
+                if stream.advertised_max_offset - stream.read_offset <= DEFAULT_STREAM_WINDOW / 2 {
+                    connection.notify_driver();
+                }
+                Ok(read)
+            } else if buf.is_empty() || stream.remote_closed || connection.is_closed() {
+                Ok(0)
+            } else {
+                stream.read_task = Some(task::current());
+                connection.notify_driver();
+                Err(io::Error::from(io::ErrorKind::WouldBlock))
+            }
+        })
+    }
+}
+
+impl AsyncRead for DataMoneyStream {}
+
+impl Write for DataMoneyStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_state(|connection, stream| {
+            if stream.local_closed || connection.is_closed() {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+            let len = min(
+                buf.len(),
+                MAX_SEND_BUFFER.saturating_sub(stream.send_buffer.len()),
+            );
+            if len == 0 && !buf.is_empty() {
+                stream.write_task = Some(task::current());
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            stream.send_buffer.extend_from_slice(&buf[..len]);
+            connection.notify_driver();
+            Ok(len)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_state(|connection, stream| {
+            if stream.is_flushed() {
+                Ok(())
+            } else if connection.is_closed() {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            } else {
+                stream.write_task = Some(task::current());
+                Err(io::Error::from(io::ErrorKind::WouldBlock))
+            }
+        })
+    }
+}
+
+impl AsyncWrite for DataMoneyStream {
+    /// Close our side of the stream once all buffered data has been sent
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.with_state(|connection, stream| {
+            stream.local_closed = true;
+            connection.notify_driver();
+        });
+        match self.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Future returned by `DataMoneyStream::send_money`
+pub struct MoneySent {
+    stream: DataMoneyStream,
+    target: u64,
+}
+
+impl Future for MoneySent {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let target = self.target;
+        self.stream.with_state(|connection, stream| {
+            if stream.total_sent >= target {
+                Ok(Async::Ready(stream.total_sent))
+            } else if connection.is_server() {
+                Err(Error::SendMoneyError(
+                    "Cannot send money from the receiving side of a connection".to_string(),
+                ))
+            } else if connection.is_closed() || stream.remote_closed || stream.send_max < target {
+                Err(Error::SendMoneyError(format!(
+                    "Stream was closed or blocked after sending {} of {}",
+                    stream.total_sent, target
+                )))
+            } else {
+                stream.money_tasks.push(task::current());
+                Ok(Async::NotReady)
+            }
+        })
+    }
+}
+
+/// Stream returned by `DataMoneyStream::receive_money`
+pub struct MoneyReceiver {
+    stream: DataMoneyStream,
+    last_total: u64,
+}
+
+impl Stream for MoneyReceiver {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let last_total = self.last_total;
+        let (result, total) = self.stream.with_state(|connection, stream| {
+            if stream.total_received > last_total {
+                (
+                    Async::Ready(Some(stream.total_received - last_total)),
+                    stream.total_received,
+                )
+            } else if connection.is_closed() || stream.remote_closed {
+                (Async::Ready(None), last_total)
+            } else {
+                stream.money_tasks.push(task::current());
+                (Async::NotReady, last_total)
+            }
+        });
+        self.last_total = total;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod stream_state {
+    use super::*;
+
+    #[test]
+    fn reassembles_out_of_order_data() {
+        let mut stream = StreamState::new();
+        stream.receive_data(5, b"world").unwrap();
+        stream.receive_data(0, b"hello").unwrap();
+        stream.receive_data(2, b"llo").unwrap();
+
+        let mut buf = [0; 20];
+        let read = stream.read(&mut buf);
+        assert_eq!(&buf[..read], b"helloworld");
+        assert_eq!(stream.read(&mut buf), 0);
+    }
+
+    #[test]
+    fn rejects_data_beyond_window() {
+        let mut stream = StreamState::new();
+        assert!(stream
+            .receive_data(DEFAULT_STREAM_WINDOW - 1, b"ab")
+            .is_err());
+        assert!(stream
+            .receive_data(DEFAULT_STREAM_WINDOW - 2, b"ab")
+            .is_ok());
+    }
+
+    #[test]
+    fn extends_window_after_reading() {
+        let mut stream = StreamState::new();
+        let data = vec![1; DEFAULT_STREAM_WINDOW as usize / 2];
+        stream.receive_data(0, &data[..]).unwrap();
+        assert_eq!(stream.max_offset(), DEFAULT_STREAM_WINDOW);
+
+        let mut buf = vec![0; data.len() - 1];
+        stream.read(&mut buf[..]);
+        assert_eq!(stream.max_offset(), DEFAULT_STREAM_WINDOW * 3 / 2 - 1);
+
+        stream.read(&mut buf[..1]);
+        assert_eq!(stream.max_offset(), DEFAULT_STREAM_WINDOW * 3 / 2);
+    }
+
+    #[test]
+    fn respects_remote_window_and_retransmits() {
+        let mut stream = StreamState::new();
+        stream.remote_max_offset = 4;
+        stream.send_buffer.extend_from_slice(b"abcdef");
+
+        let (offset, chunk) = stream.take_data_chunk(100).unwrap();
+        assert_eq!((offset, &chunk[..]), (0, &b"abcd"[..]));
+        assert!(stream.take_data_chunk(100).is_none());
+        assert!(stream.is_blocked_on_data());
+
+        stream.retransmit_data(offset, chunk);
+        let (offset, chunk) = stream.take_data_chunk(3).unwrap();
+        assert_eq!((offset, &chunk[..]), (0, &b"abc"[..]));
+        let (offset, chunk) = stream.take_data_chunk(3).unwrap();
+        assert_eq!((offset, &chunk[..]), (3, &b"d"[..]));
+
+        stream.remote_max_offset = 10;
+        let (offset, chunk) = stream.take_data_chunk(100).unwrap();
+        assert_eq!((offset, &chunk[..]), (4, &b"ef"[..]));
+    }
+
+    #[test]
+    fn keeps_data_sent_in_responses_until_acknowledged() {
+        let mut stream = StreamState::new();
+        stream.send_buffer.extend_from_slice(b"abcdef");
+        let (offset, chunk) = stream.take_data_chunk(100).unwrap();
+        stream.data_sent_in_response(offset, chunk);
+        assert!(!stream.is_flushed());
+
+        // The response was lost, so the data is sent again
+        stream.retransmit_unacknowledged();
+        let (offset, chunk) = stream.take_data_chunk(100).unwrap();
+        assert_eq!((offset, &chunk[..]), (0, &b"abcdef"[..]));
+        stream.data_sent_in_response(offset, chunk);
+
+        stream.remote_max_offset = DEFAULT_STREAM_WINDOW + 4;
+        stream.acknowledge_data();
+        assert_eq!(stream.data_in_flight, 2);
+        stream.retransmit_unacknowledged();
+        let (offset, chunk) = stream.take_data_chunk(100).unwrap();
+        assert_eq!((offset, &chunk[..]), (4, &b"ef"[..]));
+        stream.data_sent_in_response(offset, chunk);
+
+        stream.remote_max_offset = DEFAULT_STREAM_WINDOW + 6;
+        stream.acknowledge_data();
+        assert!(stream.is_flushed());
+    }
+}
@@ -1,3 +1,4 @@
+use super::packet::ErrorCode;
 use failure::Fail;
 
 #[derive(Fail, Debug)]
@@ -8,4 +9,26 @@ pub enum Error {
     PollError(String),
     #[fail(display = "Error polling: {}", _0)]
     SendMoneyError(String),
+    #[fail(
+        display = "Receiver closed the connection with code {:?}: {}",
+        code, message
+    )]
+    RemoteClosed { code: ErrorCode, message: String },
+    #[fail(display = "Receiver is unreachable: {}", _0)]
+    ReceiverUnreachable(String),
+    #[fail(display = "Insufficient liquidity: {}", _0)]
+    InsufficientLiquidity(String),
+    #[fail(display = "Exchange rate too low: {}", _0)]
+    ExchangeRateTooLow(String),
+    #[fail(display = "Timed out: {}", _0)]
+    Timeout(String),
+    #[fail(
+        display = "Payment failed after sending {} and delivering {}: {}",
+        sent_amount, delivered_amount, error
+    )]
+    PartialDelivery {
+        sent_amount: u64,
+        delivered_amount: u64,
+        error: Box<Error>,
+    },
 }
@@ -6,13 +6,22 @@
 
 mod client;
 mod congestion;
+mod connection;
 mod crypto;
+mod data_money_stream;
 mod error;
 mod packet;
 mod server;
 
-pub use client::send_money;
+pub use client::{
+    send_money, send_money_with_congestion_control, send_money_with_progress, AssetDetails,
+    PaymentCanceller, PaymentHandle, PaymentProgress, StreamDelivery,
+};
+pub use congestion::{CongestionControl, CongestionController, ScalingCongestionController};
+pub use connection::{connect, Connection};
+pub use data_money_stream::{DataMoneyStream, MoneyReceiver, MoneySent};
 pub use error::Error;
+pub use packet::ErrorCode;
 pub use server::{ConnectionGenerator, StreamReceiverService};
 
 #[cfg(test)]
@@ -145,8 +154,16 @@ mod send_money_to_receiver {
             &shared_secret[..],
             100,
         )
-        .and_then(|(delivered_amount, _service)| {
-            assert_eq!(delivered_amount, 100);
+        .and_then(|(delivery, _service)| {
+            assert_eq!(delivery.sent_amount, 100);
+            assert_eq!(delivery.delivered_amount, 100);
+            assert_eq!(
+                delivery.destination_asset,
+                Some(AssetDetails {
+                    asset_code: "XYZ".to_string(),
+                    asset_scale: 9,
+                })
+            );
             Ok(())
         })
         .map_err(|err| panic!(err));
@@ -154,3 +171,95 @@ mod send_money_to_receiver {
         runtime.block_on_all(run).unwrap();
     }
 }
+
+#[cfg(test)]
+mod data_and_money_streams {
+    use super::test_helpers::*;
+    use super::*;
+    use bytes::Bytes;
+    use futures::{Future, Stream};
+    use interledger_ildcp::IldcpService;
+    use interledger_packet::Address;
+    use interledger_packet::{ErrorCode, RejectBuilder};
+    use interledger_router::Router;
+    use interledger_service::outgoing_service_fn;
+    use std::str::FromStr;
+    use tokio::io::{read_exact, write_all};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn sends_data_both_ways_and_money() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Address::from_str("example.receiver").unwrap();
+        let account = TestAccount {
+            id: 0,
+            ilp_address: destination_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (destination_address.to_bytes(), account.clone()),
+        };
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let mut server = StreamReceiverService::new(
+            server_secret,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let connections = server.listen();
+        let server = IldcpService::new(Router::new(store, server));
+
+        let mut runtime = Runtime::new().unwrap();
+
+        // Echo back the data on each stream in reverse, once the money has arrived
+        runtime.spawn(connections.for_each(|connection| {
+            tokio::spawn(connection.map_err(|err| panic!(err)).for_each(|stream| {
+                let money = stream.receive_money().into_future();
+                read_exact(stream.clone(), [0; 5])
+                    .map_err(|err| panic!(err))
+                    .join(money.map_err(|_| panic!("Error receiving money")))
+                    .and_then(move |((_, mut buf), (amount, _))| {
+                        assert_eq!(amount, Some(100));
+                        buf.reverse();
+                        write_all(stream, buf).map_err(|err| panic!(err))
+                    })
+                    .map(|_| ())
+            }));
+            Ok(())
+        }));
+
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address);
+        let run = connect(server, &account, destination_account, &shared_secret[..])
+            .and_then(|connection| {
+                let stream = connection.new_stream();
+                let money = stream.send_money(100);
+                write_all(stream.clone(), b"hello")
+                    .and_then(|(stream, _)| read_exact(stream, [0; 5]))
+                    .map_err(|err| Error::PollError(err.to_string()))
+                    .join(money)
+                    .map(move |((stream, buf), total_sent)| {
+                        assert_eq!(&buf, b"olleh");
+                        assert_eq!(total_sent, 100);
+                        assert_eq!(stream.total_delivered(), 100);
+                        assert_eq!(
+                            connection.remote_asset_details(),
+                            Some(AssetDetails {
+                                asset_code: "XYZ".to_string(),
+                                asset_scale: 9,
+                            })
+                        );
+                        connection.close();
+                    })
+            })
+            .map_err(|err| panic!(err));
+        runtime.block_on(run).unwrap();
+    }
+}
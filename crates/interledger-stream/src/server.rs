@@ -1,8 +1,14 @@
+use super::client::AssetDetails;
+use super::connection::{Connection, ConnectionState};
 use super::crypto::*;
 use super::packet::*;
 use base64;
 use bytes::Bytes;
-use futures::future::result;
+use futures::{
+    future::result,
+    sync::mpsc::{unbounded, UnboundedSender},
+    Stream,
+};
 use hex;
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::{
@@ -11,10 +17,17 @@ use interledger_packet::{
 };
 use interledger_service::{Account, BoxedIlpFuture, OutgoingRequest, OutgoingService};
 use log::{debug, warn};
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const STREAM_SERVER_SECRET_GENERATOR: &[u8] = b"ilp_stream_secret_generator";
+/// How long a connection kept by a listening StreamReceiverService may go without
+/// packets before it is closed and forgotten
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 /// A STREAM connection generator that creates `destination_account` and `shared_secret` values
 /// based on a single root secret.
@@ -90,16 +103,64 @@ impl ConnectionGenerator {
     }
 }
 
+/// The connections a StreamReceiverService is keeping state for, keyed by shared secret,
+/// along with when each last got a packet
+#[derive(Clone)]
+struct ConnectionRegistry {
+    connections: Arc<Mutex<HashMap<[u8; 32], (Connection, Instant)>>>,
+    new_connections: UnboundedSender<Connection>,
+    idle_timeout: Duration,
+}
+
+impl ConnectionRegistry {
+    fn get_or_create(&self, shared_secret: &[u8; 32]) -> Connection {
+        let now = Instant::now();
+        let mut connections = self.connections.lock();
+        if let Some((connection, last_packet)) = connections.get_mut(shared_secret) {
+            *last_packet = now;
+            return connection.clone();
+        }
+
+        // Senders often go away without closing their connections, so the idle ones
+        // are dropped whenever a new one is added
+        let idle_timeout = self.idle_timeout;
+        connections.retain(|_, (connection, last_packet)| {
+            if now.duration_since(*last_packet) >= idle_timeout {
+                debug!("Closing idle STREAM connection");
+                connection.state.lock().time_out();
+                false
+            } else {
+                true
+            }
+        });
+
+        debug!("Accepting new STREAM connection");
+        let connection = Connection::new(ConnectionState::new(true));
+        connections.insert(*shared_secret, (connection.clone(), now));
+        if self
+            .new_connections
+            .unbounded_send(connection.clone())
+            .is_err()
+        {
+            warn!("Got new STREAM connection but nothing is listening for connections");
+        }
+        connection
+    }
+}
+
 /// An OutgoingService that fulfills incoming STREAM packets.
 ///
-/// Note this does **not** maintain STREAM state, but instead fulfills
+/// By default this does **not** maintain STREAM state, but instead fulfills
 /// all incoming packets to collect the money.
 ///
-/// This does not currently support handling data sent via STREAM.
+/// Calling `listen` makes it keep the state of each connection instead, so that
+/// data and money can be received on individual streams.
 #[derive(Clone)]
 pub struct StreamReceiverService<O: OutgoingService<A>, A: Account> {
     connection_generator: ConnectionGenerator,
     next: O,
+    registry: Option<ConnectionRegistry>,
+    idle_timeout: Duration,
     account_type: PhantomData<A>,
 }
 
@@ -113,9 +174,35 @@ where
         StreamReceiverService {
             connection_generator,
             next,
+            registry: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
             account_type: PhantomData,
         }
     }
+
+    /// Set how long a connection may go without packets before it is closed and
+    /// forgotten, when the service is keeping the state of connections. Defaults to 60 seconds
+    pub fn idle_timeout(&mut self, idle_timeout: Duration) -> &mut Self {
+        self.idle_timeout = idle_timeout;
+        if let Some(ref mut registry) = self.registry {
+            registry.idle_timeout = idle_timeout;
+        }
+        self
+    }
+
+    /// Keep the state of each incoming connection and return a Stream of the new connections.
+    ///
+    /// Data sent back on these connections is included in the responses to the sender's packets.
+    /// Connections are forgotten once they are closed or have been idle for the `idle_timeout`.
+    pub fn listen(&mut self) -> impl Stream<Item = Connection, Error = ()> {
+        let (new_connections, incoming) = unbounded();
+        self.registry = Some(ConnectionRegistry {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            new_connections,
+            idle_timeout: self.idle_timeout,
+        });
+        incoming
+    }
 }
 
 // TODO should this be an OutgoingService instead so the balance logic is applied before this is called?
@@ -138,19 +225,39 @@ where
         let dest: &[u8] = destination.as_ref();
         if dest.starts_with(to.as_ref()) {
             if let Ok(shared_secret) = self.connection_generator.rederive_secret(&destination) {
-                {
-                    return Box::new(result(receive_money(&shared_secret, &to, request.prepare)));
+                if let Some(ref registry) = self.registry {
+                    let connection = registry.get_or_create(&shared_secret);
+                    let response = receive_with_connection(
+                        &connection,
+                        &shared_secret,
+                        &to,
+                        request.to.asset_code(),
+                        request.to.asset_scale(),
+                        request.prepare,
+                    );
+                    if connection.is_closed() {
+                        registry.connections.lock().remove(&shared_secret);
+                    }
+                    return Box::new(result(response));
                 }
+                return Box::new(result(receive_money(
+                    &shared_secret,
+                    &to,
+                    request.to.asset_code(),
+                    request.to.asset_scale(),
+                    request.prepare,
+                )));
             }
         }
         Box::new(self.next.send_request(request))
     }
 }
 
-// TODO send asset code and scale back to sender also
 fn receive_money(
     shared_secret: &[u8; 32],
     client_address: &Address,
+    asset_code: &str,
+    asset_scale: u8,
     prepare: Prepare,
 ) -> Result<Fulfill, Reject> {
     // Generate fulfillment
@@ -178,24 +285,133 @@ fn receive_money(
     // Handle STREAM frames
     // TODO reject if they send data?
     for frame in stream_packet.frames() {
-        // Tell the sender the stream can handle lots of money
-        if let Frame::StreamMoney(frame) = frame {
-            response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
-                stream_id: frame.stream_id,
-                // TODO will returning zero here cause problems?
-                total_received: 0,
-                receive_max: u64::max_value(),
-            }));
+        match frame {
+            // Tell the sender the stream can handle lots of money
+            Frame::StreamMoney(frame) => {
+                response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                    stream_id: frame.stream_id,
+                    // TODO will returning zero here cause problems?
+                    total_received: 0,
+                    receive_max: u64::max_value(),
+                }));
+            }
+            // The sender includes its address in its first packets, so that is when we tell it our asset details
+            Frame::ConnectionNewAddress(_) => {
+                response_frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: asset_code,
+                    source_asset_scale: asset_scale,
+                }));
+            }
+            _ => {}
         }
     }
 
-    // Return Fulfill or Reject Packet
-    if is_fulfillable && prepare_amount >= stream_packet.prepare_amount() {
+    let is_acceptable = is_fulfillable && prepare_amount >= stream_packet.prepare_amount();
+    build_response(
+        shared_secret,
+        client_address,
+        &stream_packet,
+        prepare_amount,
+        if is_acceptable {
+            Some(fulfillment)
+        } else {
+            None
+        },
+        &response_frames,
+    )
+}
+
+/// Handle a Prepare for a connection we are keeping state for.
+///
+/// Data and control frames are applied even if the Prepare is rejected, because the
+/// sender uses unfulfillable Prepares to send packets that only carry frames.
+fn receive_with_connection(
+    connection: &Connection,
+    shared_secret: &[u8; 32],
+    client_address: &Address,
+    asset_code: &str,
+    asset_scale: u8,
+    prepare: Prepare,
+) -> Result<Fulfill, Reject> {
+    let fulfillment = generate_fulfillment(&shared_secret[..], prepare.data());
+    let condition = hash_sha256(&fulfillment);
+    let is_fulfillable = condition == prepare.execution_condition();
+
+    let prepare_amount = prepare.amount();
+    let stream_packet =
+        StreamPacket::from_encrypted(shared_secret, prepare.into_data()).map_err(|_| {
+            debug!("Unable to parse data, rejecting Prepare packet");
+            RejectBuilder {
+                code: ErrorCode::F06_UNEXPECTED_PAYMENT,
+                message: b"Could not decrypt data",
+                triggered_by: Some(client_address),
+                data: &[],
+            }
+            .build()
+        })?;
+
+    let mut state = connection.state.lock();
+    let mut is_acceptable = is_fulfillable && prepare_amount >= stream_packet.prepare_amount();
+    if let Err(code) = state.handle_frames(stream_packet.frames()) {
+        state.close_with_error(code, "");
+        is_acceptable = false;
+    }
+
+    let money_streams: Vec<u64> = stream_packet
+        .frames()
+        .filter_map(|frame| match frame {
+            Frame::StreamMoney(frame) => Some(frame.stream_id),
+            _ => None,
+        })
+        .collect();
+    if is_acceptable && !state.is_closed() {
+        match state.allocate_money(stream_packet.frames(), prepare_amount) {
+            Some(allocations) => state.credit_money(&allocations),
+            None => is_acceptable = false,
+        }
+    }
+    let mut response_frames = state.response_frames(&money_streams);
+    drop(state);
+    if stream_packet.frames().any(|frame| match frame {
+        Frame::ConnectionNewAddress(_) => true,
+        _ => false,
+    }) {
+        response_frames.asset_details = Some(AssetDetails {
+            asset_code: asset_code.to_string(),
+            asset_scale,
+        });
+    }
+
+    build_response(
+        shared_secret,
+        client_address,
+        &stream_packet,
+        prepare_amount,
+        if is_acceptable {
+            Some(fulfillment)
+        } else {
+            None
+        },
+        &response_frames.frames(),
+    )
+}
+
+/// Fulfill the Prepare if a fulfillment is given and reject it otherwise,
+/// including an encrypted STREAM packet with the given frames either way.
+fn build_response(
+    shared_secret: &[u8; 32],
+    client_address: &Address,
+    stream_packet: &StreamPacket,
+    prepare_amount: u64,
+    fulfillment: Option<[u8; 32]>,
+    response_frames: &[Frame],
+) -> Result<Fulfill, Reject> {
+    if let Some(fulfillment) = fulfillment {
         let response_packet = StreamPacketBuilder {
             sequence: stream_packet.sequence(),
             ilp_packet_type: IlpPacketType::Fulfill,
             prepare_amount,
-            frames: &response_frames,
+            frames: response_frames,
         }
         .build();
         debug!(
@@ -215,12 +431,10 @@ fn receive_money(
             sequence: stream_packet.sequence(),
             ilp_packet_type: IlpPacketType::Reject,
             prepare_amount,
-            frames: &response_frames,
+            frames: response_frames,
         }
         .build();
-        if !is_fulfillable {
-            debug!("Packet is unfulfillable");
-        } else if prepare_amount < stream_packet.prepare_amount() {
+        if prepare_amount < stream_packet.prepare_amount() {
             debug!(
                 "Received only: {} when we should have received at least: {}",
                 prepare_amount,
@@ -329,10 +543,52 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &client_address, prepare);
+        let result = receive_money(&shared_secret, &client_address, "XYZ", 9, prepare);
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn sends_asset_details_in_response_to_new_address() {
+        let client_address = Address::from_str("example.destination").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&client_address);
+        let stream_packet = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[
+                Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                }),
+                Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                    source_account: Address::from_str("example.sender").unwrap(),
+                }),
+            ],
+        }
+        .build();
+        let data = stream_packet.into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        let prepare = PrepareBuilder {
+            destination: destination_account,
+            amount: 100,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build();
+
+        let fulfill = receive_money(&shared_secret, &client_address, "XYZ", 9, prepare).unwrap();
+        let response = StreamPacket::from_encrypted(&shared_secret, fulfill.into_data()).unwrap();
+        assert!(response.frames().any(|frame| match frame {
+            Frame::ConnectionAssetDetails(frame) => {
+                frame.source_asset_code == "XYZ" && frame.source_asset_scale == 9
+            }
+            _ => false,
+        }));
+    }
+
     #[test]
     fn fulfills_valid_packet_without_connection_tag() {
         let client_address = Address::from_str("example.destination").unwrap();
@@ -357,7 +613,7 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &client_address, prepare);
+        let result = receive_money(&shared_secret, &client_address, "XYZ", 9, prepare);
         assert!(result.is_ok());
     }
 
@@ -386,7 +642,7 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &client_address, prepare);
+        let result = receive_money(&shared_secret, &client_address, "XYZ", 9, prepare);
         assert!(result.is_err());
     }
 
@@ -425,11 +681,47 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &client_address, prepare);
+        let result = receive_money(&shared_secret, &client_address, "XYZ", 9, prepare);
         assert!(result.is_err());
     }
 }
 
+#[cfg(test)]
+mod connection_registry {
+    use super::*;
+
+    #[test]
+    fn closes_idle_connections() {
+        let (new_connections, _incoming) = unbounded();
+        let registry = ConnectionRegistry {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            new_connections,
+            idle_timeout: Duration::from_millis(0),
+        };
+        let idle = registry.get_or_create(&[1; 32]);
+        let active = registry.get_or_create(&[2; 32]);
+        assert!(idle.is_closed());
+        assert!(!active.is_closed());
+        let connections = registry.connections.lock();
+        assert!(!connections.contains_key(&[1; 32]));
+        assert!(connections.contains_key(&[2; 32]));
+    }
+
+    #[test]
+    fn keeps_active_connections() {
+        let (new_connections, _incoming) = unbounded();
+        let registry = ConnectionRegistry {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            new_connections,
+            idle_timeout: Duration::from_secs(60),
+        };
+        let first = registry.get_or_create(&[1; 32]);
+        registry.get_or_create(&[2; 32]);
+        assert!(!first.is_closed());
+        assert_eq!(registry.connections.lock().len(), 2);
+    }
+}
+
 #[cfg(test)]
 mod stream_receiver_service {
     use super::*;
@@ -1,10 +1,10 @@
 use base64;
 use bytes::Bytes;
-use futures::{future::ok, Future};
+use futures::{future::ok, Future, Stream};
 use hyper::{
-    header::{HeaderValue, ACCEPT},
+    header::{HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
     service::{service_fn, Service},
-    Body, Error, Method, Request, Response, Server,
+    Body, Client, Error, Method, Request, Response, Server,
 };
 use interledger_btp::{connect_client, create_open_signup_server, parse_btp_url};
 use interledger_http::{HttpClientService, HttpServerService};
@@ -20,8 +20,17 @@ use lazy_static::lazy_static;
 use log::debug;
 use parking_lot::RwLock;
 use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value};
 use std::str::FromStr;
-use std::{convert::TryFrom, net::SocketAddr, str, sync::Arc, u64};
+use std::{
+    convert::TryFrom,
+    net::SocketAddr,
+    str,
+    sync::Arc,
+    time::{Duration, Instant},
+    u64,
+};
+use tokio::timer::Interval;
 use url::Url;
 
 lazy_static! {
@@ -338,3 +347,98 @@ pub fn run_moneyd_local(
         },
     )
 }
+
+#[doc(hidden)]
+pub fn send_pings(
+    node_url: &str,
+    admin_auth_token: &str,
+    account_id: &str,
+    destination: &str,
+    count: u64,
+    interval: u64,
+    amount: u64,
+) -> impl Future<Item = (), Error = ()> {
+    let url = Url::parse(node_url)
+        .and_then(|url| url.join("ping"))
+        .expect("Invalid node URL");
+    let authorization = format!("Bearer {}", admin_auth_token);
+    let body = json!({
+        "destination": destination,
+        "account_id": account_id,
+        "amount": amount,
+    })
+    .to_string();
+    let destination = destination.to_string();
+    let client = Client::new();
+    println!("Pinging {} from account {}", destination, account_id);
+
+    Interval::new(Instant::now(), Duration::from_millis(interval))
+        .take(count)
+        .map_err(|err| eprintln!("Timer error: {:?}", err))
+        .fold(Vec::new(), move |mut round_trip_times, _| {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url.as_str())
+                .header(AUTHORIZATION, authorization.as_str())
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.clone()))
+                .unwrap();
+            let destination = destination.clone();
+            client
+                .request(request)
+                .and_then(|response| {
+                    let status = response.status();
+                    response
+                        .into_body()
+                        .concat2()
+                        .map(move |body| (status, body))
+                })
+                .then(move |result| {
+                    match result {
+                        Ok((status, body)) => {
+                            let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                            if status.is_success() {
+                                let round_trip_time =
+                                    body["round_trip_time_ms"].as_u64().unwrap_or(0);
+                                println!("Reply from {}: time={}ms", destination, round_trip_time);
+                                round_trip_times.push(round_trip_time);
+                            } else if let Some(code) = body["error"].as_str() {
+                                println!(
+                                    "Ping to {} failed: {} {} (triggered by: {})",
+                                    destination,
+                                    code,
+                                    body["message"].as_str().unwrap_or(""),
+                                    body["triggered_by"].as_str().unwrap_or("unknown"),
+                                );
+                            } else {
+                                println!(
+                                    "Ping to {} failed with HTTP status {}",
+                                    destination, status
+                                );
+                            }
+                        }
+                        Err(err) => eprintln!("Error sending ping request to node: {:?}", err),
+                    }
+                    Ok::<_, ()>(round_trip_times)
+                })
+        })
+        .map(move |round_trip_times| {
+            let received = round_trip_times.len() as u64;
+            println!(
+                "{} pings sent, {} replies received, {}% lost",
+                count,
+                received,
+                ((count - received) * 100).checked_div(count).unwrap_or(0)
+            );
+            if let (Some(min), Some(max), Some(average)) = (
+                round_trip_times.iter().min(),
+                round_trip_times.iter().max(),
+                round_trip_times.iter().sum::<u64>().checked_div(received),
+            ) {
+                println!(
+                    "Round trip time min/avg/max: {}/{}/{} ms",
+                    min, average, max
+                );
+            }
+        })
+}
@@ -81,6 +81,16 @@ pub mod store_memory {
     pub use interledger_store_memory::*;
 }
 
+/// SQL data store
+#[cfg(feature = "store-sql")]
+pub mod store_sql {
+    //! # interledger-store-sql
+    //!
+    //! A store that keeps accounts, balances and routes in SQLite or PostgreSQL
+    //! and updates balances in database transactions.
+    pub use interledger_store_sql::*;
+}
+
 /// Interledger Dynamic Configuration Protocol (ILDCP)
 #[cfg(feature = "ildcp")]
 pub mod ildcp {
@@ -1,12 +1,12 @@
 use base64;
-use clap::value_t;
+use clap::{value_t, values_t};
 use clap::{App, Arg, ArgGroup, SubCommand};
 use config;
-use hex;
+use futures::Future;
 use interledger::{cli::*, node::*};
 use interledger_ildcp::IldcpResponseBuilder;
 use interledger_packet::Address;
-use std::str::FromStr;
+use std::{fs, str::FromStr};
 use tokio;
 use url::Url;
 
@@ -109,23 +109,55 @@ pub fn main() {
                         .short("c")
                         .takes_value(true)
                         .help("Name of config file (in JSON, TOML, YAML, or INI format)"))
+                    .subcommand(SubCommand::with_name("migrate-keys")
+                        .about("Re-encrypt the tokens in the node's Redis store with the keys derived from the current secret_seed, so the previous_secret_seeds can be removed")
+                        .arg(Arg::with_name("remove_stale_tokens")
+                            .long("remove_stale_tokens")
+                            .help("Remove the incoming tokens that are still indexed with keys derived from the previous_secret_seeds. The accounts they belong to need new incoming tokens")))
+                    .subcommand(SubCommand::with_name("export")
+                        .about("Export the accounts, balances, routes and rates of the node's store, with the tokens encrypted using a transport key")
+                        .args(&[
+                            Arg::with_name("transport_key")
+                                .long("transport_key")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Hex-encoded 32-byte key to encrypt the tokens with"),
+                            Arg::with_name("drop_incoming_tokens")
+                                .long("drop_incoming_tokens")
+                                .help("Export even though the store only keeps HMACs of the accounts' incoming tokens, so the accounts need new ones after the import"),
+                            Arg::with_name("output")
+                                .long("output")
+                                .short("o")
+                                .takes_value(true)
+                                .help("File to write the exported state to (defaults to stdout)"),
+                        ]))
+                    .subcommand(SubCommand::with_name("import")
+                        .about("Import a state exported from another node into the node's empty store")
+                        .args(&[
+                            Arg::with_name("transport_key")
+                                .long("transport_key")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Hex-encoded 32-byte key the tokens were encrypted with"),
+                            Arg::with_name("input")
+                                .long("input")
+                                .short("i")
+                                .takes_value(true)
+                                .required(true)
+                                .help("File the state was exported to"),
+                        ]))
                     .subcommand(SubCommand::with_name("accounts")
                         .subcommand(SubCommand::with_name("add")
                         .args(&[
-                            Arg::with_name("redis_uri")
-                                .long("redis_uri")
-                                .help("Redis database to add the account to")
-                                .default_value("redis://127.0.0.1:6379"),
-                            Arg::with_name("server_secret")
-                                .long("server_secret")
-                                .help("Cryptographic seed used to derive keys")
-                                .takes_value(true)
-                                .required(true),
                             Arg::with_name("ilp_address")
                                 .long("ilp_address")
                                 .help("ILP Address of this account")
                                 .takes_value(true)
                                 .required(true),
+                            Arg::with_name("username")
+                                .long("username")
+                                .help("Name used in this account's payment pointer, such as $alice.example.com or $example.com/alice")
+                                .takes_value(true),
                             Arg::with_name("asset_code")
                                 .long("asset_code")
                                 .help("Asset that this account's balance is denominated in")
@@ -170,6 +202,11 @@ pub fn main() {
                                 .long("routing_relation")
                                 .help("Either 'Parent', 'Peer', or 'Child' to indicate our relationship to this account (used for routing)")
                                 .default_value("Child"),
+                            Arg::with_name("allowed_route_prefixes")
+                                .long("allowed_route_prefixes")
+                                .help("Comma-separated ILP address prefixes this account may advertise routes for. Defaults to any prefix for parents and peers, and to the account's own address for children")
+                                .takes_value(true)
+                                .use_delimiter(true),
                             Arg::with_name("min_balance")
                                 .long("min_balance")
                                 .help("Minimum balance this account is allowed to have (can be negative)")
@@ -187,6 +224,43 @@ pub fn main() {
                                 .help("Total amount of value this account can send per minute. Defaults to no limit")
                                 .takes_value(true),
                         ]))),
+                SubCommand::with_name("ping")
+                    .about("Send ILP echo packets through a running node to check whether an address is reachable")
+                    .args(&[
+                        Arg::with_name("destination")
+                            .help("ILP address to ping")
+                            .takes_value(true)
+                            .required(true),
+                        Arg::with_name("node")
+                            .long("node")
+                            .help("URL of the node's HTTP API")
+                            .default_value("http://127.0.0.1:7770"),
+                        Arg::with_name("admin_auth_token")
+                            .long("admin_auth_token")
+                            .help("Admin token of the node's HTTP API")
+                            .takes_value(true)
+                            .required(true),
+                        Arg::with_name("account")
+                            .long("account")
+                            .help("ID of the account the pings should be sent from")
+                            .takes_value(true)
+                            .required(true),
+                        Arg::with_name("count")
+                            .long("count")
+                            .short("c")
+                            .help("Number of pings to send")
+                            .default_value("4"),
+                        Arg::with_name("interval")
+                            .long("interval")
+                            .short("i")
+                            .help("Time to wait between pings (in milliseconds)")
+                            .default_value("1000"),
+                        Arg::with_name("amount")
+                            .long("amount")
+                            .short("a")
+                            .help("Amount to send with each ping, denominated in the account's units")
+                            .default_value("0"),
+                    ]),
         ]);
 
     match app.clone().get_matches().subcommand() {
@@ -268,10 +342,10 @@ pub fn main() {
             _ => app.print_help().unwrap(),
         },
         ("node", Some(matches)) => match matches.subcommand() {
-            ("accounts", Some(matches)) => match matches.subcommand() {
-                ("add", Some(matches)) => {
+            ("accounts", Some(accounts_matches)) => match accounts_matches.subcommand() {
+                ("add", Some(add_matches)) => {
                     let (http_endpoint, http_outgoing_token) =
-                        if let Some(url) = matches.value_of("http_url") {
+                        if let Some(url) = add_matches.value_of("http_url") {
                             let url = Url::parse(url).expect("Invalid URL");
                             let auth = if !url.username().is_empty() {
                                 Some(format!(
@@ -291,74 +365,148 @@ pub fn main() {
                         } else {
                             (None, None)
                         };
-                    let redis_uri =
-                        value_t!(matches, "redis_uri", String).expect("redis_uri is required");
-                    let redis_uri = Url::parse(&redis_uri).expect("redis_uri is not a valid URI");
-                    let server_secret: [u8; 32] = {
-                        let encoded: String = value_t!(matches, "server_secret", String).unwrap();
-                        let mut server_secret = [0; 32];
-                        let decoded =
-                            hex::decode(encoded).expect("server_secret must be hex-encoded");
-                        assert_eq!(decoded.len(), 32, "server_secret must be 32 bytes");
-                        server_secret.clone_from_slice(&decoded);
-                        server_secret
-                    };
                     let account = AccountDetails {
                         ilp_address: Address::from_str(
-                            &value_t!(matches, "ilp_address", String).unwrap(),
+                            &value_t!(add_matches, "ilp_address", String).unwrap(),
                         )
                         .unwrap(),
-                        asset_code: value_t!(matches, "asset_code", String).unwrap(),
-                        asset_scale: value_t!(matches, "asset_scale", u8).unwrap(),
-                        btp_incoming_token: matches
+                        username: add_matches.value_of("username").map(|s| s.to_string()),
+                        asset_code: value_t!(add_matches, "asset_code", String).unwrap(),
+                        asset_scale: value_t!(add_matches, "asset_scale", u8).unwrap(),
+                        btp_incoming_token: add_matches
                             .value_of("btp_incoming_token")
                             .map(|s| s.to_string()),
-                        btp_uri: matches.value_of("btp_uri").map(|s| s.to_string()),
-                        http_incoming_token: matches
+                        btp_uri: add_matches.value_of("btp_uri").map(|s| s.to_string()),
+                        http_incoming_token: add_matches
                             .value_of("http_incoming_token")
                             .map(|s| format!("Bearer {}", s)),
                         http_outgoing_token,
                         http_endpoint,
                         max_packet_amount: u64::max_value(),
-                        min_balance: value_t!(matches, "min_balance", i64).ok(),
-                        settle_threshold: value_t!(matches, "settle_threshold", i64).ok(),
-                        settle_to: value_t!(matches, "settle_to", i64).ok(),
-                        send_routes: matches.is_present("send_routes"),
-                        receive_routes: matches.is_present("receive_routes"),
-                        routing_relation: value_t!(matches, "routing_relation", String).ok(),
-                        round_trip_time: value_t!(matches, "round_trip_time", u64).ok(),
+                        min_balance: value_t!(add_matches, "min_balance", i64).ok(),
+                        settle_threshold: value_t!(add_matches, "settle_threshold", i64).ok(),
+                        settle_to: value_t!(add_matches, "settle_to", i64).ok(),
+                        send_routes: add_matches.is_present("send_routes"),
+                        receive_routes: add_matches.is_present("receive_routes"),
+                        routing_relation: value_t!(add_matches, "routing_relation", String).ok(),
+                        allowed_route_prefixes: values_t!(
+                            add_matches,
+                            "allowed_route_prefixes",
+                            String
+                        )
+                        .ok(),
+                        route_import_deny: None,
+                        route_import_max_path_length: None,
+                        route_export_allow: None,
+                        route_export_deny: None,
+                        route_export_max_path_length: None,
+                        advertise_parent_routes: None,
+                        route_cost: None,
+                        round_trip_time: value_t!(add_matches, "round_trip_time", u64).ok(),
                         packets_per_minute_limit: value_t!(
-                            matches,
+                            add_matches,
                             "packets_per_minute_limit",
                             u32
                         )
                         .ok(),
-                        amount_per_minute_limit: value_t!(matches, "amount_per_minute_limit", u64)
-                            .ok(),
+                        amount_per_minute_limit: value_t!(
+                            add_matches,
+                            "amount_per_minute_limit",
+                            u64
+                        )
+                        .ok(),
                         settlement_engine_url: None,
                         settlement_engine_asset_scale: None,
                     };
-                    tokio::run(insert_account_redis(redis_uri, &server_secret, account));
+                    let node = load_node_config(matches.value_of("config"));
+                    tokio::run(node.insert_account(account));
                 }
                 _ => app.print_help().unwrap(),
             },
+            ("migrate-keys", Some(migrate_matches)) => {
+                let node = load_node_config(matches.value_of("config"));
+                let remove_stale_tokens = migrate_matches.is_present("remove_stale_tokens");
+                tokio::run(node.migrate_keys(remove_stale_tokens));
+            }
+            ("export", Some(export_matches)) => {
+                let node = load_node_config(matches.value_of("config"));
+                let transport_key =
+                    parse_transport_key(export_matches.value_of("transport_key").unwrap())
+                        .expect("transport_key must be 32 bytes, hex-encoded");
+                let output = export_matches.value_of("output").map(String::from);
+                let drop_incoming_tokens = export_matches.is_present("drop_incoming_tokens");
+                tokio::run(
+                    node.export_state(transport_key, drop_incoming_tokens)
+                        .and_then(move |state| {
+                            let json = serde_json::to_string_pretty(&state).unwrap();
+                            if let Some(output) = output {
+                                fs::write(&output, json).map_err(|err| {
+                                    eprintln!("Unable to write {}: {:?}", output, err)
+                                })
+                            } else {
+                                println!("{}", json);
+                                Ok(())
+                            }
+                        }),
+                );
+            }
+            ("import", Some(import_matches)) => {
+                let node = load_node_config(matches.value_of("config"));
+                let transport_key =
+                    parse_transport_key(import_matches.value_of("transport_key").unwrap())
+                        .expect("transport_key must be 32 bytes, hex-encoded");
+                let input = import_matches.value_of("input").unwrap();
+                let state: NodeState = serde_json::from_slice(
+                    &fs::read(input).expect("Unable to read the exported state"),
+                )
+                .expect("Invalid exported state");
+                tokio::run(node.import_state(transport_key, state));
+            }
             _ => {
-                let mut node_config = config::Config::new();
-                if let Some(config_path) = matches.value_of("config") {
-                    node_config
-                        .merge(config::File::with_name(config_path))
-                        .unwrap();
-                }
-                node_config
-                    .merge(config::Environment::with_prefix("ILP"))
-                    .unwrap();
-
-                let node: InterledgerNode = node_config
-                    .try_into()
-                    .expect("Must provide config file name or config environment variables");
+                let node = load_node_config(matches.value_of("config"));
                 node.run();
             }
         },
+        ("ping", Some(matches)) => {
+            let destination =
+                value_t!(matches, "destination", String).expect("destination is required");
+            let node_url = value_t!(matches, "node", String).expect("node is required");
+            let admin_auth_token = value_t!(matches, "admin_auth_token", String)
+                .expect("admin_auth_token is required");
+            let account_id = value_t!(matches, "account", String).expect("account is required");
+            let count = value_t!(matches, "count", u64).expect("Invalid count");
+            // The timer panics if the interval is 0
+            let interval = value_t!(matches, "interval", u64)
+                .ok()
+                .filter(|interval| *interval > 0)
+                .expect("Invalid interval (must be at least 1 millisecond)");
+            let amount = value_t!(matches, "amount", u64).expect("Invalid amount");
+            tokio::run(send_pings(
+                &node_url,
+                &admin_auth_token,
+                &account_id,
+                &destination,
+                count,
+                interval,
+                amount,
+            ));
+        }
         _ => app.print_help().unwrap(),
     }
 }
+
+fn load_node_config(config_path: Option<&str>) -> InterledgerNode {
+    let mut node_config = config::Config::new();
+    if let Some(config_path) = config_path {
+        node_config
+            .merge(config::File::with_name(config_path))
+            .unwrap();
+    }
+    node_config
+        .merge(config::Environment::with_prefix("ILP"))
+        .unwrap();
+
+    node_config
+        .try_into()
+        .expect("Must provide config file name or config environment variables")
+}
@@ -5,7 +5,7 @@ use futures::{
 };
 use interledger::{
     cli,
-    node::{AccountDetails, InterledgerNode},
+    node::{AccountDetails, InterledgerNode, StoreBackend},
 };
 use interledger_packet::Address;
 use log::debug;
@@ -23,21 +23,38 @@ fn btp_end_to_end() {
     let http_port = get_open_port(Some(7770));
     let settlement_port = get_open_port(Some(7771));
     let node = InterledgerNode {
-        ilp_address: Address::from_str("example.node").unwrap(),
+        ilp_address: Some(Address::from_str("example.node").unwrap()),
         default_spsp_account: None,
+        spsp_domain: None,
         admin_auth_token: "admin".to_string(),
+        store: StoreBackend::Redis,
         redis_connection: context.get_client_connection_info(),
+        redis_key_prefix: None,
+        redis_sentinels: Vec::new(),
+        redis_sentinel_master: None,
+        redis_cluster: false,
+        database_url: None,
+        journal_max_entries: None,
         btp_address: ([127, 0, 0, 1], btp_port).into(),
+        forwarding_url: None,
+        forwarding_address: ([127, 0, 0, 1], get_open_port(None)).into(),
         http_address: ([127, 0, 0, 1], http_port).into(),
         settlement_address: ([127, 0, 0, 1], settlement_port).into(),
         secret_seed: cli::random_secret(),
+        previous_secret_seeds: Vec::new(),
         route_broadcast_interval: Some(200),
+        route_expiry_time: None,
+        route_hold_down_time: None,
+        weighted_route_scoring: false,
+        route_failover_attempts: None,
+        route_load_balancing: false,
     };
     let run = ok(()).and_then(move |_| {
         let spawn_connector = ok(tokio::spawn(node.serve())).and_then(move |_| {
             join_all(vec![
                 node.insert_account(AccountDetails {
                     ilp_address: Address::from_str("example.node.one").unwrap(),
+                    username: None,
                     asset_code: "XYZ".to_string(),
                     asset_scale: 9,
                     btp_incoming_token: Some("token-one".to_string()),
@@ -52,6 +69,14 @@ fn btp_end_to_end() {
                     send_routes: false,
                     receive_routes: false,
                     routing_relation: None,
+                    allowed_route_prefixes: None,
+                    route_import_deny: None,
+                    route_import_max_path_length: None,
+                    route_export_allow: None,
+                    route_export_deny: None,
+                    route_export_max_path_length: None,
+                    advertise_parent_routes: None,
+                    route_cost: None,
                     round_trip_time: None,
                     packets_per_minute_limit: None,
                     amount_per_minute_limit: None,
@@ -60,6 +85,7 @@ fn btp_end_to_end() {
                 }),
                 node.insert_account(AccountDetails {
                     ilp_address: Address::from_str("example.node.two").unwrap(),
+                    username: None,
                     asset_code: "XYZ".to_string(),
                     asset_scale: 9,
                     btp_incoming_token: Some("token-two".to_string()),
@@ -74,6 +100,14 @@ fn btp_end_to_end() {
                     send_routes: false,
                     receive_routes: false,
                     routing_relation: None,
+                    allowed_route_prefixes: None,
+                    route_import_deny: None,
+                    route_import_max_path_length: None,
+                    route_export_allow: None,
+                    route_export_deny: None,
+                    route_export_max_path_length: None,
+                    advertise_parent_routes: None,
+                    route_cost: None,
                     round_trip_time: None,
                     packets_per_minute_limit: None,
                     amount_per_minute_limit: None,
@@ -4,7 +4,7 @@ use env_logger;
 use futures::{future::join_all, Future, Stream};
 use interledger::{
     cli,
-    node::{AccountDetails, InterledgerNode},
+    node::{AccountDetails, InterledgerNode, StoreBackend},
 };
 use interledger_packet::Address;
 use serde_json::json;
@@ -43,15 +43,31 @@ fn three_nodes() {
         .unwrap();
 
     let node1 = InterledgerNode {
-        ilp_address: Address::from_str("example.one").unwrap(),
+        ilp_address: Some(Address::from_str("example.one").unwrap()),
         default_spsp_account: Some(0),
+        spsp_domain: None,
         admin_auth_token: "admin".to_string(),
+        store: StoreBackend::Redis,
         redis_connection: connection_info1,
+        redis_key_prefix: None,
+        redis_sentinels: Vec::new(),
+        redis_sentinel_master: None,
+        redis_cluster: false,
+        database_url: None,
+        journal_max_entries: None,
         btp_address: ([127, 0, 0, 1], get_open_port(None)).into(),
+        forwarding_url: None,
+        forwarding_address: ([127, 0, 0, 1], get_open_port(None)).into(),
         http_address: ([127, 0, 0, 1], node1_http).into(),
         settlement_address: ([127, 0, 0, 1], node1_settlement).into(),
         secret_seed: cli::random_secret(),
+        previous_secret_seeds: Vec::new(),
         route_broadcast_interval: Some(200),
+        route_expiry_time: None,
+        route_hold_down_time: None,
+        weighted_route_scoring: false,
+        route_failover_attempts: None,
+        route_load_balancing: false,
     };
     let node1_clone = node1.clone();
     runtime.spawn(
@@ -59,6 +75,7 @@ fn three_nodes() {
         node1_clone
             .insert_account(AccountDetails {
                 ilp_address: Address::from_str("example.one").unwrap(),
+                username: None,
                 asset_code: "XYZ".to_string(),
                 asset_scale: 9,
                 btp_incoming_token: None,
@@ -73,6 +90,14 @@ fn three_nodes() {
                 send_routes: false,
                 receive_routes: false,
                 routing_relation: None,
+                allowed_route_prefixes: None,
+                route_import_deny: None,
+                route_import_max_path_length: None,
+                route_export_allow: None,
+                route_export_deny: None,
+                route_export_max_path_length: None,
+                advertise_parent_routes: None,
+                route_cost: None,
                 round_trip_time: None,
                 packets_per_minute_limit: None,
                 amount_per_minute_limit: None,
@@ -84,6 +109,7 @@ fn three_nodes() {
         node1_clone
             .insert_account(AccountDetails {
                 ilp_address: Address::from_str("example.two").unwrap(),
+                username: None,
                 asset_code: "XYZ".to_string(),
                 asset_scale: 9,
                 btp_incoming_token: None,
@@ -98,6 +124,14 @@ fn three_nodes() {
                 send_routes: true,
                 receive_routes: true,
                 routing_relation: Some("Peer".to_string()),
+                allowed_route_prefixes: None,
+                route_import_deny: None,
+                route_import_max_path_length: None,
+                route_export_allow: None,
+                route_export_deny: None,
+                route_export_max_path_length: None,
+                advertise_parent_routes: None,
+                route_cost: None,
                 round_trip_time: None,
                 packets_per_minute_limit: None,
                 amount_per_minute_limit: None,
@@ -108,20 +142,37 @@ fn three_nodes() {
     );
 
     let node2 = InterledgerNode {
-        ilp_address: Address::from_str("example.two").unwrap(),
+        ilp_address: Some(Address::from_str("example.two").unwrap()),
         default_spsp_account: Some(0),
+        spsp_domain: None,
         admin_auth_token: "admin".to_string(),
+        store: StoreBackend::Redis,
         redis_connection: connection_info2,
+        redis_key_prefix: None,
+        redis_sentinels: Vec::new(),
+        redis_sentinel_master: None,
+        redis_cluster: false,
+        database_url: None,
+        journal_max_entries: None,
         btp_address: ([127, 0, 0, 1], node2_btp).into(),
+        forwarding_url: None,
+        forwarding_address: ([127, 0, 0, 1], get_open_port(None)).into(),
         http_address: ([127, 0, 0, 1], node2_http).into(),
         settlement_address: ([127, 0, 0, 1], node2_settlement).into(),
         secret_seed: cli::random_secret(),
+        previous_secret_seeds: Vec::new(),
         route_broadcast_interval: Some(200),
+        route_expiry_time: None,
+        route_hold_down_time: None,
+        weighted_route_scoring: false,
+        route_failover_attempts: None,
+        route_load_balancing: false,
     };
     runtime.spawn(
         join_all(vec![
             node2.insert_account(AccountDetails {
                 ilp_address: Address::from_str("example.one").unwrap(),
+                username: None,
                 asset_code: "XYZ".to_string(),
                 asset_scale: 9,
                 btp_incoming_token: None,
@@ -136,6 +187,14 @@ fn three_nodes() {
                 send_routes: true,
                 receive_routes: true,
                 routing_relation: Some("Peer".to_string()),
+                allowed_route_prefixes: None,
+                route_import_deny: None,
+                route_import_max_path_length: None,
+                route_export_allow: None,
+                route_export_deny: None,
+                route_export_max_path_length: None,
+                advertise_parent_routes: None,
+                route_cost: None,
                 round_trip_time: None,
                 packets_per_minute_limit: None,
                 amount_per_minute_limit: None,
@@ -144,6 +203,7 @@ fn three_nodes() {
             }),
             node2.insert_account(AccountDetails {
                 ilp_address: Address::from_str("example.two.three").unwrap(),
+                username: None,
                 asset_code: "ABC".to_string(),
                 asset_scale: 6,
                 btp_incoming_token: Some("three".to_string()),
@@ -158,6 +218,14 @@ fn three_nodes() {
                 send_routes: true,
                 receive_routes: false,
                 routing_relation: Some("Child".to_string()),
+                allowed_route_prefixes: None,
+                route_import_deny: None,
+                route_import_max_path_length: None,
+                route_export_allow: None,
+                route_export_deny: None,
+                route_export_max_path_length: None,
+                advertise_parent_routes: None,
+                route_cost: None,
                 round_trip_time: None,
                 packets_per_minute_limit: None,
                 amount_per_minute_limit: None,
@@ -183,15 +251,31 @@ fn three_nodes() {
     );
 
     let node3 = InterledgerNode {
-        ilp_address: Address::from_str("example.two.three").unwrap(),
+        ilp_address: Some(Address::from_str("example.two.three").unwrap()),
         default_spsp_account: Some(0),
+        spsp_domain: None,
         admin_auth_token: "admin".to_string(),
+        store: StoreBackend::Redis,
         redis_connection: connection_info3,
+        redis_key_prefix: None,
+        redis_sentinels: Vec::new(),
+        redis_sentinel_master: None,
+        redis_cluster: false,
+        database_url: None,
+        journal_max_entries: None,
         btp_address: ([127, 0, 0, 1], get_open_port(None)).into(),
+        forwarding_url: None,
+        forwarding_address: ([127, 0, 0, 1], get_open_port(None)).into(),
         http_address: ([127, 0, 0, 1], node3_http).into(),
         settlement_address: ([127, 0, 0, 1], node3_settlement).into(),
         secret_seed: cli::random_secret(),
+        previous_secret_seeds: Vec::new(),
         route_broadcast_interval: Some(200),
+        route_expiry_time: None,
+        route_hold_down_time: None,
+        weighted_route_scoring: false,
+        route_failover_attempts: None,
+        route_load_balancing: false,
     };
     let node3_clone = node3.clone();
     runtime.spawn(
@@ -200,6 +284,7 @@ fn three_nodes() {
             join_all(vec![
                 node3_clone.insert_account(AccountDetails {
                     ilp_address: Address::from_str("example.two.three").unwrap(),
+                    username: None,
                     asset_code: "ABC".to_string(),
                     asset_scale: 6,
                     btp_incoming_token: None,
@@ -214,6 +299,14 @@ fn three_nodes() {
                     send_routes: false,
                     receive_routes: false,
                     routing_relation: None,
+                    allowed_route_prefixes: None,
+                    route_import_deny: None,
+                    route_import_max_path_length: None,
+                    route_export_allow: None,
+                    route_export_deny: None,
+                    route_export_max_path_length: None,
+                    advertise_parent_routes: None,
+                    route_cost: None,
                     round_trip_time: None,
                     packets_per_minute_limit: None,
                     amount_per_minute_limit: None,
@@ -222,6 +315,7 @@ fn three_nodes() {
                 }),
                 node3_clone.insert_account(AccountDetails {
                     ilp_address: Address::from_str("example.two").unwrap(),
+                    username: None,
                     asset_code: "ABC".to_string(),
                     asset_scale: 6,
                     btp_incoming_token: None,
@@ -236,6 +330,14 @@ fn three_nodes() {
                     send_routes: false,
                     receive_routes: true,
                     routing_relation: Some("Parent".to_string()),
+                    allowed_route_prefixes: None,
+                    route_import_deny: None,
+                    route_import_max_path_length: None,
+                    route_export_allow: None,
+                    route_export_deny: None,
+                    route_export_max_path_length: None,
+                    advertise_parent_routes: None,
+                    route_cost: None,
                     round_trip_time: None,
                     packets_per_minute_limit: None,
                     amount_per_minute_limit: None,